@@ -0,0 +1,100 @@
+//! interpreter measures the instruction throughput of the Chip8
+//! interpreter, with and without the decoded instruction cache, and of
+//! the threaded code backend and, with the jit feature, the native code
+//! compiler.
+//!
+//! Run with `cargo bench --bench interpreter`, adding `--features jit`
+//! for the native code compiler.
+
+use rustyemulator::chip8::chip8::Chip8;
+#[cfg(feature = "jit")]
+use rustyemulator::chip8::cpu::jit::Jit;
+use rustyemulator::chip8::cpu::Backend;
+use std::time::{Duration, Instant};
+
+// Instructions executed for each frame.
+const INSTRUCTIONS_PER_FRAME: u32 = 10_000;
+// Frames executed for each measurement.
+const FRAMES: u32 = 200;
+// Measurements taken for each configuration, keeping the fastest.
+const SAMPLES: u32 = 5;
+
+const ROMS: [(&str, &[u8]); 4] = [
+  ("BRIX", include_bytes!("../games/BRIX")),
+  ("INVADERS", include_bytes!("../games/INVADERS")),
+  ("PONG", include_bytes!("../games/PONG")),
+  ("TETRIS", include_bytes!("../games/TETRIS")),
+];
+
+// Mode is a way of executing instructions.
+#[derive(Clone, Copy)]
+enum Mode {
+  Uncached,
+  Cached,
+  Threaded,
+  #[cfg(feature = "jit")]
+  Jit,
+}
+
+const MODES: &[(&str, Mode)] = &[
+  ("uncached", Mode::Uncached),
+  ("cached", Mode::Cached),
+  ("threaded", Mode::Threaded),
+  #[cfg(feature = "jit")]
+  ("jit", Mode::Jit),
+];
+
+// run returns the fastest time taken to execute `FRAMES` frames of
+// `rom` with `mode`.
+fn run(rom: &[u8], mode: Mode) -> Duration {
+  (0..SAMPLES)
+    .map(|_| {
+      let mut chip8 = Chip8::new();
+      chip8.set_instructions_per_frame(INSTRUCTIONS_PER_FRAME);
+      chip8.set_decode_cache(!matches!(mode, Mode::Uncached));
+      if let Mode::Threaded = mode {
+        chip8.set_backend(Backend::Threaded);
+      }
+      chip8.load_program(rom).unwrap();
+      #[cfg(feature = "jit")]
+      let mut jit = Jit::new();
+      let start = Instant::now();
+      for _ in 0..FRAMES {
+        let result = match mode {
+          #[cfg(feature = "jit")]
+          Mode::Jit => chip8.run_frame_jit(&mut jit),
+          _ => chip8.run_frame(),
+        };
+        if let Err(err) = result {
+          panic!("{}", err);
+        }
+      }
+      start.elapsed()
+    })
+    .min()
+    .unwrap()
+}
+
+fn instructions_per_second(duration: Duration) -> f64 {
+  (INSTRUCTIONS_PER_FRAME * FRAMES) as f64 / duration.as_secs_f64()
+}
+
+fn main() {
+  print!("{:<10}", "ROM");
+  for (name, _) in MODES.iter() {
+    print!(" {:>16}", format!("{} inst/s", name));
+  }
+  println!(" {:>8}", "speedup");
+  for (name, rom) in ROMS.iter() {
+    print!("{:<10}", name);
+    let durations: Vec<Duration> = MODES.iter().map(|(_, mode)| run(rom, *mode)).collect();
+    for duration in durations.iter() {
+      print!(" {:>16.0}", instructions_per_second(*duration));
+    }
+    let fastest = durations.iter().min().unwrap();
+    println!(
+      " {:>7.2}x",
+      durations[0].as_secs_f64() / fastest.as_secs_f64()
+    );
+  }
+}
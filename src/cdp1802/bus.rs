@@ -0,0 +1,58 @@
+//! bus provides the interface between the CDP1802 and the rest of the
+//! system it is part of: memory, the I/O ports, the external flags and
+//! the Q output.
+
+/// Bus is implemented by the system the CDP1802 is connected to.
+///
+/// Only memory access is required. The I/O ports read as zero, writes
+/// to them are ignored and the external flags are unset by default.
+pub trait Bus {
+  /// read returns the byte at `address`.
+  fn read(&mut self, address: u16) -> u8;
+
+  /// write stores `value` at `address`.
+  fn write(&mut self, address: u16, value: u8);
+
+  /// output is called by `OUT port` (61-67), with the byte read from
+  /// memory at R(X).
+  fn output(&mut self, _port: u8, _value: u8) {}
+
+  /// input is called by `INP port` (69-6F) and returns the byte that is
+  /// stored at R(X) and in D.
+  fn input(&mut self, _port: u8) -> u8 {
+    0
+  }
+
+  /// flag returns whether the external flag EF1-EF4 (`flag` 1-4) is
+  /// set.
+  fn flag(&mut self, _flag: u8) -> bool {
+    false
+  }
+
+  /// set_q is called when the Q output is changed by REQ or SEQ.
+  fn set_q(&mut self, _q: bool) {}
+}
+
+/// Memory is a Bus with only memory attached. Addresses wrap around
+/// the memory, so its length must be a power of two.
+pub struct Memory<'a> {
+  memory: &'a mut [u8],
+}
+
+impl<'a> Memory<'a> {
+  pub fn new(memory: &'a mut [u8]) -> Self {
+    debug_assert!(memory.len().is_power_of_two());
+    Memory { memory }
+  }
+}
+
+impl<'a> Bus for Memory<'a> {
+  fn read(&mut self, address: u16) -> u8 {
+    self.memory[address as usize & (self.memory.len() - 1)]
+  }
+
+  fn write(&mut self, address: u16, value: u8) {
+    let mask = self.memory.len() - 1;
+    self.memory[address as usize & mask] = value
+  }
+}
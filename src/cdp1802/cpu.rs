@@ -0,0 +1,734 @@
+//! cpu provides the emulation of the RCA CDP1802 microprocessor, as
+//! used in the COSMAC VIP.
+//!
+//! References include:
+//! * RCA CDP1802 datasheet, "Instruction Summary".
+//! * RCA MPM-201 User Manual for the CDP1802 COSMAC Microprocessor.
+
+use crate::cdp1802::bus::Bus;
+use crate::interface::emulator::Clocked;
+use crate::interface::serialization::Savable;
+use std::convert::Infallible;
+
+/// Clock rate, in hertz, of the COSMAC VIP's CDP1802.
+pub const VIP_CLOCK_RATE: u32 = 1_760_640;
+/// Clock cycles per machine cycle. Instructions take two machine cycles,
+/// except for the long branches and skips (C0-CF) which take three.
+pub const CLOCKS_PER_MACHINE_CYCLE: u32 = 8;
+
+/// Registers is the register file of the CDP1802.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Hash)]
+pub struct Registers {
+  /// Scratchpad registers R0-RF. R(P) is the program counter, R(X) the
+  /// data pointer and R0 the DMA pointer.
+  pub r: [u16; 16],
+  /// Designates which of R0-RF is the program counter.
+  pub p: u8,
+  /// Designates which of R0-RF is the data pointer.
+  pub x: u8,
+  /// Data register (accumulator).
+  pub d: u8,
+  /// Data flag, the carry of arithmetic and shifts.
+  pub df: bool,
+  /// Holds X and P on interrupts, as (X << 4) | P.
+  pub t: u8,
+  /// Interrupt enable.
+  pub ie: bool,
+  /// Q output flip-flop.
+  pub q: bool,
+  /// Set by IDL until the next interrupt or DMA cycle.
+  pub idle: bool,
+}
+
+impl Savable for Registers {
+  fn save(&self, fh: &mut dyn std::io::Write) -> std::io::Result<()> {
+    self.r.save(fh)?;
+    self.p.save(fh)?;
+    self.x.save(fh)?;
+    self.d.save(fh)?;
+    self.df.save(fh)?;
+    self.t.save(fh)?;
+    self.ie.save(fh)?;
+    self.q.save(fh)?;
+    self.idle.save(fh)?;
+    Ok(())
+  }
+  fn load(&mut self, fh: &mut dyn std::io::Read) -> std::io::Result<()> {
+    self.r.load(fh)?;
+    self.p.load(fh)?;
+    self.x.load(fh)?;
+    self.d.load(fh)?;
+    self.df.load(fh)?;
+    self.t.load(fh)?;
+    self.ie.load(fh)?;
+    self.q.load(fh)?;
+    self.idle.load(fh)?;
+    Ok(())
+  }
+}
+
+impl Default for Registers {
+  fn default() -> Self {
+    Registers::new()
+  }
+}
+
+impl Registers {
+  /// new returns the registers after a reset: X, P and R0 are zero and
+  /// interrupts are enabled.
+  pub fn new() -> Self {
+    Registers {
+      r: [0; 16],
+      p: 0,
+      x: 0,
+      d: 0,
+      df: false,
+      t: 0,
+      ie: true,
+      q: false,
+      idle: false,
+    }
+  }
+}
+
+/// Cdp1802 is an emulation component for the CDP1802, connected to the
+/// system through `bus`.
+#[derive(Clone, Debug)]
+pub struct Cdp1802<B: Bus> {
+  registers: Registers,
+  bus: B,
+  // Machine cycles left of the instruction currently executing.
+  busy_cycles: u32,
+  // Level of the INT input, which is driven by the system every cycle
+  // and so is not saved.
+  interrupt_line: bool,
+  clock_rate: u32,
+}
+
+// The bus is owned (or borrowed) state of the surrounding system, which
+// saves it, so only the CPU's own state is saved here.
+impl<B: Bus> Savable for Cdp1802<B> {
+  fn save(&self, fh: &mut dyn std::io::Write) -> std::io::Result<()> {
+    self.registers.save(fh)?;
+    self.busy_cycles.save(fh)?;
+    Ok(())
+  }
+  fn load(&mut self, fh: &mut dyn std::io::Read) -> std::io::Result<()> {
+    self.registers.load(fh)?;
+    self.busy_cycles.load(fh)?;
+    Ok(())
+  }
+}
+
+impl<B: Bus> Clocked for Cdp1802<B> {
+  type Fault = Infallible;
+
+  /// clock executes one machine cycle. An instruction executes on its
+  /// first machine cycle, and its remaining machine cycles are spent
+  /// busy. Between instructions, a pending interrupt is taken instead,
+  /// which takes one machine cycle.
+  fn clock(&mut self) {
+    if self.busy_cycles > 0 {
+      self.busy_cycles -= 1;
+      return;
+    }
+    if self.interrupt_line && self.interrupt() {
+      return;
+    }
+    self.busy_cycles = self.step() - 1;
+  }
+
+  /// clock_rate returns the machine cycle rate, which is the clock
+  /// rate of the crystal divided by `CLOCKS_PER_MACHINE_CYCLE`.
+  fn clock_rate(&self) -> u32 {
+    self.clock_rate / CLOCKS_PER_MACHINE_CYCLE
+  }
+
+  fn busy_cycles(&self) -> u32 {
+    self.busy_cycles
+  }
+}
+
+impl<B: Bus> Cdp1802<B> {
+  /// new returns a CDP1802 in its reset state, clocked at the COSMAC
+  /// VIP's clock rate.
+  pub fn new(bus: B) -> Self {
+    Cdp1802::with_registers(Registers::new(), bus)
+  }
+
+  /// with_registers returns a CDP1802 resuming from `registers`.
+  pub fn with_registers(registers: Registers, bus: B) -> Self {
+    Cdp1802 {
+      registers,
+      bus,
+      busy_cycles: 0,
+      interrupt_line: false,
+      clock_rate: VIP_CLOCK_RATE,
+    }
+  }
+
+  /// set_clock_rate sets the crystal clock rate, in hertz.
+  pub fn set_clock_rate(&mut self, clock_rate: u32) {
+    self.clock_rate = clock_rate
+  }
+
+  pub fn registers(&self) -> &Registers {
+    &self.registers
+  }
+
+  pub fn registers_mut(&mut self) -> &mut Registers {
+    &mut self.registers
+  }
+
+  pub fn bus(&self) -> &B {
+    &self.bus
+  }
+
+  pub fn bus_mut(&mut self) -> &mut B {
+    &mut self.bus
+  }
+
+  /// into_registers releases the bus, and returns the registers so that
+  /// execution can later be resumed with `with_registers`.
+  pub fn into_registers(self) -> Registers {
+    self.registers
+  }
+
+  /// reset puts the CPU in its reset state. D, DF, T and R1-RF are left
+  /// unchanged, as on the real CPU.
+  pub fn reset(&mut self) {
+    self.registers.x = 0;
+    self.registers.p = 0;
+    self.registers.r[0] = 0;
+    self.registers.ie = true;
+    self.set_q(false);
+    self.registers.idle = false;
+    self.busy_cycles = 0;
+  }
+
+  /// set_interrupt_line sets the level of the INT input, which `clock`
+  /// samples between instructions.
+  pub fn set_interrupt_line(&mut self, level: bool) {
+    self.interrupt_line = level
+  }
+
+  /// interrupt requests an interrupt. It is taken if interrupts are
+  /// enabled: X and P are saved in T, P is set to 1 and X to 2, and
+  /// interrupts are disabled. Returns whether the interrupt was taken.
+  pub fn interrupt(&mut self) -> bool {
+    if !self.registers.ie {
+      return false;
+    }
+    self.registers.t = (self.registers.x << 4) | self.registers.p;
+    self.registers.p = 1;
+    self.registers.x = 2;
+    self.registers.ie = false;
+    self.registers.idle = false;
+    true
+  }
+
+  /// dma_out performs an output DMA cycle: the byte at R0 is read and
+  /// returned, and R0 is incremented.
+  pub fn dma_out(&mut self) -> u8 {
+    let address = self.registers.r[0];
+    self.registers.r[0] = address.wrapping_add(1);
+    self.registers.idle = false;
+    self.bus.read(address)
+  }
+
+  /// dma_in performs an input DMA cycle: `value` is stored at R0, and R0
+  /// is incremented.
+  pub fn dma_in(&mut self, value: u8) {
+    let address = self.registers.r[0];
+    self.registers.r[0] = address.wrapping_add(1);
+    self.registers.idle = false;
+    self.bus.write(address, value)
+  }
+
+  /// step executes a single instruction, and returns the number of
+  /// machine cycles it took. While idle, no instruction is executed and
+  /// the CPU spends two machine cycles waiting.
+  pub fn step(&mut self) -> u32 {
+    if self.registers.idle {
+      return 2;
+    }
+    let opcode = self.fetch();
+    let n = (opcode & 0xF) as usize;
+    match opcode >> 4 {
+      0x0 => {
+        if n == 0 {
+          // IDL
+          self.registers.idle = true
+        } else {
+          // LDN
+          self.registers.d = self.bus.read(self.registers.r[n])
+        }
+      }
+      // INC
+      0x1 => self.registers.r[n] = self.registers.r[n].wrapping_add(1),
+      // DEC
+      0x2 => self.registers.r[n] = self.registers.r[n].wrapping_sub(1),
+      0x3 => self.short_branch(n),
+      0x4 => {
+        // LDA
+        self.registers.d = self.bus.read(self.registers.r[n]);
+        self.registers.r[n] = self.registers.r[n].wrapping_add(1)
+      }
+      // STR
+      0x5 => self.bus.write(self.registers.r[n], self.registers.d),
+      0x6 => self.input_output(n),
+      0x7 => self.control_and_carry(n),
+      // GLO
+      0x8 => self.registers.d = self.registers.r[n] as u8,
+      // GHI
+      0x9 => self.registers.d = (self.registers.r[n] >> 8) as u8,
+      // PLO
+      0xA => self.registers.r[n] = (self.registers.r[n] & 0xFF00) | self.registers.d as u16,
+      // PHI
+      0xB => {
+        self.registers.r[n] = (self.registers.r[n] & 0x00FF) | ((self.registers.d as u16) << 8)
+      }
+      0xC => {
+        self.long_branch(n);
+        return 3;
+      }
+      // SEP
+      0xD => self.registers.p = n as u8,
+      // SEX
+      0xE => self.registers.x = n as u8,
+      _ => self.logic_and_arithmetic(n),
+    }
+    2
+  }
+
+  // fetch reads the byte at R(P), and increments R(P).
+  fn fetch(&mut self) -> u8 {
+    let p = self.registers.p as usize;
+    let address = self.registers.r[p];
+    self.registers.r[p] = address.wrapping_add(1);
+    self.bus.read(address)
+  }
+
+  // rx returns R(X).
+  fn rx(&self) -> u16 {
+    self.registers.r[self.registers.x as usize]
+  }
+
+  fn increment_rx(&mut self) {
+    let x = self.registers.x as usize;
+    self.registers.r[x] = self.registers.r[x].wrapping_add(1)
+  }
+
+  fn set_q(&mut self, q: bool) {
+    self.registers.q = q;
+    self.bus.set_q(q)
+  }
+
+  // condition returns the condition tested by the branches 30-37 and
+  // C0-C3, selected by the low 3 bits of `n`.
+  fn condition(&mut self, n: usize) -> bool {
+    match n & 0x7 {
+      0 => true,
+      1 => self.registers.q,
+      2 => self.registers.d == 0,
+      3 => self.registers.df,
+      flag => self.bus.flag(flag as u8 - 3),
+    }
+  }
+
+  // short_branch executes 3N: branches to the byte at R(P) within the
+  // current page when the condition holds (inverted for 38-3F, where 38
+  // is SKP).
+  fn short_branch(&mut self, n: usize) {
+    let taken = if n == 0x8 {
+      false
+    } else {
+      self.condition(n) != (n >= 0x8)
+    };
+    let p = self.registers.p as usize;
+    if taken {
+      let target = self.bus.read(self.registers.r[p]);
+      self.registers.r[p] = (self.registers.r[p] & 0xFF00) | target as u16
+    } else {
+      self.registers.r[p] = self.registers.r[p].wrapping_add(1)
+    }
+  }
+
+  // long_branch executes CN: the long branches C0-C3 and C8-CB, and the
+  // long skips C4-C7 and CC-CF (C4 is NOP).
+  fn long_branch(&mut self, n: usize) {
+    let p = self.registers.p as usize;
+    let is_skip = n & 0x4 != 0;
+    if !is_skip {
+      // C8 is LSKP, a "long branch never" which skips the address.
+      let taken = n != 0x8 && self.condition(n) != (n >= 0x8);
+      if taken {
+        let high = self.bus.read(self.registers.r[p]) as u16;
+        let low = self.bus.read(self.registers.r[p].wrapping_add(1)) as u16;
+        self.registers.r[p] = (high << 8) | low
+      } else {
+        self.registers.r[p] = self.registers.r[p].wrapping_add(2)
+      }
+      return;
+    }
+    let skip = match n {
+      // NOP
+      0x4 => false,
+      // LSNQ, LSNZ, LSNF
+      0x5 => !self.registers.q,
+      0x6 => self.registers.d != 0,
+      0x7 => !self.registers.df,
+      // LSIE, LSQ, LSZ, LSDF
+      0xC => self.registers.ie,
+      0xD => self.registers.q,
+      0xE => self.registers.d == 0,
+      _ => self.registers.df,
+    };
+    if skip {
+      self.registers.r[p] = self.registers.r[p].wrapping_add(2)
+    }
+  }
+
+  // input_output executes 6N: IRX, OUT 1-7 and INP 1-7. 68 is not an
+  // instruction on the CDP1802 and does nothing.
+  fn input_output(&mut self, n: usize) {
+    match n {
+      0x0 => self.increment_rx(),
+      0x1..=0x7 => {
+        let value = self.bus.read(self.rx());
+        self.increment_rx();
+        self.bus.output(n as u8, value)
+      }
+      0x8 => {}
+      _ => {
+        let value = self.bus.input(n as u8 - 8);
+        self.bus.write(self.rx(), value);
+        self.registers.d = value
+      }
+    }
+  }
+
+  // control_and_carry executes 7N.
+  fn control_and_carry(&mut self, n: usize) {
+    match n {
+      // RET and DIS
+      0x0 | 0x1 => {
+        let value = self.bus.read(self.rx());
+        self.increment_rx();
+        self.registers.x = value >> 4;
+        self.registers.p = value & 0xF;
+        self.registers.ie = n == 0x0
+      }
+      // LDXA
+      0x2 => {
+        self.registers.d = self.bus.read(self.rx());
+        self.increment_rx()
+      }
+      // STXD
+      0x3 => {
+        self.bus.write(self.rx(), self.registers.d);
+        let x = self.registers.x as usize;
+        self.registers.r[x] = self.registers.r[x].wrapping_sub(1)
+      }
+      // ADC, SDB, SMB
+      0x4 => {
+        let value = self.bus.read(self.rx());
+        self.add(value, self.registers.df)
+      }
+      0x5 => {
+        let value = self.bus.read(self.rx());
+        self.subtract(value, self.registers.d, self.registers.df)
+      }
+      0x7 => {
+        let value = self.bus.read(self.rx());
+        self.subtract(self.registers.d, value, self.registers.df)
+      }
+      // SHRC
+      0x6 => {
+        let carry = self.registers.df as u8;
+        self.registers.df = self.registers.d & 0x01 != 0;
+        self.registers.d = (self.registers.d >> 1) | (carry << 7)
+      }
+      // SAV
+      0x8 => self.bus.write(self.rx(), self.registers.t),
+      // MARK
+      0x9 => {
+        self.registers.t = (self.registers.x << 4) | self.registers.p;
+        self.bus.write(self.registers.r[2], self.registers.t);
+        self.registers.x = self.registers.p;
+        self.registers.r[2] = self.registers.r[2].wrapping_sub(1)
+      }
+      // REQ, SEQ
+      0xA => self.set_q(false),
+      0xB => self.set_q(true),
+      // ADCI, SDBI, SMBI
+      0xC => {
+        let value = self.fetch();
+        self.add(value, self.registers.df)
+      }
+      0xD => {
+        let value = self.fetch();
+        self.subtract(value, self.registers.d, self.registers.df)
+      }
+      0xF => {
+        let value = self.fetch();
+        self.subtract(self.registers.d, value, self.registers.df)
+      }
+      // SHLC
+      _ => {
+        let carry = self.registers.df as u8;
+        self.registers.df = self.registers.d & 0x80 != 0;
+        self.registers.d = (self.registers.d << 1) | carry
+      }
+    }
+  }
+
+  // logic_and_arithmetic executes FN. F0-F7 operate on the byte at R(X)
+  // and F8-FF on the immediate byte.
+  fn logic_and_arithmetic(&mut self, n: usize) {
+    if n == 0x6 {
+      // SHR
+      self.registers.df = self.registers.d & 0x01 != 0;
+      self.registers.d >>= 1;
+      return;
+    }
+    if n == 0xE {
+      // SHL
+      self.registers.df = self.registers.d & 0x80 != 0;
+      self.registers.d <<= 1;
+      return;
+    }
+    let value = if n < 0x8 {
+      self.bus.read(self.rx())
+    } else {
+      self.fetch()
+    };
+    match n & 0x7 {
+      // LDX, LDI
+      0x0 => self.registers.d = value,
+      // OR, ORI
+      0x1 => self.registers.d |= value,
+      // AND, ANI
+      0x2 => self.registers.d &= value,
+      // XOR, XRI
+      0x3 => self.registers.d ^= value,
+      // ADD, ADI
+      0x4 => self.add(value, false),
+      // SD, SDI
+      0x5 => self.subtract(value, self.registers.d, true),
+      // SM, SMI
+      _ => self.subtract(self.registers.d, value, true),
+    }
+  }
+
+  // add sets D to D + value + carry, and DF to the carry out.
+  fn add(&mut self, value: u8, carry: bool) {
+    let sum = self.registers.d as u16 + value as u16 + carry as u16;
+    self.registers.d = sum as u8;
+    self.registers.df = sum > 0xFF
+  }
+
+  // subtract sets D to minuend - subtrahend, borrowing when `no_borrow`
+  // is unset, and DF to 1 when there was no borrow out.
+  fn subtract(&mut self, minuend: u8, subtrahend: u8, no_borrow: bool) {
+    let difference = minuend as i16 - subtrahend as i16 - (!no_borrow) as i16;
+    self.registers.d = difference as u8;
+    self.registers.df = difference >= 0
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use crate::cdp1802::bus::Memory;
+  use crate::interface::serialization;
+
+  // run executes `program` from address 0 until it executes IDL, and
+  // returns the registers and memory.
+  fn run(program: &[u8]) -> (Registers, [u8; 256]) {
+    let mut memory = [0u8; 256];
+    memory[..program.len()].copy_from_slice(program);
+    let mut cpu = Cdp1802::new(Memory::new(&mut memory));
+    for _ in 0..1000 {
+      cpu.step();
+      if cpu.registers().idle {
+        break;
+      }
+    }
+    let registers = cpu.into_registers();
+    (registers, memory)
+  }
+
+  #[test]
+  fn serialization_roundtrip_works() -> std::io::Result<()> {
+    let buf = &mut Vec::new();
+    let mut registers = Registers::new();
+    registers.r[3] = 0x1234;
+    registers.df = true;
+    registers.save(buf)?;
+    let loaded = serialization::read_value::<Registers>(&mut buf.as_slice())?;
+    assert_eq!(registers, loaded);
+    Ok(())
+  }
+
+  #[test]
+  fn register_transfers_work() {
+    // LDI 12; PHI R5; LDI 34; PLO R5; INC R5; GLO R5; IDL
+    let (registers, _) = run(&[0xF8, 0x12, 0xB5, 0xF8, 0x34, 0xA5, 0x15, 0x85, 0x00]);
+    assert_eq!(registers.r[5], 0x1235);
+    assert_eq!(registers.d, 0x35);
+  }
+
+  #[test]
+  fn memory_reference_works() {
+    // LDI 80; PLO R6; LDI AB; STR R6; LDI 0; LDA R6; IDL
+    let (registers, memory) = run(&[0xF8, 0x80, 0xA6, 0xF8, 0xAB, 0x56, 0xF8, 0x00, 0x46, 0x00]);
+    assert_eq!(memory[0x80], 0xAB);
+    assert_eq!(registers.d, 0xAB);
+    assert_eq!(registers.r[6], 0x81);
+  }
+
+  #[test]
+  fn arithmetic_sets_data_flag() {
+    // LDI F0; ADI 20; IDL
+    let (registers, _) = run(&[0xF8, 0xF0, 0xFC, 0x20, 0x00]);
+    assert_eq!(registers.d, 0x10);
+    assert!(registers.df);
+    // LDI 10; SMI 20; IDL
+    let (registers, _) = run(&[0xF8, 0x10, 0xFF, 0x20, 0x00]);
+    assert_eq!(registers.d, 0xF0);
+    assert!(!registers.df);
+    // LDI 10; SDI 20; IDL
+    let (registers, _) = run(&[0xF8, 0x10, 0xFD, 0x20, 0x00]);
+    assert_eq!(registers.d, 0x10);
+    assert!(registers.df);
+    // LDI 81; SHR; SHRC; IDL
+    let (registers, _) = run(&[0xF8, 0x81, 0xF6, 0x76, 0x00]);
+    assert_eq!(registers.d, 0xA0);
+    assert!(!registers.df);
+  }
+
+  #[test]
+  fn branches_work() {
+    // LDI 0; BZ 06; LDI FF; IDL; (06) LDI 01; BNZ 0B; IDL; ... (0B) IDL
+    let (registers, _) = run(&[
+      0xF8, 0x00, 0x32, 0x06, 0xF8, 0xFF, 0xF8, 0x01, 0x3A, 0x0B, 0x00, 0x00,
+    ]);
+    assert_eq!(registers.d, 0x01);
+    assert_eq!(registers.r[0], 0x0C);
+    // LBR 0010; ... (10) IDL
+    let mut program = [0u8; 0x11];
+    program[..3].copy_from_slice(&[0xC0, 0x00, 0x10]);
+    let (registers, _) = run(&program);
+    assert_eq!(registers.r[0], 0x11);
+  }
+
+  #[test]
+  fn long_instructions_take_three_cycles() {
+    let mut memory = [0xC4u8, 0xE2, 0x00, 0x00];
+    let mut cpu = Cdp1802::new(Memory::new(&mut memory));
+    assert_eq!(cpu.step(), 3);
+    assert_eq!(cpu.step(), 2);
+  }
+
+  #[test]
+  fn clock_spends_remaining_cycles_busy() {
+    // INC R1; INC R1
+    let mut memory = [0x11u8, 0x11, 0x00, 0x00];
+    let mut cpu = Cdp1802::new(Memory::new(&mut memory));
+    cpu.clock();
+    assert_eq!(cpu.registers().r[1], 1);
+    assert_eq!(cpu.busy_cycles(), 1);
+    cpu.clock();
+    assert_eq!(cpu.registers().r[1], 1);
+    assert_eq!(cpu.busy_cycles(), 0);
+    cpu.clock();
+    assert_eq!(cpu.registers().r[1], 2);
+  }
+
+  #[test]
+  fn mark_and_return_work() {
+    // LDI 80; PLO R2; SEX R2; MARK (stores X = 2, P = 0 at 0x80, and
+    // sets X = P = 0); INC R2; SEX R2; RET (restores X and P); IDL
+    let (registers, memory) = run(&[0xF8, 0x80, 0xA2, 0xE2, 0x79, 0x12, 0xE2, 0x70, 0x00]);
+    assert_eq!(memory[0x80], 0x20);
+    assert_eq!(registers.x, 2);
+    assert_eq!(registers.p, 0);
+    assert!(registers.ie);
+  }
+
+  #[test]
+  fn interrupts_and_dma_work() {
+    let mut memory = [0u8; 16];
+    memory[4] = 0x5A;
+    let mut cpu = Cdp1802::new(Memory::new(&mut memory));
+    cpu.registers_mut().r[0] = 4;
+    assert_eq!(cpu.dma_out(), 0x5A);
+    assert_eq!(cpu.registers().r[0], 5);
+
+    cpu.registers_mut().x = 3;
+    cpu.registers_mut().p = 4;
+    assert!(cpu.interrupt());
+    assert_eq!(cpu.registers().t, 0x34);
+    assert_eq!((cpu.registers().x, cpu.registers().p), (2, 1));
+    assert!(!cpu.interrupt());
+  }
+
+  #[test]
+  fn interrupt_line_is_sampled_between_instructions() {
+    // LBR 0000, which takes three machine cycles.
+    let mut memory = [0xC0u8, 0x00, 0x00, 0x00];
+    let mut cpu = Cdp1802::new(Memory::new(&mut memory));
+    cpu.clock();
+    cpu.set_interrupt_line(true);
+    cpu.clock();
+    cpu.clock();
+    assert_eq!(cpu.registers().p, 0);
+    cpu.clock();
+    assert_eq!(cpu.registers().p, 1);
+    assert!(!cpu.registers().ie);
+  }
+
+  #[test]
+  fn io_uses_bus() {
+    struct Io {
+      memory: [u8; 16],
+      output: Option<(u8, u8)>,
+    }
+    impl Bus for Io {
+      fn read(&mut self, address: u16) -> u8 {
+        self.memory[address as usize & 0xF]
+      }
+      fn write(&mut self, address: u16, value: u8) {
+        self.memory[address as usize & 0xF] = value
+      }
+      fn output(&mut self, port: u8, value: u8) {
+        self.output = Some((port, value))
+      }
+      fn input(&mut self, port: u8) -> u8 {
+        port * 0x11
+      }
+      fn flag(&mut self, flag: u8) -> bool {
+        flag == 3
+      }
+    }
+    // SEX R1; (R1=0) OUT 2 (outputs 0xE1 and R1=1); INP 3; B3 0A; IDL;
+    // (0A) SEQ; IDL
+    let mut memory = [0u8; 16];
+    memory[..0xC].copy_from_slice(&[
+      0xE1, 0x62, 0x6B, 0x36, 0x0A, 0x00, 0x00, 0x00, 0x00, 0x00, 0x7B, 0x00,
+    ]);
+    let mut cpu = Cdp1802::new(Io {
+      memory,
+      output: None,
+    });
+    for _ in 0..6 {
+      cpu.step();
+    }
+    assert_eq!(cpu.bus().output, Some((2, 0xE1)));
+    assert_eq!(cpu.registers().d, 0x33);
+    assert!(cpu.registers().q);
+    assert!(cpu.registers().idle);
+  }
+}
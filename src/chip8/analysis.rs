@@ -0,0 +1,411 @@
+//! analysis guesses the platform and quirks a ROM was written for, from
+//! the instructions `chip8::flow` finds it can execute, for ROMs that
+//! the database does not know.
+//!
+//! SCHIP and XO-CHIP instructions show which platform a ROM needs. The
+//! quirks are guessed from instructions that only make sense with one
+//! behaviour: shifts of another register, which the original shift
+//! reads, shifts with Y left as 0, which CHIP-48 programs wrote as it
+//! ignores Vy, uses of I straight after FX55 or FX65, which need I left
+//! incremented, and the register a BNNN jump table sets up. Sprites
+//! drawn across the edge of the display are reported too, as they look
+//! different when clipped and wrapped. None of this is certain, so the
+//! suggestion comes with a confidence.
+
+use crate::chip8::cpu::PROGRAM_START;
+use crate::chip8::flow::ControlFlow;
+use crate::chip8::instruction::{parse_instruction, Instruction};
+use crate::chip8::platform::Platform;
+use crate::chip8::quirks::Quirks;
+use std::fmt;
+
+// Instructions after FX55 or FX65 searched for a use of I.
+const I_USE_WINDOW: u16 = 8;
+
+/// Evidence is something found in a ROM that suggests its platform or
+/// quirks.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum Evidence {
+  /// An instruction only SCHIP has.
+  SchipInstruction { opcode: u16 },
+  /// An instruction only XO-CHIP has.
+  XoChipInstruction { opcode: u16 },
+  /// A ROM too large for 4 KiB of memory.
+  LargeRom { size: usize },
+  /// 8XY6 or 8XYE with X and Y different, which only make sense if the
+  /// shift reads Vy.
+  ShiftOfOtherRegister { opcode: u16 },
+  /// 8X06 or 8X0E with X not 0, as written for CHIP-48, which shifts Vx
+  /// in place.
+  ShiftIgnoringVy { opcode: u16 },
+  /// I is used after FX55 or FX65 without being set again, which needs
+  /// it left incremented.
+  IncrementedIUsed,
+  /// BNNN after setting `register`, which is V0 for the original jump
+  /// and VX for SCHIP's BXNN.
+  JumpTable { register: u8 },
+  /// A sprite drawn across the edge of the display, which differs when
+  /// sprites are clipped rather than wrapped.
+  SpriteAcrossEdge,
+  /// An instruction that no platform has.
+  UnknownInstruction { opcode: u16 },
+}
+
+/// Finding is evidence found at an address.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub struct Finding {
+  pub address: u16,
+  pub evidence: Evidence,
+}
+
+impl fmt::Display for Finding {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{:#05X}: ", self.address)?;
+    match self.evidence {
+      Evidence::SchipInstruction { opcode } => write!(f, "SCHIP instruction {:04X}", opcode),
+      Evidence::XoChipInstruction { opcode } => write!(f, "XO-CHIP instruction {:04X}", opcode),
+      Evidence::LargeRom { size } => write!(f, "{} bytes only fit in XO-CHIP memory", size),
+      Evidence::ShiftOfOtherRegister { opcode } => {
+        write!(f, "{:04X} shifts another register into Vx", opcode)
+      }
+      Evidence::ShiftIgnoringVy { opcode } => write!(f, "{:04X} shifts Vx in place", opcode),
+      Evidence::IncrementedIUsed => write!(f, "I is used as left by a load or store"),
+      Evidence::JumpTable { register } => write!(f, "jump table indexed by V{:X}", register),
+      Evidence::SpriteAcrossEdge => write!(f, "sprite drawn across the edge of the display"),
+      Evidence::UnknownInstruction { opcode } => write!(f, "unknown instruction {:04X}", opcode),
+    }
+  }
+}
+
+/// Analysis is the platform and quirks suggested for a ROM.
+#[derive(PartialEq, Clone, Debug)]
+pub struct Analysis {
+  pub platform: Platform,
+  pub quirks: Quirks,
+  /// How likely the suggestion is to be right, from 0 to 1.
+  pub confidence: f32,
+  /// What the suggestion is based on, in the order found.
+  pub findings: Vec<Finding>,
+}
+
+impl Analysis {
+  /// analyse suggests the platform and quirks of `rom`, loaded at
+  /// 0x200.
+  pub fn analyse(rom: &[u8]) -> Analysis {
+    let findings = find(rom);
+    let has = |check: fn(&Evidence) -> bool| findings.iter().any(|f| check(&f.evidence));
+
+    let (platform, mut quirks, mut confidence) = if has(|e| {
+      matches!(
+        e,
+        Evidence::XoChipInstruction { .. } | Evidence::LargeRom { .. }
+      )
+    }) {
+      (Platform::XoChip, Quirks::xo_chip(), 0.9)
+    } else if has(|e| matches!(e, Evidence::SchipInstruction { .. })) {
+      (Platform::Schip, Quirks::schip(), 0.8)
+    } else if has(|e| matches!(e, Evidence::ShiftIgnoringVy { .. })) {
+      (Platform::Schip, Quirks::chip48(), 0.5)
+    } else {
+      // Most ROMs without newer instructions were written for the VIP.
+      (Platform::Chip8, Quirks::cosmac_vip(), 0.5)
+    };
+
+    // Each kind of evidence moves the confidence once: up when it agrees
+    // with the quirks of the platform, and down when they are changed to
+    // follow it.
+    let mut weigh = |agrees: bool| confidence += if agrees { 0.1 } else { -0.2 };
+    if has(|e| matches!(e, Evidence::ShiftIgnoringVy { .. })) {
+      weigh(!quirks.shift_uses_vy);
+    }
+    if has(|e| matches!(e, Evidence::ShiftOfOtherRegister { .. })) {
+      weigh(quirks.shift_uses_vy);
+      quirks.shift_uses_vy = true;
+    }
+    if has(|e| *e == Evidence::IncrementedIUsed) {
+      weigh(quirks.memory_increments_i);
+      quirks.memory_increments_i = true;
+    }
+    let mut jump_registers = findings.iter().filter_map(|f| match f.evidence {
+      Evidence::JumpTable { register } => Some(register),
+      _ => None,
+    });
+    if let Some(register) = jump_registers.next_back() {
+      let uses_vx = register != 0;
+      weigh(quirks.jump_uses_vx == uses_vx);
+      quirks.jump_uses_vx = uses_vx;
+    }
+    let unknown = findings
+      .iter()
+      .filter(|f| matches!(f.evidence, Evidence::UnknownInstruction { .. }))
+      .count();
+    confidence -= 0.1 * unknown.min(3) as f32;
+
+    Analysis {
+      platform,
+      quirks,
+      confidence: confidence.clamp(0.0, 1.0),
+      findings,
+    }
+  }
+}
+
+// find returns the evidence in the code of `rom` that `ControlFlow`
+// finds.
+fn find(rom: &[u8]) -> Vec<Finding> {
+  let mut findings = Vec::new();
+  if rom.len() > Platform::Chip8.max_program_size() {
+    findings.push(Finding {
+      address: PROGRAM_START,
+      evidence: Evidence::LargeRom { size: rom.len() },
+    });
+  }
+  let opcode_at = |address: u16| {
+    let offset = (address - PROGRAM_START) as usize;
+    rom
+      .get(offset..offset + 2)
+      .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+  };
+  let flow = ControlFlow::recover(rom);
+  for block in flow.blocks() {
+    // Values of the registers set to constants in the block so far.
+    let mut constants: [Option<u8>; 16] = [None; 16];
+    // Register written last in the block so far.
+    let mut last_set: Option<u8> = None;
+    for address in (block.start..block.end()).step_by(2) {
+      let opcode = match opcode_at(address) {
+        Some(opcode) => opcode,
+        None => break,
+      };
+      let mut found = |evidence| findings.push(Finding { address, evidence });
+      if let Some(evidence) = platform_evidence(opcode) {
+        found(evidence);
+      }
+      let instr = match parse_instruction(opcode) {
+        Ok(instr) => instr,
+        Err(_) => {
+          if platform_evidence(opcode).is_none() {
+            found(Evidence::UnknownInstruction { opcode });
+          }
+          break;
+        }
+      };
+      match instr {
+        Instruction::BitShiftRightVar {
+          x_register,
+          y_register,
+        }
+        | Instruction::BitShiftLeftVar {
+          x_register,
+          y_register,
+        } if x_register != y_register => found(if y_register == 0 {
+          Evidence::ShiftIgnoringVy { opcode }
+        } else {
+          Evidence::ShiftOfOtherRegister { opcode }
+        }),
+        Instruction::MemoryDump { .. } | Instruction::MemoryLoad { .. }
+          if uses_i_next(address, &opcode_at) =>
+        {
+          found(Evidence::IncrementedIUsed)
+        }
+        Instruction::FlowJumpToAddressPlusVar { .. } => {
+          if let Some(register) = last_set {
+            let x = (opcode >> 8 & 0xF) as u8;
+            if register == 0 || register == x {
+              found(Evidence::JumpTable { register });
+            }
+          }
+        }
+        Instruction::DisplayDraw {
+          x_register,
+          y_register,
+          constant,
+        } => {
+          let height = if constant == 0 { 16 } else { constant as usize };
+          if let (Some(x), Some(y)) = (constants[x_register], constants[y_register]) {
+            if x as usize % 64 > 56 || y as usize % 32 + height > 32 {
+              found(Evidence::SpriteAcrossEdge);
+            }
+          }
+        }
+        _ => {}
+      }
+      for register in written_registers(&instr) {
+        constants[register] = match instr {
+          Instruction::ConstSetVar { constant, .. } => Some(constant),
+          Instruction::ConstIncrementVar { constant, .. } => {
+            constants[register].map(|value| value.wrapping_add(constant))
+          }
+          _ => None,
+        };
+        last_set = Some(register as u8);
+      }
+    }
+  }
+  findings
+}
+
+// platform_evidence returns the platform `opcode` is only on, if any.
+fn platform_evidence(opcode: u16) -> Option<Evidence> {
+  let schip = Some(Evidence::SchipInstruction { opcode });
+  let xo_chip = Some(Evidence::XoChipInstruction { opcode });
+  match (opcode >> 12, opcode & 0xFF) {
+    (0x0, 0xFB..=0xFF) if opcode & 0xF00 == 0 => schip,
+    (0x0, 0xC1..=0xCF) if opcode & 0xF00 == 0 => schip,
+    (0x0, 0xD1..=0xDF) if opcode & 0xF00 == 0 => xo_chip,
+    (0xD, _) if opcode & 0xF == 0 => schip,
+    (0xF, 0x30) | (0xF, 0x75) | (0xF, 0x85) => schip,
+    (0x5, _) if matches!(opcode & 0xF, 0x2 | 0x3) => xo_chip,
+    (0xF, 0x00) if opcode == 0xF000 => xo_chip,
+    (0xF, 0x01) | (0xF, 0x3A) => xo_chip,
+    (0xF, 0x02) if opcode == 0xF002 => xo_chip,
+    _ => None,
+  }
+}
+
+// uses_i_next returns whether the instructions after a load or store at
+// `address` use I before setting it or leaving the straight line.
+fn uses_i_next(address: u16, opcode_at: &dyn Fn(u16) -> Option<u16>) -> bool {
+  use Instruction::*;
+  for next in 1..=I_USE_WINDOW {
+    let instr = match opcode_at(address + next * 2).map(parse_instruction) {
+      Some(Ok(instr)) => instr,
+      _ => return false,
+    };
+    match instr {
+      DisplayDraw { .. }
+      | MemoryDump { .. }
+      | MemoryLoad { .. }
+      | LoadBinaryCodedDecimal { .. }
+      | MemoryAddVerToAddress { .. } => return true,
+      MemorySetAddress { .. }
+      | MemorySetToVarSpriteLocation { .. }
+      | FlowJumpToAddress { .. }
+      | FlowJumpToAddressPlusVar { .. }
+      | FlowSubroutineCall { .. }
+      | FlowSubroutineReturn()
+      | CondSkipIfEqualConst { .. }
+      | CondSkipIfNotEqualConst { .. }
+      | CondSkipIfEqualVar { .. }
+      | CondSkipIfNotEqualVar { .. }
+      | InputKeyIsPressed { .. }
+      | InputKeyIsNotPressed { .. } => return false,
+      _ => {}
+    }
+  }
+  false
+}
+
+// written_registers returns the registers `instr` writes, besides vF as
+// a flag.
+fn written_registers(instr: &Instruction) -> std::ops::Range<usize> {
+  use Instruction::*;
+  match *instr {
+    ConstSetVar { x_register, .. }
+    | ConstIncrementVar { x_register, .. }
+    | AssignVar { x_register, .. }
+    | BitwiseOrVar { x_register, .. }
+    | BitwiseAndVar { x_register, .. }
+    | BitwiseXorVar { x_register, .. }
+    | MathAddVar { x_register, .. }
+    | MathSubVar { x_register, .. }
+    | BitShiftRightVar { x_register, .. }
+    | MathReverseSubtractVar { x_register, .. }
+    | BitShiftLeftVar { x_register, .. }
+    | RandomByConstant { x_register, .. }
+    | TimerGetDelay { x_register }
+    | InputKeyAwaitPress { x_register } => x_register..x_register + 1,
+    MemoryLoad { x_register } => 0..x_register + 1,
+    _ => 0..0,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn evidence(rom: &[u8]) -> Vec<Evidence> {
+    find(rom).iter().map(|finding| finding.evidence).collect()
+  }
+
+  #[test]
+  fn newer_instructions_choose_the_platform() {
+    // 0x200: hires; 0x202: jump 0x202.
+    let schip = Analysis::analyse(&[0x00, 0xFF, 0x12, 0x02]);
+    assert_eq!(schip.platform, Platform::Schip);
+    assert_eq!(schip.quirks, Quirks::schip());
+    assert_eq!(
+      schip.findings,
+      vec![Finding {
+        address: 0x200,
+        evidence: Evidence::SchipInstruction { opcode: 0x00FF }
+      }]
+    );
+    // 0x200: save v1 - v2, which is XO-CHIP's and ends the flow.
+    let xo_chip = Analysis::analyse(&[0x51, 0x22]);
+    assert_eq!(xo_chip.platform, Platform::XoChip);
+    assert!(xo_chip.confidence > schip.confidence);
+    let large = Analysis::analyse(&vec![0x12; 4000]);
+    assert_eq!(large.platform, Platform::XoChip);
+  }
+
+  #[test]
+  fn quirk_patterns_are_found() {
+    let rom = [
+      0x81, 0x26, // 0x200: v1 >>= v2
+      0xF2, 0x65, // 0x202: load v2
+      0xD0, 0x15, // 0x204: sprite v0 v1 5, with I left by the load
+      0x61, 0x02, // 0x206: v1 := 2
+      0xB1, 0x00, // 0x208: jump0 0x100, indexed by v1
+    ];
+    assert_eq!(
+      evidence(&rom),
+      vec![
+        Evidence::ShiftOfOtherRegister { opcode: 0x8126 },
+        Evidence::IncrementedIUsed,
+        Evidence::JumpTable { register: 1 },
+      ]
+    );
+    let analysis = Analysis::analyse(&rom);
+    assert_eq!(analysis.platform, Platform::Chip8);
+    assert_eq!(
+      analysis.quirks,
+      Quirks {
+        jump_uses_vx: true,
+        ..Quirks::cosmac_vip()
+      }
+    );
+    // The shift and load agree with the VIP, the jump table does not.
+    assert!((analysis.confidence - 0.5).abs() < 1e-6);
+  }
+
+  #[test]
+  fn shifts_written_for_chip48_suggest_it() {
+    // 0x200: v1 >>= v0; 0x202: jump 0x202.
+    let analysis = Analysis::analyse(&[0x81, 0x06, 0x12, 0x02]);
+    assert_eq!(
+      analysis.findings[0].evidence,
+      Evidence::ShiftIgnoringVy { opcode: 0x8106 }
+    );
+    assert_eq!(analysis.platform, Platform::Schip);
+    assert_eq!(analysis.quirks, Quirks::chip48());
+  }
+
+  #[test]
+  fn sprites_across_edges_and_unknown_instructions_are_found() {
+    let rom = [
+      0x60, 0x3C, // 0x200: v0 := 60
+      0x61, 0x00, // 0x202: v1 := 0
+      0xD0, 0x15, // 0x204: sprite v0 v1 5
+      0xE0, 0x00, // 0x206: not an instruction
+    ];
+    assert_eq!(
+      evidence(&rom),
+      vec![
+        Evidence::SpriteAcrossEdge,
+        Evidence::UnknownInstruction { opcode: 0xE000 }
+      ]
+    );
+    assert!(Analysis::analyse(&rom).confidence < 0.5);
+  }
+}
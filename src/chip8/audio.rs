@@ -0,0 +1,414 @@
+//! audio synthesises the sound of the Chip8 buzzer, which sounds while
+//! the sound timer is not zero.
+//!
+//! The buzzer is a square wave. Its edges are band-limited with
+//! PolyBLEP, so that the wave does not alias at the sample rate, and it
+//! fades in and out over a couple of milliseconds, so that starting and
+//! stopping it does not click.
+//!
+//! XO-CHIP programs can instead load a pattern of 128 one bit samples
+//! with F002, which is played in a loop at a rate set by FX3A. Patterns
+//! are band-limited by averaging the pattern over each output sample.
+
+use crate::interface::audio::AudioSink;
+use crate::interface::serialization::Savable;
+
+/// Sample rate used when none is configured.
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+/// Pitch, in hertz, used when none is configured.
+pub const DEFAULT_PITCH: f32 = 440.0;
+
+// Frames per second, at which the sound timer counts down.
+const FRAME_RATE: u64 = 60;
+// Peak amplitude of the square wave.
+const VOLUME: f32 = 0.25;
+// Seconds taken to fade in and out.
+const FADE_SECONDS: f32 = 0.002;
+
+/// Pitch of patterns until FX3A sets one, at which they play at 4000
+/// samples per second.
+pub const DEFAULT_PATTERN_PITCH: u8 = 64;
+/// Size, in bytes, of an audio pattern.
+pub const PATTERN_SIZE: usize = 16;
+// Number of one bit samples in an audio pattern.
+const PATTERN_BITS: usize = PATTERN_SIZE * 8;
+
+/// Sound is what the buzzer plays.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Hash)]
+pub enum Sound {
+  Silent,
+  /// A square wave at the Beeper's pitch.
+  Beep,
+  /// The XO-CHIP audio pattern, at `pitch`.
+  Pattern {
+    pattern: [u8; PATTERN_SIZE],
+    pitch: u8,
+  },
+}
+
+/// pattern_rate returns the samples per second at which patterns are
+/// played at `pitch`.
+pub fn pattern_rate(pitch: u8) -> f32 {
+  4000.0 * 2f32.powf((pitch as f32 - 64.0) / 48.0)
+}
+
+/// Beeper produces the samples of the buzzer, a frame at a time.
+///
+/// Its sample rate and pitch are configuration, so are not saved, but
+/// where it is in the wave and fade is.
+#[derive(Clone, Debug)]
+pub struct Beeper {
+  sample_rate: u32,
+  pitch: f32,
+  // Position in the current cycle of the wave, from 0 up to 1.
+  phase: f32,
+  // Position in the audio pattern, in samples of the pattern from 0
+  // up to PATTERN_BITS.
+  position: f32,
+  // Amplitude of the fade, from 0 up to 1.
+  gain: f32,
+  // Last sound that was not silent, which is faded out.
+  audible: Sound,
+  // Frames produced so far.
+  frames: u64,
+  // Samples of the frame being produced, kept to reuse the buffer.
+  samples: Vec<f32>,
+}
+
+impl Beeper {
+  // state returns what the Beeper compares and hashes by: all but the
+  // sample buffer, with floats by their bits.
+  fn state(&self) -> (u32, [u32; 4], Sound, u64) {
+    let floats = [self.pitch, self.phase, self.position, self.gain];
+    (
+      self.sample_rate,
+      floats.map(f32::to_bits),
+      self.audible,
+      self.frames,
+    )
+  }
+}
+
+impl PartialEq for Beeper {
+  fn eq(&self, other: &Self) -> bool {
+    self.state() == other.state()
+  }
+}
+
+impl Eq for Beeper {}
+
+impl std::hash::Hash for Beeper {
+  fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+    self.state().hash(state)
+  }
+}
+
+impl Savable for Beeper {
+  fn save(&self, fh: &mut dyn std::io::Write) -> std::io::Result<()> {
+    self.phase.save(fh)?;
+    self.position.save(fh)?;
+    self.gain.save(fh)?;
+    let (tag, pattern, pitch) = match self.audible {
+      Sound::Silent => (0u8, [0; PATTERN_SIZE], 0),
+      Sound::Beep => (1, [0; PATTERN_SIZE], 0),
+      Sound::Pattern { pattern, pitch } => (2, pattern, pitch),
+    };
+    tag.save(fh)?;
+    pattern.save(fh)?;
+    pitch.save(fh)?;
+    self.frames.save(fh)?;
+    Ok(())
+  }
+  fn load(&mut self, fh: &mut dyn std::io::Read) -> std::io::Result<()> {
+    self.phase.load(fh)?;
+    self.position.load(fh)?;
+    self.gain.load(fh)?;
+    let (mut tag, mut pattern, mut pitch) = (0u8, [0; PATTERN_SIZE], 0u8);
+    tag.load(fh)?;
+    pattern.load(fh)?;
+    pitch.load(fh)?;
+    self.audible = match tag {
+      0 => Sound::Silent,
+      1 => Sound::Beep,
+      _ => Sound::Pattern { pattern, pitch },
+    };
+    self.frames.load(fh)?;
+    Ok(())
+  }
+}
+
+impl Default for Beeper {
+  fn default() -> Self {
+    Beeper::new(DEFAULT_SAMPLE_RATE, DEFAULT_PITCH)
+  }
+}
+
+impl Beeper {
+  /// new returns a silent Beeper producing `sample_rate` samples a
+  /// second, which beeps at `pitch` hertz.
+  ///
+  /// Panics if the sample rate is zero, or the pitch is not below half
+  /// the sample rate.
+  pub fn new(sample_rate: u32, pitch: f32) -> Self {
+    assert!(sample_rate > 0, "sample rate must not be zero");
+    let mut beeper = Beeper {
+      sample_rate,
+      pitch: 0.0,
+      phase: 0.0,
+      position: 0.0,
+      gain: 0.0,
+      audible: Sound::Beep,
+      frames: 0,
+      samples: Vec::new(),
+    };
+    beeper.set_pitch(pitch);
+    beeper
+  }
+
+  pub fn sample_rate(&self) -> u32 {
+    self.sample_rate
+  }
+
+  pub fn pitch(&self) -> f32 {
+    self.pitch
+  }
+
+  /// set_pitch changes the pitch of the beep, in hertz.
+  ///
+  /// Panics if the pitch is not above zero and below half the sample
+  /// rate.
+  pub fn set_pitch(&mut self, pitch: f32) {
+    assert!(
+      pitch > 0.0 && pitch < self.sample_rate as f32 / 2.0,
+      "pitch {} is out of range for sample rate {}",
+      pitch,
+      self.sample_rate
+    );
+    self.pitch = pitch
+  }
+
+  /// samples_in_frame returns the number of samples the next frame
+  /// has. Frames have whole numbers of samples, which add up to exactly
+  /// the sample rate each second.
+  pub fn samples_in_frame(&self) -> usize {
+    let rate = self.sample_rate as u64;
+    let (start, end) = (self.frames * rate, (self.frames + 1) * rate);
+    (end / FRAME_RATE - start / FRAME_RATE) as usize
+  }
+
+  /// run_frame pushes the samples of the next frame to `sink`, playing
+  /// `sound`.
+  pub fn run_frame(&mut self, sound: Sound, sink: &mut dyn AudioSink) {
+    let count = self.samples_in_frame();
+    self.frames += 1;
+    let rate = self.sample_rate as f32;
+    let step = self.pitch / rate;
+    let fade = 1.0 / (FADE_SECONDS * rate);
+    let target = if sound == Sound::Silent { 0.0 } else { 1.0 };
+    if sound != Sound::Silent {
+      self.audible = sound;
+    }
+
+    self.samples.clear();
+    for _ in 0..count {
+      self.gain = if self.gain < target {
+        (self.gain + fade).min(target)
+      } else {
+        (self.gain - fade).max(target)
+      };
+      if self.gain == 0.0 {
+        // Start the next sound at the beginning.
+        self.phase = 0.0;
+        self.position = 0.0;
+        self.samples.push(0.0);
+        continue;
+      }
+      let wave = match self.audible {
+        Sound::Pattern { pattern, pitch } => {
+          let width = pattern_rate(pitch) / rate;
+          let wave = 2.0 * pattern_average(&pattern, self.position, width) - 1.0;
+          self.position = (self.position + width) % PATTERN_BITS as f32;
+          wave
+        }
+        Sound::Beep | Sound::Silent => {
+          let square = if self.phase < 0.5 { 1.0 } else { -1.0 };
+          let falling = (self.phase + 0.5) % 1.0;
+          let wave = square + poly_blep(self.phase, step) - poly_blep(falling, step);
+          self.phase = (self.phase + step) % 1.0;
+          wave
+        }
+      };
+      self.samples.push(wave * self.gain * VOLUME);
+    }
+    sink.push_samples(&self.samples);
+  }
+}
+
+// pattern_average returns the average of the one bit samples of
+// `pattern` from `start` for `width` samples, as the fraction set.
+fn pattern_average(pattern: &[u8; PATTERN_SIZE], start: f32, width: f32) -> f32 {
+  let (mut position, end) = (start, start + width);
+  let mut set = 0.0;
+  while position < end {
+    let next = (position.floor() + 1.0).min(end);
+    let bit = position as usize % PATTERN_BITS;
+    if pattern[bit / 8] & (0x80 >> (bit % 8)) != 0 {
+      set += next - position;
+    }
+    position = next;
+  }
+  set / width
+}
+
+// poly_blep returns the correction to a step of 2 at phase 0, for a wave
+// at `phase` that advances `step` each sample.
+fn poly_blep(phase: f32, step: f32) -> f32 {
+  if phase < step {
+    let t = phase / step;
+    2.0 * t - t * t - 1.0
+  } else if phase > 1.0 - step {
+    let t = (phase - 1.0) / step;
+    t * t + 2.0 * t + 1.0
+  } else {
+    0.0
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn frames_have_exact_sample_counts() {
+    let mut beeper = Beeper::new(44100, DEFAULT_PITCH);
+    let mut samples = Vec::new();
+    for _ in 0..3 {
+      assert_eq!(beeper.samples_in_frame(), 735);
+      beeper.run_frame(Sound::Beep, &mut samples);
+    }
+    assert_eq!(samples.len(), 3 * 735);
+
+    // 22050 / 60 is 367.5 samples, so frames alternate.
+    let mut beeper = Beeper::new(22050, DEFAULT_PITCH);
+    let mut counts = Vec::new();
+    let mut samples = Vec::new();
+    for frame in 0..60 {
+      let before = samples.len();
+      let sound = if frame % 2 == 0 {
+        Sound::Beep
+      } else {
+        Sound::Silent
+      };
+      beeper.run_frame(sound, &mut samples);
+      counts.push(samples.len() - before);
+    }
+    assert_eq!(&counts[..4], &[367, 368, 367, 368]);
+    assert_eq!(samples.len(), 22050);
+  }
+
+  #[test]
+  fn saved_beepers_carry_on_the_wave() -> std::io::Result<()> {
+    let mut beeper = Beeper::new(48000, 1000.0);
+    let mut samples = Vec::new();
+    beeper.run_frame(Sound::Beep, &mut samples);
+    let mut buf = Vec::new();
+    beeper.save(&mut buf)?;
+    let mut loaded = Beeper::new(48000, 1000.0);
+    assert_ne!(loaded, beeper);
+    loaded.load(&mut buf.as_slice())?;
+    assert_eq!(loaded, beeper);
+
+    let (mut played, mut replayed) = (Vec::new(), Vec::new());
+    beeper.run_frame(Sound::Silent, &mut played);
+    loaded.run_frame(Sound::Silent, &mut replayed);
+    assert_eq!(played, replayed);
+    Ok(())
+  }
+
+  #[test]
+  fn silence_is_zero() {
+    let mut beeper = Beeper::default();
+    let mut samples = Vec::new();
+    beeper.run_frame(Sound::Silent, &mut samples);
+    assert!(samples.iter().all(|&sample| sample == 0.0));
+  }
+
+  #[test]
+  fn beeps_fade_without_clicks() {
+    let mut beeper = Beeper::new(48000, 1000.0);
+    let mut samples = Vec::new();
+    beeper.run_frame(Sound::Silent, &mut samples);
+    beeper.run_frame(Sound::Beep, &mut samples);
+    beeper.run_frame(Sound::Beep, &mut samples);
+    beeper.run_frame(Sound::Silent, &mut samples);
+    // Without the fade, the first sample would jump to full volume.
+    let start = 800;
+    assert!(samples[start].abs() < VOLUME / 10.0);
+    assert!(samples[start + 200..start + 1600]
+      .iter()
+      .any(|&sample| sample.abs() > VOLUME * 0.9));
+    // It fades out within the fade time of the end of the beep.
+    let fade = (FADE_SECONDS * 48000.0) as usize + 1;
+    assert!(samples[2400 + fade..].iter().all(|&sample| sample == 0.0));
+    assert!(samples.iter().all(|&sample| sample.abs() <= VOLUME * 1.01));
+  }
+
+  #[test]
+  fn beeps_at_pitch() {
+    let mut beeper = Beeper::new(44100, 441.0);
+    let mut samples = Vec::new();
+    for _ in 0..60 {
+      beeper.run_frame(Sound::Beep, &mut samples);
+    }
+    // Each cycle crosses from positive to negative once.
+    let falling = samples
+      .windows(2)
+      .filter(|pair| pair[0] > 0.0 && pair[1] <= 0.0)
+      .count();
+    assert!((440..=442).contains(&falling), "{} cycles", falling);
+  }
+
+  #[test]
+  fn pattern_rate_doubles_every_48_pitches() {
+    assert_eq!(pattern_rate(64), 4000.0);
+    assert!((pattern_rate(112) - 8000.0).abs() < 0.01);
+    assert!((pattern_rate(16) - 2000.0).abs() < 0.01);
+  }
+
+  #[test]
+  fn patterns_play_at_their_rate() {
+    // Alternate bytes of set and unset samples, so the pattern is a
+    // square wave of 8 cycles.
+    let mut pattern = [0; PATTERN_SIZE];
+    for byte in pattern.iter_mut().step_by(2) {
+      *byte = 0xFF;
+    }
+    // At 4000 samples per second, that is 250 cycles a second.
+    let sound = Sound::Pattern { pattern, pitch: 64 };
+    let mut beeper = Beeper::new(48000, DEFAULT_PITCH);
+    let mut samples = Vec::new();
+    for _ in 0..60 {
+      beeper.run_frame(sound, &mut samples);
+    }
+    let falling = samples
+      .windows(2)
+      .filter(|pair| pair[0] > 0.0 && pair[1] <= 0.0)
+      .count();
+    assert_eq!(falling, 250);
+    // Samples straddling an edge are averaged.
+    assert!(samples
+      .iter()
+      .any(|&sample| sample.abs() > 0.01 && sample.abs() < VOLUME * 0.9));
+  }
+
+  #[test]
+  fn pattern_average_covers_fractions() {
+    let mut pattern = [0; PATTERN_SIZE];
+    pattern[0] = 0b1010_0000;
+    assert_eq!(pattern_average(&pattern, 0.0, 1.0), 1.0);
+    assert_eq!(pattern_average(&pattern, 0.5, 1.0), 0.5);
+    assert_eq!(pattern_average(&pattern, 0.0, 4.0), 0.5);
+    // Wraps around to the start.
+    assert_eq!(pattern_average(&pattern, 127.5, 1.0), 0.5);
+  }
+}
@@ -0,0 +1,329 @@
+//! cache provides a cache of decoded instructions, so that executing an
+//! instruction does not decode it again every time, the write counts
+//! that tell compiled code when it may be stale, and the log of writes
+//! to memory that tells the Cpu what to discard.
+
+use crate::chip8::cpu::MEMORY_SIZE;
+use crate::chip8::instruction::{parse_instruction, Instruction, InstructionError};
+use crate::chip8::memory::Memory;
+use crate::interface::bus::{Access, Bus, Observer, ObserverId};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// DecodeCache holds the decoded instruction at each address of memory.
+/// It is filled lazily as instructions are executed, and entries must
+/// be invalidated whenever the memory they were decoded from is
+/// written.
+///
+/// The cache is derived from memory, so it is not saved, and two
+/// caches always compare equal.
+#[derive(Clone, Copy)]
+pub struct DecodeCache {
+  entries: [Option<Instruction>; MEMORY_SIZE],
+  // When disabled, every instruction is decoded again.
+  enabled: bool,
+}
+
+impl PartialEq for DecodeCache {
+  fn eq(&self, _other: &Self) -> bool {
+    true
+  }
+}
+
+impl Eq for DecodeCache {}
+
+impl std::hash::Hash for DecodeCache {
+  fn hash<H: std::hash::Hasher>(&self, _state: &mut H) {}
+}
+
+impl std::fmt::Debug for DecodeCache {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("DecodeCache")
+      .field(
+        "cached",
+        &self.entries.iter().filter(|e| e.is_some()).count(),
+      )
+      .field("enabled", &self.enabled)
+      .finish()
+  }
+}
+
+impl Default for DecodeCache {
+  fn default() -> Self {
+    DecodeCache::new()
+  }
+}
+
+impl DecodeCache {
+  pub fn new() -> Self {
+    DecodeCache {
+      entries: [None; MEMORY_SIZE],
+      enabled: true,
+    }
+  }
+
+  pub fn enabled(&self) -> bool {
+    self.enabled
+  }
+
+  pub fn set_enabled(&mut self, enabled: bool) {
+    self.enabled = enabled;
+    self.clear()
+  }
+
+  /// get returns the instruction at `address`, which is decoded from
+  /// `opcode` and cached if it is not cached yet.
+  pub fn get(&mut self, address: u16, opcode: u16) -> Result<Instruction, InstructionError> {
+    let index = address as usize % MEMORY_SIZE;
+    if let Some(instr) = self.entries[index] {
+      return Ok(instr);
+    }
+    let instr = parse_instruction(opcode)?;
+    if self.enabled {
+      self.entries[index] = Some(instr);
+    }
+    Ok(instr)
+  }
+
+  /// peek returns the instruction cached at `address`, if any.
+  pub fn peek(&self, address: u16) -> Option<Instruction> {
+    self.entries[address as usize % MEMORY_SIZE]
+  }
+
+  /// invalidate removes the instructions that include the byte at
+  /// `address`, which are the ones starting at `address` and at the byte
+  /// before it.
+  pub fn invalidate(&mut self, address: u16) {
+    let index = address as usize % MEMORY_SIZE;
+    self.entries[index] = None;
+    self.entries[(index + MEMORY_SIZE - 1) % MEMORY_SIZE] = None;
+  }
+
+  /// clear removes every cached instruction.
+  pub fn clear(&mut self) {
+    self.entries = [None; MEMORY_SIZE];
+  }
+}
+
+/// Size, in bytes, of the regions of memory whose writes are tracked by
+/// WriteGuard.
+pub const GUARD_REGION_SIZE: usize = 16;
+
+// Last version given to a region of memory, by any WriteGuard.
+static LAST_VERSION: AtomicU64 = AtomicU64::new(0);
+
+/// WriteGuard gives each region of memory a new version whenever it is
+/// written, so that code compiled from memory can tell when it may be
+/// stale.
+///
+/// Versions are unique across all guards, so a version identifies the
+/// contents of memory even in copies of a Cpu. The versions are derived
+/// from execution, so they are not saved, and two guards always compare
+/// equal.
+#[derive(Clone, Copy)]
+pub struct WriteGuard {
+  versions: [u64; MEMORY_SIZE / GUARD_REGION_SIZE],
+  // The version of the last write.
+  newest: u64,
+}
+
+impl PartialEq for WriteGuard {
+  fn eq(&self, _other: &Self) -> bool {
+    true
+  }
+}
+
+impl Eq for WriteGuard {}
+
+impl std::hash::Hash for WriteGuard {
+  fn hash<H: std::hash::Hasher>(&self, _state: &mut H) {}
+}
+
+impl std::fmt::Debug for WriteGuard {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("WriteGuard")
+      .field("version", &self.newest)
+      .finish()
+  }
+}
+
+impl Default for WriteGuard {
+  fn default() -> Self {
+    WriteGuard::new()
+  }
+}
+
+impl WriteGuard {
+  pub fn new() -> Self {
+    WriteGuard {
+      versions: [0; MEMORY_SIZE / GUARD_REGION_SIZE],
+      newest: 0,
+    }
+  }
+
+  /// write gives a new version to the region holding the byte at
+  /// `address`.
+  pub fn write(&mut self, address: u16) {
+    let region = address as usize % MEMORY_SIZE / GUARD_REGION_SIZE;
+    self.newest = next_version();
+    self.versions[region] = self.newest
+  }
+
+  /// write_all gives a new version to every region, for when all of
+  /// memory may have changed.
+  pub fn write_all(&mut self) {
+    self.newest = next_version();
+    for region in self.versions.iter_mut() {
+      *region = self.newest
+    }
+  }
+
+  /// newest returns the version of the last write, which changes with
+  /// every write to memory.
+  pub fn newest(&self) -> u64 {
+    self.newest
+  }
+
+  /// stamp returns the newest version of memory from `start` up to
+  /// `end`. Memory with the same stamp has the same contents, as no
+  /// write gives an older version.
+  pub fn stamp(&self, start: usize, end: usize) -> u64 {
+    if start >= end {
+      return 0;
+    }
+    self.versions[start / GUARD_REGION_SIZE..=(end - 1) / GUARD_REGION_SIZE]
+      .iter()
+      .copied()
+      .max()
+      .unwrap_or(0)
+  }
+}
+
+fn next_version() -> u64 {
+  LAST_VERSION.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+// Written holds the addresses written since they were last taken.
+#[derive(Default)]
+struct Written(Vec<u16>);
+
+impl Observer for Written {
+  fn access(&mut self, _access: Access, address: u16, _value: u8) {
+    self.0.push(address)
+  }
+
+  fn observes(&self, access: Access) -> bool {
+    access == Access::Write
+  }
+}
+
+/// WriteLog observes the writes to a Memory, whoever makes them, and
+/// keeps the written addresses until they are taken.
+///
+/// It is derived from execution, so it is not saved, and two logs
+/// always compare equal.
+pub struct WriteLog {
+  id: ObserverId,
+  written: Arc<Mutex<Written>>,
+}
+
+impl PartialEq for WriteLog {
+  fn eq(&self, _other: &Self) -> bool {
+    true
+  }
+}
+
+impl Eq for WriteLog {}
+
+impl std::hash::Hash for WriteLog {
+  fn hash<H: std::hash::Hasher>(&self, _state: &mut H) {}
+}
+
+impl std::fmt::Debug for WriteLog {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("WriteLog").field("id", &self.id).finish()
+  }
+}
+
+impl WriteLog {
+  /// observe adds a log of the writes to the first MEMORY_SIZE bytes of
+  /// `memory`, which code runs from.
+  pub fn observe(memory: &mut Memory) -> Self {
+    let written = Arc::new(Mutex::new(Written::default()));
+    let id = memory.observe(0..MEMORY_SIZE as u16, written.clone());
+    WriteLog { id, written }
+  }
+
+  /// moved returns a log of `memory`, a copy of the memory this log
+  /// observes, which no longer tells this log of its writes.
+  pub fn moved(&self, memory: &mut Memory) -> Self {
+    memory.remove_observer(self.id);
+    WriteLog::observe(memory)
+  }
+
+  /// take returns the addresses written since they were last taken.
+  pub fn take(&self) -> Vec<u16> {
+    let mut written = self.written.lock().unwrap_or_else(|err| err.into_inner());
+    std::mem::take(&mut written.0)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  #[test]
+  fn get_fills_cache() {
+    let mut cache = DecodeCache::new();
+    assert_eq!(cache.peek(0x200), None);
+    let instr = cache.get(0x200, 0x6012).unwrap();
+    assert_eq!(cache.peek(0x200), Some(instr));
+    // A cached instruction is not decoded again.
+    assert_eq!(cache.get(0x200, 0x00E0).unwrap(), instr);
+  }
+
+  #[test]
+  fn invalidate_removes_overlapping_instructions() {
+    let mut cache = DecodeCache::new();
+    for address in 0x1FF..=0x201 {
+      cache.get(address, 0x6012).unwrap();
+    }
+    cache.invalidate(0x200);
+    assert_eq!(cache.peek(0x1FF), None);
+    assert_eq!(cache.peek(0x200), None);
+    assert!(cache.peek(0x201).is_some());
+  }
+
+  #[test]
+  fn invalid_and_disabled_are_not_cached() {
+    let mut cache = DecodeCache::new();
+    assert!(cache.get(0x200, 0x5001).is_err());
+    assert_eq!(cache.peek(0x200), None);
+    cache.set_enabled(false);
+    cache.get(0x200, 0x6012).unwrap();
+    assert_eq!(cache.peek(0x200), None);
+  }
+
+  #[test]
+  fn stamp_changes_with_writes_in_range() {
+    let mut guard = WriteGuard::new();
+    let stamp = guard.stamp(0x200, 0x210);
+    guard.write(0x210);
+    assert_eq!(guard.stamp(0x200, 0x210), stamp);
+    guard.write(0x20F);
+    assert_ne!(guard.stamp(0x200, 0x210), stamp);
+    let stamp = guard.stamp(0x200, 0x210);
+    guard.write_all();
+    assert_ne!(guard.stamp(0x200, 0x210), stamp);
+  }
+
+  #[test]
+  fn copies_write_new_versions() {
+    let mut guard = WriteGuard::new();
+    let mut copy = guard;
+    guard.write(0x200);
+    copy.write(0x200);
+    assert_ne!(guard.stamp(0x200, 0x202), copy.stamp(0x200, 0x202));
+  }
+}
@@ -0,0 +1,406 @@
+//! cartridge loads Octo cartridges: GIF images whose palette indices
+//! carry the Octo source of a program, and the options its author
+//! configured it with in Octo, such as its speed, quirks and colours.
+//!
+//! The low two bits of each pixel's palette index are two bits of the
+//! payload, from the high bits of each byte. The payload starts with its
+//! length as a big-endian 32-bit number, followed by that many bytes of
+//! JSON:
+//!
+//! ```text
+//! {"program": "<Octo source>", "options": {"tickrate": 20, ...}}
+//! ```
+//!
+//! The source is assembled with `octo::assemble`. Options that are
+//! missing take Octo's defaults.
+
+use crate::chip8::chip8::Chip8;
+use crate::chip8::cpu::CpuError;
+use crate::chip8::gif::{self, GifError};
+use crate::chip8::json::{Json, JsonError};
+use crate::chip8::octo::{self, AssembleError};
+use crate::chip8::platform::Platform;
+use crate::chip8::quirks::Quirks;
+use crate::chip8::rom::{Rom, RomError};
+use std::fs;
+use std::io;
+use std::path::Path;
+use thiserror::Error;
+
+/// Extension of the files cartridges are loaded from.
+pub const CARTRIDGE_EXTENSION: &str = ".gif";
+
+// Octo's default speed, in instructions per frame.
+const DEFAULT_TICK_RATE: u32 = 20;
+
+/// CartridgeError is returned when a cartridge can't be loaded.
+#[derive(Error, Debug)]
+pub enum CartridgeError {
+  #[error("Failed to read cartridge {path}: {source}")]
+  Io { path: String, source: io::Error },
+
+  #[error("Invalid cartridge image: {0}")]
+  Gif(#[from] GifError),
+
+  #[error("Cartridge payload of {size} bytes is longer than its image")]
+  Truncated { size: usize },
+
+  #[error("Cartridge payload is not UTF-8")]
+  NotUtf8,
+
+  #[error("Invalid cartridge payload: {0}")]
+  Json(#[from] JsonError),
+
+  #[error("Cartridge has no {field:?}")]
+  Missing { field: &'static str },
+
+  #[error("Invalid cartridge option {name}: {value}")]
+  InvalidOption { name: &'static str, value: String },
+
+  #[error("Failed to assemble cartridge program: {0}")]
+  Assemble(#[from] AssembleError),
+
+  #[error(transparent)]
+  Rom(#[from] RomError),
+}
+
+/// Colours are the colours Octo draws with. XO-CHIP's two display
+/// planes draw with `fill`, `fill2`, and `blend` where both are set.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub struct Colours {
+  pub background: [u8; 3],
+  pub fill: [u8; 3],
+  pub fill2: [u8; 3],
+  pub blend: [u8; 3],
+  /// Colour of the border while the buzzer sounds, and while it is quiet.
+  pub buzz: [u8; 3],
+  pub quiet: [u8; 3],
+}
+
+impl Default for Colours {
+  /// default is Octo's default palette.
+  fn default() -> Self {
+    Colours {
+      background: [0x99, 0x66, 0x00],
+      fill: [0xFF, 0xCC, 0x00],
+      fill2: [0xFF, 0x66, 0x00],
+      blend: [0x66, 0x22, 0x00],
+      buzz: [0xFF, 0xAA, 0x00],
+      quiet: [0x00, 0x00, 0x00],
+    }
+  }
+}
+
+/// Options are the settings a cartridge's program is run with.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct Options {
+  pub instructions_per_frame: u32,
+  pub quirks: Quirks,
+  /// Platform the program was written for, from the memory Octo gave
+  /// it.
+  pub platform: Platform,
+  pub colours: Colours,
+  /// Degrees clockwise to rotate the display by: 0, 90, 180 or 270.
+  /// The frontends do not rotate the display, so any other than 0 is
+  /// unsupported.
+  pub rotation: u16,
+  /// Names of the options that are set but can't be applied, as the
+  /// core does not have the behaviour they choose.
+  pub unsupported: Vec<&'static str>,
+}
+
+impl Default for Options {
+  /// default is the settings of a new Octo program.
+  fn default() -> Self {
+    Options {
+      instructions_per_frame: DEFAULT_TICK_RATE,
+      quirks: Quirks::xo_chip(),
+      platform: Platform::Chip8,
+      colours: Colours::default(),
+      rotation: 0,
+      unsupported: Vec::new(),
+    }
+  }
+}
+
+/// Cartridge is an Octo program with the options to run it with.
+#[derive(Clone, Debug)]
+pub struct Cartridge {
+  source: String,
+  rom: Rom,
+  options: Options,
+}
+
+impl Cartridge {
+  /// from_gif loads the cartridge in the GIF image `bytes`.
+  pub fn from_gif(bytes: &[u8]) -> Result<Cartridge, CartridgeError> {
+    let payload = payload(&gif::decode(bytes)?)?;
+    let text = std::str::from_utf8(&payload).map_err(|_| CartridgeError::NotUtf8)?;
+    let json = Json::parse(text)?;
+    let source = match json.get("program") {
+      Some(Json::String(source)) => source.clone(),
+      _ => return Err(CartridgeError::Missing { field: "program" }),
+    };
+    let options = match json.get("options") {
+      Some(json) => Options::from_json(json)?,
+      None => Options::default(),
+    };
+    let rom = Rom::from_bytes(&octo::assemble(&source)?, options.platform)?;
+    Ok(Cartridge {
+      source,
+      rom,
+      options,
+    })
+  }
+
+  /// from_path loads the cartridge in the GIF image at `path`.
+  pub fn from_path(path: &Path) -> Result<Cartridge, CartridgeError> {
+    let bytes = fs::read(path).map_err(|source| CartridgeError::Io {
+      path: path.display().to_string(),
+      source,
+    })?;
+    Cartridge::from_gif(&bytes)
+  }
+
+  /// source returns the Octo source of the program.
+  pub fn source(&self) -> &str {
+    &self.source
+  }
+
+  pub fn rom(&self) -> &Rom {
+    &self.rom
+  }
+
+  pub fn options(&self) -> &Options {
+    &self.options
+  }
+
+  /// apply sets the quirks and speed of `chip8` to the cartridge's, and
+  /// loads its program.
+  pub fn apply(&self, chip8: &mut Chip8) -> Result<(), CpuError> {
+    chip8.set_quirks(self.options.quirks);
+    chip8.set_instructions_per_frame(self.options.instructions_per_frame);
+    chip8.load_rom(&self.rom)
+  }
+}
+
+// payload returns the bytes carried by the palette indices of `image`'s
+// images, up to the length it starts with.
+fn payload(image: &gif::Gif) -> Result<Vec<u8>, CartridgeError> {
+  let mut bytes = image
+    .images
+    .iter()
+    .flat_map(|image| image.indices.iter())
+    .collect::<Vec<_>>()
+    .chunks_exact(4)
+    .map(|bits| bits.iter().fold(0, |byte, index| byte << 2 | (*index & 3)))
+    .collect::<Vec<u8>>();
+  if bytes.len() < 4 {
+    return Err(CartridgeError::Truncated { size: 4 });
+  }
+  let size = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+  if bytes.len() - 4 < size {
+    return Err(CartridgeError::Truncated { size });
+  }
+  bytes.truncate(size + 4);
+  bytes.drain(..4);
+  Ok(bytes)
+}
+
+impl Options {
+  fn from_json(json: &Json) -> Result<Options, CartridgeError> {
+    let mut options = Options::default();
+    if let Some(rate) = number(json, "tickrate")? {
+      if !(1.0..=1e6).contains(&rate) {
+        return Err(CartridgeError::InvalidOption {
+          name: "tickrate",
+          value: rate.to_string(),
+        });
+      }
+      options.instructions_per_frame = rate as u32;
+    }
+
+    // Octo's quirks are named for the behaviour they choose, except the
+    // shift and load/store quirks, which turn off the original.
+    let quirks = &mut options.quirks;
+    let flags: [(&'static str, &mut bool, bool); 6] = [
+      ("shiftQuirks", &mut quirks.shift_uses_vy, false),
+      ("loadStoreQuirks", &mut quirks.memory_increments_i, false),
+      ("logicQuirks", &mut quirks.logic_resets_vf, true),
+      ("jumpQuirks", &mut quirks.jump_uses_vx, true),
+      ("clipQuirks", &mut quirks.clip_sprites, true),
+      ("vBlankQuirks", &mut quirks.display_wait, true),
+    ];
+    for (name, quirk, when_set) in flags {
+      if let Some(set) = flag(json, name)? {
+        *quirk = set == when_set;
+      }
+    }
+    // vF is set before the result is stored, which the core does not
+    // support.
+    if flag(json, "vfOrderQuirks")? == Some(true) {
+      options.unsupported.push("vfOrderQuirks");
+    }
+
+    if let Some(size) = number(json, "maxSize")? {
+      options.platform = match size as u32 {
+        3216 | 3584 => Platform::Chip8,
+        3583 => Platform::Schip,
+        65024 => Platform::XoChip,
+        _ => {
+          return Err(CartridgeError::InvalidOption {
+            name: "maxSize",
+            value: size.to_string(),
+          })
+        }
+      };
+    }
+
+    if let Some(rotation) = number(json, "screenRotation")? {
+      if ![0.0, 90.0, 180.0, 270.0].contains(&rotation) {
+        return Err(CartridgeError::InvalidOption {
+          name: "screenRotation",
+          value: rotation.to_string(),
+        });
+      }
+      options.rotation = rotation as u16;
+      if options.rotation != 0 {
+        options.unsupported.push("screenRotation");
+      }
+    }
+
+    let colours = &mut options.colours;
+    let names: [(&'static str, &mut [u8; 3]); 6] = [
+      ("backgroundColor", &mut colours.background),
+      ("fillColor", &mut colours.fill),
+      ("fillColor2", &mut colours.fill2),
+      ("blendColor", &mut colours.blend),
+      ("buzzColor", &mut colours.buzz),
+      ("quietColor", &mut colours.quiet),
+    ];
+    for (name, colour) in names {
+      match json.get(name) {
+        Some(Json::String(text)) => {
+          *colour = parse_colour(text).ok_or_else(|| CartridgeError::InvalidOption {
+            name,
+            value: text.clone(),
+          })?
+        }
+        Some(_) => {
+          return Err(CartridgeError::InvalidOption {
+            name,
+            value: String::from("not a string"),
+          })
+        }
+        None => {}
+      }
+    }
+    Ok(options)
+  }
+}
+
+// parse_colour returns the colour written as "#RRGGBB".
+fn parse_colour(text: &str) -> Option<[u8; 3]> {
+  let hex = text.strip_prefix('#')?;
+  if hex.len() != 6 || !hex.is_ascii() {
+    return None;
+  }
+  let mut colour = [0; 3];
+  for (i, channel) in colour.iter_mut().enumerate() {
+    *channel = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+  }
+  Some(colour)
+}
+
+// number returns the option `name`, if it is set to a number.
+fn number(json: &Json, name: &'static str) -> Result<Option<f64>, CartridgeError> {
+  match json.get(name) {
+    None | Some(Json::Null) => Ok(None),
+    Some(Json::Number(number)) => Ok(Some(*number)),
+    // Older versions of Octo saved numbers as strings.
+    Some(Json::String(text)) => match text.parse() {
+      Ok(number) => Ok(Some(number)),
+      Err(_) => Err(CartridgeError::InvalidOption {
+        name,
+        value: text.clone(),
+      }),
+    },
+    Some(_) => Err(CartridgeError::InvalidOption {
+      name,
+      value: String::from("not a number"),
+    }),
+  }
+}
+
+// flag returns the option `name`, if it is set to a boolean. Numbers are
+// set if they are not zero.
+fn flag(json: &Json, name: &'static str) -> Result<Option<bool>, CartridgeError> {
+  match json.get(name) {
+    None | Some(Json::Null) => Ok(None),
+    Some(Json::Bool(set)) => Ok(Some(*set)),
+    Some(Json::Number(number)) => Ok(Some(*number != 0.0)),
+    Some(_) => Err(CartridgeError::InvalidOption {
+      name,
+      value: String::from("not a boolean"),
+    }),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn cartridges_load_their_program_and_options() {
+    let cartridge = Cartridge::from_gif(include_bytes!("../../tests/carts/flags.gif")).unwrap();
+    assert!(cartridge.source().contains(": main"));
+    let options = cartridge.options();
+    assert_eq!(options.instructions_per_frame, 200);
+    assert_eq!(options.platform, Platform::Schip);
+    assert_eq!(
+      options.quirks,
+      Quirks {
+        shift_uses_vy: false,
+        memory_increments_i: false,
+        logic_resets_vf: false,
+        jump_uses_vx: true,
+        clip_sprites: true,
+        display_wait: false,
+      }
+    );
+    assert_eq!(options.colours.fill, [0xFF, 0xFF, 0xFF]);
+    assert_eq!(options.colours.background, [0x00, 0x00, 0x00]);
+    assert_eq!(options.colours.buzz, Colours::default().buzz);
+    assert_eq!(options.rotation, 90);
+    assert_eq!(options.unsupported, vec!["vfOrderQuirks", "screenRotation"]);
+
+    let mut chip8 = Chip8::new();
+    cartridge.apply(&mut chip8).unwrap();
+    assert_eq!(chip8.quirks(), options.quirks);
+    assert_eq!(chip8.instructions_per_frame(), 200);
+  }
+
+  #[test]
+  fn missing_options_take_octo_defaults() {
+    let cartridge = Cartridge::from_gif(include_bytes!("../../tests/carts/count.gif")).unwrap();
+    assert_eq!(cartridge.options(), &Options::default());
+    assert_eq!(
+      cartridge.rom().bytes(),
+      &[0x12, 0x02, 0x60, 0x00, 0x70, 0x01, 0x12, 0x04]
+    );
+  }
+
+  #[test]
+  fn other_images_are_errors() {
+    let mut image = include_bytes!("../../tests/carts/count.gif").to_vec();
+    assert!(matches!(
+      Cartridge::from_gif(&image[..20]),
+      Err(CartridgeError::Gif(GifError::Truncated))
+    ));
+    image[0] = b'P';
+    assert!(matches!(
+      Cartridge::from_gif(&image),
+      Err(CartridgeError::Gif(GifError::NotGif))
+    ));
+  }
+}
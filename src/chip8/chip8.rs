@@ -1,31 +1,111 @@
-use crate::chip8::cpu::Cpu;
+use crate::chip8::audio::{Beeper, Sound};
+#[cfg(feature = "jit")]
+use crate::chip8::cpu::jit::Jit;
+use crate::chip8::cpu::recompiled::Recompiled;
+use crate::chip8::cpu::{Backend, Cpu, CpuError, PROGRAM_START};
+use crate::chip8::display::Display;
+use crate::chip8::instruction::Instruction;
+use crate::chip8::quirks::Quirks;
+use crate::chip8::rng::DEFAULT_SEED;
+use crate::chip8::rom::Rom;
+use crate::chip8::timer::Timer;
+use crate::chip8::timing::{self, Timing};
+use crate::interface::audio::AudioSink;
+use crate::interface::bus::Bus;
 use crate::interface::emulator::Clocked;
 use crate::interface::serialization::Savable;
+use crate::interface::system::System;
+use crate::interface::video::Framebuffer;
 
 // Chip8 is the root emulation tree for the Chip8 system.
-#[derive(Eq, PartialEq, Clone, Copy, Debug, Hash)]
+//
+// Emulation is scheduled in frames: each frame executes instructions
+// until the frame's budget is used up, and then clocks the 60hz timers
+// once. The budget is set by the Timing.
+#[derive(Eq, PartialEq, Clone, Debug, Hash)]
 pub struct Chip8 {
   cpu: Cpu,
+  // How instructions are scheduled into frames.
+  timing: Timing,
+  // Number of instructions executed for each 60hz frame, when using
+  // Timing::InstructionsPerFrame.
+  instructions_per_frame: u32,
+  // Budget used so far in the current frame, which is instructions or
+  // machine cycles depending on the timing.
+  frame_progress: u32,
+  // Cycles left of the instruction executing when clocked a cycle at a
+  // time.
+  busy_cycles: u32,
+  // Program last loaded, and the address it was loaded at, which reset
+  // loads again. They are saved, so that a loaded state can be reset.
+  program: Vec<u8>,
+  entry_point: u16,
+  // Seed of the random number generator, which reset seeds it with
+  // again.
+  seed: u32,
+  // Synthesiser of the buzzer for System::push_audio.
+  beeper: Beeper,
 }
 
 impl Savable for Chip8 {
   fn save(&self, fh: &mut dyn std::io::Write) -> std::io::Result<()> {
     self.cpu.save(fh)?;
+    self.frame_progress.save(fh)?;
+    self.busy_cycles.save(fh)?;
+    self.program.save(fh)?;
+    self.entry_point.save(fh)?;
+    self.beeper.save(fh)?;
     Ok(())
   }
   fn load(&mut self, fh: &mut dyn std::io::Read) -> std::io::Result<()> {
     self.cpu.load(fh)?;
+    self.frame_progress.load(fh)?;
+    self.busy_cycles.load(fh)?;
+    self.program.load(fh)?;
+    self.entry_point.load(fh)?;
+    self.beeper.load(fh)?;
     Ok(())
   }
 }
 
 impl Clocked for Chip8 {
+  type Fault = CpuError;
+
+  /// clock runs one cycle. An instruction executes on its first cycle,
+  /// and the rest of its cycles are spent busy.
   fn clock(&mut self) {
-    unimplemented!("TODO(joey): implement");
+    if self.busy_cycles > 0 {
+      self.busy_cycles -= 1;
+      return;
+    }
+    match self.execute() {
+      Ok((_, cycles, _)) => self.busy_cycles = cycles - 1,
+      Err(err) => log::error!("{}", err),
+    }
+  }
+
+  /// clock_rate returns the cycles run each second. With
+  /// Timing::InstructionsPerFrame every instruction is a cycle, and with
+  /// Timing::CosmacVip cycles are the machine cycles the VIP leaves to
+  /// the interpreter.
+  fn clock_rate(&self) -> u32 {
+    self.frame_budget() * Timer::new().clock_rate()
+  }
+
+  fn busy_cycles(&self) -> u32 {
+    self.busy_cycles
   }
 
-  fn clock_rate(self) -> u32 {
-    self.cpu.clock_rate()
+  fn run_cycles(&mut self, cycles: u32) -> (u32, Result<(), CpuError>) {
+    let mut executed = self.busy_cycles.min(cycles);
+    self.busy_cycles -= executed;
+    while executed < cycles {
+      match self.execute() {
+        Ok((_, cost, _)) => executed += cost,
+        Err(err) => return (executed, Err(err)),
+      }
+    }
+    (executed, Ok(()))
   }
 }
 
@@ -37,6 +117,559 @@ impl Default for Chip8 {
 
 impl Chip8 {
   pub fn new() -> Self {
-    Chip8 { cpu: Cpu::new() }
+    let cpu = Cpu::new();
+    Chip8 {
+      instructions_per_frame: cpu.clock_rate() / Timer::new().clock_rate(),
+      cpu,
+      timing: Timing::InstructionsPerFrame,
+      frame_progress: 0,
+      busy_cycles: 0,
+      program: Vec::new(),
+      entry_point: PROGRAM_START,
+      seed: DEFAULT_SEED,
+      beeper: Beeper::default(),
+    }
+  }
+
+  /// load_program copies `program` into memory, ready for execution.
+  pub fn load_program(&mut self, program: &[u8]) -> Result<(), CpuError> {
+    self.cpu.load_program(program)?;
+    self.program = program.to_vec();
+    self.entry_point = PROGRAM_START;
+    Ok(())
+  }
+
+  /// load_rom copies `rom` into memory at its entry point, ready for
+  /// execution from there.
+  pub fn load_rom(&mut self, rom: &Rom) -> Result<(), CpuError> {
+    self.cpu.load_rom(rom)?;
+    self.program = rom.bytes().to_vec();
+    self.entry_point = rom.entry_point();
+    Ok(())
+  }
+
+  /// reset restarts the program last loaded, as if the machine was
+  /// turned off and on again. The configuration is kept, and the random
+  /// number generator is seeded as it was.
+  pub fn reset(&mut self) {
+    self.cpu.reset();
+    self.cpu.seed_rng(self.seed);
+    // The program fitted in memory when it was loaded.
+    self
+      .cpu
+      .load_program_from(&self.program, self.entry_point)
+      .expect("loaded program fits in memory");
+    self.frame_progress = 0;
+    self.busy_cycles = 0;
+  }
+
+  pub fn quirks(&self) -> Quirks {
+    self.cpu.quirks()
+  }
+
+  pub fn set_quirks(&mut self, quirks: Quirks) {
+    self.cpu.set_quirks(quirks)
+  }
+
+  pub fn seed_rng(&mut self, seed: u32) {
+    self.seed = seed;
+    self.cpu.seed_rng(seed)
+  }
+
+  pub fn backend(&self) -> Backend {
+    self.cpu.backend()
+  }
+
+  /// set_backend changes how instructions are executed, which does not
+  /// change the results of execution.
+  pub fn set_backend(&mut self, backend: Backend) {
+    self.cpu.set_backend(backend)
+  }
+
+  /// set_decode_cache enables or disables caching decoded instructions,
+  /// which is enabled by default.
+  pub fn set_decode_cache(&mut self, enabled: bool) {
+    self.cpu.set_decode_cache(enabled)
+  }
+
+  pub fn instructions_per_frame(&self) -> u32 {
+    self.instructions_per_frame
+  }
+
+  /// set_instructions_per_frame sets the emulation speed. Values below
+  /// one are treated as one.
+  pub fn set_instructions_per_frame(&mut self, instructions_per_frame: u32) {
+    self.instructions_per_frame = instructions_per_frame.max(1)
+  }
+
+  pub fn timing(&self) -> Timing {
+    self.timing
+  }
+
+  /// set_timing changes how instructions are scheduled into frames,
+  /// starting with a new frame.
+  pub fn set_timing(&mut self, timing: Timing) {
+    self.timing = timing;
+    self.frame_progress = 0;
+    self.busy_cycles = 0
+  }
+
+  pub fn display(&self) -> &Display {
+    self.cpu.display()
+  }
+
+  /// program_counter returns the address of the next instruction.
+  pub fn program_counter(&self) -> u16 {
+    self.cpu.program_counter()
+  }
+
+  /// tone returns whether the buzzer sounds.
+  pub fn tone(&self) -> bool {
+    self.cpu.tone()
+  }
+
+  /// sound returns what the buzzer plays.
+  pub fn sound(&self) -> Sound {
+    self.cpu.sound()
+  }
+
+  pub fn beeper(&self) -> &Beeper {
+    &self.beeper
+  }
+
+  /// set_beeper sets the Beeper that System::push_audio plays the buzzer
+  /// with, to change its sample rate or pitch.
+  pub fn set_beeper(&mut self, beeper: Beeper) {
+    self.beeper = beeper
+  }
+
+  pub fn press_key(&mut self, key: usize) {
+    self.cpu.keypad_mut().press(key)
+  }
+
+  pub fn release_key(&mut self, key: usize) {
+    self.cpu.keypad_mut().release(key)
+  }
+
+  /// cpu returns the Cpu, to inspect its state.
+  pub fn cpu(&self) -> &Cpu {
+    &self.cpu
+  }
+
+  /// cpu_mut returns the Cpu, to set up its state.
+  pub fn cpu_mut(&mut self) -> &mut Cpu {
+    &mut self.cpu
+  }
+
+  /// bus returns the memory the program runs in, to observe, protect,
+  /// look at or change.
+  pub fn bus(&mut self) -> &mut impl Bus {
+    &mut self.cpu
+  }
+
+  /// step will execute a single instruction, and clock the timers if
+  /// the instruction ended the frame.
+  pub fn step(&mut self) -> Result<Instruction, CpuError> {
+    self.execute().map(|(instr, _, _)| instr)
+  }
+
+  /// run_frame will execute instructions until the end of the current
+  /// frame.
+  pub fn run_frame(&mut self) -> Result<(), CpuError> {
+    self.run_frame_with(Cpu::run)
+  }
+
+  /// run_frame_jit will execute instructions until the end of the
+  /// current frame, running them with `jit`. Frames timed as on the
+  /// COSMAC VIP are interpreted.
+  #[cfg(feature = "jit")]
+  pub fn run_frame_jit(&mut self, jit: &mut Jit) -> Result<(), CpuError> {
+    self.run_frame_with(|cpu, count, stop_at_draw| jit.run(cpu, count, stop_at_draw))
+  }
+
+  /// run_frame_recompiled will execute instructions until the end of
+  /// the current frame, running them with the recompiled program of
+  /// `recompiled`. Frames timed as on the COSMAC VIP are interpreted.
+  pub fn run_frame_recompiled(&mut self, recompiled: &mut Recompiled) -> Result<(), CpuError> {
+    self.run_frame_with(|cpu, count, stop_at_draw| recompiled.run(cpu, count, stop_at_draw))
+  }
+
+  // run_frame_with runs the current frame, executing instructions with
+  // `run`, which behaves as `Cpu::run`.
+  fn run_frame_with<F>(&mut self, mut run: F) -> Result<(), CpuError>
+  where
+    F: FnMut(&mut Cpu, u32, bool) -> (u32, Result<bool, CpuError>),
+  {
+    if self.timing == Timing::CosmacVip {
+      while !self.execute()?.2 {}
+      return Ok(());
+    }
+    // Every instruction costs the same, so the rest of the frame can be
+    // run at once.
+    loop {
+      let remaining = self
+        .instructions_per_frame
+        .saturating_sub(self.frame_progress)
+        .max(1);
+      let display_wait = self.cpu.quirks().display_wait;
+      let (executed, result) = run(&mut self.cpu, remaining, display_wait);
+      self.frame_progress += executed;
+      if result? {
+        self.cpu.tick_timers();
+        self.frame_progress = 0;
+        return Ok(());
+      }
+      if self.end_frames(self.instructions_per_frame) {
+        return Ok(());
+      }
+    }
+  }
+
+  // frame_budget returns the cycles of each frame: instructions with
+  // Timing::InstructionsPerFrame, and machine cycles with
+  // Timing::CosmacVip.
+  fn frame_budget(&self) -> u32 {
+    match self.timing {
+      Timing::InstructionsPerFrame => self.instructions_per_frame,
+      Timing::CosmacVip => timing::INTERPRETER_CYCLES_PER_FRAME,
+    }
+  }
+
+  // execute will execute a single instruction, and returns it along with
+  // the cycles it took and whether it ended the frame.
+  #[inline]
+  fn execute(&mut self) -> Result<(Instruction, u32, bool), CpuError> {
+    let budget = self.frame_budget();
+    let cost = match self.timing {
+      Timing::InstructionsPerFrame => 1,
+      Timing::CosmacVip => timing::instruction_cycles(&self.cpu.next_instruction()?, &self.cpu),
+    };
+    let instr = self.cpu.step()?;
+    let cost = match (self.timing, instr) {
+      (Timing::CosmacVip, Instruction::Call { .. }) => cost + self.cpu.machine_cycles(),
+      _ => cost,
+    };
+
+    // Waiting for the display takes the rest of the frame.
+    if self.cpu.quirks().display_wait && matches!(instr, Instruction::DisplayDraw { .. }) {
+      let cycles = budget.saturating_sub(self.frame_progress).max(1);
+      self.cpu.tick_timers();
+      self.frame_progress = 0;
+      return Ok((instr, cycles, true));
+    }
+    self.frame_progress += cost;
+    Ok((instr, cost, self.end_frames(budget)))
+  }
+
+  // end_frames ends every frame of `budget` that the progress so far
+  // fills, and returns whether any frame ended.
+  fn end_frames(&mut self, budget: u32) -> bool {
+    let mut frame_ended = false;
+    while self.frame_progress >= budget {
+      self.cpu.tick_timers();
+      self.frame_progress -= budget;
+      frame_ended = true;
+    }
+    frame_ended
+  }
+}
+
+impl System for Chip8 {
+  type Rom = Rom;
+  type Error = CpuError;
+
+  fn reset(&mut self) {
+    Chip8::reset(self)
+  }
+
+  fn load_rom(&mut self, rom: &Rom) -> Result<(), CpuError> {
+    Chip8::load_rom(self, rom)?;
+    Chip8::reset(self);
+    Ok(())
+  }
+
+  fn run_frame(&mut self) -> Result<(), CpuError> {
+    Chip8::run_frame(self)
+  }
+
+  fn frame_rate(&self) -> u32 {
+    Timer::new().clock_rate()
+  }
+
+  fn framebuffer(&self) -> Framebuffer<'_> {
+    self.display().frame().into()
+  }
+
+  fn sample_rate(&self) -> u32 {
+    self.beeper.sample_rate()
+  }
+
+  fn push_audio(&mut self, sink: &mut dyn AudioSink) {
+    let sound = self.sound();
+    self.beeper.run_frame(sound, sink)
+  }
+
+  fn input_count(&self) -> usize {
+    16
+  }
+
+  fn set_input(&mut self, input: usize) {
+    self.press_key(input)
+  }
+
+  fn clear_input(&mut self, input: usize) {
+    self.release_key(input)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use crate::chip8::platform::Platform;
+  use crate::interface::serialization;
+  use crate::interface::video::PixelFormat;
+
+  #[test]
+  fn serialization_roundtrip_works() -> std::io::Result<()> {
+    let buf = &mut Vec::new();
+    let mut chip8 = Chip8::new();
+    chip8.load_program(&[0x60, 0x01]).unwrap();
+    chip8.step().unwrap();
+    chip8.save(buf)?;
+    let loaded_chip8 = serialization::read_value::<Chip8>(&mut buf.as_slice())?;
+    assert_eq!(chip8, loaded_chip8);
+    Ok(())
+  }
+
+  #[test]
+  fn saved_state_keeps_the_buzzer_playing() -> std::io::Result<()> {
+    // sound = V0 = 10; loop
+    let mut chip8 = Chip8::new();
+    chip8
+      .load_program(&[0x60, 0x0A, 0xF0, 0x18, 0x12, 0x04])
+      .unwrap();
+    chip8.run_frame().unwrap();
+    chip8.push_audio(&mut Vec::new());
+    let buf = &mut Vec::new();
+    chip8.save(buf)?;
+    let mut loaded_chip8 = serialization::read_value::<Chip8>(&mut buf.as_slice())?;
+    assert_eq!(chip8, loaded_chip8);
+
+    let (mut played, mut replayed) = (Vec::new(), Vec::new());
+    chip8.push_audio(&mut played);
+    loaded_chip8.push_audio(&mut replayed);
+    assert_eq!(played, replayed);
+    Ok(())
+  }
+
+  #[test]
+  fn reset_restarts_the_program() -> Result<(), CpuError> {
+    // V0 = random; V1 += 1; jump to start
+    let mut chip8 = Chip8::new();
+    chip8.load_program(&[0xC0, 0xFF, 0x71, 0x01, 0x12, 0x00])?;
+    chip8.seed_rng(7);
+    chip8.run_frame()?;
+    let first_frame = chip8.clone();
+    chip8.run_frame()?;
+    chip8.reset();
+    chip8.run_frame()?;
+    assert_eq!(chip8, first_frame);
+    Ok(())
+  }
+
+  // run_frames runs `frames` frames of `system` holding down `input`, as
+  // a frontend would, and returns the audio it played.
+  fn run_frames<S: System>(
+    system: &mut S,
+    frames: usize,
+    input: usize,
+  ) -> Result<Vec<f32>, S::Error> {
+    let mut samples = Vec::new();
+    system.set_input(input);
+    for _ in 0..frames {
+      system.run_frame()?;
+      system.push_audio(&mut samples);
+    }
+    system.clear_input(input);
+    Ok(samples)
+  }
+
+  #[test]
+  fn chip8_runs_as_a_system() -> Result<(), CpuError> {
+    // V0 = 5; skip if key V0 is up; sound = V0; jump to the skip
+    let program = [0x60, 0x05, 0xE0, 0xA1, 0xF0, 0x18, 0x12, 0x02];
+    let rom = Rom::from_bytes(&program, Platform::Chip8).unwrap();
+    let mut chip8 = Chip8::new();
+    System::load_rom(&mut chip8, &rom)?;
+    let framebuffer = chip8.framebuffer();
+    assert_eq!(
+      (framebuffer.width, framebuffer.height, framebuffer.format()),
+      (64, 32, PixelFormat::Monochrome)
+    );
+    assert_eq!(chip8.frame_rate(), 60);
+
+    let silence = run_frames(&mut chip8, 2, 4)?;
+    assert_eq!(silence.len(), 2 * chip8.sample_rate() as usize / 60);
+    assert!(silence.iter().all(|sample| *sample == 0.0));
+    let beep = run_frames(&mut chip8, 2, 5)?;
+    assert!(beep.iter().any(|sample| *sample != 0.0));
+
+    System::reset(&mut chip8);
+    assert!(!chip8.tone());
+    assert_eq!(chip8.program_counter(), PROGRAM_START);
+    Ok(())
+  }
+
+  #[test]
+  fn run_frame_ticks_timers_once() -> Result<(), CpuError> {
+    // V0 = 5; delay = V0; V1 = delay; jump to V1 = delay
+    let mut chip8 = Chip8::new();
+    chip8.load_program(&[0x60, 0x05, 0xF0, 0x15, 0xF1, 0x07, 0x12, 0x04])?;
+    chip8.set_instructions_per_frame(4);
+    chip8.run_frame()?;
+    assert_eq!(chip8.cpu.v_registers()[1], 5);
+    chip8.run_frame()?;
+    assert_eq!(chip8.cpu.v_registers()[1], 4);
+    Ok(())
+  }
+
+  #[test]
+  fn display_wait_ends_frame_on_draw() -> Result<(), CpuError> {
+    // draw V0, V0, 0; jump to self
+    let mut chip8 = Chip8::new();
+    chip8.load_program(&[0xD0, 0x00, 0x12, 0x02])?;
+    chip8.set_quirks(Quirks::cosmac_vip());
+    chip8.run_frame()?;
+    assert_eq!(chip8.cpu.program_counter(), 0x202);
+    Ok(())
+  }
+
+  #[test]
+  fn clocked_instructions_take_their_cycles() {
+    // V0 = 5; V1 = 6
+    let mut chip8 = Chip8::new();
+    chip8.load_program(&[0x60, 0x05, 0x61, 0x06]).unwrap();
+    chip8.set_timing(Timing::CosmacVip);
+    assert_eq!(
+      chip8.clock_rate(),
+      timing::INTERPRETER_CYCLES_PER_FRAME * 60
+    );
+    let cycles = timing::instruction_cycles(&chip8.cpu.next_instruction().unwrap(), &chip8.cpu);
+    assert!(cycles > 1);
+
+    chip8.clock();
+    assert_eq!(chip8.cpu.v_registers()[0], 5);
+    assert_eq!(chip8.busy_cycles(), cycles - 1);
+    let (executed, result) = chip8.run_cycles(cycles - 2);
+    assert_eq!(executed, cycles - 2);
+    assert!(result.is_ok());
+    chip8.clock();
+    assert_eq!(chip8.busy_cycles(), 0);
+    assert_eq!(chip8.cpu.v_registers()[1], 0);
+
+    // The next instruction is not split, so it runs over.
+    let (executed, result) = chip8.run_cycles(1);
+    assert_eq!(executed, cycles);
+    assert!(result.is_ok());
+    assert_eq!(chip8.cpu.v_registers()[1], 6);
+  }
+
+  #[test]
+  fn vip_timing_runs_fewer_slow_instructions_per_frame() -> Result<(), CpuError> {
+    // V0 += 1; V1 = 0xFF; bcd V1; jump to start
+    let program = [0x70, 0x01, 0x61, 0xFF, 0xF1, 0x33, 0x12, 0x00];
+    let mut chip8 = Chip8::new();
+    chip8.load_program(&program)?;
+    chip8.set_timing(Timing::CosmacVip);
+    chip8.run_frame()?;
+    let loops = chip8.cpu.v_registers()[0] as u32;
+
+    // Each loop costs the cycles of its four instructions.
+    let fetch = 4 * timing::FETCH_CYCLES;
+    let bcd = 84 + 16 * (2 + 5 + 5);
+    let per_loop = fetch + 10 + 6 + bcd + 12;
+    assert_eq!(loops, timing::INTERPRETER_CYCLES_PER_FRAME / per_loop + 1);
+    Ok(())
+  }
+
+  // assert_games_match runs every ROM in games/ for a few seconds with
+  // `backend` and `run_frame`, pressing a different key every few frames,
+  // and asserts that every frame matches the interpreter's.
+  fn assert_games_match<F>(backend: Backend, mut run_frame: F)
+  where
+    F: FnMut(&mut Chip8) -> Result<(), CpuError>,
+  {
+    let mut paths: Vec<_> = std::fs::read_dir("games")
+      .unwrap()
+      .map(|entry| entry.unwrap().path())
+      .collect();
+    paths.sort();
+    for quirks in [Quirks::modern(), Quirks::cosmac_vip()].iter() {
+      for path in paths.iter() {
+        let mut expected = Chip8::new();
+        expected.set_quirks(*quirks);
+        expected.set_instructions_per_frame(100);
+        expected.seed_rng(1);
+        expected
+          .load_program(&std::fs::read(path).unwrap())
+          .unwrap();
+        let mut actual = expected.clone();
+        actual.set_backend(backend);
+        for frame in 0..300 {
+          let key = (frame / 8) as usize % 16;
+          for chip8 in [&mut expected, &mut actual].iter_mut() {
+            if frame % 8 == 0 {
+              chip8.press_key(key);
+            } else if frame % 8 == 4 {
+              chip8.release_key(key);
+            }
+          }
+          let want = expected.run_frame().map_err(|err| err.to_string());
+          let got = run_frame(&mut actual).map_err(|err| err.to_string());
+          assert_eq!(got, want, "{} at frame {}", path.display(), frame);
+          // States are compared without the backend that produced them.
+          actual.set_backend(Backend::Interpreter);
+          assert!(
+            actual == expected,
+            "{} diverges at frame {}",
+            path.display(),
+            frame
+          );
+          actual.set_backend(backend);
+          if want.is_err() {
+            break;
+          }
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn backends_run_games_identically() {
+    assert_games_match(Backend::Threaded, Chip8::run_frame);
+  }
+
+  #[cfg(feature = "jit")]
+  #[test]
+  fn jit_runs_games_identically() {
+    let mut jit = Jit::new();
+    assert_games_match(Backend::Interpreter, |chip8| chip8.run_frame_jit(&mut jit));
+  }
+
+  #[test]
+  fn backends_step_identically() -> Result<(), CpuError> {
+    let program = std::fs::read("games/BRIX").unwrap();
+    let mut interpreted = Chip8::new();
+    let mut threaded = Chip8::new();
+    threaded.set_backend(Backend::Threaded);
+    interpreted.load_program(&program)?;
+    threaded.load_program(&program)?;
+    for _ in 0..10_000 {
+      assert_eq!(interpreted.step()?, threaded.step()?);
+    }
+    threaded.set_backend(Backend::Interpreter);
+    assert!(interpreted == threaded);
+    Ok(())
   }
 }
@@ -1,10 +1,116 @@
-use crate::chip8::instruction::Instruction;
+use crate::cdp1802::bus;
+use crate::cdp1802::cpu::{Cdp1802, Registers};
+use crate::chip8::audio::{Sound, DEFAULT_PATTERN_PITCH, PATTERN_SIZE};
+use crate::chip8::cache::{DecodeCache, WriteGuard, WriteLog};
+use crate::chip8::display::{Display, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use crate::chip8::instruction::{parse_instruction, Instruction, InstructionError};
+use crate::chip8::keypad::Keypad;
+use crate::chip8::memory::Memory;
+use crate::chip8::quirks::Quirks;
+use crate::chip8::rng::Rng;
+use crate::chip8::rom::Rom;
 use crate::chip8::timer::Timer;
+use crate::interface::bus::{Access, Bus, BusError, Observer, ObserverId};
 use crate::interface::emulator::Clocked;
 use crate::interface::serialization::Savable;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use threaded::ThreadedCode;
+
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod recompiled;
+pub mod state;
+mod threaded;
+
+/// Address where programs are loaded, and where execution starts.
+pub const PROGRAM_START: u16 = 0x200;
+/// Address of the built-in hexadecimal font.
+pub const FONT_ADDRESS: u16 = 0x000;
+/// Size, in bytes, of each character of the built-in font.
+pub const FONT_CHARACTER_SIZE: u16 = 5;
+/// Size, in bytes, of the Chip8 memory. XO-CHIP has more, which I
+/// addresses, but code runs from these first 4 KiB, as far as jumps and
+/// calls reach.
+pub const MEMORY_SIZE: usize = 4096;
+// Mask applied to the program counter so that it wraps around the memory
+// code runs from.
+const ADDRESS_MASK: u16 = (MEMORY_SIZE - 1) as u16;
+
+/// Address of V0-VF in memory while a machine code routine runs, as
+/// laid out by the COSMAC VIP interpreter with 4K of memory.
+pub const MACHINE_V_ADDRESS: u16 = 0xEF0;
+/// Address of the 1802 stack pointer (R2) given to machine code
+/// routines.
+pub const MACHINE_STACK_ADDRESS: u16 = 0xECF;
+/// Address of the display memory while a machine code routine runs,
+/// one bit per pixel and 8 bytes per row.
+pub const MACHINE_DISPLAY_ADDRESS: u16 = 0xF00;
+/// Maximum machine cycles a machine code routine may run before it is
+/// considered hung, which is about one second on the VIP.
+pub const MACHINE_CYCLE_LIMIT: u32 = 220_080;
+
+/// Built-in 4x5 font for the hexadecimal characters 0-F.
+pub const FONT: [u8; 80] = [
+  0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+  0x20, 0x60, 0x20, 0x20, 0x70, // 1
+  0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+  0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+  0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+  0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+  0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+  0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+  0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+  0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+  0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+  0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+  0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+  0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+  0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+  0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
+
+/// CpuError is a fault raised while executing a program. The program
+/// counter is left on the faulting instruction.
+#[derive(Error, Debug)]
+pub enum CpuError {
+  #[error("Unsupported instruction at {address:#05X}")]
+  Instruction {
+    address: u16,
+    source: InstructionError,
+  },
+
+  #[error("Stack overflow calling subroutine at {address:#05X}")]
+  StackOverflow { address: u16 },
+
+  #[error("Stack underflow returning from subroutine at {address:#05X}")]
+  StackUnderflow { address: u16 },
+
+  #[error("Machine code routine at {address:#05X} did not return with SEP R4")]
+  MachineCodeTimeout { address: u16 },
+
+  #[error("Program of {size} bytes does not fit in memory")]
+  ProgramTooLarge { size: usize },
+
+  #[error("{source} by the instruction at {address:#05X}")]
+  ProtectedWrite { address: u16, source: BusError },
+}
+
+/// Backend is how the Cpu executes instructions. Both backends execute
+/// instructions identically, so they can be switched at any time.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Hash, Default)]
+pub enum Backend {
+  /// Decodes and executes one instruction at a time.
+  #[default]
+  Interpreter,
+  /// Compiles basic blocks into threaded code, which runs without
+  /// decoding. Self-modifying code falls back to the interpreter.
+  Threaded,
+}
 
 // Cpu is an emulation component for the Chip8 CPU.
-#[derive(Eq, PartialEq, Clone, Copy, Debug, Hash)]
+#[derive(Eq, PartialEq, Debug, Hash)]
 pub struct Cpu {
   // Program counter for the CPU.
   program_counter: u16,
@@ -20,8 +126,69 @@ pub struct Cpu {
   delay_timer: Timer,
   // TODO(joey): document
   stack: [u16; 16],
-  // TODO(joey): document
-  memory: [u8; 4096],
+  // Memory, which instructions read and write through the Bus.
+  memory: Memory,
+  // Framebuffer drawn to by 00E0 and DXYN.
+  display: Display,
+  // Keys read by EX9E, EXA1 and FX0A.
+  keypad: Keypad,
+  // Random number generator for CXNN.
+  rng: Rng,
+  // XO-CHIP audio pattern loaded by F002, which the buzzer plays
+  // instead of a beep once loaded.
+  audio_pattern: Option<[u8; PATTERN_SIZE]>,
+  // XO-CHIP pitch of the audio pattern, set by FX3A.
+  audio_pitch: u8,
+  // Registers of the RCA 1802 running 0NNN machine code routines. They
+  // are kept between routines, as on the VIP.
+  machine: Registers,
+  // Machine cycles taken by the last 0NNN machine code routine.
+  machine_cycles: u32,
+  // Interpreter behaviour, which is configuration and not saved.
+  quirks: Quirks,
+  // Execution backend, which is configuration and not saved.
+  backend: Backend,
+  // Decoded instructions, which are derived from memory and not saved.
+  decode_cache: DecodeCache,
+  // Compiled code, which is derived from memory and not saved.
+  threaded: ThreadedCode,
+  // Versions of memory for native code compiled from it, which are not
+  // saved.
+  writes: WriteGuard,
+  // Addresses written in memory, whose decoded and compiled instructions
+  // are yet to be discarded.
+  write_log: WriteLog,
+}
+
+impl Clone for Cpu {
+  /// clone copies the Cpu, with its own log of the writes to its memory.
+  fn clone(&self) -> Self {
+    let mut memory = self.memory.clone();
+    let write_log = self.write_log.moved(&mut memory);
+    Cpu {
+      program_counter: self.program_counter,
+      i_register: self.i_register,
+      sp: self.sp,
+      v_registers: self.v_registers,
+      sound_timer: self.sound_timer,
+      delay_timer: self.delay_timer,
+      stack: self.stack,
+      memory,
+      display: self.display,
+      keypad: self.keypad,
+      rng: self.rng,
+      audio_pattern: self.audio_pattern,
+      audio_pitch: self.audio_pitch,
+      machine: self.machine,
+      machine_cycles: self.machine_cycles,
+      quirks: self.quirks,
+      backend: self.backend,
+      decode_cache: self.decode_cache,
+      threaded: self.threaded.clone(),
+      writes: self.writes,
+      write_log,
+    }
+  }
 }
 
 impl Savable for Cpu {
@@ -34,6 +201,14 @@ impl Savable for Cpu {
     self.delay_timer.save(fh)?;
     self.memory.save(fh)?;
     self.stack.save(fh)?;
+    self.display.save(fh)?;
+    self.keypad.save(fh)?;
+    self.rng.save(fh)?;
+    self.machine.save(fh)?;
+    self.machine_cycles.save(fh)?;
+    self.audio_pattern.is_some().save(fh)?;
+    self.audio_pattern.unwrap_or_default().save(fh)?;
+    self.audio_pitch.save(fh)?;
     Ok(())
   }
   fn load(&mut self, fh: &mut dyn std::io::Read) -> std::io::Result<()> {
@@ -44,23 +219,103 @@ impl Savable for Cpu {
     self.sound_timer.load(fh)?;
     self.delay_timer.load(fh)?;
     self.memory.load(fh)?;
+    self.decode_cache.clear();
+    self.threaded.clear(true);
+    self.writes.write_all();
     self.stack.load(fh)?;
+    self.display.load(fh)?;
+    self.keypad.load(fh)?;
+    self.rng.load(fh)?;
+    self.machine.load(fh)?;
+    self.machine_cycles.load(fh)?;
+    let mut loaded = false;
+    let mut pattern = [0; PATTERN_SIZE];
+    loaded.load(fh)?;
+    pattern.load(fh)?;
+    self.audio_pattern = if loaded { Some(pattern) } else { None };
+    self.audio_pitch.load(fh)?;
     Ok(())
   }
 }
 
 impl Clocked for Cpu {
+  type Fault = CpuError;
+
   fn clock(&mut self) {
-    unimplemented!("TODO(joey): implement");
+    if let Err(err) = self.step() {
+      log::error!("{}", err);
+    }
   }
 
-  fn clock_rate(self) -> u32 {
+  fn clock_rate(&self) -> u32 {
     // The CHIP has a clock rate of approximately 500hz, but in practice
     // emulating (inaccurately) at 500hz is sufficient.
     //
     // TODO(joey): Clock rates for SuperCHIP should be 1000hz.
     500
   }
+
+  /// run_cycles executes `cycles` instructions, each of which is a
+  /// cycle, stopping early at a fault.
+  fn run_cycles(&mut self, cycles: u32) -> (u32, Result<(), CpuError>) {
+    let (executed, result) = self.run(cycles, false);
+    (executed, result.map(|_| ()))
+  }
+}
+
+/// Cpu is the Bus its instructions use. Its own log of writes observes
+/// its memory, so that written memory is discarded from the decoded and
+/// compiled instructions.
+impl Bus for Cpu {
+  fn read(&mut self, address: u16) -> u8 {
+    self.memory.read(address)
+  }
+
+  fn peek(&self, address: u16) -> u8 {
+    self.memory.peek(address)
+  }
+
+  fn write(&mut self, address: u16, value: u8) -> Result<(), BusError> {
+    self.memory.write(address, value)?;
+    self.discard_written();
+    Ok(())
+  }
+
+  fn observe(&mut self, range: Range<u16>, observer: Arc<Mutex<dyn Observer>>) -> ObserverId {
+    self.memory.observe(range, observer)
+  }
+
+  fn remove_observer(&mut self, id: ObserverId) -> bool {
+    self.memory.remove_observer(id)
+  }
+
+  fn protect(&mut self, range: Range<u16>) {
+    self.memory.protect(range)
+  }
+
+  fn unprotect(&mut self, range: Range<u16>) {
+    self.memory.unprotect(range)
+  }
+}
+
+// MachineBus is the memory of a machine code routine, which it reads and
+// writes through the Chip8's Bus. Protected writes are dropped, and the
+// first is kept to fault on once the routine returns.
+struct MachineBus<'a> {
+  memory: &'a mut Memory,
+  refused: Option<BusError>,
+}
+
+impl<'a> bus::Bus for MachineBus<'a> {
+  fn read(&mut self, address: u16) -> u8 {
+    self.memory.read(address)
+  }
+
+  fn write(&mut self, address: u16, value: u8) {
+    if let Err(err) = self.memory.write(address, value) {
+      self.refused.get_or_insert(err);
+    }
+  }
 }
 
 impl Default for Cpu {
@@ -71,50 +326,408 @@ impl Default for Cpu {
 
 impl Cpu {
   pub fn new() -> Self {
+    let mut memory = Memory::new();
+    let font_start = FONT_ADDRESS as usize;
+    memory.bytes_mut()[font_start..font_start + FONT.len()].copy_from_slice(&FONT);
+    let write_log = WriteLog::observe(&mut memory);
     Cpu {
       v_registers: [0; 16],
       i_register: 0,
-      program_counter: 0,
+      program_counter: PROGRAM_START,
       delay_timer: Timer::new(),
       sound_timer: Timer::new(),
-      memory: [0; 4096],
+      memory,
       stack: [0; 16],
       sp: 0,
+      display: Display::new(),
+      keypad: Keypad::new(),
+      rng: Rng::default(),
+      audio_pattern: None,
+      audio_pitch: DEFAULT_PATTERN_PITCH,
+      machine: Registers::new(),
+      machine_cycles: 0,
+      quirks: Quirks::default(),
+      backend: Backend::default(),
+      decode_cache: DecodeCache::new(),
+      threaded: ThreadedCode::new(),
+      writes: WriteGuard::new(),
+      write_log,
+    }
+  }
+
+  /// load_program copies `program` into memory at `PROGRAM_START`.
+  pub fn load_program(&mut self, program: &[u8]) -> Result<(), CpuError> {
+    self.load_program_at(program, PROGRAM_START)
+  }
+
+  /// load_rom copies `rom` into memory at its entry point, and starts
+  /// execution there. Memory is first resized to its platform's.
+  pub fn load_rom(&mut self, rom: &Rom) -> Result<(), CpuError> {
+    self
+      .memory
+      .resize(rom.platform().memory_size());
+    self.load_program_from(rom.bytes(), rom.entry_point())
+  }
+
+  /// load_program_from copies `program` into memory at `start`, and
+  /// starts execution there.
+  pub(crate) fn load_program_from(&mut self, program: &[u8], start: u16) -> Result<(), CpuError> {
+    self.load_program_at(program, start)?;
+    self.program_counter = start;
+    Ok(())
+  }
+
+  /// reset returns the Cpu to how `new` creates it, with only the font in
+  /// memory. Its configuration is kept: the quirks, backend and decode
+  /// cache setting, and the size, observers and protection of its
+  /// memory.
+  pub fn reset(&mut self) {
+    let memory = self.memory.bytes_mut();
+    memory.fill(0);
+    let font_start = FONT_ADDRESS as usize;
+    memory[font_start..font_start + FONT.len()].copy_from_slice(&FONT);
+    self.decode_cache.clear();
+    self.threaded.clear(true);
+    self.writes.write_all();
+    self.v_registers = [0; 16];
+    self.i_register = 0;
+    self.program_counter = PROGRAM_START;
+    self.delay_timer = Timer::new();
+    self.sound_timer = Timer::new();
+    self.stack = [0; 16];
+    self.sp = 0;
+    self.display = Display::new();
+    self.keypad = Keypad::new();
+    self.rng = Rng::default();
+    self.audio_pattern = None;
+    self.audio_pitch = DEFAULT_PATTERN_PITCH;
+    self.machine = Registers::new();
+    self.machine_cycles = 0;
+  }
+
+  fn load_program_at(&mut self, program: &[u8], start: u16) -> Result<(), CpuError> {
+    let start = start as usize;
+    if program.len() > self.memory.bytes().len() - start {
+      return Err(CpuError::ProgramTooLarge {
+        size: program.len(),
+      });
+    }
+    self.memory.bytes_mut()[start..start + program.len()].copy_from_slice(program);
+    self.decode_cache.clear();
+    self.threaded.clear(true);
+    self.writes.write_all();
+    Ok(())
+  }
+
+  pub fn quirks(&self) -> Quirks {
+    self.quirks
+  }
+
+  pub fn set_quirks(&mut self, quirks: Quirks) {
+    self.quirks = quirks
+  }
+
+  pub fn backend(&self) -> Backend {
+    self.backend
+  }
+
+  pub fn set_backend(&mut self, backend: Backend) {
+    self.backend = backend
+  }
+
+  pub fn decode_cache_enabled(&self) -> bool {
+    self.decode_cache.enabled()
+  }
+
+  /// set_decode_cache enables or disables caching decoded instructions.
+  /// It is enabled by default, and only worth disabling to measure it.
+  pub fn set_decode_cache(&mut self, enabled: bool) {
+    self.decode_cache.set_enabled(enabled)
+  }
+
+  /// seed_rng reseeds the random number generator used by CXNN.
+  pub fn seed_rng(&mut self, seed: u32) {
+    self.rng = Rng::new(seed)
+  }
+
+  pub fn display(&self) -> &Display {
+    &self.display
+  }
+
+  pub fn keypad(&self) -> &Keypad {
+    &self.keypad
+  }
+
+  pub fn keypad_mut(&mut self) -> &mut Keypad {
+    &mut self.keypad
+  }
+
+  /// tone returns whether the buzzer sounds, which it does while the
+  /// sound timer is not zero.
+  pub fn tone(&self) -> bool {
+    !self.sound_timer.is_zero()
+  }
+
+  /// sound returns what the buzzer plays: nothing, a beep, or the
+  /// XO-CHIP audio pattern once one is loaded.
+  pub fn sound(&self) -> Sound {
+    match self.audio_pattern {
+      _ if !self.tone() => Sound::Silent,
+      Some(pattern) => Sound::Pattern {
+        pattern,
+        pitch: self.audio_pitch,
+      },
+      None => Sound::Beep,
     }
   }
 
-  fn handle_instruction(&mut self, instr: Instruction) {
+  /// tick_timers will clock the delay and sound timers, which should
+  /// happen at their 60hz clock rate.
+  pub fn tick_timers(&mut self) {
+    self.delay_timer.clock();
+    self.sound_timer.clock();
+  }
+
+  pub fn program_counter(&self) -> u16 {
+    self.program_counter
+  }
+
+  pub fn i_register(&self) -> u16 {
+    self.i_register
+  }
+
+  pub(crate) fn sp(&self) -> u8 {
+    self.sp
+  }
+
+  pub fn v_registers(&self) -> &[u8; 16] {
+    &self.v_registers
+  }
+
+  /// stack returns the return addresses of the subroutines called, from
+  /// the first called.
+  pub fn stack(&self) -> &[u16] {
+    &self.stack[..self.sp as usize]
+  }
+
+  pub fn delay_timer(&self) -> u8 {
+    self.delay_timer.counter()
+  }
+
+  pub fn sound_timer(&self) -> u8 {
+    self.sound_timer.counter()
+  }
+
+  /// memory returns the bytes of memory, of which there are MEMORY_SIZE
+  /// unless an XO-CHIP ROM was loaded.
+  pub fn memory(&self) -> &[u8] {
+    self.memory.bytes()
+  }
+
+  /// machine_cycles returns the 1802 machine cycles taken by the last
+  /// 0NNN machine code routine.
+  pub fn machine_cycles(&self) -> u32 {
+    self.machine_cycles
+  }
+
+  /// opcode_at returns the big-endian instruction bytes at `address`,
+  /// which wraps around the memory code runs from as fetching does.
+  pub(crate) fn opcode_at(&self, address: u16) -> u16 {
+    let high = self.memory.peek(address & ADDRESS_MASK) as u16;
+    let low = self.memory.peek(address.wrapping_add(1) & ADDRESS_MASK) as u16;
+    (high << 8) | low
+  }
+
+  /// next_instruction returns the decoded instruction at the program
+  /// counter, which is the next to be executed.
+  pub(crate) fn next_instruction(&self) -> Result<Instruction, CpuError> {
+    let address = self.program_counter;
+    if let Some(instr) = self.decode_cache.peek(address) {
+      return Ok(instr);
+    }
+    parse_instruction(self.opcode_at(address))
+      .map_err(|source| CpuError::Instruction { address, source })
+  }
+
+  /// step will fetch, decode and execute a single instruction, and
+  /// returns the executed instruction.
+  #[inline]
+  pub fn step(&mut self) -> Result<Instruction, CpuError> {
+    if self.backend == Backend::Threaded {
+      let address = self.program_counter;
+      let opcode = self.step_threaded()?.opcode();
+      return parse_instruction(opcode).map_err(|source| CpuError::Instruction { address, source });
+    }
+    self.interpret()
+  }
+
+  /// run executes up to `count` instructions, stopping early after a
+  /// DXYN when `stop_at_draw` is set. Returns the number of instructions
+  /// executed, along with whether it stopped at a DXYN or the fault
+  /// that stopped it.
+  pub fn run(&mut self, count: u32, stop_at_draw: bool) -> (u32, Result<bool, CpuError>) {
+    if self.backend == Backend::Threaded {
+      return self.run_threaded(count, stop_at_draw);
+    }
+    let mut executed = 0;
+    while executed < count {
+      match self.interpret() {
+        Err(err) => return (executed, Err(err)),
+        Ok(instr) => {
+          executed += 1;
+          if stop_at_draw && matches!(instr, Instruction::DisplayDraw { .. }) {
+            return (executed, Ok(true));
+          }
+        }
+      }
+    }
+    (executed, Ok(false))
+  }
+
+  // run_threaded is `run` for the threaded code. Each block is looked up
+  // once and run through, until it ends or one of its instructions
+  // invalidates compiled code, which may include the rest of the block.
+  fn run_threaded(&mut self, count: u32, stop_at_draw: bool) -> (u32, Result<bool, CpuError>) {
+    let mut executed = 0;
+    while executed < count {
+      let block = self
+        .threaded
+        .block(self.program_counter, self.memory.bytes());
+      let generation = self.threaded.generation();
+      for index in block.take((count - executed) as usize) {
+        let op = self.threaded.op(index);
+        if let Err(err) = self.execute_op(&op) {
+          return (executed, Err(err));
+        }
+        executed += 1;
+        if op.draws() && stop_at_draw {
+          return (executed, Ok(true));
+        }
+        if self.threaded.generation() != generation {
+          break;
+        }
+      }
+    }
+    (executed, Ok(false))
+  }
+
+  // step_threaded executes the threaded code at the program counter, and
+  // returns the executed micro-op.
+  #[inline]
+  fn step_threaded(&mut self) -> Result<threaded::MicroOp, CpuError> {
+    let block = self
+      .threaded
+      .block(self.program_counter, self.memory.bytes());
+    let op = self.threaded.op(block.start);
+    self.execute_op(&op)?;
+    Ok(op)
+  }
+
+  // execute_op executes the micro-op of the instruction at the program
+  // counter.
+  #[inline]
+  fn execute_op(&mut self, op: &threaded::MicroOp) -> Result<(), CpuError> {
+    let address = self.program_counter;
+    if self.memory.is_observed(Access::Fetch) {
+      self.memory.fetch(address);
+    }
+    self.program_counter = address.wrapping_add(2) & ADDRESS_MASK;
+    if let Err(err) = op.execute(self) {
+      self.program_counter = address;
+      return Err(err);
+    }
+    Ok(())
+  }
+
+  // interpret decodes and executes the instruction at the program
+  // counter, and returns it.
+  #[inline]
+  fn interpret(&mut self) -> Result<Instruction, CpuError> {
+    let address = self.program_counter;
+    let opcode = self.memory.fetch(address);
+    let instr = self
+      .decode_cache
+      .get(address, opcode)
+      .map_err(|source| CpuError::Instruction { address, source })?;
+    self.program_counter = address.wrapping_add(2) & ADDRESS_MASK;
+    if let Err(err) = self.handle_instruction(instr) {
+      self.program_counter = address;
+      return Err(err);
+    }
+    Ok(instr)
+  }
+
+  // skip_next_instruction advances the program counter over the next
+  // instruction.
+  fn skip_next_instruction(&mut self) {
+    self.program_counter = self.program_counter.wrapping_add(2) & ADDRESS_MASK
+  }
+
+  // write_byte stores `value` at `address` for the instruction being
+  // executed, which faults if the address is protected.
+  fn write_byte(&mut self, address: u16, value: u8) -> Result<(), CpuError> {
+    self
+      .write(address, value)
+      .map_err(|source| self.protected_write(source))
+  }
+
+  // protected_write returns the fault of the instruction being executed
+  // for a write refused by memory.
+  fn protected_write(&self, source: BusError) -> CpuError {
+    CpuError::ProtectedWrite {
+      // The program counter has already moved past the instruction.
+      address: self.program_counter.wrapping_sub(2) & ADDRESS_MASK,
+      source,
+    }
+  }
+
+  // discard_written discards the instructions decoded and compiled from
+  // the memory written since it was last called.
+  fn discard_written(&mut self) {
+    for address in self.write_log.take() {
+      self.invalidate(address);
+    }
+  }
+
+  // invalidate discards the instructions decoded and compiled from the
+  // byte at `address`, after it is written. There are none past the
+  // memory code runs from.
+  fn invalidate(&mut self, address: u16) {
+    if address as usize >= MEMORY_SIZE {
+      return;
+    }
+    self.decode_cache.invalidate(address);
+    self.threaded.invalidate(address);
+    self.writes.write(address)
+  }
+
+  // memory_address returns the address of `self.i_register` plus
+  // `offset`, which memory wraps around itself.
+  fn memory_address(&self, offset: usize) -> u16 {
+    self.i_register.wrapping_add(offset as u16)
+  }
+
+  #[inline]
+  fn handle_instruction(&mut self, instr: Instruction) -> Result<(), CpuError> {
     use Instruction::*;
     match instr {
-      Call { address } => unimplemented!("TODO(joey): implement"),
-      DisplayClear() => unimplemented!("TODO(joey): implemenent"),
-      FlowSubroutineReturn() => unimplemented!("TODO(joey): implemenent"),
-      FlowJumpToAddress { address } => unimplemented!("TODO(joey): implement"),
-      FlowSubroutineCall { address } => unimplemented!("TODO(joey): implement"),
+      Call { address } => self.call_machine_code(address as u16)?,
+      DisplayClear() => self.display.clear(),
+      FlowSubroutineReturn() => self.return_from_subroutine()?,
+      FlowJumpToAddress { address } => self.program_counter = address as u16,
+      FlowSubroutineCall { address } => self.call_subroutine(address as u16)?,
       CondSkipIfEqualConst {
         x_register,
         constant,
-      } => {
-        if self.v_registers[x_register] == constant {
-          self.program_counter += 1
-        }
-      }
+      } => self.skip_if(self.v_registers[x_register] == constant),
       CondSkipIfNotEqualConst {
         x_register,
         constant,
-      } => {
-        if self.v_registers[x_register] != constant {
-          self.program_counter += 1
-        }
-      }
+      } => self.skip_if(self.v_registers[x_register] != constant),
       CondSkipIfEqualVar {
         x_register,
         y_register,
-      } => {
-        if self.v_registers[x_register] == self.v_registers[y_register] {
-          self.program_counter += 1
-        }
-      }
+      } => self.skip_if(self.v_registers[x_register] == self.v_registers[y_register]),
       ConstSetVar {
         x_register,
         constant,
@@ -122,7 +735,7 @@ impl Cpu {
       ConstIncrementVar {
         x_register,
         constant,
-      } => self.v_registers[x_register] += constant,
+      } => self.v_registers[x_register] = self.v_registers[x_register].wrapping_add(constant),
       AssignVar {
         x_register,
         y_register,
@@ -130,103 +743,331 @@ impl Cpu {
       BitwiseOrVar {
         x_register,
         y_register,
-      } => self.v_registers[x_register] |= self.v_registers[y_register],
+      } => {
+        self.v_registers[x_register] |= self.v_registers[y_register];
+        self.reset_flag_for_logic()
+      }
       BitwiseAndVar {
         x_register,
         y_register,
-      } => self.v_registers[x_register] &= self.v_registers[y_register],
+      } => {
+        self.v_registers[x_register] &= self.v_registers[y_register];
+        self.reset_flag_for_logic()
+      }
       BitwiseXorVar {
         x_register,
         y_register,
-      } => self.v_registers[x_register] ^= self.v_registers[y_register],
+      } => {
+        self.v_registers[x_register] ^= self.v_registers[y_register];
+        self.reset_flag_for_logic()
+      }
       MathAddVar {
         x_register,
         y_register,
-      } => {
-        let result = self.v_registers[x_register] + self.v_registers[y_register];
-        let has_overflow = (self.v_registers[x_register] < result);
-        self.v_registers[0xF] = if has_overflow { 1 } else { 0 };
-        self.v_registers[x_register] = result
-      }
+      } => self.add(x_register, y_register),
       MathSubVar {
         x_register,
         y_register,
-      } => {
-        let result = self.v_registers[x_register] - self.v_registers[y_register];
-        let has_underflow = (self.v_registers[x_register] > result);
-        self.v_registers[0xF] = if has_underflow { 0 } else { 1 };
-        self.v_registers[x_register] = result
-      }
+      } => self.subtract(x_register, x_register, y_register),
       BitShiftRightVar {
         x_register,
         y_register,
-      } => {
-        let lsb = self.v_registers[x_register] & 0b0001;
-        self.v_registers[0xF] = lsb;
-        self.v_registers[x_register] >>= 1
-      }
+      } => self.shift_right(x_register, y_register),
       MathReverseSubtractVar {
         x_register,
         y_register,
-      } => {
-        let result = self.v_registers[y_register] - self.v_registers[x_register];
-        let has_underflow = (self.v_registers[y_register] > result);
-        self.v_registers[0xF] = if has_underflow { 0 } else { 1 };
-        self.v_registers[y_register] = result
-      }
+      } => self.subtract(x_register, y_register, x_register),
       BitShiftLeftVar {
         x_register,
         y_register,
-      } => {
-        let msb = self.v_registers[x_register] & 0b1000;
-        self.v_registers[0xF] = msb;
-        self.v_registers[x_register] <<= 1
-      }
+      } => self.shift_left(x_register, y_register),
       CondSkipIfNotEqualVar {
         x_register,
         y_register,
-      } => {
-        if self.v_registers[x_register] != self.v_registers[y_register] {
-          self.program_counter += 1
-        }
-      }
+      } => self.skip_if(self.v_registers[x_register] != self.v_registers[y_register]),
       MemorySetAddress { constant } => self.i_register = constant,
-      FlowJumpToAddressPlusVar { constant } => {
-        self.program_counter = (self.v_registers[0] as u16) + constant
-      }
+      FlowJumpToAddressPlusVar { constant } => self.jump_plus_register(constant),
       RandomByConstant {
         x_register,
         constant,
-      } => unimplemented!("TODO(joey): implement"),
+      } => self.v_registers[x_register] = self.rng.next_byte() & constant,
       DisplayDraw {
         x_register,
         y_register,
         constant,
-      } => unimplemented!("TODO(joey): implement"),
-      InputKeyIsPressed { x_register } => unimplemented!("TODO(joey): implement"),
-      InputKeyIsNotPressed { x_register } => unimplemented!("TODO(joey): implement"),
-      TimerGetDelay { x_register } => unimplemented!("TODO(joey): implement"),
-      InputKeyAwaitPress { x_register } => unimplemented!("TODO(joey): implement"),
+      } => self.draw(x_register, y_register, constant),
+      InputKeyIsPressed { x_register } => self.skip_if(
+        self
+          .keypad
+          .is_pressed(self.v_registers[x_register] as usize),
+      ),
+      InputKeyIsNotPressed { x_register } => self.skip_if(
+        !self
+          .keypad
+          .is_pressed(self.v_registers[x_register] as usize),
+      ),
+      TimerGetDelay { x_register } => self.v_registers[x_register] = self.delay_timer.counter(),
+      InputKeyAwaitPress { x_register } => self.await_key(x_register),
       TimerSetDelay { x_register } => self.delay_timer.set_counter(self.v_registers[x_register]),
       TimerSetSound { x_register } => self.sound_timer.set_counter(self.v_registers[x_register]),
       MemoryAddVerToAddress { x_register } => {
-        self.i_register += self.v_registers[x_register] as u16
+        self.i_register = self
+          .i_register
+          .wrapping_add(self.v_registers[x_register] as u16)
       }
-      MemorySetToVarSpriteLocation { x_register } => unimplemented!("TODO(joey): implement"),
-      LoadBinaryCodedDecimal { x_register } => unimplemented!("TODO(joey): implement"),
-      MemoryDump { x_register } => {
-        // FIXME(joey): Check bounds on iteration, this should be inclusive of `x_register`.
-        for register in 0..x_register {
-          self.memory[self.i_register as usize + register] = self.v_registers[register]
-        }
+      MemorySetToVarSpriteLocation { x_register } => self.set_i_to_character(x_register),
+      LoadBinaryCodedDecimal { x_register } => self.store_decimal(x_register)?,
+      MemoryDump { x_register } => self.dump_registers(x_register)?,
+      MemoryLoad { x_register } => self.load_registers(x_register),
+      AudioLoadPattern() => self.load_audio_pattern(),
+      AudioSetPitch { x_register } => self.audio_pitch = self.v_registers[x_register],
+    }
+    Ok(())
+  }
+
+  // The methods below implement the instructions that are more than an
+  // assignment, and are shared by the interpreter and the threaded code.
+
+  fn skip_if(&mut self, condition: bool) {
+    if condition {
+      self.skip_next_instruction()
+    }
+  }
+
+  fn return_from_subroutine(&mut self) -> Result<(), CpuError> {
+    if self.sp == 0 {
+      return Err(CpuError::StackUnderflow {
+        address: self.program_counter.wrapping_sub(2),
+      });
+    }
+    self.sp -= 1;
+    self.program_counter = self.stack[self.sp as usize];
+    Ok(())
+  }
+
+  fn call_subroutine(&mut self, address: u16) -> Result<(), CpuError> {
+    if self.sp as usize >= self.stack.len() {
+      return Err(CpuError::StackOverflow { address });
+    }
+    self.stack[self.sp as usize] = self.program_counter;
+    self.sp += 1;
+    self.program_counter = address;
+    Ok(())
+  }
+
+  fn add(&mut self, x_register: usize, y_register: usize) {
+    let (result, has_overflow) =
+      self.v_registers[x_register].overflowing_add(self.v_registers[y_register]);
+    // The flag is written last, so that it wins when Vx is Vf.
+    self.v_registers[x_register] = result;
+    self.v_registers[0xF] = if has_overflow { 1 } else { 0 };
+  }
+
+  // subtract stores `minuend` - `subtrahend` in `x_register`, for 8XY5
+  // and 8XY7.
+  fn subtract(&mut self, x_register: usize, minuend: usize, subtrahend: usize) {
+    let (result, has_underflow) =
+      self.v_registers[minuend].overflowing_sub(self.v_registers[subtrahend]);
+    self.v_registers[x_register] = result;
+    self.v_registers[0xF] = if has_underflow { 0 } else { 1 };
+  }
+
+  fn shift_right(&mut self, x_register: usize, y_register: usize) {
+    let value = self.shift_operand(x_register, y_register);
+    self.v_registers[x_register] = value >> 1;
+    self.v_registers[0xF] = value & 0b0000_0001;
+  }
+
+  fn shift_left(&mut self, x_register: usize, y_register: usize) {
+    let value = self.shift_operand(x_register, y_register);
+    self.v_registers[x_register] = value << 1;
+    self.v_registers[0xF] = (value & 0b1000_0000) >> 7;
+  }
+
+  fn jump_plus_register(&mut self, constant: u16) {
+    let register = if self.quirks.jump_uses_vx {
+      ((constant & 0x0F00) >> 8) as usize
+    } else {
+      0
+    };
+    self.program_counter = (self.v_registers[register] as u16 + constant) & ADDRESS_MASK
+  }
+
+  fn draw(&mut self, x_register: usize, y_register: usize, rows: u8) {
+    let mut sprite = [0u8; 15];
+    for (offset, row) in sprite.iter_mut().enumerate().take(rows as usize) {
+      *row = self.memory.read(self.memory_address(offset));
+    }
+    let collision = self.display.draw_sprite(
+      self.v_registers[x_register] as usize,
+      self.v_registers[y_register] as usize,
+      &sprite[..rows as usize],
+      self.quirks.clip_sprites,
+    );
+    self.v_registers[0xF] = if collision { 1 } else { 0 };
+  }
+
+  fn await_key(&mut self, x_register: usize) {
+    match self.keypad.first_pressed() {
+      Some(key) => self.v_registers[x_register] = key as u8,
+      // Blocks by executing this instruction again until a key is
+      // pressed.
+      None => self.program_counter = self.program_counter.wrapping_sub(2) & ADDRESS_MASK,
+    }
+  }
+
+  fn set_i_to_character(&mut self, x_register: usize) {
+    let character = (self.v_registers[x_register] & 0xF) as u16;
+    self.i_register = FONT_ADDRESS + character * FONT_CHARACTER_SIZE
+  }
+
+  fn store_decimal(&mut self, x_register: usize) -> Result<(), CpuError> {
+    let value = self.v_registers[x_register];
+    let digits = [value / 100, (value / 10) % 10, value % 10];
+    for (offset, digit) in digits.iter().enumerate() {
+      self.write_byte(self.memory_address(offset), *digit)?
+    }
+    Ok(())
+  }
+
+  fn dump_registers(&mut self, x_register: usize) -> Result<(), CpuError> {
+    for register in 0..=x_register {
+      self.write_byte(self.memory_address(register), self.v_registers[register])?
+    }
+    self.increment_i_for_memory(x_register);
+    Ok(())
+  }
+
+  fn load_registers(&mut self, x_register: usize) {
+    for register in 0..=x_register {
+      self.v_registers[register] = self.memory.read(self.memory_address(register))
+    }
+    self.increment_i_for_memory(x_register)
+  }
+
+  fn load_audio_pattern(&mut self) {
+    let mut pattern = [0; PATTERN_SIZE];
+    for (offset, byte) in pattern.iter_mut().enumerate() {
+      *byte = self.memory.read(self.memory_address(offset));
+    }
+    self.audio_pattern = Some(pattern)
+  }
+
+  // call_machine_code runs the 1802 routine at `address` the way the
+  // VIP interpreter does: it is entered with P = 3, and returns to the
+  // interpreter with SEP R4 (D4).
+  //
+  // The routine sees the interpreter's state as on the VIP: V0-VF in
+  // memory at `MACHINE_V_ADDRESS`, I in RA, the program counter in R5,
+  // a stack in R2 (with X = 2) and the display at
+  // `MACHINE_DISPLAY_ADDRESS`. Changes it makes to any of them are
+  // copied back when it returns.
+  fn call_machine_code(&mut self, address: u16) -> Result<(), CpuError> {
+    let v_registers = self.v_registers;
+    for (offset, value) in v_registers.iter().enumerate() {
+      self.write_byte(MACHINE_V_ADDRESS + offset as u16, *value)?;
+    }
+    self.store_display()?;
+
+    let mut registers = self.machine;
+    registers.r[0x2] = MACHINE_STACK_ADDRESS;
+    registers.r[0x3] = address;
+    registers.r[0x5] = self.program_counter;
+    registers.r[0xA] = self.i_register;
+    registers.x = 2;
+    registers.p = 3;
+    registers.idle = false;
+
+    let machine_bus = MachineBus {
+      memory: &mut self.memory,
+      refused: None,
+    };
+    let mut machine = Cdp1802::with_registers(registers, machine_bus);
+    let mut cycles = 0;
+    let mut hung = false;
+    while machine.registers().p != 4 {
+      if cycles >= MACHINE_CYCLE_LIMIT || machine.registers().idle {
+        hung = true;
+        break;
       }
-      MemoryLoad { x_register } => {
-        // FIXME(joey): Check bounds on iteration, this should be inclusive of `x_register`.
-        for register in 0..x_register {
-          self.v_registers[register] = self.memory[self.i_register as usize + register]
+      cycles += machine.step();
+    }
+    let refused = machine.bus().refused;
+    let registers = machine.into_registers();
+    self.discard_written();
+    if hung {
+      return Err(CpuError::MachineCodeTimeout { address });
+    }
+    if let Some(source) = refused {
+      return Err(self.protected_write(source));
+    }
+    self.machine = registers;
+    self.machine_cycles = cycles;
+    let v_start = MACHINE_V_ADDRESS as usize;
+    self
+      .v_registers
+      .copy_from_slice(&self.memory.bytes()[v_start..v_start + 16]);
+    self.i_register = registers.r[0xA] & ADDRESS_MASK;
+    self.program_counter = registers.r[0x5] & ADDRESS_MASK;
+    self.load_display();
+    Ok(())
+  }
+
+  // store_display copies the display into memory at
+  // `MACHINE_DISPLAY_ADDRESS`, most significant bit leftmost.
+  fn store_display(&mut self) -> Result<(), CpuError> {
+    for y in 0..DISPLAY_HEIGHT {
+      for column in 0..DISPLAY_WIDTH / 8 {
+        let mut byte = 0;
+        for bit in 0..8 {
+          if self.display.pixel(column * 8 + bit, y) {
+            byte |= 0x80 >> bit
+          }
         }
+        let address = MACHINE_DISPLAY_ADDRESS + (y * DISPLAY_WIDTH / 8 + column) as u16;
+        self.write_byte(address, byte)?;
       }
     }
+    Ok(())
+  }
+
+  // load_display redraws the display from memory at
+  // `MACHINE_DISPLAY_ADDRESS`.
+  fn load_display(&mut self) {
+    let start = MACHINE_DISPLAY_ADDRESS as usize;
+    self.display.clear();
+    for y in 0..DISPLAY_HEIGHT {
+      let row = start + y * DISPLAY_WIDTH / 8;
+      for column in 0..DISPLAY_WIDTH / 8 {
+        self.display.draw_sprite(
+          column * 8,
+          y,
+          &self.memory.bytes()[row + column..row + column + 1],
+          true,
+        );
+      }
+    }
+  }
+
+  // shift_operand returns the register value shifted by 8XY6 and 8XYE.
+  fn shift_operand(&self, x_register: usize, y_register: usize) -> u8 {
+    if self.quirks.shift_uses_vy {
+      self.v_registers[y_register]
+    } else {
+      self.v_registers[x_register]
+    }
+  }
+
+  fn reset_flag_for_logic(&mut self) {
+    if self.quirks.logic_resets_vf {
+      self.v_registers[0xF] = 0
+    }
+  }
+
+  fn increment_i_for_memory(&mut self, x_register: usize) {
+    if self.quirks.memory_increments_i {
+      self.i_register = self.i_register.wrapping_add(x_register as u16 + 1)
+    }
   }
 }
 
@@ -235,8 +1076,17 @@ mod tests {
 
   use super::*;
 
+  use crate::chip8::platform::Platform;
+  use crate::interface::bus::Access;
   use crate::interface::serialization;
 
+  // cpu_with_program returns a Cpu with `program` loaded.
+  fn cpu_with_program(program: &[u8]) -> Cpu {
+    let mut cpu = Cpu::new();
+    cpu.load_program(program).unwrap();
+    cpu
+  }
+
   #[test]
   fn serialization_roundtrip_works() -> std::io::Result<()> {
     let buf = &mut Vec::new();
@@ -246,6 +1096,497 @@ mod tests {
     assert_eq!(cpu, loaded_cpu);
     Ok(())
   }
+
+  #[test]
+  fn audio_pattern_plays_while_sound_timer_runs() -> Result<(), Box<dyn std::error::Error>> {
+    // I = pattern; load pattern; V0 = 0x70; pitch = V0; sound = V0
+    let mut program = vec![
+      0xA2, 0x0C, 0xF0, 0x02, 0x60, 0x70, 0xF0, 0x3A, 0xF0, 0x18, 0x12, 0x0A,
+    ];
+    let pattern: Vec<u8> = (1..=16).collect();
+    program.extend_from_slice(&pattern);
+    for &backend in &[Backend::Interpreter, Backend::Threaded] {
+      let mut cpu = cpu_with_program(&program);
+      cpu.set_backend(backend);
+      assert_eq!(cpu.sound(), Sound::Silent);
+      cpu.run(4, false).1?;
+      assert_eq!(cpu.sound(), Sound::Silent);
+      assert_eq!(cpu.audio_pitch, 0x70);
+      cpu.run(1, false).1?;
+      let expected = Sound::Pattern {
+        pattern: [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16],
+        pitch: 0x70,
+      };
+      assert_eq!(cpu.sound(), expected);
+
+      // The pattern and pitch are saved.
+      let buf = &mut Vec::new();
+      cpu.save(buf)?;
+      let loaded = serialization::read_value::<Cpu>(&mut buf.as_slice())?;
+      assert_eq!(loaded.sound(), expected);
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn beeps_without_audio_pattern() -> Result<(), CpuError> {
+    // V0 = 2; sound = V0
+    let mut cpu = cpu_with_program(&[0x60, 0x02, 0xF0, 0x18]);
+    cpu.run(2, false).1?;
+    assert_eq!(cpu.sound(), Sound::Beep);
+    cpu.tick_timers();
+    cpu.tick_timers();
+    assert_eq!(cpu.sound(), Sound::Silent);
+    Ok(())
+  }
+
+  #[test]
+  fn skips_advance_over_next_instruction() -> Result<(), CpuError> {
+    // V0 = 1; skip if V0 == 1; V1 = 1; V2 = 2
+    let mut cpu = cpu_with_program(&[0x60, 0x01, 0x30, 0x01, 0x61, 0x01, 0x62, 0x02]);
+    cpu.step()?;
+    cpu.step()?;
+    assert_eq!(cpu.program_counter, PROGRAM_START + 6);
+    cpu.step()?;
+    assert_eq!(cpu.v_registers[1], 0);
+    assert_eq!(cpu.v_registers[2], 2);
+    Ok(())
+  }
+
+  #[test]
+  fn subroutines_call_and_return() -> Result<(), CpuError> {
+    // call 0x206; jump 0x202 (unused); return
+    let mut cpu = cpu_with_program(&[0x22, 0x06, 0x12, 0x02, 0x00, 0x00, 0x00, 0xEE]);
+    cpu.step()?;
+    assert_eq!(cpu.program_counter, 0x206);
+    assert_eq!(cpu.sp, 1);
+    cpu.step()?;
+    assert_eq!(cpu.program_counter, 0x202);
+    assert_eq!(cpu.sp, 0);
+    Ok(())
+  }
+
+  #[test]
+  fn return_without_call_faults() {
+    let mut cpu = cpu_with_program(&[0x00, 0xEE]);
+    assert!(matches!(cpu.step(), Err(CpuError::StackUnderflow { .. })));
+    assert_eq!(cpu.program_counter, PROGRAM_START);
+  }
+
+  #[test]
+  fn add_sets_carry_flag_after_result() -> Result<(), CpuError> {
+    // V0 = 0xFF; V1 = 0x02; V0 += V1; VF = 0xFF; VF += V1
+    let mut cpu = cpu_with_program(&[0x60, 0xFF, 0x61, 0x02, 0x80, 0x14, 0x6F, 0xFF, 0x8F, 0x14]);
+    for _ in 0..3 {
+      cpu.step()?;
+    }
+    assert_eq!(cpu.v_registers[0], 0x01);
+    assert_eq!(cpu.v_registers[0xF], 1);
+    cpu.step()?;
+    cpu.step()?;
+    assert_eq!(cpu.v_registers[0xF], 1);
+    Ok(())
+  }
+
+  #[test]
+  fn subtractions_set_borrow_flag() -> Result<(), CpuError> {
+    // V0 = 1; V1 = 2; V2 = V0 (copy); V0 -= V1; V2 =- V1 (V2 = V1 - V2)
+    let mut cpu = cpu_with_program(&[0x60, 0x01, 0x61, 0x02, 0x82, 0x00, 0x80, 0x15, 0x82, 0x17]);
+    for _ in 0..4 {
+      cpu.step()?;
+    }
+    assert_eq!(cpu.v_registers[0], 0xFF);
+    assert_eq!(cpu.v_registers[0xF], 0);
+    cpu.step()?;
+    assert_eq!(cpu.v_registers[2], 1);
+    assert_eq!(cpu.v_registers[0xF], 1);
+    Ok(())
+  }
+
+  #[test]
+  fn shifts_follow_quirks() -> Result<(), CpuError> {
+    // V0 = 0x01; V1 = 0x81; V0 <<= V1
+    let program = [0x60, 0x01, 0x61, 0x81, 0x80, 0x1E];
+    let mut modern = cpu_with_program(&program);
+    let mut vip = cpu_with_program(&program);
+    vip.set_quirks(Quirks::cosmac_vip());
+    for _ in 0..3 {
+      modern.step()?;
+      vip.step()?;
+    }
+    assert_eq!(modern.v_registers[0], 0x02);
+    assert_eq!(modern.v_registers[0xF], 0);
+    assert_eq!(vip.v_registers[0], 0x02);
+    assert_eq!(vip.v_registers[0xF], 1);
+    Ok(())
+  }
+
+  #[test]
+  fn memory_dump_and_load_are_inclusive() -> Result<(), CpuError> {
+    // V0 = 1; V1 = 2; I = 0x300; dump V0-V1; V0 = 0; V1 = 0; load V0-V1
+    let mut cpu = cpu_with_program(&[
+      0x60, 0x01, 0x61, 0x02, 0xA3, 0x00, 0xF1, 0x55, 0x60, 0x00, 0x61, 0x00, 0xF1, 0x65,
+    ]);
+    for _ in 0..4 {
+      cpu.step()?;
+    }
+    assert_eq!(cpu.memory.bytes()[0x300..0x303], [1, 2, 0]);
+    assert_eq!(cpu.i_register, 0x300);
+    for _ in 0..3 {
+      cpu.step()?;
+    }
+    assert_eq!(cpu.v_registers[0..2], [1, 2]);
+    Ok(())
+  }
+
+  #[test]
+  fn binary_coded_decimal_works() -> Result<(), CpuError> {
+    // V0 = 254; I = 0x300; bcd V0
+    let mut cpu = cpu_with_program(&[0x60, 0xFE, 0xA3, 0x00, 0xF0, 0x33]);
+    for _ in 0..3 {
+      cpu.step()?;
+    }
+    assert_eq!(cpu.memory.bytes()[0x300..0x303], [2, 5, 4]);
+    Ok(())
+  }
+
+  #[test]
+  fn draw_uses_font_and_sets_collision() -> Result<(), CpuError> {
+    // V0 = 0; I = font(V0); draw V0, V0, 5; draw V0, V0, 5
+    let mut cpu = cpu_with_program(&[0x60, 0x00, 0xF0, 0x29, 0xD0, 0x05, 0xD0, 0x05]);
+    for _ in 0..3 {
+      cpu.step()?;
+    }
+    assert!(cpu.display.pixel(0, 0));
+    assert!(!cpu.display.pixel(1, 1));
+    assert_eq!(cpu.v_registers[0xF], 0);
+    cpu.step()?;
+    assert!(!cpu.display.pixel(0, 0));
+    assert_eq!(cpu.v_registers[0xF], 1);
+    Ok(())
+  }
+
+  #[test]
+  fn await_key_blocks_until_pressed() -> Result<(), CpuError> {
+    let mut cpu = cpu_with_program(&[0xF3, 0x0A]);
+    cpu.step()?;
+    assert_eq!(cpu.program_counter, PROGRAM_START);
+    cpu.keypad_mut().press(0xB);
+    cpu.step()?;
+    assert_eq!(cpu.program_counter, PROGRAM_START + 2);
+    assert_eq!(cpu.v_registers[3], 0xB);
+    Ok(())
+  }
+
+  #[test]
+  fn unsupported_instruction_faults() {
+    let mut cpu = cpu_with_program(&[0x50, 0x01]);
+    assert!(matches!(cpu.step(), Err(CpuError::Instruction { .. })));
+    assert_eq!(cpu.program_counter, PROGRAM_START);
+  }
+
+  #[test]
+  fn machine_code_routine_runs_and_returns() -> Result<(), CpuError> {
+    // call 0x204; V1 = 2; routine: R6 = 0xEF0; M(R6) = 0x42 (V0);
+    // RA += 1 (I); SEP R4
+    let mut cpu = cpu_with_program(&[
+      0x02, 0x04, 0x61, 0x02, 0xF8, 0xF0, 0xA6, 0xF8, 0x0E, 0xB6, 0xF8, 0x42, 0x56, 0x8A, 0xFC,
+      0x01, 0xAA, 0xD4,
+    ]);
+    cpu.i_register = 0x300;
+    cpu.step()?;
+    assert_eq!(cpu.v_registers[0], 0x42);
+    assert_eq!(cpu.i_register, 0x301);
+    assert_eq!(cpu.program_counter, PROGRAM_START + 2);
+    assert_eq!(cpu.machine_cycles(), 10 * 2);
+    cpu.step()?;
+    assert_eq!(cpu.v_registers[1], 2);
+    Ok(())
+  }
+
+  #[test]
+  fn machine_code_routine_draws_to_display() -> Result<(), CpuError> {
+    // call 0x202; routine: R6 = 0xF00; M(R6) = 0x81; SEP R4
+    let mut cpu = cpu_with_program(&[
+      0x02, 0x02, 0xF8, 0x0F, 0xB6, 0xF8, 0x00, 0xA6, 0xF8, 0x81, 0x56, 0xD4,
+    ]);
+    cpu.step()?;
+    assert!(cpu.display.pixel(0, 0));
+    assert!(!cpu.display.pixel(1, 0));
+    assert!(cpu.display.pixel(7, 0));
+    Ok(())
+  }
+
+  #[test]
+  fn hung_machine_code_routine_faults() {
+    // call 0x202; routine: BR 02
+    let mut cpu = cpu_with_program(&[0x02, 0x02, 0x30, 0x02]);
+    assert!(matches!(
+      cpu.step(),
+      Err(CpuError::MachineCodeTimeout { address: 0x202 })
+    ));
+    assert_eq!(cpu.program_counter, PROGRAM_START);
+  }
+
+  #[test]
+  fn memory_writes_invalidate_decoded_instructions() -> Result<(), CpuError> {
+    for backend in [Backend::Interpreter, Backend::Threaded].iter() {
+      // I = 0x208; V0 = 0x62; V1 = 0x33; store V0-V1 over the next
+      // instruction, which becomes V2 = 0x33 instead of V0 = 0xFF.
+      let mut cpu = cpu_with_program(&[0xA2, 0x08, 0x60, 0x62, 0x61, 0x33, 0xF1, 0x55, 0x60, 0xFF]);
+      cpu.set_backend(*backend);
+      // Decode the instruction at 0x208 before it is overwritten.
+      cpu.program_counter = PROGRAM_START + 8;
+      cpu.step()?;
+      cpu.program_counter = PROGRAM_START;
+      for _ in 0..5 {
+        cpu.step()?;
+      }
+      assert_eq!(cpu.v_registers[0], 0x62, "{:?}", backend);
+      assert_eq!(cpu.v_registers[2], 0x33, "{:?}", backend);
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn run_cycles_executes_an_instruction_a_cycle() {
+    // V0 += 1; jump to start
+    let mut cpu = cpu_with_program(&[0x70, 0x01, 0x12, 0x00]);
+    assert!(matches!(cpu.run_cycles(10), (10, Ok(()))));
+    assert_eq!(cpu.v_registers[0], 5);
+
+    // V0 += 1; unsupported instruction
+    let mut cpu = cpu_with_program(&[0x70, 0x01, 0xFF, 0xFF]);
+    let (executed, result) = cpu.run_cycles(10);
+    assert_eq!(executed, 1);
+    assert!(matches!(
+      result,
+      Err(CpuError::Instruction { address: 0x202, .. })
+    ));
+    assert_eq!(cpu.program_counter, 0x202);
+  }
+
+  #[test]
+  fn reset_keeps_configuration() {
+    let mut cpu = cpu_with_program(&[0x60, 0x01]);
+    cpu.set_quirks(Quirks::cosmac_vip());
+    cpu.protect(0x000..PROGRAM_START);
+    cpu.step().unwrap();
+    cpu.reset();
+    let mut expected = Cpu::new();
+    expected.set_quirks(Quirks::cosmac_vip());
+    assert_eq!(cpu, expected);
+    assert!(cpu.write(0x010, 0).is_err());
+  }
+
+  #[test]
+  fn protected_writes_fault() {
+    for backend in [Backend::Interpreter, Backend::Threaded].iter() {
+      // I = 0x010; store V0 over the font.
+      let mut cpu = cpu_with_program(&[0xA0, 0x10, 0xF0, 0x55]);
+      cpu.set_backend(*backend);
+      cpu.protect(0..PROGRAM_START);
+      cpu.step().unwrap();
+      let err = cpu.step().unwrap_err();
+      assert!(
+        matches!(
+          err,
+          CpuError::ProtectedWrite {
+            address: 0x202,
+            source: BusError::Protected { address: 0x010, .. }
+          }
+        ),
+        "{:?}",
+        backend
+      );
+      assert_eq!(cpu.program_counter, 0x202);
+      assert_eq!(cpu.memory.peek(0x010), FONT[0x10]);
+    }
+  }
+
+  #[test]
+  fn observers_see_instruction_accesses() {
+    // I = 0x300; V0 = 7; store V0; load V0.
+    let mut cpu = cpu_with_program(&[0xA3, 0x00, 0x60, 0x07, 0xF0, 0x55, 0xF0, 0x65]);
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let log = seen.clone();
+    cpu.observe(
+      0x300..0x301,
+      Arc::new(Mutex::new(move |access, address, _| {
+        log.lock().unwrap().push((access, address))
+      })),
+    );
+    for _ in 0..4 {
+      cpu.step().unwrap();
+    }
+    assert_eq!(
+      *seen.lock().unwrap(),
+      vec![(Access::Write, 0x300), (Access::Read, 0x300)]
+    );
+  }
+
+  #[test]
+  fn observers_see_fetches() {
+    for backend in [Backend::Interpreter, Backend::Threaded].iter() {
+      // V0 = 1; jump 0x200
+      let mut cpu = cpu_with_program(&[0x60, 0x01, 0x12, 0x00]);
+      cpu.set_backend(*backend);
+      let seen = Arc::new(Mutex::new(Vec::new()));
+      let log = seen.clone();
+      cpu.observe(
+        PROGRAM_START..PROGRAM_START + 4,
+        Arc::new(Mutex::new(move |access, address, value| {
+          log.lock().unwrap().push((access, address, value))
+        })),
+      );
+      cpu.run(3, false).1.unwrap();
+      assert_eq!(
+        *seen.lock().unwrap(),
+        vec![
+          (Access::Fetch, 0x200, 0x60),
+          (Access::Fetch, 0x201, 0x01),
+          (Access::Fetch, 0x202, 0x12),
+          (Access::Fetch, 0x203, 0x00),
+          (Access::Fetch, 0x200, 0x60),
+          (Access::Fetch, 0x201, 0x01),
+        ],
+        "{:?}",
+        backend
+      );
+    }
+  }
+
+  #[test]
+  fn machine_code_writes_go_through_the_bus() {
+    // call 0x204; routine: R6 = 0x300; SEX R6; STXD; SEP R4
+    let program = [
+      0x02, 0x04, 0x00, 0x00, 0xF8, 0x03, 0xB6, 0xF8, 0x00, 0xA6, 0xE6, 0x73, 0xD4,
+    ];
+    let mut cpu = cpu_with_program(&program);
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let log = seen.clone();
+    cpu.observe(
+      0x300..0x301,
+      Arc::new(Mutex::new(move |access, address, _| {
+        log.lock().unwrap().push((access, address))
+      })),
+    );
+    cpu.step().unwrap();
+    assert_eq!(*seen.lock().unwrap(), vec![(Access::Write, 0x300)]);
+
+    let mut cpu = cpu_with_program(&program);
+    cpu.protect(0x300..0x301);
+    assert!(matches!(
+      cpu.step(),
+      Err(CpuError::ProtectedWrite {
+        address: 0x200,
+        source: BusError::Protected { address: 0x300, .. }
+      })
+    ));
+    assert_eq!(cpu.program_counter, PROGRAM_START);
+  }
+
+  #[test]
+  fn clones_discard_their_own_writes() -> Result<(), CpuError> {
+    // V0 = 1; jump 0x200
+    let mut cpu = cpu_with_program(&[0x60, 0x01, 0x12, 0x00]);
+    cpu.step()?;
+    let mut copy = cpu.clone();
+    copy.write(0x201, 0x02).unwrap();
+    for cpu in [&mut cpu, &mut copy].iter_mut() {
+      cpu.step()?;
+      cpu.step()?;
+    }
+    assert_eq!(cpu.v_registers[0], 1);
+    assert_eq!(copy.v_registers[0], 2);
+    Ok(())
+  }
+
+  #[test]
+  fn cpus_can_be_sent_between_threads() {
+    fn assert_send<T: Send>() {}
+    assert_send::<Cpu>();
+  }
+
+  #[test]
+  fn bus_writes_invalidate_decoded_instructions() -> Result<(), CpuError> {
+    // V0 = 1; jump 0x200.
+    let mut cpu = cpu_with_program(&[0x60, 0x01, 0x12, 0x00]);
+    cpu.step()?;
+    // Change the first instruction to V0 = 2, as a cheat would.
+    cpu.write(0x201, 0x02).unwrap();
+    cpu.step()?;
+    cpu.step()?;
+    assert_eq!(cpu.v_registers[0], 2);
+    Ok(())
+  }
+
+  #[test]
+  fn threaded_run_stops_at_draw_and_faults() {
+    // V0 = 1; draw; V1 = 2; invalid
+    let mut cpu = cpu_with_program(&[0x60, 0x01, 0xD0, 0x01, 0x61, 0x02, 0x50, 0x01]);
+    cpu.set_backend(Backend::Threaded);
+    let (executed, result) = cpu.run(10, true);
+    assert_eq!(executed, 2);
+    assert!(result.unwrap());
+    let (executed, result) = cpu.run(10, true);
+    assert_eq!(executed, 1);
+    assert!(matches!(
+      result,
+      Err(CpuError::Instruction { address: 0x206, .. })
+    ));
+    assert_eq!(cpu.program_counter, PROGRAM_START + 6);
+  }
+
+  #[test]
+  fn threaded_run_sees_writes_to_its_own_block() {
+    // I = 0x207; V0 = 0x99; store V0 into the next instruction, which
+    // becomes V1 = 0x99; loop
+    let mut cpu = cpu_with_program(&[0xA2, 0x07, 0x60, 0x99, 0xF0, 0x55, 0x61, 0x00, 0x12, 0x08]);
+    cpu.set_backend(Backend::Threaded);
+    let (executed, result) = cpu.run(4, false);
+    assert_eq!(executed, 4);
+    assert!(result.is_ok());
+    assert_eq!(cpu.v_registers[1], 0x99);
+  }
+
+  #[test]
+  fn program_too_large_is_rejected() {
+    let mut cpu = Cpu::new();
+    assert!(matches!(
+      cpu.load_program(&[0; MEMORY_SIZE]),
+      Err(CpuError::ProgramTooLarge { .. })
+    ));
+  }
+  #[test]
+  fn roms_start_at_their_entry_point() {
+    let rom = Rom::from_bytes(&[0x60, 0x2A], Platform::Eti660).unwrap();
+    let mut cpu = Cpu::new();
+    cpu.load_rom(&rom).unwrap();
+    assert_eq!(cpu.program_counter, 0x600);
+    cpu.step().unwrap();
+    assert_eq!(cpu.v_registers[0], 0x2A);
+
+    // Every ROM a platform accepts fits in the Cpu's memory.
+    for platform in [Platform::Chip8, Platform::Eti660, Platform::XoChip] {
+      let rom = Rom::from_bytes(&vec![0; platform.max_program_size()], platform).unwrap();
+      cpu.load_rom(&rom).unwrap();
+      assert_eq!(cpu.memory().len(), platform.memory_size());
+    }
+  }
+
+  #[test]
+  fn xo_chip_data_is_read_past_4k() {
+    // I := 0xF00, then I += 0xF0 twice, and load V0 from 0x10E0.
+    let mut program = vec![0xAF, 0x00, 0x60, 0xF0, 0xF0, 0x1E, 0xF0, 0x1E, 0xF0, 0x65];
+    program.resize(0x10E0 - PROGRAM_START as usize, 0);
+    program.push(0x2A);
+    let rom = Rom::from_bytes(&program, Platform::XoChip).unwrap();
+    let mut cpu = Cpu::new();
+    cpu.load_rom(&rom).unwrap();
+    cpu.run(5, false).1.unwrap();
+    assert_eq!(cpu.v_registers[0], 0x2A);
+  }
 }
 
 // use display::Display;
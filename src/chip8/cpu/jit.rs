@@ -0,0 +1,965 @@
+//! jit provides a compiler from Chip8 code to native x86-64 code, for
+//! batch workloads that run many frames.
+//!
+//! As with the threaded code, code is compiled one block at a time, when
+//! execution first reaches the block. The instructions that only work on
+//! registers, 1NNN, 3XNN-9XY0, 6XNN, 7XNN, 8XYN, ANNN and FX1E, are
+//! compiled to native code that works on the registers in place in the
+//! Cpu. Every other instruction is compiled to a call to its threaded
+//! code handler.
+//!
+//! Skips branch within a block, and jumps back into a block loop within
+//! it, so a block ends only after a jump elsewhere, a call, a return, or
+//! an instruction that may write memory, or after a draw when asked to
+//! stop at draws. Blocks therefore never
+//! modify their own code. Each block records the version, kept by the
+//! Cpu's `WriteGuard`, of the memory it was compiled from, and a block
+//! whose memory was written since is compiled again.
+//!
+//! On other architectures, or when executable memory is unavailable,
+//! everything is run as threaded code.
+
+use super::{Cpu, CpuError};
+#[cfg(all(target_arch = "x86_64", unix))]
+use crate::interface::bus::Access;
+
+/// Jit runs a Cpu with native code. It holds the compiled code, and can
+/// run any Cpu, though it is fastest with copies of the same one.
+pub struct Jit {
+  #[cfg(all(target_arch = "x86_64", unix))]
+  native: Option<native::Compiler>,
+}
+
+impl Default for Jit {
+  fn default() -> Self {
+    Jit::new()
+  }
+}
+
+impl Jit {
+  pub fn new() -> Self {
+    Jit {
+      #[cfg(all(target_arch = "x86_64", unix))]
+      native: native::Compiler::new(),
+    }
+  }
+
+  /// is_native returns whether code is compiled to native code, rather
+  /// than run as threaded code.
+  pub fn is_native(&self) -> bool {
+    #[cfg(all(target_arch = "x86_64", unix))]
+    return self.native.is_some();
+    #[cfg(not(all(target_arch = "x86_64", unix)))]
+    return false;
+  }
+
+  /// run executes up to `count` instructions of `cpu`, as `Cpu::run`
+  /// does.
+  pub fn run(
+    &mut self,
+    cpu: &mut Cpu,
+    count: u32,
+    stop_at_draw: bool,
+  ) -> (u32, Result<bool, CpuError>) {
+    let mut executed = 0;
+    while executed < count {
+      #[cfg(all(target_arch = "x86_64", unix))]
+      {
+        // Native code does not tell observers of its fetches.
+        let observed = cpu.memory.is_observed(Access::Fetch);
+        if let Some(native) = self.native.as_mut().filter(|_| !observed) {
+          if let Some((instructions, result)) = native.execute(cpu, count - executed, stop_at_draw)
+          {
+            executed += instructions;
+            match result {
+              Err(err) => return (executed, Err(err)),
+              Ok(true) => return (executed, Ok(true)),
+              Ok(false) => continue,
+            }
+          }
+        }
+      }
+      match cpu.step_threaded() {
+        Err(err) => return (executed, Err(err)),
+        Ok(op) => {
+          executed += 1;
+          if op.draws() && stop_at_draw {
+            return (executed, Ok(true));
+          }
+        }
+      }
+    }
+    (executed, Ok(false))
+  }
+}
+
+#[cfg(all(target_arch = "x86_64", unix))]
+mod native {
+  use super::super::threaded::{self, Handler};
+  use super::super::{Cpu, CpuError, ADDRESS_MASK, MEMORY_SIZE};
+  use crate::chip8::instruction::{parse_instruction, Instruction};
+  use crate::chip8::quirks::Quirks;
+
+  // Size, in bytes, of the executable memory. It is cleared when full.
+  const CODE_SIZE: usize = 1 << 20;
+  // Maximum instructions compiled into one block.
+  const MAX_BLOCK_INSTRUCTIONS: usize = 64;
+  // Bit set in the value returned by a block that stopped at a DXYN.
+  const DREW: u32 = 1 << 31;
+
+  // Block is the native code of a block. It runs at most `limit`
+  // instructions of `cpu`, stopping after a DXYN if `stop_at_draw` is
+  // not zero, and returns how many it ran, with `DREW` set if it stopped
+  // at a DXYN. The fault of an instruction that faulted is stored in
+  // `fault`.
+  type Block = unsafe extern "sysv64" fn(
+    cpu: *mut Cpu,
+    fault: *mut Option<CpuError>,
+    limit: u32,
+    stop_at_draw: u32,
+  ) -> u32;
+
+  // Entry is the block compiled at an address.
+  #[derive(Clone, Copy)]
+  struct Entry {
+    offset: usize,
+    // Memory the block was compiled from, which it may leave and come
+    // back to by following jumps.
+    start: usize,
+    end: usize,
+    stamp: u64,
+  }
+
+  /// Compiler compiles blocks to native code, and runs them.
+  pub struct Compiler {
+    code: CodeMemory,
+    entries: Vec<Option<Entry>>,
+    // Quirks the code was compiled for.
+    quirks: Option<Quirks>,
+  }
+
+  impl Compiler {
+    /// new returns a Compiler, or None if executable memory is not
+    /// available.
+    pub fn new() -> Option<Self> {
+      Some(Compiler {
+        code: CodeMemory::new(CODE_SIZE)?,
+        entries: vec![None; MEMORY_SIZE],
+        quirks: None,
+      })
+    }
+
+    /// execute runs the block at the program counter of `cpu`, for at
+    /// most `limit` instructions, and returns the number of instructions
+    /// it executed, along with whether it stopped at a DXYN or the fault
+    /// that stopped it. It returns None if the block could not be
+    /// compiled.
+    pub fn execute(
+      &mut self,
+      cpu: &mut Cpu,
+      limit: u32,
+      stop_at_draw: bool,
+    ) -> Option<(u32, Result<bool, CpuError>)> {
+      if self.quirks != Some(cpu.quirks) {
+        self.clear();
+        self.quirks = Some(cpu.quirks);
+      }
+      let address = cpu.program_counter as usize;
+      let offset = match self.entries[address] {
+        Some(entry) if entry.stamp == cpu.writes.stamp(entry.start, entry.end) => entry.offset,
+        _ => self.compile(cpu)?,
+      };
+      let block = self.code.block(offset);
+      let mut fault = None;
+      // Safety: the block was compiled for the layout of Cpu, and only
+      // reads and writes its registers, or calls `run_handler`.
+      let value = unsafe { block(cpu, &mut fault, limit, stop_at_draw as u32) };
+      let result = match fault {
+        Some(err) => Err(err),
+        None => Ok(value & DREW != 0),
+      };
+      Some((value & !DREW, result))
+    }
+
+    fn clear(&mut self) {
+      self.code.clear();
+      for entry in self.entries.iter_mut() {
+        *entry = None
+      }
+    }
+
+    // compile compiles the block at the program counter of `cpu`, and
+    // returns its code offset, or None if it does not fit in the
+    // executable memory.
+    fn compile(&mut self, cpu: &Cpu) -> Option<usize> {
+      let start = cpu.program_counter as usize;
+      let mut assembler = Assembler::new(cpu);
+      assembler.prologue();
+      let mut address = start;
+      let (mut low, mut high) = (start, start);
+      let mut ended = false;
+      while assembler.instructions() < MAX_BLOCK_INSTRUCTIONS && address + 1 < MEMORY_SIZE {
+        low = low.min(address);
+        high = high.max(address + 2);
+        match assembler.instruction(cpu.opcode_at(address as u16), address, cpu.quirks) {
+          Flow::Next => address += 2,
+          Flow::Jump(target) => address = target,
+          Flow::Ended => {
+            // The block goes on after an instruction that ends it if a
+            // skip jumps over that instruction.
+            address += 2;
+            ended = !assembler.is_skipped_to(address);
+            if ended {
+              break;
+            }
+          }
+        }
+      }
+      assembler.finish(address, ended);
+
+      let offset = match self.code.write(&assembler.code) {
+        Some(offset) => offset,
+        None => {
+          self.clear();
+          self.code.write(&assembler.code)?
+        }
+      };
+      self.entries[start] = Some(Entry {
+        offset,
+        start: low,
+        end: high,
+        stamp: cpu.writes.stamp(low, high),
+      });
+      Some(offset)
+    }
+  }
+
+  // run_handler is called by native code to run the instruction at
+  // `address` with its threaded code `handler`. It returns whether the
+  // instruction faulted, and stores the fault in `fault`.
+  extern "sysv64" fn run_handler(
+    cpu: *mut Cpu,
+    address: u32,
+    fault: *mut Option<CpuError>,
+    handler: usize,
+    opcode: u32,
+  ) -> bool {
+    // Safety: native code passes on the pointers its block was given,
+    // and a handler from `threaded::compile`.
+    let (cpu, fault, handler) = unsafe {
+      (
+        &mut *cpu,
+        &mut *fault,
+        std::mem::transmute::<usize, Handler>(handler),
+      )
+    };
+    let address = address as u16;
+    cpu.program_counter = address.wrapping_add(2) & ADDRESS_MASK;
+    match handler(cpu, opcode as u16) {
+      Ok(()) => false,
+      Err(err) => {
+        cpu.program_counter = address;
+        *fault = Some(err);
+        true
+      }
+    }
+  }
+
+  // ends_block returns whether a block ends after `instr`, because it
+  // changes control flow, draws, or may write memory. Skips and jumps
+  // are compiled by the Assembler, which decides whether they end it.
+  fn ends_block(instr: &Instruction) -> bool {
+    use Instruction::*;
+    matches!(
+      instr,
+      Call { .. }
+        | FlowSubroutineReturn()
+        | FlowSubroutineCall { .. }
+        | FlowJumpToAddressPlusVar { .. }
+        | InputKeyAwaitPress { .. }
+        | LoadBinaryCodedDecimal { .. }
+        | MemoryDump { .. }
+    )
+  }
+
+  // CodeMemory is memory that native code is written to and run from. It
+  // is either writable or executable, never both.
+  struct CodeMemory {
+    memory: *mut u8,
+    size: usize,
+    used: usize,
+    executable: bool,
+  }
+
+  impl CodeMemory {
+    fn new(size: usize) -> Option<Self> {
+      // Safety: this maps new anonymous memory.
+      let memory = unsafe {
+        libc::mmap(
+          std::ptr::null_mut(),
+          size,
+          libc::PROT_READ | libc::PROT_WRITE,
+          libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+          -1,
+          0,
+        )
+      };
+      if memory == libc::MAP_FAILED {
+        return None;
+      }
+      Some(CodeMemory {
+        memory: memory as *mut u8,
+        size,
+        used: 0,
+        executable: false,
+      })
+    }
+
+    fn clear(&mut self) {
+      self.used = 0
+    }
+
+    // write copies `code` into memory, and returns its offset, or None
+    // if it does not fit.
+    fn write(&mut self, code: &[u8]) -> Option<usize> {
+      if self.used + code.len() > self.size || !self.protect(false) {
+        return None;
+      }
+      let offset = self.used;
+      // Safety: the code fits in the mapping, which is writable.
+      unsafe { std::ptr::copy_nonoverlapping(code.as_ptr(), self.memory.add(offset), code.len()) };
+      self.used += code.len();
+      Some(offset)
+    }
+
+    // block returns the native block written at `offset`.
+    fn block(&mut self, offset: usize) -> Block {
+      assert!(
+        self.protect(true),
+        "native code could not be made executable"
+      );
+      // Safety: the offset is the start of a block written by `write`,
+      // and the mapping is executable.
+      unsafe { std::mem::transmute::<*mut u8, Block>(self.memory.add(offset)) }
+    }
+
+    // protect makes the memory executable or writable, and returns
+    // whether it succeeded.
+    fn protect(&mut self, executable: bool) -> bool {
+      if self.executable == executable {
+        return true;
+      }
+      let protection = if executable {
+        libc::PROT_READ | libc::PROT_EXEC
+      } else {
+        libc::PROT_READ | libc::PROT_WRITE
+      };
+      // Safety: this changes the protection of our own mapping.
+      if unsafe { libc::mprotect(self.memory as *mut libc::c_void, self.size, protection) } != 0 {
+        return false;
+      }
+      self.executable = executable;
+      true
+    }
+  }
+
+  impl Drop for CodeMemory {
+    fn drop(&mut self) {
+      // Safety: the mapping is not used after this.
+      unsafe { libc::munmap(self.memory as *mut libc::c_void, self.size) };
+    }
+  }
+
+  // Scratch registers, numbered as in the reg field of a ModRM byte. Their
+  // low bytes are AL, CL and DL.
+  const EAX: u8 = 0;
+  const ECX: u8 = 1;
+  const EDX: u8 = 2;
+
+  // Opcodes of byte operations between registers, `op r/m8, r8`.
+  const ADD: u8 = 0x00;
+  const OR: u8 = 0x08;
+  const AND: u8 = 0x20;
+  const SUB: u8 = 0x28;
+  const XOR: u8 = 0x30;
+  const CMP: u8 = 0x38;
+
+  // Second opcode bytes of SETcc, for the carry flag set and clear.
+  const SETC: u8 = 0x92;
+  const SETNC: u8 = 0x93;
+
+  // Second opcode bytes of near conditional jumps.
+  const JE: u8 = 0x84;
+  const JNE: u8 = 0x85;
+  const JAE: u8 = 0x83;
+
+  // Jump is a near jump whose rel32 is filled in once its target is
+  // emitted.
+  struct Jump {
+    // Position of the rel32.
+    position: usize,
+    target: Target,
+  }
+
+  // Flow is where a block goes on after an instruction.
+  enum Flow {
+    // The next instruction.
+    Next,
+    // The instruction at an address, jumped to.
+    Jump(usize),
+    // Nowhere, unless a skip jumps over the instruction.
+    Ended,
+  }
+
+  enum Target {
+    // The instruction at an address, skipped to.
+    Instruction(usize),
+    // An exit storing the program counter, when the limit is reached
+    // before the instruction at an address.
+    Limit(usize),
+    // An exit for a faulted instruction.
+    Fault,
+    // An exit after a DXYN, when stopping at draws.
+    Drew,
+  }
+
+  // Assembler emits the native code of a block. In the block, RBX holds
+  // the Cpu, R12 the fault, R13D the limit, R14D the number of
+  // instructions executed and R15D whether to stop at draws.
+  struct Assembler {
+    code: Vec<u8>,
+    // Addresses of the instructions emitted, and their code positions.
+    labels: Vec<(usize, usize)>,
+    jumps: Vec<Jump>,
+    // Offsets of the registers within Cpu.
+    v_registers: i32,
+    i_register: i32,
+    program_counter: i32,
+  }
+
+  impl Assembler {
+    fn new(cpu: &Cpu) -> Self {
+      let base = cpu as *const Cpu as usize;
+      let offset = |field: usize| (field - base) as i32;
+      Assembler {
+        code: Vec::new(),
+        labels: Vec::new(),
+        jumps: Vec::new(),
+        v_registers: offset(cpu.v_registers.as_ptr() as usize),
+        i_register: offset(&cpu.i_register as *const u16 as usize),
+        program_counter: offset(&cpu.program_counter as *const u16 as usize),
+      }
+    }
+
+    fn instructions(&self) -> usize {
+      self.labels.len()
+    }
+
+    // is_skipped_to returns whether a skip jumps to `address`.
+    fn is_skipped_to(&self, address: usize) -> bool {
+      self
+        .jumps
+        .iter()
+        .any(|jump| matches!(jump.target, Target::Instruction(target) if target == address))
+    }
+
+    // prologue saves the callee-saved registers used by the block, which
+    // also aligns the stack for calls, and sets up the block's registers.
+    fn prologue(&mut self) {
+      // push rbx; push r12; push r13; push r14; push r15
+      self
+        .code
+        .extend_from_slice(&[0x53, 0x41, 0x54, 0x41, 0x55, 0x41, 0x56, 0x41, 0x57]);
+      // mov rbx, rdi; mov r12, rsi; mov r13d, edx; xor r14d, r14d;
+      // mov r15d, ecx
+      self.code.extend_from_slice(&[
+        0x48, 0x89, 0xFB, 0x49, 0x89, 0xF4, 0x41, 0x89, 0xD5, 0x45, 0x31, 0xF6, 0x41, 0x89, 0xCF,
+      ]);
+    }
+
+    // exit returns from the block, with the number of instructions
+    // executed less `uncounted`, and `flags` set.
+    fn exit(&mut self, uncounted: u32, flags: u32) {
+      // mov eax, r14d
+      self.code.extend_from_slice(&[0x44, 0x89, 0xF0]);
+      if uncounted > 0 {
+        // sub eax, imm32
+        self.code.push(0x2D);
+        self.code.extend_from_slice(&uncounted.to_le_bytes());
+      }
+      if flags != 0 {
+        // or eax, imm32
+        self.code.push(0x0D);
+        self.code.extend_from_slice(&flags.to_le_bytes());
+      }
+      // pop r15; pop r14; pop r13; pop r12; pop rbx; ret
+      self
+        .code
+        .extend_from_slice(&[0x41, 0x5F, 0x41, 0x5E, 0x41, 0x5D, 0x41, 0x5C, 0x5B, 0xC3]);
+    }
+
+    // finish ends the block before `address`, and emits its exits.
+    // Unless the last instruction `ended` the block, execution continues
+    // at `address`.
+    fn finish(&mut self, address: usize, ended: bool) {
+      if !ended {
+        self.bind(address);
+        self.set_pc(address as u16 & ADDRESS_MASK);
+        self.exit(0, 0);
+      }
+      for jump in std::mem::take(&mut self.jumps) {
+        self.patch(jump.position, self.code.len());
+        match jump.target {
+          Target::Instruction(address) | Target::Limit(address) => {
+            self.set_pc(address as u16 & ADDRESS_MASK);
+            self.exit(0, 0)
+          }
+          Target::Fault => self.exit(1, 0),
+          Target::Drew => self.exit(0, DREW),
+        }
+      }
+    }
+
+    // bind points the jumps to the instruction at `address` here.
+    fn bind(&mut self, address: usize) {
+      let here = self.code.len();
+      let mut jumps = std::mem::take(&mut self.jumps);
+      jumps.retain(|jump| match jump.target {
+        Target::Instruction(target) if target == address => {
+          self.patch(jump.position, here);
+          false
+        }
+        _ => true,
+      });
+      self.jumps = jumps;
+    }
+
+    fn patch(&mut self, position: usize, target: usize) {
+      let relative = target as i32 - (position as i32 + 4);
+      self.code[position..position + 4].copy_from_slice(&relative.to_le_bytes());
+    }
+
+    // jump emits the near conditional jump `0F condition rel32` to
+    // `target`.
+    fn jump(&mut self, condition: u8, target: Target) {
+      self.code.extend_from_slice(&[0x0F, condition]);
+      self.jumps.push(Jump {
+        position: self.code.len(),
+        target,
+      });
+      self.code.extend_from_slice(&[0; 4]);
+    }
+
+    // instruction emits the instruction `opcode` at `address`, and
+    // returns where the block goes on.
+    fn instruction(&mut self, opcode: u16, address: usize, quirks: Quirks) -> Flow {
+      use Instruction::*;
+      self.bind(address);
+      self.labels.push((address, self.code.len()));
+      // Leave before the instruction if the limit is reached.
+      // cmp r14d, r13d; jae exit; inc r14d
+      self.code.extend_from_slice(&[0x45, 0x39, 0xEE]);
+      self.jump(JAE, Target::Limit(address));
+      self.code.extend_from_slice(&[0x41, 0xFF, 0xC6]);
+
+      let instr = match parse_instruction(opcode) {
+        Ok(instr) => instr,
+        Err(_) => {
+          // The threaded code raises the fault.
+          self.call_handler(opcode, address);
+          self.exit(0, 0);
+          return Flow::Ended;
+        }
+      };
+      let skipped = address + 4;
+      match instr {
+        FlowJumpToAddress { address } => return self.jump_to(address),
+        CondSkipIfEqualConst {
+          x_register,
+          constant,
+        } => self.skip_if_constant(JE, x_register, constant, skipped),
+        CondSkipIfNotEqualConst {
+          x_register,
+          constant,
+        } => self.skip_if_constant(JNE, x_register, constant, skipped),
+        CondSkipIfEqualVar {
+          x_register,
+          y_register,
+        } => self.skip_if_register(JE, x_register, y_register, skipped),
+        CondSkipIfNotEqualVar {
+          x_register,
+          y_register,
+        } => self.skip_if_register(JNE, x_register, y_register, skipped),
+        ConstSetVar {
+          x_register,
+          constant,
+        } => self.set_v(x_register, constant),
+        ConstIncrementVar {
+          x_register,
+          constant,
+        } => {
+          // add byte [rbx + Vx], imm8
+          self.code.push(0x80);
+          self.memory(EAX, self.v_registers + x_register as i32);
+          self.code.push(constant)
+        }
+        AssignVar {
+          x_register,
+          y_register,
+        } => {
+          self.load_v(EAX, y_register);
+          self.store_v(EAX, x_register)
+        }
+        BitwiseOrVar {
+          x_register,
+          y_register,
+        } => self.logic(OR, x_register, y_register, quirks),
+        BitwiseAndVar {
+          x_register,
+          y_register,
+        } => self.logic(AND, x_register, y_register, quirks),
+        BitwiseXorVar {
+          x_register,
+          y_register,
+        } => self.logic(XOR, x_register, y_register, quirks),
+        MathAddVar {
+          x_register,
+          y_register,
+        } => self.arithmetic(ADD, x_register, x_register, y_register, SETC),
+        MathSubVar {
+          x_register,
+          y_register,
+        } => self.arithmetic(SUB, x_register, x_register, y_register, SETNC),
+        MathReverseSubtractVar {
+          x_register,
+          y_register,
+        } => self.arithmetic(SUB, x_register, y_register, x_register, SETNC),
+        BitShiftRightVar {
+          x_register,
+          y_register,
+        } => self.shift(0xE8, x_register, y_register, quirks),
+        BitShiftLeftVar {
+          x_register,
+          y_register,
+        } => self.shift(0xE0, x_register, y_register, quirks),
+        MemorySetAddress { constant } => {
+          // mov word [rbx + I], imm16
+          self.code.extend_from_slice(&[0x66, 0xC7]);
+          self.memory(EAX, self.i_register);
+          self.code.extend_from_slice(&constant.to_le_bytes())
+        }
+        MemoryAddVerToAddress { x_register } => {
+          // add word [rbx + I], ax
+          self.load_v(EAX, x_register);
+          self.code.extend_from_slice(&[0x66, 0x01]);
+          self.memory(EAX, self.i_register)
+        }
+        InputKeyIsPressed { .. } | InputKeyIsNotPressed { .. } => {
+          // The threaded code skips by moving the program counter.
+          self.call_handler(opcode, address);
+          // cmp word [rbx + PC], imm16; je skipped
+          self.code.extend_from_slice(&[0x66, 0x81]);
+          self.memory(7, self.program_counter);
+          self
+            .code
+            .extend_from_slice(&(skipped as u16 & ADDRESS_MASK).to_le_bytes());
+          self.jump(JE, Target::Instruction(skipped))
+        }
+        DisplayDraw { .. } => {
+          self.call_handler(opcode, address);
+          // test r15d, r15d; jnz exit
+          self.code.extend_from_slice(&[0x45, 0x85, 0xFF]);
+          self.jump(JNE, Target::Drew)
+        }
+        _ => self.call_handler(opcode, address),
+      }
+      if ends_block(&instr) {
+        self.exit(0, 0);
+        return Flow::Ended;
+      }
+      Flow::Next
+    }
+
+    // jump_to emits 1NNN, which loops within the block if it jumps back
+    // to one of its instructions. Otherwise the block goes on at
+    // `address`, unless a skip still needs the instruction after the
+    // jump, in which case it ends.
+    fn jump_to(&mut self, address: usize) -> Flow {
+      if let Some(&(_, position)) = self.labels.iter().find(|(label, _)| *label == address) {
+        // jmp rel32
+        self.code.push(0xE9);
+        let jump = self.code.len();
+        self.code.extend_from_slice(&[0; 4]);
+        self.patch(jump, position);
+        return Flow::Ended;
+      }
+      let pending = self
+        .jumps
+        .iter()
+        .any(|jump| matches!(jump.target, Target::Instruction(_)));
+      if pending {
+        self.set_pc(address as u16);
+        self.exit(0, 0);
+        return Flow::Ended;
+      }
+      Flow::Jump(address)
+    }
+
+    // call_handler emits a call to `run_handler` for the instruction
+    // `opcode` at `address`, leaving the block if it faults.
+    fn call_handler(&mut self, opcode: u16, address: usize) {
+      let handler = threaded::compile(opcode).handler();
+      // mov rdi, rbx; mov esi, imm32
+      self.code.extend_from_slice(&[0x48, 0x89, 0xDF, 0xBE]);
+      self.code.extend_from_slice(&(address as u32).to_le_bytes());
+      // mov rdx, r12; mov rcx, imm64
+      self.code.extend_from_slice(&[0x4C, 0x89, 0xE2, 0x48, 0xB9]);
+      self
+        .code
+        .extend_from_slice(&(handler as usize as u64).to_le_bytes());
+      // mov r8d, imm32
+      self.code.extend_from_slice(&[0x41, 0xB8]);
+      self.code.extend_from_slice(&(opcode as u32).to_le_bytes());
+      // mov rax, imm64; call rax
+      self.code.extend_from_slice(&[0x48, 0xB8]);
+      let function: extern "sysv64" fn(*mut Cpu, u32, *mut Option<CpuError>, usize, u32) -> bool =
+        run_handler;
+      self
+        .code
+        .extend_from_slice(&(function as usize as u64).to_le_bytes());
+      self.code.extend_from_slice(&[0xFF, 0xD0]);
+      // test al, al; jnz exit
+      self.code.extend_from_slice(&[0x84, 0xC0]);
+      self.jump(JNE, Target::Fault);
+    }
+
+    // logic emits 8XY1, 8XY2 or 8XY3.
+    fn logic(&mut self, op: u8, x_register: usize, y_register: usize, quirks: Quirks) {
+      self.load_v(EAX, x_register);
+      self.load_v(ECX, y_register);
+      self.operate(op, EAX, ECX);
+      self.store_v(EAX, x_register);
+      if quirks.logic_resets_vf {
+        self.set_v(0xF, 0)
+      }
+    }
+
+    // arithmetic emits 8XY4, 8XY5 or 8XY7, storing `left` op `right` in
+    // Vx, and the carry flag as set by `setcc` in VF after it.
+    fn arithmetic(&mut self, op: u8, x_register: usize, left: usize, right: usize, setcc: u8) {
+      self.load_v(EAX, left);
+      self.load_v(ECX, right);
+      self.operate(op, EAX, ECX);
+      // setcc dl
+      self.code.extend_from_slice(&[0x0F, setcc, 0xC0 | EDX]);
+      self.store_v(EAX, x_register);
+      self.store_v(EDX, 0xF)
+    }
+
+    // shift emits 8XY6 or 8XYE, with the ModRM byte of `shr al, 1` or
+    // `shl al, 1`, which leave the bit shifted out in the carry flag.
+    fn shift(&mut self, modrm: u8, x_register: usize, y_register: usize, quirks: Quirks) {
+      let source = if quirks.shift_uses_vy {
+        y_register
+      } else {
+        x_register
+      };
+      self.load_v(EAX, source);
+      self.code.extend_from_slice(&[0xD0, modrm]);
+      // setc dl
+      self.code.extend_from_slice(&[0x0F, SETC, 0xC0 | EDX]);
+      self.store_v(EAX, x_register);
+      self.store_v(EDX, 0xF)
+    }
+
+    // skip_if_constant emits 3XNN or 4XNN, where `condition` jumps to
+    // `skipped` when the skip is taken.
+    fn skip_if_constant(&mut self, condition: u8, x_register: usize, constant: u8, skipped: usize) {
+      self.load_v(EAX, x_register);
+      // cmp al, imm8
+      self.code.extend_from_slice(&[0x3C, constant]);
+      self.jump(condition, Target::Instruction(skipped))
+    }
+
+    // skip_if_register emits 5XY0 or 9XY0, where `condition` jumps to
+    // `skipped` when the skip is taken.
+    fn skip_if_register(
+      &mut self,
+      condition: u8,
+      x_register: usize,
+      y_register: usize,
+      skipped: usize,
+    ) {
+      self.load_v(EAX, x_register);
+      self.load_v(ECX, y_register);
+      self.operate(CMP, EAX, ECX);
+      self.jump(condition, Target::Instruction(skipped))
+    }
+
+    fn set_pc(&mut self, value: u16) {
+      // mov word [rbx + PC], imm16
+      self.code.extend_from_slice(&[0x66, 0xC7]);
+      self.memory(EAX, self.program_counter);
+      self.code.extend_from_slice(&value.to_le_bytes())
+    }
+
+    fn set_v(&mut self, register: usize, value: u8) {
+      // mov byte [rbx + Vx], imm8
+      self.code.push(0xC6);
+      self.memory(EAX, self.v_registers + register as i32);
+      self.code.push(value)
+    }
+
+    fn load_v(&mut self, target: u8, register: usize) {
+      // movzx target, byte [rbx + Vx]
+      self.code.extend_from_slice(&[0x0F, 0xB6]);
+      self.memory(target, self.v_registers + register as i32)
+    }
+
+    fn store_v(&mut self, source: u8, register: usize) {
+      // mov byte [rbx + Vx], source
+      self.code.push(0x88);
+      self.memory(source, self.v_registers + register as i32)
+    }
+
+    // operate emits `op target, source` on the low bytes of two
+    // registers.
+    fn operate(&mut self, op: u8, target: u8, source: u8) {
+      self
+        .code
+        .extend_from_slice(&[op, 0xC0 | source << 3 | target])
+    }
+
+    // memory emits the ModRM byte and displacement addressing
+    // [rbx + `offset`], with `reg` in the reg field.
+    fn memory(&mut self, reg: u8, offset: i32) {
+      self.code.push(0x83 | reg << 3);
+      self.code.extend_from_slice(&offset.to_le_bytes())
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use crate::chip8::cpu::{Backend, PROGRAM_START};
+  use crate::chip8::quirks::Quirks;
+  use crate::chip8::rng::Rng;
+  use crate::interface::bus::Bus;
+
+  // cpu_with_instruction returns a copy of `cpu` that runs `opcode`,
+  // with registers filled from `rng`.
+  fn cpu_with_instruction(cpu: &Cpu, opcode: u16, rng: &mut Rng) -> Cpu {
+    let mut cpu = cpu.clone();
+    cpu.write(PROGRAM_START, (opcode >> 8) as u8).unwrap();
+    cpu.write(PROGRAM_START + 1, opcode as u8).unwrap();
+    for register in cpu.v_registers.iter_mut() {
+      // Values near the ends make equal registers and carries likely.
+      *register = match rng.next_byte() % 4 {
+        0 => rng.next_byte() % 4,
+        1 => 0xFF - rng.next_byte() % 4,
+        _ => rng.next_byte(),
+      };
+    }
+    cpu.i_register = 0xFF0 + (rng.next_byte() % 16) as u16;
+    cpu
+  }
+
+  #[test]
+  fn instructions_match_interpreter() {
+    let mut rng = Rng::default();
+    let mut jit = Jit::new();
+    let mut cpus = [Cpu::new(), Cpu::new()];
+    cpus[0].set_quirks(Quirks::modern());
+    cpus[1].set_quirks(Quirks::cosmac_vip());
+    for opcode in 0..=0xFFFF {
+      // Machine code routines are not run, and FX0A waits for keys, so
+      // neither finishes in one step.
+      if opcode >> 12 == 0 && opcode != 0x00E0 && opcode != 0x00EE || opcode & 0xF0FF == 0xF00A {
+        continue;
+      }
+      // Alternating on Y runs each 8XYN with both sets of quirks.
+      let cpu = &cpus[(opcode as usize >> 4) & 1];
+      let mut interpreted = cpu_with_instruction(cpu, opcode, &mut rng);
+      let mut compiled = interpreted.clone();
+      let expected = interpreted
+        .step()
+        .map(|_| ())
+        .map_err(|err| err.to_string());
+      let (_, result) = jit.run(&mut compiled, 1, false);
+      let result = result.map(|_| ()).map_err(|err| err.to_string());
+      assert_eq!(result, expected, "{:04X}", opcode);
+      assert!(
+        compiled == interpreted,
+        "{:04X} with {:?}",
+        opcode,
+        cpu.quirks
+      );
+    }
+  }
+
+  #[test]
+  fn blocks_match_threaded_code() {
+    // V0 = 0; V1 = 3; V3 = 1; loop: V0 += 7; V2 = V0; V2 <<= 1;
+    // V1 -= V3; skip if V1 == 0; jump loop; I = 0x300; I += V2; jump to
+    // self
+    let program = [
+      0x60, 0x00, 0x61, 0x03, 0x63, 0x01, 0x70, 0x07, 0x82, 0x00, 0x82, 0x2E, 0x81, 0x35, 0x31,
+      0x00, 0x12, 0x06, 0xA3, 0x00, 0xF2, 0x1E, 0x12, 0x16,
+    ];
+    let mut jit = Jit::new();
+    for count in 0..40 {
+      let mut threaded = Cpu::new();
+      threaded.set_backend(Backend::Threaded);
+      threaded.load_program(&program).unwrap();
+      let mut compiled = threaded.clone();
+      let (expected, _) = threaded.run(count, false);
+      let (executed, _) = jit.run(&mut compiled, count, false);
+      assert_eq!(executed, expected);
+      assert!(compiled == threaded, "after {} instructions", count);
+    }
+  }
+
+  #[test]
+  fn written_blocks_are_compiled_again() {
+    // I = 0x205; store V0 at 0x205; V1 = 0x11, which the store changes
+    // to V1 = V0; jump to self
+    let program = [0xA2, 0x05, 0xF0, 0x55, 0x61, 0x11, 0x12, 0x06];
+    let mut cpu = Cpu::new();
+    cpu.load_program(&program).unwrap();
+    cpu.v_registers[0] = 0x42;
+    let mut jit = Jit::new();
+    // Compile the block at 0x204 before it is written.
+    cpu.program_counter = PROGRAM_START + 4;
+    jit.run(&mut cpu, 1, false).1.unwrap();
+    assert_eq!(cpu.v_registers[1], 0x11);
+    cpu.program_counter = PROGRAM_START;
+    let (executed, result) = jit.run(&mut cpu, 4, false);
+    assert_eq!(executed, 4);
+    assert!(result.is_ok());
+    assert_eq!(cpu.v_registers[1], 0x42);
+    assert_eq!(cpu.program_counter, PROGRAM_START + 6);
+  }
+
+  #[test]
+  fn blocks_stop_at_draw_and_fault() {
+    // V0 = 1; draw; V1 = 2; invalid
+    let mut cpu = Cpu::new();
+    cpu
+      .load_program(&[0x60, 0x01, 0xD0, 0x01, 0x61, 0x02, 0x50, 0x01])
+      .unwrap();
+    let mut jit = Jit::new();
+    let (executed, result) = jit.run(&mut cpu, 10, true);
+    assert_eq!(executed, 2);
+    assert!(result.unwrap());
+    let (executed, result) = jit.run(&mut cpu, 10, true);
+    assert_eq!(executed, 1);
+    assert!(matches!(
+      result,
+      Err(CpuError::Instruction { address: 0x206, .. })
+    ));
+    assert_eq!(cpu.v_registers[1], 2);
+    assert_eq!(cpu.program_counter, PROGRAM_START + 6);
+  }
+}
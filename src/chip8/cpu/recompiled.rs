@@ -0,0 +1,365 @@
+//! recompiled runs Chip8 programs that were recompiled ahead of time to
+//! Rust, by `chip8::recompiler`.
+//!
+//! A recompiled program is a `Program`, with one function for each
+//! basic block that `chip8::flow` recovered from its ROM. The functions
+//! execute their instructions on a `Context`. Instructions that only
+//! work on registers are inlined as Rust, and the rest are passed to
+//! `Context::execute`, which runs them as the interpreter does.
+//!
+//! The program's `run` function calls the block at the program counter
+//! for as long as `Context::block` lets it, which is while memory still
+//! holds the ROM's bytes that the block was recompiled from. Code that
+//! is not in any block, such as the target of a BNNN or code the
+//! program wrote to memory, is run by the Cpu's own backend instead.
+
+use super::{Cpu, CpuError, ADDRESS_MASK, MEMORY_SIZE, PROGRAM_START};
+use crate::chip8::instruction::Instruction;
+
+/// Program is a recompiled ROM.
+#[derive(Clone, Copy)]
+pub struct Program {
+  /// The ROM that was recompiled.
+  pub rom: &'static [u8],
+  /// Runs the blocks at the program counter, until there is none that
+  /// `Context::block` lets run.
+  pub run: fn(&mut Context) -> Result<(), CpuError>,
+}
+
+// Checked is what was last found of the memory of a block.
+#[derive(Clone, Copy)]
+struct Checked {
+  // Version of the last write to memory when it was checked, until
+  // which it cannot have changed.
+  newest: u64,
+  // Stamp of the memory when it was checked.
+  stamp: u64,
+  // Whether the memory held the ROM's bytes.
+  matches: bool,
+}
+
+/// Context is the state recompiled blocks execute on. It counts the
+/// instructions executed, so blocks stop at the limit they are run
+/// with.
+pub struct Context<'a> {
+  cpu: &'a mut Cpu,
+  rom: &'static [u8],
+  // The last check of the block at each address.
+  checked: &'a mut [Option<Checked>],
+  executed: u32,
+  limit: u32,
+  stop_at_draw: bool,
+  // Whether a draw was executed that execution stops at.
+  drew: bool,
+}
+
+impl<'a> Context<'a> {
+  #[inline]
+  pub fn program_counter(&self) -> u16 {
+    self.cpu.program_counter
+  }
+
+  /// block returns whether the block of the ROM's bytes from `start` up
+  /// to `end` can run. It cannot once the limit is reached or after a
+  /// draw to stop at, or if its memory no longer holds those bytes.
+  #[inline]
+  pub fn block(&mut self, start: u16, end: u16) -> bool {
+    if self.executed == self.limit || self.drew {
+      return false;
+    }
+    let newest = self.cpu.writes.newest();
+    match self.checked[start as usize] {
+      Some(checked) if checked.newest == newest => checked.matches,
+      _ => self.check(start as usize, end as usize, newest),
+    }
+  }
+
+  // check compares the memory of the block from `start` up to `end`
+  // with the ROM, unless it was not written since it was last checked.
+  #[cold]
+  fn check(&mut self, start: usize, end: usize, newest: u64) -> bool {
+    let stamp = self.cpu.writes.stamp(start, end);
+    let checked = &mut self.checked[start];
+    match checked {
+      Some(checked) if checked.stamp == stamp => checked.newest = newest,
+      _ => {
+        let offset = PROGRAM_START as usize;
+        let rom = self.rom.get(start - offset..end - offset);
+        *checked = Some(Checked {
+          newest,
+          stamp,
+          matches: rom == Some(&self.cpu.memory.bytes()[start..end]),
+        });
+      }
+    }
+    matches!(checked, Some(Checked { matches: true, .. }))
+  }
+
+  /// enter starts the instruction at `address`, and returns whether it
+  /// can be executed. If the limit is reached it leaves the program
+  /// counter at `address` instead, and the block must return.
+  #[inline]
+  pub fn enter(&mut self, address: u16) -> bool {
+    if self.executed == self.limit {
+      self.cpu.program_counter = address;
+      return false;
+    }
+    self.executed += 1;
+    true
+  }
+
+  #[inline]
+  pub fn v(&self, register: usize) -> u8 {
+    self.cpu.v_registers[register]
+  }
+
+  #[inline]
+  pub fn set_v(&mut self, register: usize, value: u8) {
+    self.cpu.v_registers[register] = value
+  }
+
+  #[inline]
+  pub fn set_i(&mut self, value: u16) {
+    self.cpu.i_register = value
+  }
+
+  /// jump continues execution at `address`.
+  #[inline]
+  pub fn jump(&mut self, address: u16) {
+    self.cpu.program_counter = address & ADDRESS_MASK
+  }
+
+  /// skip_if continues execution after the skip at `address`, skipping
+  /// the next instruction if `condition` holds.
+  #[inline]
+  pub fn skip_if(&mut self, address: u16, condition: bool) {
+    let skipped = if condition { 4 } else { 2 };
+    self.jump(address.wrapping_add(skipped))
+  }
+
+  /// execute runs `instr` at `address` as the interpreter does, leaving
+  /// the program counter after it.
+  #[inline]
+  pub fn execute(&mut self, address: u16, instr: Instruction) -> Result<(), CpuError> {
+    self.cpu.program_counter = address.wrapping_add(2) & ADDRESS_MASK;
+    if let Err(err) = self.cpu.handle_instruction(instr) {
+      self.cpu.program_counter = address;
+      self.executed -= 1;
+      return Err(err);
+    }
+    if self.stop_at_draw && matches!(instr, Instruction::DisplayDraw { .. }) {
+      self.drew = true;
+    }
+    Ok(())
+  }
+}
+
+/// Recompiled runs a Cpu with a recompiled program.
+pub struct Recompiled {
+  program: Program,
+  // The last check of the block at each address.
+  checked: Vec<Option<Checked>>,
+}
+
+impl Recompiled {
+  pub fn new(program: Program) -> Self {
+    Recompiled {
+      program,
+      checked: vec![None; MEMORY_SIZE],
+    }
+  }
+
+  /// run executes up to `count` instructions of `cpu`, as `Cpu::run`
+  /// does.
+  pub fn run(
+    &mut self,
+    cpu: &mut Cpu,
+    count: u32,
+    stop_at_draw: bool,
+  ) -> (u32, Result<bool, CpuError>) {
+    let mut context = Context {
+      cpu,
+      rom: self.program.rom,
+      checked: &mut self.checked,
+      executed: 0,
+      limit: count,
+      stop_at_draw,
+      drew: false,
+    };
+    loop {
+      if let Err(err) = (self.program.run)(&mut context) {
+        return (context.executed, Err(err));
+      }
+      if context.drew || context.executed == count {
+        return (context.executed, Ok(context.drew));
+      }
+      // There is no block to run at the program counter.
+      let (executed, result) = context.cpu.run(1, stop_at_draw);
+      context.executed += executed;
+      match result {
+        Ok(false) => {}
+        result => return (context.executed, result),
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::chip8::instruction::Instruction::*;
+
+  // A program looping over a draw, written as the recompiler writes it:
+  //   0x200: V0 = 1
+  //   0x202: I = sprite of V0
+  //   0x204: draw 5 rows at V1, V2
+  //   0x206: V1 += 4
+  //   0x208: skip if V1 == 0x40
+  //   0x20A: jump 0x202
+  //   0x20C: V0 = 2, which the program overwrites with FX55
+  //   0x20E: jump 0x20C
+  static ROM: [u8; 16] = [
+    0x60, 0x01, 0xF0, 0x29, 0xD1, 0x25, 0x71, 0x04, 0x31, 0x40, 0x12, 0x02, 0x60, 0x02, 0x12, 0x0C,
+  ];
+
+  static PROGRAM: Program = Program { rom: &ROM, run };
+
+  fn run(c: &mut Context) -> Result<(), CpuError> {
+    loop {
+      match c.program_counter() {
+        0x200 if c.block(0x200, 0x202) => block_200(c)?,
+        0x202 if c.block(0x202, 0x206) => block_202(c)?,
+        0x206 if c.block(0x206, 0x20A) => block_206(c)?,
+        0x20A if c.block(0x20A, 0x20C) => block_20a(c)?,
+        0x20C if c.block(0x20C, 0x210) => block_20c(c)?,
+        _ => return Ok(()),
+      }
+    }
+  }
+
+  fn block_200(c: &mut Context) -> Result<(), CpuError> {
+    if !c.enter(0x200) {
+      return Ok(());
+    }
+    c.set_v(0, 0x01);
+    c.jump(0x202);
+    Ok(())
+  }
+
+  fn block_202(c: &mut Context) -> Result<(), CpuError> {
+    if !c.enter(0x202) {
+      return Ok(());
+    }
+    c.execute(0x202, MemorySetToVarSpriteLocation { x_register: 0 })?;
+    if !c.enter(0x204) {
+      return Ok(());
+    }
+    c.execute(
+      0x204,
+      DisplayDraw {
+        x_register: 1,
+        y_register: 2,
+        constant: 5,
+      },
+    )?;
+    Ok(())
+  }
+
+  fn block_206(c: &mut Context) -> Result<(), CpuError> {
+    if !c.enter(0x206) {
+      return Ok(());
+    }
+    c.set_v(1, c.v(1).wrapping_add(0x04));
+    if !c.enter(0x208) {
+      return Ok(());
+    }
+    c.skip_if(0x208, c.v(1) == 0x40);
+    Ok(())
+  }
+
+  fn block_20a(c: &mut Context) -> Result<(), CpuError> {
+    if !c.enter(0x20A) {
+      return Ok(());
+    }
+    c.jump(0x202);
+    Ok(())
+  }
+
+  fn block_20c(c: &mut Context) -> Result<(), CpuError> {
+    if !c.enter(0x20C) {
+      return Ok(());
+    }
+    c.set_v(0, 0x02);
+    if !c.enter(0x20E) {
+      return Ok(());
+    }
+    c.jump(0x20C);
+    Ok(())
+  }
+
+  fn loaded_cpu() -> Cpu {
+    let mut cpu = Cpu::new();
+    cpu.load_program(&ROM).unwrap();
+    cpu
+  }
+
+  #[test]
+  fn blocks_run_as_interpreter() {
+    let mut recompiled = Recompiled::new(PROGRAM);
+    for count in 0..80 {
+      for &stop_at_draw in &[false, true] {
+        let mut expected = loaded_cpu();
+        let mut actual = expected.clone();
+        for runs in 0..20 {
+          let want = expected.run(count, stop_at_draw);
+          let got = recompiled.run(&mut actual, count, stop_at_draw);
+          assert_eq!(got.0, want.0, "count {} run {}", count, runs);
+          assert_eq!(got.1.unwrap(), want.1.unwrap());
+          assert_eq!(actual, expected, "count {} run {}", count, runs);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn changed_memory_runs_on_cpu_backend() {
+    let mut recompiled = Recompiled::new(PROGRAM);
+    let mut cpu = loaded_cpu();
+    // Overwrite 0x20C with V0 = 3 through FX55.
+    cpu.i_register = 0x20C;
+    cpu.v_registers[0] = 0x60;
+    cpu.v_registers[1] = 0x03;
+    cpu
+      .handle_instruction(MemoryDump { x_register: 1 })
+      .unwrap();
+    cpu.v_registers[0] = 0;
+    cpu.program_counter = 0x20C;
+    let (executed, result) = recompiled.run(&mut cpu, 3, false);
+    assert_eq!(executed, 3);
+    assert!(!result.unwrap());
+    assert_eq!(cpu.v_registers[0], 3);
+    assert_eq!(cpu.program_counter, 0x20E);
+  }
+
+  #[test]
+  fn faults_leave_program_counter_on_instruction() {
+    let mut recompiled = Recompiled::new(PROGRAM);
+    let mut cpu = loaded_cpu();
+    cpu.sp = 16;
+    let mut context = Context {
+      cpu: &mut cpu,
+      rom: &ROM,
+      checked: &mut recompiled.checked,
+      executed: 0,
+      limit: 1,
+      stop_at_draw: false,
+      drew: false,
+    };
+    assert!(context.block(0x20A, 0x20C));
+    assert!(context.enter(0x20A));
+    let result = context.execute(0x20A, FlowSubroutineCall { address: 0x300 });
+    assert!(matches!(result, Err(CpuError::StackOverflow { .. })));
+    assert_eq!(context.executed, 0);
+    assert_eq!(cpu.program_counter, 0x20A);
+  }
+}
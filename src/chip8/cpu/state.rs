@@ -0,0 +1,288 @@
+//! state lets tests and tools look at and set up the state of a Cpu
+//! without the serializer: `CpuState` is a snapshot of it, the setters
+//! on `Cpu` change it one part at a time, refusing values the Cpu can't
+//! hold, and `CpuBuilder` sets up a whole Cpu at once.
+
+use super::{Cpu, MEMORY_SIZE, PROGRAM_START};
+use crate::chip8::quirks::Quirks;
+use thiserror::Error;
+
+/// StateError is a value refused by a setter, which leaves the Cpu as
+/// it was.
+#[derive(Error, Debug, Eq, PartialEq, Clone, Copy)]
+pub enum StateError {
+  #[error("There is no register V{register:X}")]
+  Register { register: usize },
+
+  #[error("Address {address:#06X} is outside of memory")]
+  Address { address: u16 },
+
+  #[error("{size} bytes at {address:#05X} do not fit in memory")]
+  Span { address: u16, size: usize },
+
+  #[error("The stack is full")]
+  StackFull,
+
+  #[error("The stack is empty")]
+  StackEmpty,
+}
+
+/// CpuState is a snapshot of the registers, timers, stack and memory of
+/// a Cpu.
+#[derive(Eq, PartialEq, Clone, Debug, Hash)]
+pub struct CpuState {
+  pub v_registers: [u8; 16],
+  pub i_register: u16,
+  pub program_counter: u16,
+  /// Return addresses of the subroutines called, from the first called.
+  pub stack: Vec<u16>,
+  pub delay_timer: u8,
+  pub sound_timer: u8,
+  pub memory: Vec<u8>,
+}
+
+// check_address returns `address` if it is in the first `size` bytes of
+// memory.
+fn check_address(address: u16, size: usize) -> Result<u16, StateError> {
+  if (address as usize) < size {
+    Ok(address)
+  } else {
+    Err(StateError::Address { address })
+  }
+}
+
+impl Cpu {
+  /// state returns a snapshot of the Cpu.
+  pub fn state(&self) -> CpuState {
+    CpuState {
+      v_registers: self.v_registers,
+      i_register: self.i_register,
+      program_counter: self.program_counter,
+      stack: self.stack().to_vec(),
+      delay_timer: self.delay_timer(),
+      sound_timer: self.sound_timer(),
+      memory: self.memory().to_vec(),
+    }
+  }
+
+  /// set_v sets the register V`register`, of V0 to VF.
+  pub fn set_v(&mut self, register: usize, value: u8) -> Result<(), StateError> {
+    let v = self
+      .v_registers
+      .get_mut(register)
+      .ok_or(StateError::Register { register })?;
+    *v = value;
+    Ok(())
+  }
+
+  pub fn set_i(&mut self, address: u16) -> Result<(), StateError> {
+    self.i_register = check_address(address, self.memory().len())?;
+    Ok(())
+  }
+
+  /// set_pc sets the address of the next instruction, in the memory code
+  /// runs from.
+  pub fn set_pc(&mut self, address: u16) -> Result<(), StateError> {
+    self.program_counter = check_address(address, MEMORY_SIZE)?;
+    Ok(())
+  }
+
+  pub fn set_delay_timer(&mut self, counter: u8) {
+    self.delay_timer.set_counter(counter)
+  }
+
+  pub fn set_sound_timer(&mut self, counter: u8) {
+    self.sound_timer.set_counter(counter)
+  }
+
+  /// push_stack pushes the return address `address`, as calling a
+  /// subroutine does.
+  pub fn push_stack(&mut self, address: u16) -> Result<(), StateError> {
+    let address = check_address(address, MEMORY_SIZE)?;
+    if self.sp as usize >= self.stack.len() {
+      return Err(StateError::StackFull);
+    }
+    self.stack[self.sp as usize] = address;
+    self.sp += 1;
+    Ok(())
+  }
+
+  /// pop_stack pops the last return address pushed.
+  pub fn pop_stack(&mut self) -> Result<u16, StateError> {
+    if self.sp == 0 {
+      return Err(StateError::StackEmpty);
+    }
+    self.sp -= 1;
+    Ok(self.stack[self.sp as usize])
+  }
+
+  /// write_memory copies `bytes` into memory at `address`. Like loading
+  /// a program, it is not seen by observers or refused by protection.
+  pub fn write_memory(&mut self, address: u16, bytes: &[u8]) -> Result<(), StateError> {
+    let start = address as usize;
+    if start + bytes.len() > self.memory().len() {
+      return Err(StateError::Span {
+        address,
+        size: bytes.len(),
+      });
+    }
+    self.memory.bytes_mut()[start..start + bytes.len()].copy_from_slice(bytes);
+    for offset in 0..bytes.len() {
+      self.invalidate(address + offset as u16);
+    }
+    Ok(())
+  }
+}
+
+/// CpuBuilder sets up a Cpu in a given state, such as for a test to
+/// execute an instruction on. Setters that fail are reported by `build`.
+#[derive(Clone, Debug, Default)]
+pub struct CpuBuilder {
+  cpu: Cpu,
+  // First error from a setter, after which the rest are skipped.
+  error: Option<StateError>,
+}
+
+impl CpuBuilder {
+  /// new returns a builder of the Cpu that `Cpu::new` returns.
+  pub fn new() -> Self {
+    CpuBuilder::default()
+  }
+
+  pub fn quirks(mut self, quirks: Quirks) -> Self {
+    self.cpu.set_quirks(quirks);
+    self
+  }
+
+  pub fn v(self, register: usize, value: u8) -> Self {
+    self.apply(|cpu| cpu.set_v(register, value))
+  }
+
+  pub fn i(self, address: u16) -> Self {
+    self.apply(|cpu| cpu.set_i(address))
+  }
+
+  pub fn pc(self, address: u16) -> Self {
+    self.apply(|cpu| cpu.set_pc(address))
+  }
+
+  pub fn delay_timer(mut self, counter: u8) -> Self {
+    self.cpu.set_delay_timer(counter);
+    self
+  }
+
+  pub fn sound_timer(mut self, counter: u8) -> Self {
+    self.cpu.set_sound_timer(counter);
+    self
+  }
+
+  /// stack pushes the return addresses `addresses`, from the first
+  /// called.
+  pub fn stack(self, addresses: &[u16]) -> Self {
+    self.apply(|cpu| {
+      addresses
+        .iter()
+        .try_for_each(|&address| cpu.push_stack(address))
+    })
+  }
+
+  /// memory copies `bytes` into memory at `address`.
+  pub fn memory(self, address: u16, bytes: &[u8]) -> Self {
+    self.apply(|cpu| cpu.write_memory(address, bytes))
+  }
+
+  /// program copies `program` into memory at `PROGRAM_START`, where
+  /// execution starts.
+  pub fn program(self, program: &[u8]) -> Self {
+    self.memory(PROGRAM_START, program)
+  }
+
+  /// build returns the Cpu, or the first error of a setter.
+  pub fn build(self) -> Result<Cpu, StateError> {
+    match self.error {
+      Some(err) => Err(err),
+      None => Ok(self.cpu),
+    }
+  }
+
+  // apply runs `set` on the Cpu, unless a setter already failed.
+  fn apply<F>(mut self, set: F) -> Self
+  where
+    F: FnOnce(&mut Cpu) -> Result<(), StateError>,
+  {
+    if self.error.is_none() {
+      self.error = set(&mut self.cpu).err();
+    }
+    self
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::chip8::cpu::CpuError;
+
+  #[test]
+  fn setters_refuse_what_the_cpu_cannot_hold() {
+    let mut cpu = Cpu::new();
+    let before = cpu.state();
+    assert_eq!(cpu.set_v(16, 1), Err(StateError::Register { register: 16 }));
+    assert_eq!(
+      cpu.set_pc(0x1000),
+      Err(StateError::Address { address: 0x1000 })
+    );
+    assert_eq!(
+      cpu.write_memory(0xFFF, &[1, 2]),
+      Err(StateError::Span {
+        address: 0xFFF,
+        size: 2
+      })
+    );
+    assert_eq!(cpu.pop_stack(), Err(StateError::StackEmpty));
+    for _ in 0..16 {
+      cpu.push_stack(0x200).unwrap();
+    }
+    assert_eq!(cpu.push_stack(0x200), Err(StateError::StackFull));
+    assert_eq!(cpu.pop_stack(), Ok(0x200));
+    assert_eq!(cpu.state().stack.len(), 15);
+    assert_eq!(cpu.state().memory, before.memory);
+  }
+
+  #[test]
+  fn built_cpus_execute_from_their_state() -> Result<(), CpuError> {
+    // VA += VB, which carries.
+    let mut cpu = CpuBuilder::new()
+      .v(0xA, 0xF0)
+      .v(0xB, 0x20)
+      .stack(&[0x300])
+      .delay_timer(3)
+      .program(&[0x8A, 0xB4])
+      .build()
+      .unwrap();
+    let mut expected = cpu.state();
+    cpu.step()?;
+    expected.v_registers[0xA] = 0x10;
+    expected.v_registers[0xF] = 1;
+    expected.program_counter += 2;
+    assert_eq!(cpu.state(), expected);
+    Ok(())
+  }
+
+  #[test]
+  fn builders_report_the_first_error() {
+    let built = CpuBuilder::new().pc(0x2000).v(16, 0).build();
+    assert_eq!(built.err(), Some(StateError::Address { address: 0x2000 }));
+  }
+
+  #[test]
+  fn written_memory_is_decoded_again() -> Result<(), CpuError> {
+    // V0 = 1, which is changed to V0 = 2 after it is decoded.
+    let mut cpu = CpuBuilder::new().program(&[0x60, 0x01]).build().unwrap();
+    cpu.step()?;
+    cpu.set_pc(PROGRAM_START).unwrap();
+    cpu.write_memory(PROGRAM_START + 1, &[0x02]).unwrap();
+    cpu.step()?;
+    assert_eq!(cpu.v_registers()[0], 2);
+    Ok(())
+  }
+}
@@ -0,0 +1,578 @@
+//! threaded provides the threaded code execution backend of the Cpu.
+//!
+//! Code is compiled one basic block at a time, when execution first
+//! reaches the block. A block runs from its entry to the first
+//! instruction that can change control flow: a jump, call, return or
+//! skip. Each instruction is compiled into a `MicroOp`, which holds a
+//! handler for that kind of instruction and the opcode to read operands
+//! from, so executing it needs no decoding or matching on `Instruction`.
+//! A block is a run of micro-ops in one array shared by every block,
+//! which the Cpu runs through with a single lookup of the program
+//! counter.
+//!
+//! Writes to memory invalidate the blocks that include the written
+//! bytes. Addresses whose code was overwritten are self-modifying, and
+//! from then on are executed by the interpreter instead.
+
+use super::{Cpu, CpuError, ADDRESS_MASK, MEMORY_SIZE};
+use crate::chip8::instruction::{parse_instruction, Instruction};
+use std::ops::Range;
+
+// Handler executes a micro-op. The program counter has already been
+// advanced past the instruction.
+pub(super) type Handler = fn(&mut Cpu, u16) -> Result<(), CpuError>;
+
+// MicroOp flag for instructions that end a basic block.
+const ENDS_BLOCK: u8 = 0b01;
+// MicroOp flag for DXYN, which the display wait quirk stops on.
+const DRAWS: u8 = 0b10;
+// Micro-ops compiled before all code is cleared, as code that was
+// invalidated is not reclaimed until then.
+const OPS_LIMIT: usize = 16 * MEMORY_SIZE;
+
+/// MicroOp is a compiled instruction.
+#[derive(Clone, Copy)]
+pub struct MicroOp {
+  handler: Handler,
+  opcode: u16,
+  flags: u8,
+}
+
+impl MicroOp {
+  pub fn opcode(&self) -> u16 {
+    self.opcode
+  }
+
+  #[cfg(feature = "jit")]
+  pub(super) fn handler(&self) -> Handler {
+    self.handler
+  }
+
+  pub fn draws(&self) -> bool {
+    self.flags & DRAWS != 0
+  }
+
+  pub fn ends_block(&self) -> bool {
+    self.flags & ENDS_BLOCK != 0
+  }
+
+  /// execute runs the instruction, with the program counter of `cpu`
+  /// already advanced past it.
+  pub fn execute(&self, cpu: &mut Cpu) -> Result<(), CpuError> {
+    (self.handler)(cpu, self.opcode)
+  }
+}
+
+/// ThreadedCode holds the blocks compiled from memory, by their entry
+/// address.
+///
+/// The code is derived from memory, so it is not saved, and two
+/// ThreadedCodes always compare equal.
+#[derive(Clone)]
+pub struct ThreadedCode {
+  // Micro-ops of every block, each block's in the order of their
+  // instructions in memory.
+  ops: Vec<MicroOp>,
+  // Range of `ops` of the block with its entry at each address.
+  blocks: Vec<Option<(u32, u32)>>,
+  // Entries of the blocks that include each byte. Entries are removed
+  // when the byte is written, so some may be of blocks that were since
+  // invalidated by another byte.
+  covering: Vec<Vec<u16>>,
+  // Addresses whose compiled code was overwritten.
+  self_modified: Vec<bool>,
+  // Count of invalidations, which tells the Cpu to stop running a
+  // block that may be stale.
+  generation: u64,
+}
+
+impl PartialEq for ThreadedCode {
+  fn eq(&self, _other: &Self) -> bool {
+    true
+  }
+}
+
+impl Eq for ThreadedCode {}
+
+impl std::hash::Hash for ThreadedCode {
+  fn hash<H: std::hash::Hasher>(&self, _state: &mut H) {}
+}
+
+impl std::fmt::Debug for ThreadedCode {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("ThreadedCode")
+      .field(
+        "blocks",
+        &self.blocks.iter().filter(|block| block.is_some()).count(),
+      )
+      .finish()
+  }
+}
+
+impl Default for ThreadedCode {
+  fn default() -> Self {
+    ThreadedCode::new()
+  }
+}
+
+impl ThreadedCode {
+  pub fn new() -> Self {
+    ThreadedCode {
+      ops: Vec::new(),
+      blocks: vec![None; MEMORY_SIZE],
+      covering: vec![Vec::new(); MEMORY_SIZE],
+      self_modified: vec![false; MEMORY_SIZE],
+      generation: 0,
+    }
+  }
+
+  /// block returns the indexes of the micro-ops of the block with its
+  /// entry at `address`, compiling it if it is not compiled yet.
+  #[inline]
+  pub fn block(&mut self, address: u16, memory: &[u8]) -> Range<usize> {
+    let (start, end) = match self.blocks[address as usize] {
+      Some(block) => block,
+      None => self.compile_block(address, memory),
+    };
+    start as usize..end as usize
+  }
+
+  /// op returns the micro-op at `index`, of a block returned by `block`
+  /// since the generation last changed.
+  #[inline]
+  pub fn op(&self, index: usize) -> MicroOp {
+    self.ops[index]
+  }
+
+  /// generation changes whenever blocks are invalidated.
+  pub fn generation(&self) -> u64 {
+    self.generation
+  }
+
+  /// invalidate removes the blocks that include the byte at `address`,
+  /// and marks the instructions of theirs that include it as
+  /// self-modifying.
+  pub fn invalidate(&mut self, address: u16) {
+    let index = address as usize % MEMORY_SIZE;
+    for entry in std::mem::take(&mut self.covering[index]) {
+      if self.blocks[entry as usize].take().is_some() {
+        let offset = (index + MEMORY_SIZE - entry as usize) % MEMORY_SIZE;
+        self.self_modified[(index + MEMORY_SIZE - offset % 2) % MEMORY_SIZE] = true;
+        self.generation += 1;
+      }
+    }
+  }
+
+  /// clear removes all compiled code. When `reset` is set, addresses are
+  /// no longer treated as self-modifying, as for a new program.
+  pub fn clear(&mut self, reset: bool) {
+    self.ops.clear();
+    self.blocks.iter_mut().for_each(|block| *block = None);
+    self.covering.iter_mut().for_each(Vec::clear);
+    if reset {
+      self
+        .self_modified
+        .iter_mut()
+        .for_each(|modified| *modified = false);
+    }
+    self.generation += 1;
+  }
+
+  // compile_block compiles the basic block with its entry at `address`,
+  // and returns its range of `ops`.
+  fn compile_block(&mut self, address: u16, memory: &[u8]) -> (u32, u32) {
+    if self.ops.len() >= OPS_LIMIT {
+      self.clear(false);
+    }
+    let start = self.ops.len() as u32;
+    let mut current = address;
+    loop {
+      let index = current as usize;
+      let opcode = ((memory[index] as u16) << 8) | memory[(index + 1) % MEMORY_SIZE] as u16;
+      let op = if self.self_modified[index] {
+        MicroOp {
+          handler: interpret,
+          opcode,
+          flags: flags(opcode),
+        }
+      } else {
+        compile(opcode)
+      };
+      self.ops.push(op);
+      for byte in [index, (index + 1) % MEMORY_SIZE].iter() {
+        if !self.covering[*byte].contains(&address) {
+          self.covering[*byte].push(address);
+        }
+      }
+      current = current.wrapping_add(2) & ADDRESS_MASK;
+      if op.ends_block() || current == address {
+        break;
+      }
+    }
+    let block = (start, self.ops.len() as u32);
+    self.blocks[address as usize] = Some(block);
+    block
+  }
+}
+
+// flags returns the MicroOp flags of `opcode`.
+fn flags(opcode: u16) -> u8 {
+  use Instruction::*;
+  match parse_instruction(opcode) {
+    Ok(DisplayDraw { .. }) => DRAWS,
+    Ok(Call { .. })
+    | Ok(FlowSubroutineReturn())
+    | Ok(FlowJumpToAddress { .. })
+    | Ok(FlowSubroutineCall { .. })
+    | Ok(CondSkipIfEqualConst { .. })
+    | Ok(CondSkipIfNotEqualConst { .. })
+    | Ok(CondSkipIfEqualVar { .. })
+    | Ok(CondSkipIfNotEqualVar { .. })
+    | Ok(FlowJumpToAddressPlusVar { .. })
+    | Ok(InputKeyIsPressed { .. })
+    | Ok(InputKeyIsNotPressed { .. })
+    | Ok(InputKeyAwaitPress { .. })
+    | Err(_) => ENDS_BLOCK,
+    Ok(_) => 0,
+  }
+}
+
+/// compile returns the micro-op for `opcode`.
+pub(super) fn compile(opcode: u16) -> MicroOp {
+  use Instruction::*;
+  let handler: Handler = match parse_instruction(opcode) {
+    Err(_) => invalid,
+    Ok(instr) => match instr {
+      Call { .. } => call_machine_code,
+      DisplayClear() => clear_display,
+      FlowSubroutineReturn() => return_from_subroutine,
+      FlowJumpToAddress { .. } => jump,
+      FlowSubroutineCall { .. } => call_subroutine,
+      CondSkipIfEqualConst { .. } => skip_if_equal_constant,
+      CondSkipIfNotEqualConst { .. } => skip_if_not_equal_constant,
+      CondSkipIfEqualVar { .. } => skip_if_equal,
+      ConstSetVar { .. } => set_constant,
+      ConstIncrementVar { .. } => add_constant,
+      AssignVar { .. } => assign,
+      BitwiseOrVar { .. } => or,
+      BitwiseAndVar { .. } => and,
+      BitwiseXorVar { .. } => xor,
+      MathAddVar { .. } => add,
+      MathSubVar { .. } => subtract,
+      BitShiftRightVar { .. } => shift_right,
+      MathReverseSubtractVar { .. } => reverse_subtract,
+      BitShiftLeftVar { .. } => shift_left,
+      CondSkipIfNotEqualVar { .. } => skip_if_not_equal,
+      MemorySetAddress { .. } => set_i,
+      FlowJumpToAddressPlusVar { .. } => jump_plus_register,
+      RandomByConstant { .. } => random,
+      DisplayDraw { .. } => draw,
+      InputKeyIsPressed { .. } => skip_if_key_pressed,
+      InputKeyIsNotPressed { .. } => skip_if_key_not_pressed,
+      TimerGetDelay { .. } => get_delay,
+      InputKeyAwaitPress { .. } => await_key,
+      TimerSetDelay { .. } => set_delay,
+      TimerSetSound { .. } => set_sound,
+      MemoryAddVerToAddress { .. } => add_to_i,
+      MemorySetToVarSpriteLocation { .. } => set_i_to_character,
+      LoadBinaryCodedDecimal { .. } => store_decimal,
+      MemoryDump { .. } => dump_registers,
+      MemoryLoad { .. } => load_registers,
+      AudioLoadPattern() => load_audio_pattern,
+      AudioSetPitch { .. } => set_audio_pitch,
+    },
+  };
+  MicroOp {
+    handler,
+    opcode,
+    flags: flags(opcode),
+  }
+}
+
+// Operand fields of an opcode.
+
+fn x(opcode: u16) -> usize {
+  ((opcode >> 8) & 0xF) as usize
+}
+
+fn y(opcode: u16) -> usize {
+  ((opcode >> 4) & 0xF) as usize
+}
+
+fn nn(opcode: u16) -> u8 {
+  opcode as u8
+}
+
+fn nnn(opcode: u16) -> u16 {
+  opcode & 0xFFF
+}
+
+// interpret executes a self-modifying instruction with the interpreter,
+// decoding it from memory again.
+fn interpret(cpu: &mut Cpu, _opcode: u16) -> Result<(), CpuError> {
+  let address = cpu.program_counter.wrapping_sub(2) & ADDRESS_MASK;
+  let instr = parse_instruction(cpu.opcode_at(address))
+    .map_err(|source| CpuError::Instruction { address, source })?;
+  cpu.handle_instruction(instr)
+}
+
+fn invalid(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  let address = cpu.program_counter.wrapping_sub(2) & ADDRESS_MASK;
+  match parse_instruction(opcode) {
+    Err(source) => Err(CpuError::Instruction { address, source }),
+    Ok(_) => unreachable!("only invalid opcodes are compiled to invalid"),
+  }
+}
+
+fn call_machine_code(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  cpu.call_machine_code(nnn(opcode))
+}
+
+fn clear_display(cpu: &mut Cpu, _opcode: u16) -> Result<(), CpuError> {
+  cpu.display.clear();
+  Ok(())
+}
+
+fn return_from_subroutine(cpu: &mut Cpu, _opcode: u16) -> Result<(), CpuError> {
+  cpu.return_from_subroutine()
+}
+
+fn jump(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  cpu.program_counter = nnn(opcode);
+  Ok(())
+}
+
+fn call_subroutine(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  cpu.call_subroutine(nnn(opcode))
+}
+
+fn skip_if_equal_constant(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  cpu.skip_if(cpu.v_registers[x(opcode)] == nn(opcode));
+  Ok(())
+}
+
+fn skip_if_not_equal_constant(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  cpu.skip_if(cpu.v_registers[x(opcode)] != nn(opcode));
+  Ok(())
+}
+
+fn skip_if_equal(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  cpu.skip_if(cpu.v_registers[x(opcode)] == cpu.v_registers[y(opcode)]);
+  Ok(())
+}
+
+fn set_constant(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  cpu.v_registers[x(opcode)] = nn(opcode);
+  Ok(())
+}
+
+fn add_constant(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  let register = x(opcode);
+  cpu.v_registers[register] = cpu.v_registers[register].wrapping_add(nn(opcode));
+  Ok(())
+}
+
+fn assign(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  cpu.v_registers[x(opcode)] = cpu.v_registers[y(opcode)];
+  Ok(())
+}
+
+fn or(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  cpu.v_registers[x(opcode)] |= cpu.v_registers[y(opcode)];
+  cpu.reset_flag_for_logic();
+  Ok(())
+}
+
+fn and(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  cpu.v_registers[x(opcode)] &= cpu.v_registers[y(opcode)];
+  cpu.reset_flag_for_logic();
+  Ok(())
+}
+
+fn xor(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  cpu.v_registers[x(opcode)] ^= cpu.v_registers[y(opcode)];
+  cpu.reset_flag_for_logic();
+  Ok(())
+}
+
+fn add(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  cpu.add(x(opcode), y(opcode));
+  Ok(())
+}
+
+fn subtract(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  cpu.subtract(x(opcode), x(opcode), y(opcode));
+  Ok(())
+}
+
+fn shift_right(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  cpu.shift_right(x(opcode), y(opcode));
+  Ok(())
+}
+
+fn reverse_subtract(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  cpu.subtract(x(opcode), y(opcode), x(opcode));
+  Ok(())
+}
+
+fn shift_left(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  cpu.shift_left(x(opcode), y(opcode));
+  Ok(())
+}
+
+fn skip_if_not_equal(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  cpu.skip_if(cpu.v_registers[x(opcode)] != cpu.v_registers[y(opcode)]);
+  Ok(())
+}
+
+fn set_i(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  cpu.i_register = nnn(opcode);
+  Ok(())
+}
+
+fn jump_plus_register(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  cpu.jump_plus_register(nnn(opcode));
+  Ok(())
+}
+
+fn random(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  cpu.v_registers[x(opcode)] = cpu.rng.next_byte() & nn(opcode);
+  Ok(())
+}
+
+fn draw(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  cpu.draw(x(opcode), y(opcode), (opcode & 0xF) as u8);
+  Ok(())
+}
+
+fn skip_if_key_pressed(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  let key = cpu.v_registers[x(opcode)] as usize;
+  cpu.skip_if(cpu.keypad.is_pressed(key));
+  Ok(())
+}
+
+fn skip_if_key_not_pressed(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  let key = cpu.v_registers[x(opcode)] as usize;
+  cpu.skip_if(!cpu.keypad.is_pressed(key));
+  Ok(())
+}
+
+fn get_delay(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  cpu.v_registers[x(opcode)] = cpu.delay_timer.counter();
+  Ok(())
+}
+
+fn await_key(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  cpu.await_key(x(opcode));
+  Ok(())
+}
+
+fn set_delay(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  cpu.delay_timer.set_counter(cpu.v_registers[x(opcode)]);
+  Ok(())
+}
+
+fn set_sound(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  cpu.sound_timer.set_counter(cpu.v_registers[x(opcode)]);
+  Ok(())
+}
+
+fn add_to_i(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  cpu.i_register = cpu
+    .i_register
+    .wrapping_add(cpu.v_registers[x(opcode)] as u16);
+  Ok(())
+}
+
+fn set_i_to_character(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  cpu.set_i_to_character(x(opcode));
+  Ok(())
+}
+
+fn store_decimal(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  cpu.store_decimal(x(opcode))
+}
+
+fn dump_registers(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  cpu.dump_registers(x(opcode))
+}
+
+fn load_registers(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  cpu.load_registers(x(opcode));
+  Ok(())
+}
+
+fn load_audio_pattern(cpu: &mut Cpu, _: u16) -> Result<(), CpuError> {
+  cpu.load_audio_pattern();
+  Ok(())
+}
+
+fn set_audio_pitch(cpu: &mut Cpu, opcode: u16) -> Result<(), CpuError> {
+  cpu.audio_pitch = cpu.v_registers[x(opcode)];
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  #[test]
+  fn blocks_end_at_control_flow() {
+    let mut memory = [0u8; MEMORY_SIZE];
+    // V0 = 1; V1 = 2; skip if V0 == 1; V2 = 3; jump 0x200
+    memory[0x200..0x20A]
+      .copy_from_slice(&[0x60, 0x01, 0x61, 0x02, 0x30, 0x01, 0x62, 0x03, 0x12, 0x00]);
+    let mut code = ThreadedCode::new();
+    let block = code.block(0x200, &memory);
+    let opcodes: Vec<u16> = block.map(|index| code.op(index).opcode()).collect();
+    assert_eq!(opcodes, [0x6001, 0x6102, 0x3001]);
+    assert!(code.op(2).ends_block());
+    assert!(code.blocks[0x206].is_none());
+  }
+
+  #[test]
+  fn writes_invalidate_every_block_including_them() {
+    let mut memory = [0u8; MEMORY_SIZE];
+    // V0 = 1; V1 = 2; jump 0x200
+    memory[0x200..0x206].copy_from_slice(&[0x60, 0x01, 0x61, 0x02, 0x12, 0x00]);
+    let mut code = ThreadedCode::new();
+    code.block(0x200, &memory);
+    code.block(0x202, &memory);
+    let generation = code.generation();
+    code.invalidate(0x201);
+    assert!(code.blocks[0x200].is_none());
+    assert!(code.blocks[0x202].is_some());
+    code.invalidate(0x204);
+    assert!(code.blocks[0x202].is_none());
+    assert_ne!(code.generation(), generation);
+  }
+
+  #[test]
+  fn overwritten_code_is_interpreted() {
+    let mut memory = [0u8; MEMORY_SIZE];
+    memory[0x200..0x204].copy_from_slice(&[0x60, 0x01, 0x12, 0x00]);
+    let mut code = ThreadedCode::new();
+    code.block(0x200, &memory);
+    // Data writes do not make addresses self-modifying.
+    let generation = code.generation();
+    code.invalidate(0x300);
+    assert!(!code.self_modified[0x2FF] && !code.self_modified[0x300]);
+    assert_eq!(code.generation(), generation);
+
+    code.invalidate(0x201);
+    assert!(code.blocks[0x200].is_none());
+    assert!(code.self_modified[0x200] && !code.self_modified[0x202]);
+    let block = code.block(0x200, &memory);
+    assert_eq!(
+      code.op(block.start).handler as usize,
+      interpret as Handler as usize
+    );
+    code.clear(true);
+    let block = code.block(0x200, &memory);
+    assert_eq!(
+      code.op(block.start).handler as usize,
+      set_constant as Handler as usize
+    );
+  }
+}
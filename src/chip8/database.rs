@@ -0,0 +1,316 @@
+//! database identifies ROMs by their SHA-1 hash, and gives what is known
+//! about them: their title, the platform and quirks they were written
+//! for, the speed they play best at and the keys they are played with.
+//!
+//! Entries are in the format of the community CHIP-8 database, a list of
+//! programs with their ROMs by hash, so entries can be copied from it.
+//! See https://github.com/chip-8/chip-8-database. The entries for the
+//! ROMs in `games/` are embedded.
+
+use crate::chip8::chip8::Chip8;
+use crate::chip8::json::{Json, JsonError};
+use crate::chip8::platform::Platform;
+use crate::chip8::quirks::Quirks;
+use crate::chip8::rom::RomHash;
+use std::collections::{BTreeMap, HashMap};
+use thiserror::Error;
+
+// Entries embedded in the binary.
+const EMBEDDED: &str = include_str!("database.json");
+
+/// DatabaseError is returned when a database can't be read.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum DatabaseError {
+  #[error("Invalid database: {0}")]
+  Json(#[from] JsonError),
+
+  #[error("Invalid {field} in database entry {title:?}")]
+  Invalid { title: String, field: &'static str },
+
+  #[error("Unknown platform {platform:?} in database entry {title:?}")]
+  UnknownPlatform { title: String, platform: String },
+}
+
+/// Entry is what is known about one ROM.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct Entry {
+  pub hash: RomHash,
+  pub title: String,
+  pub authors: Vec<String>,
+  pub release: Option<String>,
+  /// Name of the file the ROM is usually found as.
+  pub file: Option<String>,
+  /// Community database identifier of the platform the ROM was written
+  /// for, such as "originalChip8".
+  pub platform_id: String,
+  pub platform: Platform,
+  /// Quirks of the platform, with the ROM's own differences.
+  pub quirks: Quirks,
+  pub instructions_per_frame: Option<u32>,
+  /// Keys the ROM is played with, by what they do, such as "up" or "a".
+  pub keys: BTreeMap<String, u8>,
+}
+
+impl Entry {
+  /// apply sets the quirks and speed of `chip8` to the entry's.
+  pub fn apply(&self, chip8: &mut Chip8) {
+    chip8.set_quirks(self.quirks);
+    if let Some(instructions_per_frame) = self.instructions_per_frame {
+      chip8.set_instructions_per_frame(instructions_per_frame);
+    }
+  }
+}
+
+/// Database is a set of entries, looked up by hash.
+#[derive(Clone, Debug, Default)]
+pub struct Database {
+  entries: Vec<Entry>,
+  by_hash: HashMap<RomHash, usize>,
+}
+
+impl Database {
+  /// embedded returns the database built into the binary.
+  pub fn embedded() -> Database {
+    Database::parse(EMBEDDED).expect("embedded database is valid")
+  }
+
+  /// parse returns the database of the community database's
+  /// `programs.json` format.
+  pub fn parse(text: &str) -> Result<Database, DatabaseError> {
+    let mut database = Database::default();
+    let programs = Json::parse(text)?;
+    let programs = programs.as_array().ok_or(DatabaseError::Invalid {
+      title: String::new(),
+      field: "programs",
+    })?;
+    for program in programs {
+      let title = program
+        .get("title")
+        .and_then(Json::as_str)
+        .ok_or(DatabaseError::Invalid {
+          title: String::new(),
+          field: "title",
+        })?;
+      let invalid = |field| DatabaseError::Invalid {
+        title: String::from(title),
+        field,
+      };
+      let authors = match program.get("authors") {
+        Some(authors) => authors
+          .as_array()
+          .and_then(|authors| {
+            authors
+              .iter()
+              .map(|a| a.as_str().map(String::from))
+              .collect()
+          })
+          .ok_or_else(|| invalid("authors"))?,
+        None => Vec::new(),
+      };
+      let release = program
+        .get("release")
+        .and_then(Json::as_str)
+        .map(String::from);
+      let roms = program
+        .get("roms")
+        .and_then(Json::as_object)
+        .ok_or_else(|| invalid("roms"))?;
+      for (hash, rom) in roms {
+        let hash = RomHash::from_hex(hash).ok_or_else(|| invalid("hash"))?;
+        let platform_id = rom
+          .get("platforms")
+          .and_then(Json::as_array)
+          .and_then(|platforms| platforms.first())
+          .and_then(Json::as_str)
+          .ok_or_else(|| invalid("platforms"))?;
+        let (platform, mut quirks) =
+          platform(platform_id).ok_or_else(|| DatabaseError::UnknownPlatform {
+            title: String::from(title),
+            platform: String::from(platform_id),
+          })?;
+        let overrides = rom
+          .get("quirkyPlatforms")
+          .and_then(|quirky| quirky.get(platform_id))
+          .and_then(Json::as_object);
+        for (name, value) in overrides.into_iter().flatten() {
+          let value = value.as_bool().ok_or_else(|| invalid("quirkyPlatforms"))?;
+          set_quirk(&mut quirks, name, value).ok_or_else(|| invalid("quirkyPlatforms"))?;
+        }
+        let instructions_per_frame = match rom.get("tickrate") {
+          Some(rate) => Some(
+            rate
+              .as_f64()
+              .filter(|rate| *rate >= 1.0 && rate.fract() == 0.0)
+              .ok_or_else(|| invalid("tickrate"))? as u32,
+          ),
+          None => None,
+        };
+        let mut keys = BTreeMap::new();
+        for (name, key) in rom
+          .get("keys")
+          .and_then(Json::as_object)
+          .into_iter()
+          .flatten()
+        {
+          let key = key
+            .as_f64()
+            .filter(|key| (0.0..16.0).contains(key) && key.fract() == 0.0)
+            .ok_or_else(|| invalid("keys"))?;
+          keys.insert(name.clone(), key as u8);
+        }
+        database.insert(Entry {
+          hash,
+          title: String::from(title),
+          authors: authors.clone(),
+          release: release.clone(),
+          file: rom.get("file").and_then(Json::as_str).map(String::from),
+          platform_id: String::from(platform_id),
+          platform,
+          quirks,
+          instructions_per_frame,
+          keys,
+        });
+      }
+    }
+    Ok(database)
+  }
+
+  fn insert(&mut self, entry: Entry) {
+    self.by_hash.insert(entry.hash, self.entries.len());
+    self.entries.push(entry);
+  }
+
+  /// get returns the entry for the ROM with `hash`.
+  pub fn get(&self, hash: &RomHash) -> Option<&Entry> {
+    self.by_hash.get(hash).map(|&index| &self.entries[index])
+  }
+
+  /// entries returns the entries in the order they were read.
+  pub fn entries(&self) -> &[Entry] {
+    &self.entries
+  }
+
+  pub fn len(&self) -> usize {
+    self.entries.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.entries.is_empty()
+  }
+}
+
+// platform returns the platform and quirks of the community database's
+// platform `id`.
+fn platform(id: &str) -> Option<(Platform, Quirks)> {
+  Some(match id {
+    "originalChip8" | "hybridVIP" => (Platform::Chip8, Quirks::cosmac_vip()),
+    "modernChip8" => (Platform::Chip8, Quirks::modern()),
+    "chip48" => (Platform::Schip, Quirks::chip48()),
+    "superchip1" | "superchip" => (Platform::Schip, Quirks::schip()),
+    "xochip" => (Platform::XoChip, Quirks::xo_chip()),
+    _ => return None,
+  })
+}
+
+// set_quirk sets the community database's quirk `name` in `quirks`, or
+// returns None if it is not one the core has.
+fn set_quirk(quirks: &mut Quirks, name: &str, value: bool) -> Option<()> {
+  match name {
+    "shift" => quirks.shift_uses_vy = !value,
+    "memoryLeaveIUnchanged" => quirks.memory_increments_i = !value,
+    // CHIP-48 leaves I incremented by X rather than X + 1, which is the
+    // nearest the core has.
+    "memoryIncrementByX" => quirks.memory_increments_i |= value,
+    "logic" => quirks.logic_resets_vf = value,
+    "jump" => quirks.jump_uses_vx = value,
+    "wrap" => quirks.clip_sprites = !value,
+    "vblank" => quirks.display_wait = value,
+    _ => return None,
+  }
+  Some(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::chip8::rom::Rom;
+  use std::fs;
+  use std::path::PathBuf;
+
+  #[test]
+  fn embedded_database_knows_the_games() {
+    let database = Database::embedded();
+    let games = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("games");
+    for file in fs::read_dir(games).unwrap() {
+      let path = file.unwrap().path();
+      let rom = Rom::from_path(&path, Platform::Chip8).unwrap();
+      let entry = database
+        .get(&rom.hash())
+        .unwrap_or_else(|| panic!("{} is not in the database", path.display()));
+      assert_eq!(
+        entry.file.as_deref(),
+        path.file_name().and_then(|name| name.to_str())
+      );
+    }
+
+    let blinky = database
+      .entries()
+      .iter()
+      .find(|entry| entry.title == "Blinky")
+      .unwrap();
+    assert_eq!(blinky.platform, Platform::Schip);
+    assert_eq!(blinky.quirks, Quirks::chip48());
+    assert_eq!(blinky.keys.get("up"), Some(&3));
+  }
+
+  #[test]
+  fn quirky_platforms_override_quirks() {
+    let database = Database::parse(
+      r#"[{"title": "Test", "roms": {"a9993e364706816aba3e25717850c26c9cd0d89d": {
+        "platforms": ["superchip", "xochip"], "tickrate": 30,
+        "quirkyPlatforms": {"superchip": {"wrap": true}, "xochip": {"shift": true}}
+      }}}]"#,
+    )
+    .unwrap();
+    let entry = database
+      .get(&RomHash::from_hex("a9993e364706816aba3e25717850c26c9cd0d89d").unwrap())
+      .unwrap();
+    assert_eq!(entry.platform_id, "superchip");
+    assert_eq!(
+      entry.quirks,
+      Quirks {
+        clip_sprites: false,
+        ..Quirks::schip()
+      }
+    );
+
+    let mut chip8 = Chip8::new();
+    entry.apply(&mut chip8);
+    assert_eq!(chip8.quirks(), entry.quirks);
+    assert_eq!(chip8.instructions_per_frame(), 30);
+  }
+
+  #[test]
+  fn invalid_entries_are_errors() {
+    let entry =
+      |rom: &str| Database::parse(&format!(r#"[{{"title": "Test", "roms": {{{}}}}}]"#, rom));
+    assert_eq!(
+      entry(r#""a9993e364706816aba3e25717850c26c9cd0d89d": {"platforms": ["vip"]}"#).unwrap_err(),
+      DatabaseError::UnknownPlatform {
+        title: String::from("Test"),
+        platform: String::from("vip")
+      }
+    );
+    assert_eq!(
+      entry(r#""abc": {"platforms": ["xochip"]}"#).unwrap_err(),
+      DatabaseError::Invalid {
+        title: String::from("Test"),
+        field: "hash"
+      }
+    );
+    assert!(entry(
+      r#""a9993e364706816aba3e25717850c26c9cd0d89d": {"platforms": ["xochip"], "keys": {"up": 16}}"#
+    )
+    .is_err());
+  }
+}
@@ -0,0 +1,158 @@
+use crate::interface::serialization::Savable;
+use crate::interface::video::Frame;
+
+/// Width, in pixels, of the Chip8 display.
+pub const DISPLAY_WIDTH: usize = 64;
+/// Height, in pixels, of the Chip8 display.
+pub const DISPLAY_HEIGHT: usize = 32;
+
+/// Display is the emulation of the monochrome Chip8 framebuffer. Pixels
+/// are either set or unset, and sprites are drawn by XOR-ing them onto
+/// the framebuffer.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Hash)]
+pub struct Display {
+  // Row-major pixels of the framebuffer.
+  pixels: [bool; DISPLAY_WIDTH * DISPLAY_HEIGHT],
+}
+
+impl Savable for Display {
+  fn save(&self, fh: &mut dyn std::io::Write) -> std::io::Result<()> {
+    self.pixels.save(fh)?;
+    Ok(())
+  }
+  fn load(&mut self, fh: &mut dyn std::io::Read) -> std::io::Result<()> {
+    self.pixels.load(fh)?;
+    Ok(())
+  }
+}
+
+impl Default for Display {
+  fn default() -> Self {
+    Display::new()
+  }
+}
+
+impl Display {
+  pub fn new() -> Self {
+    Display {
+      pixels: [false; DISPLAY_WIDTH * DISPLAY_HEIGHT],
+    }
+  }
+
+  /// clear will unset all pixels of the display.
+  pub fn clear(&mut self) {
+    self.pixels = [false; DISPLAY_WIDTH * DISPLAY_HEIGHT];
+  }
+
+  /// pixel returns whether the pixel at (x, y) is set. Coordinates
+  /// outside of the display are never set.
+  pub fn pixel(&self, x: usize, y: usize) -> bool {
+    x < DISPLAY_WIDTH && y < DISPLAY_HEIGHT && self.pixels[y * DISPLAY_WIDTH + x]
+  }
+
+  /// pixels returns the row-major pixels of the display.
+  pub fn pixels(&self) -> &[bool] {
+    &self.pixels
+  }
+
+  /// frame returns the display as a Frame, for frame sinks.
+  pub fn frame(&self) -> Frame<'_> {
+    Frame {
+      width: DISPLAY_WIDTH,
+      height: DISPLAY_HEIGHT,
+      pixels: &self.pixels,
+    }
+  }
+
+  /// draw_sprite will XOR the sprite onto the display with its top-left
+  /// corner at (x, y). Each byte of `sprite` is one 8 pixel wide row.
+  ///
+  /// The starting coordinate always wraps around the display. Pixels
+  /// that go over the edge of the display are clipped when `clip` is
+  /// set, and otherwise wrap around to the other side.
+  ///
+  /// Returns true if any set pixel was unset by the draw.
+  pub fn draw_sprite(&mut self, x: usize, y: usize, sprite: &[u8], clip: bool) -> bool {
+    let x = x % DISPLAY_WIDTH;
+    let y = y % DISPLAY_HEIGHT;
+    let mut collision = false;
+    for (row, byte) in sprite.iter().enumerate() {
+      let mut py = y + row;
+      if py >= DISPLAY_HEIGHT {
+        if clip {
+          break;
+        }
+        py %= DISPLAY_HEIGHT;
+      }
+      for column in 0..8 {
+        if byte & (0x80 >> column) == 0 {
+          continue;
+        }
+        let mut px = x + column;
+        if px >= DISPLAY_WIDTH {
+          if clip {
+            break;
+          }
+          px %= DISPLAY_WIDTH;
+        }
+        let pixel = &mut self.pixels[py * DISPLAY_WIDTH + px];
+        collision |= *pixel;
+        *pixel = !*pixel;
+      }
+    }
+    collision
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use crate::interface::serialization;
+
+  #[test]
+  fn serialization_roundtrip_works() -> std::io::Result<()> {
+    let buf = &mut Vec::new();
+    let mut display = Display::new();
+    display.draw_sprite(3, 4, &[0xF0], false);
+    display.save(buf)?;
+    let loaded_display = serialization::read_value::<Display>(&mut buf.as_slice())?;
+    assert_eq!(display, loaded_display);
+    Ok(())
+  }
+
+  #[test]
+  fn draw_sprite_reports_collisions() {
+    let mut display = Display::new();
+    assert!(!display.draw_sprite(0, 0, &[0b1010_0000], false));
+    assert!(display.pixel(0, 0));
+    assert!(!display.pixel(1, 0));
+    assert!(display.pixel(2, 0));
+    // Drawing the same sprite again unsets the pixels.
+    assert!(display.draw_sprite(0, 0, &[0b1010_0000], false));
+    assert!(!display.pixel(0, 0));
+    assert!(!display.pixel(2, 0));
+  }
+
+  #[test]
+  fn draw_sprite_wraps_or_clips_at_edges() {
+    let mut wrapped = Display::new();
+    wrapped.draw_sprite(62, 31, &[0xF0, 0xF0], false);
+    assert!(wrapped.pixel(63, 31));
+    assert!(wrapped.pixel(0, 31));
+    assert!(wrapped.pixel(1, 0));
+
+    let mut clipped = Display::new();
+    clipped.draw_sprite(62, 31, &[0xF0, 0xF0], true);
+    assert!(clipped.pixel(63, 31));
+    assert!(!clipped.pixel(0, 31));
+    assert!(!clipped.pixel(1, 0));
+  }
+
+  #[test]
+  fn starting_coordinates_always_wrap() {
+    let mut display = Display::new();
+    display.draw_sprite(64 + 2, 32 + 1, &[0x80], true);
+    assert!(display.pixel(2, 1));
+  }
+}
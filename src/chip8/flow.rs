@@ -0,0 +1,383 @@
+//! flow recovers the control flow of a Chip8 ROM, by following the
+//! instructions that `parse_instruction` decodes from the program start,
+//! which is 0x200 unless the ROM's platform loads it elsewhere.
+//!
+//! The recovered flow is only what can be shown statically. BNNN jumps
+//! to an address known at run time, and programs may write code to
+//! memory as they run, so a ROM can execute code that is not in any of
+//! its basic blocks.
+
+use crate::chip8::cpu::PROGRAM_START;
+use crate::chip8::instruction::{parse_instruction, Instruction};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// BasicBlock is a run of instructions that is only entered at its
+/// start, and only left after its last instruction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BasicBlock {
+  /// Address of the first instruction.
+  pub start: u16,
+  /// The instructions of the block, with their addresses.
+  pub instructions: Vec<(u16, Instruction)>,
+  /// Whether the last instruction could not be decoded, in which case
+  /// it is not in `instructions` and faults when executed.
+  pub invalid: bool,
+}
+
+impl BasicBlock {
+  /// end returns the address after the block, including an invalid last
+  /// instruction.
+  pub fn end(&self) -> u16 {
+    let instructions = self.instructions.len() as u16 + self.invalid as u16;
+    self.start + instructions * 2
+  }
+
+  /// falls_through returns whether execution continues at `end` after
+  /// the last instruction, instead of the instruction choosing where it
+  /// goes.
+  pub fn falls_through(&self) -> bool {
+    match self.instructions.last() {
+      _ if self.invalid => false,
+      Some((_, instr)) => !changes_control_flow(instr),
+      None => true,
+    }
+  }
+}
+
+/// ControlFlow is the control flow recovered from a ROM.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ControlFlow {
+  // Size of the ROM, and the address it is loaded at and starts from.
+  size: usize,
+  entry_point: u16,
+  // Basic blocks by their start address.
+  blocks: BTreeMap<u16, BasicBlock>,
+  // Addresses of BNNN instructions.
+  indirect_jumps: BTreeSet<u16>,
+  // Addresses outside of the ROM that execution continues at.
+  external: BTreeSet<u16>,
+}
+
+impl ControlFlow {
+  /// recover finds the basic blocks reachable from the start of `rom`,
+  /// loaded at PROGRAM_START.
+  pub fn recover(rom: &[u8]) -> Self {
+    ControlFlow::recover_at(rom, PROGRAM_START)
+  }
+
+  /// recover_at finds the basic blocks reachable from the start of
+  /// `rom`, loaded at `entry_point`.
+  pub fn recover_at(rom: &[u8], entry_point: u16) -> Self {
+    let mut flow = ControlFlow {
+      size: rom.len(),
+      entry_point,
+      ..ControlFlow::default()
+    };
+    // Addresses that start a block, because something other than the
+    // instruction before them continues at them.
+    let mut leaders = BTreeSet::new();
+    let mut visited = BTreeSet::new();
+    let mut pending = vec![entry_point];
+    leaders.insert(entry_point);
+
+    while let Some(address) = pending.pop() {
+      if !visited.insert(address) {
+        continue;
+      }
+      let instr = match flow.opcode_at(rom, address).map(parse_instruction) {
+        Some(Ok(instr)) => instr,
+        Some(Err(_)) => continue,
+        None => {
+          flow.external.insert(address);
+          continue;
+        }
+      };
+      if matches!(instr, Instruction::FlowJumpToAddressPlusVar { .. }) {
+        flow.indirect_jumps.insert(address);
+      }
+      let (successors, start_blocks) = successors(address, &instr);
+      for &successor in successors.iter().flatten() {
+        if start_blocks {
+          leaders.insert(successor);
+        }
+        pending.push(successor);
+      }
+    }
+
+    for &start in leaders.iter() {
+      if let Some(block) = flow.scan_block(rom, start, &leaders) {
+        flow.blocks.insert(start, block);
+      }
+    }
+    flow
+  }
+
+  /// blocks returns the basic blocks, in order of their start address.
+  pub fn blocks(&self) -> impl Iterator<Item = &BasicBlock> {
+    self.blocks.values()
+  }
+
+  /// block returns the basic block starting at `address`.
+  pub fn block(&self, address: u16) -> Option<&BasicBlock> {
+    self.blocks.get(&address)
+  }
+
+  /// indirect_jumps returns the addresses of the BNNN instructions,
+  /// whose targets are not followed.
+  pub fn indirect_jumps(&self) -> impl Iterator<Item = u16> + '_ {
+    self.indirect_jumps.iter().copied()
+  }
+
+  /// external returns the addresses outside of the ROM that execution
+  /// continues at, which are not followed.
+  pub fn external(&self) -> impl Iterator<Item = u16> + '_ {
+    self.external.iter().copied()
+  }
+
+  /// code_size returns the number of bytes of the ROM that are part of
+  /// an instruction in a basic block.
+  pub fn code_size(&self) -> usize {
+    let mut code = vec![false; self.size];
+    for block in self.blocks() {
+      for address in block.start..block.end() {
+        code[(address - self.entry_point) as usize] = true;
+      }
+    }
+    code.iter().filter(|&&code| code).count()
+  }
+
+  /// max_stack_depth returns the most return addresses the stack holds
+  /// when following calls from the program start, or None if a
+  /// subroutine may call itself. Calls in code only reached through BNNN
+  /// are not seen.
+  pub fn max_stack_depth(&self) -> Option<usize> {
+    self.stack_depth(self.entry_point, &mut BTreeMap::new())
+  }
+
+  // stack_depth returns the stack depth the calls in the code reached
+  // from `start` need. `depths` holds the depths of the subroutines
+  // already followed, and None for those being followed, which are
+  // recursive if they are reached again.
+  fn stack_depth(&self, start: u16, depths: &mut BTreeMap<u16, Option<usize>>) -> Option<usize> {
+    if let Some(&depth) = depths.get(&start) {
+      return depth;
+    }
+    depths.insert(start, None);
+    let mut depth = 0;
+    let mut visited = BTreeSet::new();
+    let mut pending = vec![start];
+    while let Some(address) = pending.pop() {
+      if !visited.insert(address) {
+        continue;
+      }
+      let block = match self.blocks.get(&address) {
+        Some(block) => block,
+        None => continue,
+      };
+      match block.instructions.last() {
+        Some(&(_, Instruction::FlowSubroutineCall { address: target })) => {
+          depth = depth.max(1 + self.stack_depth(target as u16, depths)?);
+          pending.push(block.end());
+        }
+        Some((at, instr)) if !block.invalid && changes_control_flow(instr) => {
+          pending.extend(successors(*at, instr).0.iter().flatten());
+        }
+        _ if block.falls_through() => pending.push(block.end()),
+        _ => {}
+      }
+    }
+    depths.insert(start, Some(depth));
+    Some(depth)
+  }
+
+  // opcode_at returns the opcode at `address` in `rom`, unless it is not
+  // entirely in the ROM.
+  fn opcode_at(&self, rom: &[u8], address: u16) -> Option<u16> {
+    let offset = address.checked_sub(self.entry_point)? as usize;
+    match rom.get(offset..offset + 2)? {
+      &[high, low] => Some((high as u16) << 8 | low as u16),
+      _ => None,
+    }
+  }
+
+  // scan_block returns the block starting at `start`, which runs until
+  // an instruction that ends it or the next leader.
+  fn scan_block(&self, rom: &[u8], start: u16, leaders: &BTreeSet<u16>) -> Option<BasicBlock> {
+    let mut block = BasicBlock {
+      start,
+      instructions: Vec::new(),
+      invalid: false,
+    };
+    let mut address = start;
+    while let Some(opcode) = self.opcode_at(rom, address) {
+      match parse_instruction(opcode) {
+        Ok(instr) => block.instructions.push((address, instr)),
+        Err(_) => {
+          block.invalid = true;
+          break;
+        }
+      }
+      address += 2;
+      if ends_block(&block.instructions.last()?.1) || leaders.contains(&address) {
+        break;
+      }
+    }
+    if block.instructions.is_empty() && !block.invalid {
+      return None;
+    }
+    Some(block)
+  }
+}
+
+/// ends_block returns whether `instr` ends a basic block. Besides the
+/// instructions that change control flow, this is DXYN, which a frame
+/// may end on, and the instructions that write memory, after which code
+/// may have changed.
+pub fn ends_block(instr: &Instruction) -> bool {
+  use Instruction::*;
+  changes_control_flow(instr)
+    || matches!(
+      instr,
+      Call { .. } | DisplayDraw { .. } | LoadBinaryCodedDecimal { .. } | MemoryDump { .. }
+    )
+}
+
+// changes_control_flow returns whether `instr` may continue anywhere
+// other than at the next instruction.
+fn changes_control_flow(instr: &Instruction) -> bool {
+  use Instruction::*;
+  matches!(
+    instr,
+    FlowSubroutineReturn()
+      | FlowJumpToAddress { .. }
+      | FlowSubroutineCall { .. }
+      | CondSkipIfEqualConst { .. }
+      | CondSkipIfNotEqualConst { .. }
+      | CondSkipIfEqualVar { .. }
+      | CondSkipIfNotEqualVar { .. }
+      | FlowJumpToAddressPlusVar { .. }
+      | InputKeyIsPressed { .. }
+      | InputKeyIsNotPressed { .. }
+      | InputKeyAwaitPress { .. }
+  )
+}
+
+// successors returns the known addresses that execution may continue at
+// after `instr` at `address`, with the next instruction first, and
+// whether they all start blocks.
+fn successors(address: u16, instr: &Instruction) -> ([Option<u16>; 2], bool) {
+  use Instruction::*;
+  let next = Some(address + 2);
+  match *instr {
+    FlowSubroutineReturn() | FlowJumpToAddressPlusVar { .. } => ([None, None], true),
+    FlowJumpToAddress { address } => ([None, Some(address as u16)], true),
+    // The subroutine returns to the next instruction.
+    FlowSubroutineCall { address } => ([next, Some(address as u16)], true),
+    CondSkipIfEqualConst { .. }
+    | CondSkipIfNotEqualConst { .. }
+    | CondSkipIfEqualVar { .. }
+    | CondSkipIfNotEqualVar { .. }
+    | InputKeyIsPressed { .. }
+    | InputKeyIsNotPressed { .. } => ([next, Some(address + 4)], true),
+    // FX0A executes again until a key is pressed.
+    InputKeyAwaitPress { .. } => ([next, Some(address)], true),
+    _ if ends_block(instr) => ([next, None], true),
+    _ => ([next, None], false),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn starts(flow: &ControlFlow) -> Vec<u16> {
+    flow.blocks().map(|block| block.start).collect()
+  }
+
+  #[test]
+  fn blocks_split_at_jumps_and_targets() {
+    // 0x200: V0 = 1; 0x202: V1 = 2; 0x204: jump 0x202.
+    let flow = ControlFlow::recover(&[0x60, 0x01, 0x61, 0x02, 0x12, 0x02]);
+    assert_eq!(starts(&flow), vec![0x200, 0x202]);
+    let first = flow.block(0x200).unwrap();
+    assert_eq!(first.end(), 0x202);
+    assert!(first.falls_through());
+    let second = flow.block(0x202).unwrap();
+    assert_eq!(second.end(), 0x206);
+    assert!(!second.falls_through());
+    assert_eq!(flow.code_size(), 6);
+  }
+
+  #[test]
+  fn roms_are_followed_from_their_entry_point() {
+    // 0x600: V0 = 1; 0x602: jump 0x602.
+    let flow = ControlFlow::recover_at(&[0x60, 0x01, 0x16, 0x02], 0x600);
+    assert_eq!(starts(&flow), vec![0x600, 0x602]);
+    assert_eq!(flow.code_size(), 4);
+    assert_eq!(flow.max_stack_depth(), Some(0));
+    assert_eq!(flow.external().count(), 0);
+  }
+
+  #[test]
+  fn skips_calls_and_key_waits_start_blocks() {
+    let rom = [
+      0x30, 0x01, // 0x200: skip if V0 == 1
+      0x22, 0x0A, // 0x202: call 0x20A
+      0xF0, 0x0A, // 0x204: wait for a key
+      0x12, 0x04, // 0x206: jump 0x204
+      0xFF, 0xFF, // 0x208: data
+      0x00, 0xEE, // 0x20A: return
+    ];
+    let flow = ControlFlow::recover(&rom);
+    assert_eq!(starts(&flow), vec![0x200, 0x202, 0x204, 0x206, 0x20A]);
+    assert_eq!(flow.code_size(), 10);
+    assert_eq!(flow.external().count(), 0);
+  }
+
+  #[test]
+  fn indirect_and_external_targets_are_not_followed() {
+    let rom = [
+      0x22, 0x06, // 0x200: call 0x206
+      0xB3, 0x00, // 0x202: jump to V0 + 0x300
+      0xFF, 0xFF, // 0x204: data
+      0x13, 0x00, // 0x206: jump 0x300
+    ];
+    let flow = ControlFlow::recover(&rom);
+    assert_eq!(starts(&flow), vec![0x200, 0x202, 0x206]);
+    assert_eq!(flow.indirect_jumps().collect::<Vec<_>>(), vec![0x202]);
+    assert_eq!(flow.external().collect::<Vec<_>>(), vec![0x300]);
+  }
+
+  #[test]
+  fn stack_depth_follows_nested_calls() {
+    let rom = [
+      0x22, 0x08, // 0x200: call 0x208
+      0x22, 0x0C, // 0x202: call 0x20C
+      0x12, 0x00, // 0x204: jump 0x200
+      0xFF, 0xFF, // 0x206: data
+      0x22, 0x0C, // 0x208: call 0x20C
+      0x00, 0xEE, // 0x20A: return
+      0x00, 0xEE, // 0x20C: return
+    ];
+    assert_eq!(ControlFlow::recover(&rom).max_stack_depth(), Some(2));
+    assert_eq!(
+      ControlFlow::recover(&[0x12, 0x00]).max_stack_depth(),
+      Some(0)
+    );
+
+    // 0x200: call 0x202; 0x202: call 0x200.
+    let recursive = ControlFlow::recover(&[0x22, 0x02, 0x22, 0x00]);
+    assert_eq!(recursive.max_stack_depth(), None);
+  }
+
+  #[test]
+  fn invalid_instructions_end_blocks() {
+    // 0x200: V0 = 1; 0x202: an unsupported instruction.
+    let flow = ControlFlow::recover(&[0x60, 0x01, 0xE0, 0x00, 0x60, 0x02]);
+    let block = flow.block(0x200).unwrap();
+    assert!(block.invalid);
+    assert!(!block.falls_through());
+    assert_eq!(block.end(), 0x204);
+    assert_eq!(flow.code_size(), 4);
+  }
+}
@@ -0,0 +1,467 @@
+//! gif reads and compresses GIF images, for recording videos and for
+//! reading Octo cartridges.
+//!
+//! Images are read as the palette indices of their pixels, and are not
+//! composed into frames, as cartridges keep data in the indices. See
+//! https://www.w3.org/Graphics/GIF/spec-gif89a.txt.
+
+use thiserror::Error;
+
+// Most codes LZW can have, with codes of up to 12 bits.
+const LZW_MAX_CODES: usize = 1 << 12;
+const LZW_MAX_CODE_SIZE: u32 = 12;
+
+// Block introducers and labels.
+const EXTENSION: u8 = 0x21;
+const IMAGE: u8 = 0x2C;
+const TRAILER: u8 = 0x3B;
+const GRAPHIC_CONTROL: u8 = 0xF9;
+
+/// GifError is returned when a GIF image can't be read.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum GifError {
+  #[error("Not a GIF image")]
+  NotGif,
+
+  #[error("GIF image ends early")]
+  Truncated,
+
+  #[error("Unknown GIF block {byte:#04X} at offset {offset}")]
+  UnknownBlock { byte: u8, offset: usize },
+
+  #[error("Invalid LZW code size {size}")]
+  InvalidCodeSize { size: u8 },
+
+  #[error("Invalid LZW code {code} in image {image}")]
+  InvalidCode { code: usize, image: usize },
+}
+
+/// Gif is a GIF image, as the images it is made of.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct Gif {
+  /// Width and height of the logical screen the images are drawn on.
+  pub width: u16,
+  pub height: u16,
+  /// Colours of the global palette, if it has one.
+  pub palette: Vec<[u8; 3]>,
+  pub images: Vec<Image>,
+}
+
+/// Image is one image of a GIF, which covers part of the screen.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct Image {
+  pub left: u16,
+  pub top: u16,
+  pub width: u16,
+  pub height: u16,
+  /// Hundredths of a second to show the image for, from its graphic
+  /// control extension.
+  pub delay: u16,
+  /// Colours of the palette of this image, if it has its own.
+  pub palette: Vec<[u8; 3]>,
+  /// Palette indices of the pixels, in rows from the top left. Rows of
+  /// interlaced images are put in order.
+  pub indices: Vec<u8>,
+}
+
+// Reader reads the bytes of a GIF in order.
+struct Reader<'a> {
+  bytes: &'a [u8],
+  offset: usize,
+}
+
+impl<'a> Reader<'a> {
+  fn bytes(&mut self, count: usize) -> Result<&'a [u8], GifError> {
+    let bytes = self
+      .bytes
+      .get(self.offset..self.offset + count)
+      .ok_or(GifError::Truncated)?;
+    self.offset += count;
+    Ok(bytes)
+  }
+
+  fn byte(&mut self) -> Result<u8, GifError> {
+    Ok(self.bytes(1)?[0])
+  }
+
+  fn u16(&mut self) -> Result<u16, GifError> {
+    let bytes = self.bytes(2)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+  }
+
+  // palette reads a palette, if `flags` has one, with its size in the
+  // low three bits.
+  fn palette(&mut self, flags: u8) -> Result<Vec<[u8; 3]>, GifError> {
+    if flags & 0x80 == 0 {
+      return Ok(Vec::new());
+    }
+    let size = 2 << (flags & 0x07);
+    Ok(
+      self
+        .bytes(size * 3)?
+        .chunks(3)
+        .map(|rgb| [rgb[0], rgb[1], rgb[2]])
+        .collect(),
+    )
+  }
+
+  // sub_blocks reads a series of data sub-blocks, up to the empty one
+  // that ends them, and returns their data.
+  fn sub_blocks(&mut self) -> Result<Vec<u8>, GifError> {
+    let mut data = Vec::new();
+    loop {
+      let length = self.byte()? as usize;
+      if length == 0 {
+        return Ok(data);
+      }
+      data.extend_from_slice(self.bytes(length)?);
+    }
+  }
+}
+
+/// decode reads the GIF image `bytes`.
+pub fn decode(bytes: &[u8]) -> Result<Gif, GifError> {
+  let mut reader = Reader { bytes, offset: 0 };
+  match reader.bytes(6) {
+    Ok(b"GIF87a") | Ok(b"GIF89a") => {}
+    _ => return Err(GifError::NotGif),
+  }
+  let width = reader.u16()?;
+  let height = reader.u16()?;
+  let flags = reader.byte()?;
+  // The background colour and pixel aspect ratio are not needed.
+  reader.bytes(2)?;
+  let palette = reader.palette(flags)?;
+
+  let mut images = Vec::new();
+  let mut delay = 0;
+  loop {
+    let offset = reader.offset;
+    match reader.byte()? {
+      EXTENSION => {
+        let label = reader.byte()?;
+        let data = reader.sub_blocks()?;
+        if label == GRAPHIC_CONTROL && data.len() >= 3 {
+          delay = u16::from_le_bytes([data[1], data[2]]);
+        }
+      }
+      IMAGE => {
+        let left = reader.u16()?;
+        let top = reader.u16()?;
+        let width = reader.u16()?;
+        let height = reader.u16()?;
+        let flags = reader.byte()?;
+        let palette = reader.palette(flags)?;
+        let min_code_size = reader.byte()?;
+        let data = reader.sub_blocks()?;
+        let size = width as usize * height as usize;
+        let mut indices = lzw_decode(&data, min_code_size, size).map_err(|err| match err {
+          GifError::InvalidCode { code, .. } => GifError::InvalidCode {
+            code,
+            image: images.len(),
+          },
+          err => err,
+        })?;
+        // Missing pixels are left as the first colour.
+        indices.resize(size, 0);
+        if flags & 0x40 != 0 {
+          indices = deinterlace(&indices, width as usize, height as usize);
+        }
+        images.push(Image {
+          left,
+          top,
+          width,
+          height,
+          delay,
+          palette,
+          indices,
+        });
+        delay = 0;
+      }
+      TRAILER => break,
+      byte => return Err(GifError::UnknownBlock { byte, offset }),
+    }
+  }
+  Ok(Gif {
+    width,
+    height,
+    palette,
+    images,
+  })
+}
+
+// deinterlace returns the rows of an interlaced image in order. They are
+// stored as every eighth row from 0, every eighth from 4, every fourth
+// from 2, and every second from 1.
+fn deinterlace(indices: &[u8], width: usize, height: usize) -> Vec<u8> {
+  let mut rows = Vec::with_capacity(height);
+  for (start, step) in [(0, 8), (4, 8), (2, 4), (1, 2)].iter() {
+    rows.extend((*start..height).step_by(*step));
+  }
+  let mut ordered = vec![0; indices.len()];
+  for (stored, row) in rows.into_iter().enumerate() {
+    ordered[row * width..(row + 1) * width]
+      .copy_from_slice(&indices[stored * width..(stored + 1) * width]);
+  }
+  ordered
+}
+
+// BitWriter packs codes into bytes from the least significant bit, as
+// GIF stores them.
+#[derive(Default)]
+struct BitWriter {
+  bytes: Vec<u8>,
+  bits: u32,
+  count: u32,
+}
+
+impl BitWriter {
+  fn write(&mut self, code: u16, size: u32) {
+    self.bits |= (code as u32) << self.count;
+    self.count += size;
+    while self.count >= 8 {
+      self.bytes.push(self.bits as u8);
+      self.bits >>= 8;
+      self.count -= 8;
+    }
+  }
+
+  fn finish(mut self) -> Vec<u8> {
+    if self.count > 0 {
+      self.bytes.push(self.bits as u8);
+    }
+    self.bytes
+  }
+}
+
+/// lzw_encode returns the GIF LZW compression of the palette `indices`,
+/// with codes starting at `min_code_size` plus one bits. The indices
+/// must be below 2 to the power of `min_code_size`, which is from 2 to
+/// 8.
+pub fn lzw_encode(indices: &[u8], min_code_size: u8) -> Vec<u8> {
+  assert!(
+    (2..=8).contains(&min_code_size),
+    "invalid LZW code size {}",
+    min_code_size
+  );
+  let alphabet = 1 << min_code_size;
+  let clear = alphabet as u16;
+  let end = clear + 1;
+  let first_size = min_code_size as u32 + 1;
+  // The code for each string extended by each index, as rows of
+  // `alphabet` codes for each code. No string extends to code 0, so 0 is
+  // none.
+  let roots = alphabet + 2;
+  let mut table = vec![0u16; roots * alphabet];
+  let mut size = first_size;
+  let mut bits = BitWriter::default();
+  bits.write(clear, size);
+
+  let mut indices = indices.iter();
+  let mut code = match indices.next() {
+    Some(&index) => index as u16,
+    None => {
+      bits.write(end, size);
+      return bits.finish();
+    }
+  };
+  for &index in indices {
+    let entry = code as usize * alphabet + index as usize;
+    if table[entry] != 0 {
+      code = table[entry];
+      continue;
+    }
+    bits.write(code, size);
+    let next = table.len() / alphabet;
+    if next < LZW_MAX_CODES {
+      if next == 1 << size {
+        size += 1;
+      }
+      table[entry] = next as u16;
+      table.resize(table.len() + alphabet, 0);
+    } else {
+      bits.write(clear, size);
+      table.truncate(roots * alphabet);
+      table.iter_mut().for_each(|code| *code = 0);
+      size = first_size;
+    }
+    code = index as u16;
+  }
+  bits.write(code, size);
+  bits.write(end, size);
+  bits.finish()
+}
+
+// lzw_decode returns the palette indices of the GIF LZW `data`, of up to
+// `limit` pixels.
+fn lzw_decode(data: &[u8], min_code_size: u8, limit: usize) -> Result<Vec<u8>, GifError> {
+  if !(1..=11).contains(&min_code_size) {
+    return Err(GifError::InvalidCodeSize {
+      size: min_code_size,
+    });
+  }
+  let clear = 1usize << min_code_size;
+  let end = clear + 1;
+  let first_size = min_code_size as u32 + 1;
+  // Each string is its code's prefix code and last index, with its first
+  // index and length to unwind it.
+  let mut prefixes = vec![0u16; LZW_MAX_CODES];
+  let mut suffixes = vec![0u8; LZW_MAX_CODES];
+  let mut firsts = vec![0u8; LZW_MAX_CODES];
+  let mut lengths = vec![0u16; LZW_MAX_CODES];
+  for code in 0..clear {
+    suffixes[code] = code as u8;
+    firsts[code] = code as u8;
+    lengths[code] = 1;
+  }
+  let (mut next, mut size) = (end + 1, first_size);
+  let mut previous: Option<usize> = None;
+  let mut out = Vec::with_capacity(limit);
+  let (mut bits, mut count, mut bytes) = (0u32, 0u32, data.iter());
+
+  while out.len() < limit {
+    while count < size {
+      match bytes.next() {
+        Some(&byte) => {
+          bits |= (byte as u32) << count;
+          count += 8;
+        }
+        // Some encoders leave out the end code.
+        None => return Ok(out),
+      }
+    }
+    let code = (bits & ((1 << size) - 1)) as usize;
+    bits >>= size;
+    count -= size;
+
+    if code == clear {
+      next = end + 1;
+      size = first_size;
+      previous = None;
+      continue;
+    }
+    if code == end {
+      break;
+    }
+    let first = match previous {
+      _ if code < clear || (code > end && code < next) => firsts[code],
+      // The code being added, which is the previous string and its first
+      // index.
+      Some(previous) if code == next => firsts[previous],
+      _ => return Err(GifError::InvalidCode { code, image: 0 }),
+    };
+    if let Some(previous) = previous {
+      if next < LZW_MAX_CODES {
+        prefixes[next] = previous as u16;
+        suffixes[next] = first;
+        firsts[next] = firsts[previous];
+        lengths[next] = lengths[previous] + 1;
+        next += 1;
+        if next == 1 << size && size < LZW_MAX_CODE_SIZE {
+          size += 1;
+        }
+      }
+    }
+
+    // Unwind the string of the code, from its end.
+    let length = lengths[code] as usize;
+    let start = out.len();
+    out.resize(start + length, 0);
+    let mut string = code;
+    for at in (start..start + length).rev() {
+      out[at] = suffixes[string];
+      string = prefixes[string] as usize;
+    }
+    previous = Some(code);
+  }
+  out.truncate(limit);
+  Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // noise returns `count` pseudo-random indices below `alphabet`.
+  fn noise(count: usize, alphabet: u32) -> Vec<u8> {
+    let mut state = 1u32;
+    (0..count)
+      .map(|_| {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        ((state >> 16) % alphabet) as u8
+      })
+      .collect()
+  }
+
+  #[test]
+  fn lzw_round_trips() {
+    let decode = |data: &[u8], size, limit| lzw_decode(data, size, limit).unwrap();
+    assert_eq!(decode(&lzw_encode(&[], 2), 2, 10), Vec::<u8>::new());
+    let runs: Vec<u8> = (0..2000).map(|i| (i / 7 % 2) as u8).collect();
+    assert_eq!(decode(&lzw_encode(&runs, 2), 2, 2000), runs);
+    // Enough noise to fill the table, so that it is cleared.
+    let bits = noise(100_000, 2);
+    assert_eq!(decode(&lzw_encode(&bits, 2), 2, 100_000), bits);
+    let bytes = noise(100_000, 256);
+    assert_eq!(decode(&lzw_encode(&bytes, 8), 8, 100_000), bytes);
+  }
+
+  #[test]
+  fn invalid_codes_are_errors() {
+    // A clear code, then code 7 before any string has been added.
+    let mut bits = BitWriter::default();
+    bits.write(4, 3);
+    bits.write(7, 3);
+    assert_eq!(
+      lzw_decode(&bits.finish(), 2, 10),
+      Err(GifError::InvalidCode { code: 7, image: 0 })
+    );
+  }
+
+  // gif returns a GIF of a 4 by 2 screen, with `images` of indices
+  // covering it.
+  fn gif(images: &[(&[u8], bool)]) -> Vec<u8> {
+    let mut bytes = b"GIF89a".to_vec();
+    bytes.extend_from_slice(&[4, 0, 2, 0, 0x81, 0, 0]);
+    bytes.extend_from_slice(&[0, 0, 0, 0xFF, 0, 0, 0, 0xFF, 0, 0, 0, 0xFF]);
+    for (delay, (indices, interlaced)) in images.iter().enumerate() {
+      bytes.extend_from_slice(&[0x21, 0xF9, 4, 0, delay as u8, 0, 0, 0]);
+      bytes.extend_from_slice(&[0x2C, 0, 0, 0, 0, 4, 0, 2, 0]);
+      bytes.push(if *interlaced { 0x40 } else { 0 });
+      bytes.push(2);
+      let data = lzw_encode(indices, 2);
+      bytes.push(data.len() as u8);
+      bytes.extend_from_slice(&data);
+      bytes.push(0);
+    }
+    bytes.push(0x3B);
+    bytes
+  }
+
+  #[test]
+  fn images_are_read_in_order() {
+    let gif = decode(&gif(&[(&[0, 1, 2, 3, 3, 2, 1, 0], false), (&[1; 8], true)])).unwrap();
+    assert_eq!((gif.width, gif.height), (4, 2));
+    assert_eq!(gif.palette.len(), 4);
+    assert_eq!(gif.palette[1], [0xFF, 0, 0]);
+    assert_eq!(gif.images.len(), 2);
+    assert_eq!(gif.images[0].indices, vec![0, 1, 2, 3, 3, 2, 1, 0]);
+    assert_eq!(gif.images[1].delay, 1);
+    assert_eq!(gif.images[1].indices, vec![1; 8]);
+  }
+
+  #[test]
+  fn interlaced_rows_are_put_in_order() {
+    let rows: Vec<u8> = (0..10).collect();
+    // Rows stored in the order 0, 8, 4, 2, 6, 1, 3, 5, 7, 9.
+    let stored = [0, 8, 4, 2, 6, 1, 3, 5, 7, 9];
+    assert_eq!(deinterlace(&stored, 1, 10), rows);
+  }
+
+  #[test]
+  fn truncated_and_other_files_are_errors() {
+    assert_eq!(decode(b"PNG"), Err(GifError::NotGif));
+    let bytes = gif(&[(&[0; 8], false)]);
+    assert_eq!(decode(&bytes[..bytes.len() - 4]), Err(GifError::Truncated));
+  }
+}
@@ -0,0 +1,376 @@
+//! headless runs a Chip8 ROM for a number of frames with no display,
+//! pressing keys from a script and writing screenshots of chosen
+//! frames. It can also record the run's sound as a WAV file and its
+//! frames as a Y4M or GIF video, to render a capture of a game from the
+//! keys recorded by `chip8 --record-keys`.
+//!
+//! Runs are deterministic: the random number generator is seeded, and
+//! keys only change between frames. It exits with status 1 if the CPU
+//! faults, and 2 if the run could not be set up or a screenshot could
+//! not be written.
+
+use clap::{App, Arg, ArgMatches};
+use rustyemulator::chip8::audio::{DEFAULT_PITCH, DEFAULT_SAMPLE_RATE};
+use rustyemulator::chip8::chip8::Chip8;
+use rustyemulator::chip8::keyscript::{KeyScript, KeyScriptError};
+use rustyemulator::chip8::launch::{self, parse_number, LaunchError, Options};
+use rustyemulator::chip8::recording::Recorder;
+use rustyemulator::chip8::screenshot::{self, Format};
+use rustyemulator::interface::system::System;
+use rustyemulator::interface::video::FrameSink;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::process;
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum HeadlessError {
+  #[error(transparent)]
+  Launch(#[from] LaunchError),
+
+  #[error("Screenshot {path} should end in .png or .pbm")]
+  UnknownFormat { path: String },
+
+  #[error("Screenshot {path} needs {{frame}} in its name to hold several frames")]
+  AmbiguousScreenshot { path: String },
+
+  #[error("Failed to read key script {path}: {source}")]
+  Keys {
+    path: String,
+    source: KeyScriptError,
+  },
+
+  #[error("CPU fault in frame {frame}: {source}")]
+  Fault {
+    frame: u32,
+    source: rustyemulator::chip8::cpu::CpuError,
+  },
+
+  #[error(transparent)]
+  IOError(#[from] std::io::Error),
+}
+
+// Exit status when the CPU faults.
+const EXIT_FAULT: i32 = 1;
+// Exit status when the run could not be done.
+const EXIT_ERROR: i32 = 2;
+
+// Rate of Chip8 frames, at which videos are recorded.
+const FRAME_RATE: u32 = 60;
+
+fn main() {
+  let (sample_rate, pitch) = (DEFAULT_SAMPLE_RATE.to_string(), DEFAULT_PITCH.to_string());
+  let matches = App::new("Chip8 headless runner")
+    .version("1.0")
+    .author("Joey Pereira <joey@pereira.io>")
+    .about("Runs a Chip8 ROM without a display and writes screenshots")
+    .arg(
+      Arg::with_name("rom")
+        .long("rom")
+        .takes_value(true)
+        .required(true)
+        .help(
+          "Sets the ROM file to run, as Intel HEX or a hex dump if it ends in .hex, .ihx or .txt, an Octo cartridge .gif, or - to read it from standard input",
+        ),
+    )
+    .arg(
+      Arg::with_name("patch")
+        .long("patch")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .help("Applies an IPS or BPS patch to the ROM, in the order given"),
+    )
+    .arg(
+      Arg::with_name("frames")
+        .long("frames")
+        .takes_value(true)
+        .required(true)
+        .help("Sets the number of frames to run"),
+    )
+    .arg(
+      Arg::with_name("keys")
+        .long("keys")
+        .takes_value(true)
+        .help("Sets the script of key presses, as lines of \"<frame> press|release <key>\""),
+    )
+    .arg(
+      Arg::with_name("quirks")
+        .long("quirks")
+        .takes_value(true)
+        .default_value("modern")
+        .help("Sets the quirk profile"),
+    )
+    .arg(
+      Arg::with_name("platform")
+        .long("platform")
+        .takes_value(true)
+        .default_value("chip8")
+        .help("Sets the platform the ROM is for, which sets where it is loaded"),
+    )
+    .arg(
+      Arg::with_name("seed")
+        .long("seed")
+        .takes_value(true)
+        .default_value("1")
+        .help("Sets the random number generator seed"),
+    )
+    .arg(
+      Arg::with_name("no-database")
+        .long("no-database")
+        .help("Ignores the platform, quirks and speed the ROM database has for the ROM"),
+    )
+    .arg(
+      Arg::with_name("protect")
+        .long("protect")
+        .help("Faults on writes to the interpreter and font memory below the program"),
+    )
+    .arg(
+      Arg::with_name("ipf")
+        .long("ipf")
+        .takes_value(true)
+        .help("Sets the number of instructions per frame"),
+    )
+    .arg(
+      Arg::with_name("screenshot")
+        .long("screenshot")
+        .takes_value(true)
+        .help("Sets the .png or .pbm file to write, with {frame} replaced by the frame"),
+    )
+    .arg(
+      Arg::with_name("at")
+        .long("at")
+        .takes_value(true)
+        .use_delimiter(true)
+        .help("Sets the frames to take screenshots after, instead of only the last"),
+    )
+    .arg(
+      Arg::with_name("scale")
+        .long("scale")
+        .takes_value(true)
+        .default_value("1")
+        .help("Sets the size in image pixels of each display pixel, in screenshots and videos"),
+    )
+    .arg(
+      Arg::with_name("wav")
+        .long("wav")
+        .takes_value(true)
+        .help("Records the sound to this WAV file"),
+    )
+    .arg(
+      Arg::with_name("video")
+        .long("video")
+        .takes_value(true)
+        .help("Records the frames to this .y4m or .gif file"),
+    )
+    .arg(
+      Arg::with_name("sample-rate")
+        .long("sample-rate")
+        .takes_value(true)
+        .default_value(&sample_rate)
+        .help("Sets the sample rate of the recorded sound, in hertz"),
+    )
+    .arg(
+      Arg::with_name("pitch")
+        .long("pitch")
+        .takes_value(true)
+        .default_value(&pitch)
+        .help("Sets the pitch of the recorded beep, in hertz"),
+    )
+    .get_matches();
+
+  if let Err(err) = run(&matches) {
+    eprintln!("error: {}", err);
+    let status = match err {
+      HeadlessError::Fault { .. } => EXIT_FAULT,
+      _ => EXIT_ERROR,
+    };
+    process::exit(status);
+  }
+}
+
+// load_chip8 creates a Chip8 with the ROM and settings from `args`.
+// Octo cartridges and ROMs in the database set their own platform,
+// quirks and speed, unless --platform, --quirks or --ipf are given.
+fn load_chip8(args: &ArgMatches) -> Result<Chip8, HeadlessError> {
+  let quirks = launch::parse_quirks(args.value_of("quirks").unwrap())?;
+  let platform = launch::parse_platform(args.value_of("platform").unwrap())?;
+  let options = Options {
+    path: String::from(args.value_of("rom").unwrap()),
+    patches: args
+      .values_of("patch")
+      .into_iter()
+      .flatten()
+      .map(PathBuf::from)
+      .collect(),
+    platform: Some(platform).filter(|_| args.occurrences_of("platform") > 0),
+    quirks: Some(quirks).filter(|_| args.occurrences_of("quirks") > 0),
+    instructions_per_frame: args
+      .value_of("ipf")
+      .map(|ipf| parse_number(ipf, "ipf"))
+      .transpose()?,
+    database: !args.is_present("no-database"),
+    seed: parse_number(args.value_of("seed").unwrap(), "seed")?,
+    protect: args.is_present("protect"),
+  };
+  let (chip8, settings) = launch::load(&options)?;
+  for name in settings.unsupported.iter() {
+    eprintln!("warning: cartridge option {} is not supported", name);
+  }
+  Ok(chip8)
+}
+
+fn load_keys(args: &ArgMatches) -> Result<KeyScript, HeadlessError> {
+  match args.value_of("keys") {
+    None => Ok(KeyScript::default()),
+    Some(path) => fs::read_to_string(path)?
+      .parse()
+      .map_err(|source| HeadlessError::Keys {
+        path: String::from(path),
+        source,
+      }),
+  }
+}
+
+// Screenshots is where and when to write screenshots.
+struct Screenshots {
+  path: String,
+  format: Format,
+  scale: usize,
+  frames: BTreeSet<u32>,
+}
+
+impl Screenshots {
+  fn from_args(args: &ArgMatches, last_frame: u32) -> Result<Option<Self>, HeadlessError> {
+    let path = match args.value_of("screenshot") {
+      Some(path) => String::from(path),
+      None => return Ok(None),
+    };
+    let format = Format::from_path(&PathBuf::from(&path))
+      .ok_or_else(|| HeadlessError::UnknownFormat { path: path.clone() })?;
+    let frames: BTreeSet<u32> = match args.values_of("at") {
+      Some(values) => values
+        .map(|value| match parse_number(value, "at")? {
+          frame if frame <= last_frame => Ok(frame),
+          _ => Err(HeadlessError::from(LaunchError::InvalidArgument {
+            arg: "at",
+            value: String::from(value),
+          })),
+        })
+        .collect::<Result<_, _>>()?,
+      None => vec![last_frame].into_iter().collect(),
+    };
+    if frames.len() > 1 && !path.contains("{frame}") {
+      return Err(HeadlessError::AmbiguousScreenshot { path });
+    }
+    Ok(Some(Screenshots {
+      path,
+      format,
+      scale: parse_number(args.value_of("scale").unwrap(), "scale")?,
+      frames,
+    }))
+  }
+
+  // take writes the screenshot of `chip8` if one is wanted after
+  // `frame` frames.
+  fn take(&self, frame: u32, chip8: &Chip8) -> Result<(), HeadlessError> {
+    if !self.frames.contains(&frame) {
+      return Ok(());
+    }
+    let path = self.path.replace("{frame}", &frame.to_string());
+    let mut out = BufWriter::new(fs::File::create(&path)?);
+    screenshot::write(chip8.display(), self.format, self.scale, &mut out)?;
+    out.flush()?;
+    println!("Wrote frame {} to {}.", frame, path);
+    Ok(())
+  }
+}
+
+// Recording is the sound and video being recorded, if any.
+struct Recording {
+  recorder: Recorder,
+  paths: Vec<String>,
+}
+
+impl Recording {
+  // from_args starts the recordings asked for by `args`, and sets the
+  // Beeper `chip8` plays its sound with.
+  fn from_args(args: &ArgMatches, chip8: &mut Chip8) -> Result<Option<Self>, HeadlessError> {
+    if !args.is_present("wav") && !args.is_present("video") {
+      return Ok(None);
+    }
+    chip8.set_beeper(launch::parse_beeper(
+      args.value_of("sample-rate").unwrap(),
+      args.value_of("pitch").unwrap(),
+    )?);
+    let mut recording = Recording {
+      recorder: Recorder::default(),
+      paths: Vec::new(),
+    };
+    if let Some(path) = args.value_of("video") {
+      let format = launch::parse_video_format(path)?;
+      let scale = parse_number(args.value_of("scale").unwrap(), "scale")?;
+      recording
+        .recorder
+        .record_video(&PathBuf::from(path), format, scale, FRAME_RATE)?;
+      recording.paths.push(String::from(path));
+    }
+    if let Some(path) = args.value_of("wav") {
+      recording
+        .recorder
+        .record_wav(&PathBuf::from(path), chip8.sample_rate())?;
+      recording.paths.push(String::from(path));
+    }
+    Ok(Some(recording))
+  }
+
+  // record records the frame `chip8` has just run.
+  fn record(&mut self, chip8: &mut Chip8) {
+    chip8.push_audio(&mut self.recorder);
+    self.recorder.push_frame(&chip8.display().frame());
+  }
+
+  fn finish(self, frames: u32) -> Result<(), HeadlessError> {
+    self.recorder.finish()?;
+    for path in self.paths {
+      println!("Recorded {} frames to {}.", frames, path);
+    }
+    Ok(())
+  }
+}
+
+fn run(args: &ArgMatches) -> Result<(), HeadlessError> {
+  let frames: u32 = parse_number(args.value_of("frames").unwrap(), "frames")?;
+  let mut chip8 = load_chip8(args)?;
+  let keys = load_keys(args)?;
+  let screenshots = Screenshots::from_args(args, frames)?;
+  let mut recording = Recording::from_args(args, &mut chip8)?;
+
+  for frame in 0..=frames {
+    if let Some(screenshots) = &screenshots {
+      screenshots.take(frame, &chip8)?;
+    }
+    if frame == frames {
+      break;
+    }
+    keys.apply(frame, &mut chip8);
+    let result = chip8.run_frame();
+    if let Some(recording) = &mut recording {
+      recording.record(&mut chip8);
+    }
+    if let Err(source) = result {
+      // Keep the recording up to the fault, for bug reports.
+      if let Some(recording) = recording {
+        recording.finish(frame + 1)?;
+      }
+      return Err(HeadlessError::Fault { frame, source });
+    }
+  }
+  if let Some(recording) = recording {
+    recording.finish(frames)?;
+  }
+  Ok(())
+}
@@ -0,0 +1,248 @@
+//! hex decodes programs written out as text: Intel HEX files, as
+//! homebrew assemblers write, and hexadecimal dumps, as old listings
+//! print programs.
+//!
+//! Intel HEX records each hold bytes for an address in memory, with a
+//! checksum. The program is the bytes from the platform's entry point
+//! to the highest address written, with any gaps left as zeros, and
+//! bytes for addresses below the entry point are rejected. Hex dumps are
+//! bytes written as pairs of hexadecimal digits, separated by any
+//! whitespace.
+
+use thiserror::Error;
+
+/// Largest span of addresses a program may cover. This is more memory
+/// than any platform has, but stops a stray address from asking for
+/// gigabytes.
+pub const MAX_SPAN: usize = 0x10000;
+
+/// HexError is returned when text can't be decoded.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum HexError {
+  #[error("Line {line} is not an Intel HEX record")]
+  InvalidRecord { line: usize },
+
+  #[error("Line {line} has checksum {found:02X}, but its bytes need {expected:02X}")]
+  Checksum {
+    line: usize,
+    expected: u8,
+    found: u8,
+  },
+
+  #[error("Line {line} has record type {record_type:02X}, which is not supported")]
+  UnsupportedRecord { line: usize, record_type: u8 },
+
+  #[error("Line {line} writes {address:#06X}, below the program at {entry_point:#05X}")]
+  BelowProgram {
+    line: usize,
+    address: usize,
+    entry_point: u16,
+  },
+
+  #[error("Intel HEX has no end of file record")]
+  MissingEnd,
+
+  #[error("Program spans {span} bytes, more than the {} allowed", MAX_SPAN)]
+  TooLarge { span: usize },
+
+  #[error("Line {line} has {token:?}, which is not pairs of hexadecimal digits")]
+  InvalidDigits { line: usize, token: String },
+}
+
+/// is_intel_hex returns whether `text` looks like Intel HEX rather than
+/// a hex dump, as its first record starts with a colon.
+pub fn is_intel_hex(text: &str) -> bool {
+  text.trim_start().starts_with(':')
+}
+
+/// decode returns the program in `text`, as Intel HEX or a hex dump, for
+/// a platform whose programs start at `entry_point`.
+pub fn decode(text: &str, entry_point: u16) -> Result<Vec<u8>, HexError> {
+  if is_intel_hex(text) {
+    decode_intel_hex(text, entry_point)
+  } else {
+    decode_dump(text)
+  }
+}
+
+/// decode_intel_hex returns the program in the Intel HEX `text` that
+/// starts at `entry_point`, after checking the checksum of each record.
+pub fn decode_intel_hex(text: &str, entry_point: u16) -> Result<Vec<u8>, HexError> {
+  // Written bytes by address.
+  let mut writes: Vec<(usize, Vec<u8>)> = Vec::new();
+  // Base added to record addresses, set by extended address records.
+  let mut base = 0;
+  let mut ended = false;
+  for (index, record) in text.lines().enumerate() {
+    let line = index + 1;
+    let record = record.trim();
+    if record.is_empty() {
+      continue;
+    }
+    if ended {
+      return Err(HexError::InvalidRecord { line });
+    }
+    let bytes = record
+      .strip_prefix(':')
+      .and_then(pairs)
+      .filter(|bytes| bytes.len() >= 5 && bytes.len() == bytes[0] as usize + 5)
+      .ok_or(HexError::InvalidRecord { line })?;
+    let (body, checksum) = bytes.split_at(bytes.len() - 1);
+    let expected = body
+      .iter()
+      .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+      .wrapping_neg();
+    if checksum[0] != expected {
+      return Err(HexError::Checksum {
+        line,
+        expected,
+        found: checksum[0],
+      });
+    }
+    let address = (body[1] as usize) << 8 | body[2] as usize;
+    let data = &body[4..];
+    match body[3] {
+      // Empty records write nothing, wherever they are.
+      0x00 if data.is_empty() => {}
+      0x00 if base + address < entry_point as usize => {
+        return Err(HexError::BelowProgram {
+          line,
+          address: base + address,
+          entry_point,
+        })
+      }
+      0x00 => writes.push((base + address, data.to_vec())),
+      0x01 => ended = true,
+      // Extended segment address, in 16 byte paragraphs.
+      0x02 if data.len() == 2 => base = ((data[0] as usize) << 8 | data[1] as usize) << 4,
+      // Extended linear address, the upper 16 bits.
+      0x04 if data.len() == 2 => base = ((data[0] as usize) << 8 | data[1] as usize) << 16,
+      // Start addresses are for x86 registers, and have no meaning here.
+      0x03 | 0x05 => {}
+      0x02 | 0x04 => return Err(HexError::InvalidRecord { line }),
+      record_type => return Err(HexError::UnsupportedRecord { line, record_type }),
+    }
+  }
+  if !ended {
+    return Err(HexError::MissingEnd);
+  }
+
+  let start = entry_point as usize;
+  let end = writes
+    .iter()
+    .map(|(address, data)| address + data.len())
+    .max()
+    .unwrap_or(start);
+  if end - start > MAX_SPAN {
+    return Err(HexError::TooLarge { span: end - start });
+  }
+  let mut program = vec![0; end - start];
+  for (address, data) in writes {
+    program[address - start..address - start + data.len()].copy_from_slice(&data);
+  }
+  Ok(program)
+}
+
+/// decode_dump returns the bytes of the hex dump `text`.
+pub fn decode_dump(text: &str) -> Result<Vec<u8>, HexError> {
+  let mut program = Vec::new();
+  for (index, line) in text.lines().enumerate() {
+    for token in line.split_whitespace() {
+      let bytes = pairs(token).ok_or_else(|| HexError::InvalidDigits {
+        line: index + 1,
+        token: String::from(token),
+      })?;
+      program.extend(bytes);
+    }
+  }
+  Ok(program)
+}
+
+// pairs returns the bytes written as pairs of hexadecimal digits in
+// `digits`.
+fn pairs(digits: &str) -> Option<Vec<u8>> {
+  if !digits.len().is_multiple_of(2) || !digits.bytes().all(|c| c.is_ascii_hexdigit()) {
+    return None;
+  }
+  (0..digits.len())
+    .step_by(2)
+    .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).ok())
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn intel_hex_addresses_are_absolute() {
+    let text = "\
+:04020000124E600139
+:02020600A22A2A
+:00000001FF
+";
+    assert_eq!(
+      decode(text, 0x200).unwrap(),
+      vec![0x12, 0x4E, 0x60, 0x01, 0x00, 0x00, 0xA2, 0x2A]
+    );
+    // Programs start at the entry point, even if the first record is
+    // after it.
+    assert_eq!(
+      decode(":02020600A22A2A\n:00000001FF\n", 0x200).unwrap(),
+      vec![0, 0, 0, 0, 0, 0, 0xA2, 0x2A]
+    );
+    assert_eq!(
+      decode(text, 0x600),
+      Err(HexError::BelowProgram {
+        line: 1,
+        address: 0x200,
+        entry_point: 0x600
+      })
+    );
+    assert_eq!(decode(":0000000000\n:00000001FF\n", 0x200).unwrap(), vec![]);
+  }
+
+  #[test]
+  fn intel_hex_checksums_are_verified() {
+    assert_eq!(
+      decode(":04020000124E60013A\n:00000001FF\n", 0x200),
+      Err(HexError::Checksum {
+        line: 1,
+        expected: 0x39,
+        found: 0x3A
+      })
+    );
+    assert_eq!(
+      decode(":04020000124E600139\n", 0x200),
+      Err(HexError::MissingEnd)
+    );
+    assert_eq!(
+      decode(":04020000124E60B7\n:00000001FF\n", 0x200),
+      Err(HexError::InvalidRecord { line: 1 })
+    );
+  }
+
+  #[test]
+  fn extended_addresses_are_limited() {
+    let text = ":020000040001F9\n:01020000AA53\n:020000040000FA\n:01020000BB42\n:00000001FF\n";
+    assert_eq!(
+      decode(text, 0x200),
+      Err(HexError::TooLarge { span: 0x10001 })
+    );
+  }
+
+  #[test]
+  fn dumps_are_pairs_of_digits() {
+    assert_eq!(
+      decode("124E 6001\n  a2 2a\n", 0x200).unwrap(),
+      vec![0x12, 0x4E, 0x60, 0x01, 0xA2, 0x2A]
+    );
+    assert_eq!(
+      decode("12 4E\n60 0x01\n", 0x200),
+      Err(HexError::InvalidDigits {
+        line: 2,
+        token: String::from("0x01")
+      })
+    );
+  }
+}
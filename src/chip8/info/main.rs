@@ -0,0 +1,204 @@
+//! info shows what can be found out about ROM files without running them:
+//! their size and SHA-1 hash, how many bytes are code and how many data,
+//! how often the code uses each instruction, how deep its calls go, the
+//! addresses it refers to outside of the ROM, and its platform, from the
+//! ROM database or else as `chip8::analysis` guesses it.
+//!
+//! `--all` shows a table of every ROM in a directory, `games/` unless one
+//! is given, with the instructions counted over all of them.
+
+use clap::{App, Arg};
+use rustyemulator::chip8::database::Database;
+use rustyemulator::chip8::platform::Platform;
+use rustyemulator::chip8::rom::{Rom, RomError};
+use rustyemulator::chip8::rominfo::{PlatformSource, RomInfo};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::process;
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum InfoError {
+  #[error("Failed to read ROM {path}: {source}")]
+  Rom { path: String, source: RomError },
+
+  #[error("Failed to read directory {path}: {source}")]
+  Directory {
+    path: String,
+    source: std::io::Error,
+  },
+}
+
+fn main() {
+  let matches = App::new("Chip8 ROM info")
+    .version("1.0")
+    .author("Joey Pereira <joey@pereira.io>")
+    .about("Shows what static analysis finds in Chip8 ROMs")
+    .arg(
+      Arg::with_name("rom")
+        .long("rom")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .required_unless("all")
+        .help("Sets a ROM file to show, or - to read it from standard input"),
+    )
+    .arg(
+      Arg::with_name("all")
+        .long("all")
+        .takes_value(true)
+        .value_name("DIR")
+        .min_values(0)
+        .max_values(1)
+        .conflicts_with("rom")
+        .help("Shows a table of every ROM in a directory, games/ by default"),
+    )
+    .get_matches();
+
+  let database = Database::embedded();
+  let result = if matches.is_present("all") {
+    print_table(&database, matches.value_of("all").unwrap_or("games"))
+  } else {
+    matches
+      .values_of("rom")
+      .unwrap()
+      .try_for_each(|path| print_info(&database, path))
+  };
+  if let Err(err) = result {
+    eprintln!("error: {}", err);
+    process::exit(1);
+  }
+}
+
+// read returns the summary of the ROM at `path`.
+fn read(database: &Database, path: &str) -> Result<RomInfo, InfoError> {
+  // The platform is not known until the ROM is read, so the one with the
+  // most memory is used to read ROMs of any size.
+  let rom = Rom::open(path, Platform::XoChip).map_err(|source| InfoError::Rom {
+    path: String::from(path),
+    source,
+  })?;
+  Ok(RomInfo::new(&rom, database))
+}
+
+fn print_info(database: &Database, path: &str) -> Result<(), InfoError> {
+  let info = read(database, path)?;
+  println!("{}", path);
+  println!("  Size:      {} bytes", info.size);
+  println!("  SHA-1:     {}", info.hash);
+  println!(
+    "  Code:      {} bytes ({:.0}%)",
+    info.code_size,
+    percent(info.code_size, info.size)
+  );
+  println!("  Data:      {} bytes", info.data_size());
+  println!("  Entropy:   {:.2} bits per byte", info.entropy);
+  match info.max_stack_depth {
+    Some(depth) => println!("  Stack:     {} deep", depth),
+    None => println!("  Stack:     unbounded, subroutines recurse"),
+  }
+  if !info.outside.is_empty() {
+    let outside: Vec<String> = info
+      .outside
+      .iter()
+      .map(|address| format!("{:#05X}", address))
+      .collect();
+    println!("  Outside:   {}", outside.join(", "));
+  }
+  match &info.platform_source {
+    PlatformSource::Database { title } => println!(
+      "  Platform:  {} (database entry {:?})",
+      info.platform.name(),
+      title
+    ),
+    PlatformSource::Analysis { confidence } => println!(
+      "  Platform:  {} (guessed, {:.0}% confident)",
+      info.platform.name(),
+      confidence * 100.0
+    ),
+  }
+  println!("  Instructions:");
+  print_histogram(&info.instructions);
+  Ok(())
+}
+
+// print_table prints a row for each ROM in the directory at `path`,
+// followed by the instructions of all of them.
+fn print_table(database: &Database, path: &str) -> Result<(), InfoError> {
+  let directory_error = |source| InfoError::Directory {
+    path: String::from(path),
+    source,
+  };
+  let mut paths = Vec::new();
+  for entry in fs::read_dir(path).map_err(directory_error)? {
+    let entry = entry.map_err(directory_error)?;
+    if entry.path().is_file() {
+      paths.push(entry.path());
+    }
+  }
+  paths.sort();
+
+  println!(
+    "{:<16} {:>5} {:>5} {:>5} {:>7} {:>5} {:>7} {:<8} SHA-1",
+    "ROM", "SIZE", "CODE", "DATA", "ENTROPY", "STACK", "OUTSIDE", "PLATFORM"
+  );
+  let mut instructions = BTreeMap::new();
+  for path in paths.iter() {
+    let info = read(database, &path.to_string_lossy())?;
+    let name = path
+      .file_name()
+      .map(|name| name.to_string_lossy())
+      .unwrap_or_default();
+    let guessed = match info.platform_source {
+      PlatformSource::Database { .. } => "",
+      PlatformSource::Analysis { .. } => "?",
+    };
+    println!(
+      "{:<16} {:>5} {:>5} {:>5} {:>7.2} {:>5} {:>7} {:<8} {}",
+      name,
+      info.size,
+      info.code_size,
+      info.data_size(),
+      info.entropy,
+      info
+        .max_stack_depth
+        .map_or_else(|| String::from("-"), |depth| depth.to_string()),
+      info.outside.len(),
+      format!("{}{}", info.platform.name(), guessed),
+      info.hash
+    );
+    for (name, count) in info.instructions {
+      *instructions.entry(name).or_insert(0) += count;
+    }
+  }
+  println!();
+  println!(
+    "Instructions in the {} ROMs of {}:",
+    paths.len(),
+    Path::new(path).display()
+  );
+  print_histogram(&instructions);
+  Ok(())
+}
+
+// print_histogram prints `counts` from the most to the least common,
+// with a bar scaled to the most common.
+fn print_histogram(counts: &BTreeMap<&'static str, usize>) {
+  let mut counts: Vec<_> = counts.iter().collect();
+  counts.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+  let max = counts.first().map_or(0, |(_, &count)| count);
+  for (name, &count) in counts {
+    let bar = "#".repeat((count * 40).div_ceil(max));
+    println!("    {:<28} {:>5} {}", name, count, bar);
+  }
+}
+
+fn percent(part: usize, whole: usize) -> f64 {
+  if whole == 0 {
+    0.0
+  } else {
+    part as f64 * 100.0 / whole as f64
+  }
+}
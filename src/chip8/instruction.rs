@@ -511,6 +511,31 @@ pub enum Instruction {
   /// assert_eq!(instr, Instruction::MemoryLoad { x_register: 0x2 });
   /// ```
   MemoryLoad { x_register: usize },
+  /// Opcode F002
+  ///
+  /// XO-CHIP: Loads the 16 bytes of memory starting at address I into
+  /// the audio pattern buffer, which the buzzer then plays as 128 one
+  /// bit samples.
+  ///
+  /// ```
+  /// # use rustyemulator::chip8::instruction::{parse_instruction, Instruction};
+  /// let instr = parse_instruction(0xF002).unwrap();
+  /// assert!(matches!(instr, Instruction::AudioLoadPattern()), "Expected to parse AudioLoadPattern, instead parsed opcode: {:?}", instr);
+  /// assert!(parse_instruction(0xF102).is_err());
+  /// ```
+  AudioLoadPattern(),
+  /// Opcode FX3A
+  ///
+  /// XO-CHIP: Sets the pitch of the audio pattern to Vx. The pattern is
+  /// played at 4000*2^((Vx-64)/48) samples per second.
+  ///
+  /// ```
+  /// # use rustyemulator::chip8::instruction::{parse_instruction, Instruction};
+  /// let instr = parse_instruction(0xF23A).unwrap();
+  /// assert!(matches!(instr, Instruction::AudioSetPitch {..}), "Expected to parse AudioSetPitch, instead parsed opcode: {:?}", instr);
+  /// assert_eq!(instr, Instruction::AudioSetPitch { x_register: 0x2 });
+  /// ```
+  AudioSetPitch { x_register: usize },
 }
 
 /// parse_instruction will parse a Chip8 instruction (2 bytes) into the
@@ -656,6 +681,10 @@ pub fn parse_instruction(instr: u16) -> Result<Instruction, InstructionError> {
       0x0065 => Ok(Instruction::MemoryLoad {
         x_register: bitmask_0X00(instr) as usize,
       }),
+      0x0002 if bitmask_0X00(instr) == 0 => Ok(Instruction::AudioLoadPattern()),
+      0x003A => Ok(Instruction::AudioSetPitch {
+        x_register: bitmask_0X00(instr) as usize,
+      }),
       _ => Err(InstructionError::UnsupportedInstructionError { instruction: instr }),
     },
     _ => Err(InstructionError::UnsupportedInstructionError { instruction: instr }),
@@ -5,15 +5,18 @@
 //! complete machine language parsing, but does not ensure the parsing
 //! is correct (that would require a comparable ROM with structured
 //! data).
-
-// FIXME(joey): This file actually makes no sense! This is because
-// developers can intertwine data with code, meaning not everything
-// should be a parsable instruction.
+//!
+//! Developers intertwine data with code, so not every pair of bytes is
+//! an instruction, and ROMs for SCHIP and XO-CHIP have instructions the
+//! parser does not know. Rather than failing on them, the tester counts
+//! them, and reports the platform and quirks `chip8::analysis` suggests
+//! from the code it can follow.
 
 use clap::{App, Arg};
 use env_logger;
 use log::LevelFilter;
-use rustyemulator::chip8::instruction::{parse_instruction, Instruction, InstructionError};
+use rustyemulator::chip8::analysis::Analysis;
+use rustyemulator::chip8::instruction::parse_instruction;
 use std::env;
 use std::fs::File;
 use std::io::prelude::*;
@@ -22,20 +25,6 @@ use std::path::Path;
 #[macro_use]
 extern crate log;
 
-use thiserror::Error;
-
-#[derive(Error, Debug)]
-pub enum InstructionReadTesterError {
-  #[error("Parse error")]
-  ParseError {
-    file: String,
-    source: InstructionError,
-  },
-
-  #[error(transparent)]
-  IOError(#[from] std::io::Error),
-}
-
 fn main() -> std::io::Result<()> {
   let matches = App::new("Chip8 instruction read tester")
     .version("1.0")
@@ -94,7 +83,7 @@ fn main() -> std::io::Result<()> {
   Ok(())
 }
 
-fn read_and_parse_file(path: &Path) -> std::io::Result<Vec<Instruction>> {
+fn read_and_parse_file(path: &Path) -> std::io::Result<()> {
   let file = File::open(path)?;
   let attr = std::fs::metadata(path)?;
   let filename = path.file_name().unwrap().to_str().unwrap();
@@ -111,25 +100,30 @@ fn read_and_parse_file(path: &Path) -> std::io::Result<Vec<Instruction>> {
   let mut data = Vec::new();
   reader.read_to_end(&mut data)?;
 
-  let instructions = data
-    .chunks_exact(2)
-    .enumerate()
-    .map(|(idx, chunk)| {
-      let instr_bytes = ((chunk[0] as u16) << 8) | chunk[1] as u16;
-      parse_instruction(instr_bytes)
-        .map_err(|source| {
-          panic!(
-            "Error on instruction #{}: {:X?}",
-            idx,
-            InstructionReadTesterError::ParseError {
-              file: String::from(filename),
-              source: source,
-            }
-          )
-        })
-        .unwrap()
-    })
-    .collect();
+  let mut unparsed = 0;
+  for (idx, chunk) in data.chunks_exact(2).enumerate() {
+    let instr_bytes = ((chunk[0] as u16) << 8) | chunk[1] as u16;
+    if let Err(source) = parse_instruction(instr_bytes) {
+      debug!("Error on instruction #{} of {}: {}", idx, filename, source);
+      unparsed += 1;
+    }
+  }
+  println!(
+    "{}: {} of {} words are not instructions the parser knows",
+    filename,
+    unparsed,
+    data.len() / 2
+  );
 
-  Ok(instructions)
+  let analysis = Analysis::analyse(&data);
+  println!(
+    "  Suggests {} with {} quirks ({:.0}% confident)",
+    analysis.platform.name(),
+    analysis.quirks.preset_name().unwrap_or("custom"),
+    analysis.confidence * 100.0
+  );
+  for finding in analysis.findings.iter() {
+    println!("  {}", finding);
+  }
+  Ok(())
 }
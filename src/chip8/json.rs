@@ -0,0 +1,262 @@
+//! json reads JSON text, as specified in RFC 8259, for the files that
+//! describe Chip8 programs and how to run them.
+
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+/// JsonError is returned when JSON text can't be read.
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("Invalid JSON at byte {offset}")]
+pub struct JsonError {
+  pub offset: usize,
+}
+
+/// Json is a JSON value.
+#[derive(PartialEq, Clone, Debug)]
+pub enum Json {
+  Null,
+  Bool(bool),
+  Number(f64),
+  String(String),
+  Array(Vec<Json>),
+  Object(BTreeMap<String, Json>),
+}
+
+impl Json {
+  /// parse returns the value of the JSON `text`.
+  pub fn parse(text: &str) -> Result<Json, JsonError> {
+    Parser::parse(text)
+  }
+
+  /// get returns the member `name` of an object.
+  pub fn get(&self, name: &str) -> Option<&Json> {
+    match self {
+      Json::Object(members) => members.get(name),
+      _ => None,
+    }
+  }
+
+  pub fn as_str(&self) -> Option<&str> {
+    match self {
+      Json::String(string) => Some(string),
+      _ => None,
+    }
+  }
+
+  pub fn as_f64(&self) -> Option<f64> {
+    match self {
+      Json::Number(number) => Some(*number),
+      _ => None,
+    }
+  }
+
+  pub fn as_bool(&self) -> Option<bool> {
+    match self {
+      Json::Bool(value) => Some(*value),
+      _ => None,
+    }
+  }
+
+  pub fn as_array(&self) -> Option<&[Json]> {
+    match self {
+      Json::Array(values) => Some(values),
+      _ => None,
+    }
+  }
+
+  pub fn as_object(&self) -> Option<&BTreeMap<String, Json>> {
+    match self {
+      Json::Object(members) => Some(members),
+      _ => None,
+    }
+  }
+}
+
+// Parser parses JSON text, as specified in RFC 8259.
+struct Parser<'a> {
+  text: &'a str,
+  offset: usize,
+}
+
+impl<'a> Parser<'a> {
+  fn parse(text: &'a str) -> Result<Json, JsonError> {
+    let mut parser = Parser { text, offset: 0 };
+    let value = parser.value()?;
+    parser.whitespace();
+    if parser.offset != text.len() {
+      return Err(parser.error());
+    }
+    Ok(value)
+  }
+
+  fn error(&self) -> JsonError {
+    JsonError {
+      offset: self.offset,
+    }
+  }
+
+  fn peek(&self) -> Option<u8> {
+    self.text.as_bytes().get(self.offset).copied()
+  }
+
+  fn whitespace(&mut self) {
+    while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.peek() {
+      self.offset += 1;
+    }
+  }
+
+  // eat skips `expected` and the whitespace after it.
+  fn eat(&mut self, expected: &str) -> Result<(), JsonError> {
+    if !self.text[self.offset..].starts_with(expected) {
+      return Err(self.error());
+    }
+    self.offset += expected.len();
+    self.whitespace();
+    Ok(())
+  }
+
+  fn value(&mut self) -> Result<Json, JsonError> {
+    self.whitespace();
+    let value = match self.peek().ok_or_else(|| self.error())? {
+      b'{' => {
+        self.eat("{")?;
+        let mut members = BTreeMap::new();
+        if self.peek() != Some(b'}') {
+          loop {
+            let name = self.string()?;
+            self.whitespace();
+            self.eat(":")?;
+            members.insert(name, self.value()?);
+            if self.peek() != Some(b',') {
+              break;
+            }
+            self.eat(",")?;
+          }
+        }
+        self.eat("}")?;
+        return Ok(Json::Object(members));
+      }
+      b'[' => {
+        self.eat("[")?;
+        let mut values = Vec::new();
+        if self.peek() != Some(b']') {
+          loop {
+            values.push(self.value()?);
+            if self.peek() != Some(b',') {
+              break;
+            }
+            self.eat(",")?;
+          }
+        }
+        self.eat("]")?;
+        return Ok(Json::Array(values));
+      }
+      b'"' => Json::String(self.string()?),
+      b't' => {
+        self.eat("true")?;
+        return Ok(Json::Bool(true));
+      }
+      b'f' => {
+        self.eat("false")?;
+        return Ok(Json::Bool(false));
+      }
+      b'n' => {
+        self.eat("null")?;
+        return Ok(Json::Null);
+      }
+      _ => Json::Number(self.number()?),
+    };
+    self.whitespace();
+    Ok(value)
+  }
+
+  fn number(&mut self) -> Result<f64, JsonError> {
+    let start = self.offset;
+    while let Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') = self.peek() {
+      self.offset += 1;
+    }
+    let text = &self.text[start..self.offset];
+    text.parse().map_err(|_| JsonError { offset: start })
+  }
+
+  fn string(&mut self) -> Result<String, JsonError> {
+    if self.peek() != Some(b'"') {
+      return Err(self.error());
+    }
+    self.offset += 1;
+    let mut string = String::new();
+    loop {
+      let rest = &self.text[self.offset..];
+      let end = rest.find(['"', '\\']).ok_or_else(|| self.error())?;
+      string.push_str(&rest[..end]);
+      self.offset += end + 1;
+      if rest.as_bytes()[end] == b'"' {
+        return Ok(string);
+      }
+      let escape = self.peek().ok_or_else(|| self.error())?;
+      self.offset += 1;
+      string.push(match escape {
+        b'"' => '"',
+        b'\\' => '\\',
+        b'/' => '/',
+        b'b' => '\u{8}',
+        b'f' => '\u{c}',
+        b'n' => '\n',
+        b'r' => '\r',
+        b't' => '\t',
+        b'u' => {
+          let high = self.code_unit()?;
+          let code = if (0xD800..0xDC00).contains(&high) {
+            // A surrogate pair.
+            self.eat("\\u")?;
+            let low = self.code_unit()?;
+            0x10000 + ((high - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF)
+          } else {
+            high
+          };
+          std::char::from_u32(code).ok_or_else(|| self.error())?
+        }
+        _ => return Err(self.error()),
+      });
+    }
+  }
+
+  // code_unit reads the four hexadecimal digits of a \u escape.
+  fn code_unit(&mut self) -> Result<u32, JsonError> {
+    let digits = self
+      .text
+      .get(self.offset..self.offset + 4)
+      .ok_or_else(|| self.error())?;
+    let unit = u32::from_str_radix(digits, 16).map_err(|_| self.error())?;
+    self.offset += 4;
+    Ok(unit)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn values_parse() {
+    let json = Json::parse(r#" {"a": [1, -2.5e1, true, null], "b": "x\"\né😀"} "#).unwrap();
+    assert_eq!(
+      json.get("a"),
+      Some(&Json::Array(vec![
+        Json::Number(1.0),
+        Json::Number(-25.0),
+        Json::Bool(true),
+        Json::Null
+      ]))
+    );
+    assert_eq!(
+      json.get("b"),
+      Some(&Json::String(String::from("x\"\n\u{e9}\u{1F600}")))
+    );
+    assert!(matches!(
+      Json::parse(r#"{"a": }"#),
+      Err(JsonError { offset: 6 })
+    ));
+    assert!(Json::parse("[1] 2").is_err());
+  }
+}
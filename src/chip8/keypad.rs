@@ -0,0 +1,76 @@
+use crate::interface::serialization::Savable;
+
+/// Keypad is the emulation of the Chip8 hexadecimal keypad, with the
+/// keys 0-F.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Hash)]
+pub struct Keypad {
+  keys: [bool; 16],
+}
+
+impl Savable for Keypad {
+  fn save(&self, fh: &mut dyn std::io::Write) -> std::io::Result<()> {
+    self.keys.save(fh)?;
+    Ok(())
+  }
+  fn load(&mut self, fh: &mut dyn std::io::Read) -> std::io::Result<()> {
+    self.keys.load(fh)?;
+    Ok(())
+  }
+}
+
+impl Default for Keypad {
+  fn default() -> Self {
+    Keypad::new()
+  }
+}
+
+impl Keypad {
+  pub fn new() -> Self {
+    Keypad { keys: [false; 16] }
+  }
+
+  /// is_pressed returns whether `key` is held down. Only the low nibble
+  /// of `key` is used, as the Chip8 does when reading a key from Vx.
+  pub fn is_pressed(&self, key: usize) -> bool {
+    self.keys[key & 0xF]
+  }
+
+  pub fn press(&mut self, key: usize) {
+    self.keys[key & 0xF] = true
+  }
+
+  pub fn release(&mut self, key: usize) {
+    self.keys[key & 0xF] = false
+  }
+
+  /// first_pressed returns the lowest key that is held down, if any.
+  pub fn first_pressed(&self) -> Option<usize> {
+    self.keys.iter().position(|pressed| *pressed)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  #[test]
+  fn press_and_release_works() {
+    let mut keypad = Keypad::new();
+    assert_eq!(keypad.first_pressed(), None);
+    keypad.press(0xA);
+    keypad.press(0x3);
+    assert!(keypad.is_pressed(0xA));
+    assert_eq!(keypad.first_pressed(), Some(0x3));
+    keypad.release(0x3);
+    assert!(!keypad.is_pressed(0x3));
+    assert_eq!(keypad.first_pressed(), Some(0xA));
+  }
+
+  #[test]
+  fn only_low_nibble_is_used() {
+    let mut keypad = Keypad::new();
+    keypad.press(0x1F);
+    assert!(keypad.is_pressed(0xF));
+  }
+}
@@ -0,0 +1,190 @@
+//! keyscript provides scripted key presses, for running ROMs without a
+//! keyboard.
+//!
+//! A script has one event per line: the frame it happens before, the
+//! action, and the key as a hexadecimal digit. Blank lines and text
+//! after a `#` are ignored.
+//!
+//! ```text
+//! # Start the game, then hold 4 for a second.
+//!  60 press 5
+//!  62 release 5
+//! 100 press 4
+//! 160 release 4
+//! ```
+
+use crate::chip8::chip8::Chip8;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// KeyScriptError is returned when a line of a key script can't be
+/// read.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum KeyScriptError {
+  #[error("Line {line}: expected \"<frame> press|release <key>\", found {text:?}")]
+  Syntax { line: usize, text: String },
+
+  #[error("Line {line}: invalid key {key:?}, expected 0-F")]
+  InvalidKey { line: usize, key: String },
+}
+
+/// KeyAction is what happens to a key.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Hash)]
+pub enum KeyAction {
+  Press,
+  Release,
+}
+
+/// KeyEvent is a key press or release before a frame.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Hash)]
+pub struct KeyEvent {
+  /// Number of frames run before the event.
+  pub frame: u32,
+  pub action: KeyAction,
+  /// Key 0-F.
+  pub key: usize,
+}
+
+impl fmt::Display for KeyEvent {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let action = match self.action {
+      KeyAction::Press => "press",
+      KeyAction::Release => "release",
+    };
+    write!(f, "{} {} {:X}", self.frame, action, self.key)
+  }
+}
+
+/// KeyScript is a list of key events, in the order they happen.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeyScript {
+  events: Vec<KeyEvent>,
+}
+
+impl FromStr for KeyScript {
+  type Err = KeyScriptError;
+
+  fn from_str(text: &str) -> Result<Self, Self::Err> {
+    let mut events = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+      let line_number = index + 1;
+      let content = line.split('#').next().unwrap_or("").trim();
+      if content.is_empty() {
+        continue;
+      }
+      let syntax = || KeyScriptError::Syntax {
+        line: line_number,
+        text: String::from(content),
+      };
+      let fields: Vec<_> = content.split_whitespace().collect();
+      let (frame, action, key) = match fields.as_slice() {
+        [frame, action, key] => (frame, action, key),
+        _ => return Err(syntax()),
+      };
+      let frame = frame.parse().map_err(|_| syntax())?;
+      let action = match action.to_ascii_lowercase().as_str() {
+        "press" => KeyAction::Press,
+        "release" => KeyAction::Release,
+        _ => return Err(syntax()),
+      };
+      let key = match usize::from_str_radix(key, 16) {
+        Ok(value) if value < 16 => value,
+        _ => {
+          return Err(KeyScriptError::InvalidKey {
+            line: line_number,
+            key: String::from(*key),
+          })
+        }
+      };
+      events.push(KeyEvent { frame, action, key });
+    }
+    Ok(KeyScript::new(events))
+  }
+}
+
+/// KeyScript is printed with one event to a line, so it reads back as
+/// the same script.
+impl fmt::Display for KeyScript {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    for event in self.events.iter() {
+      writeln!(f, "{}", event)?;
+    }
+    Ok(())
+  }
+}
+
+impl KeyScript {
+  /// new returns the script of `events`. Events are ordered by frame,
+  /// keeping the given order within a frame.
+  pub fn new(mut events: Vec<KeyEvent>) -> Self {
+    events.sort_by_key(|event| event.frame);
+    KeyScript { events }
+  }
+
+  pub fn events(&self) -> &[KeyEvent] {
+    &self.events
+  }
+
+  /// apply presses and releases the keys of `chip8` for the events
+  /// before `frame`, which is the number of frames run so far.
+  pub fn apply(&self, frame: u32, chip8: &mut Chip8) {
+    let start = self.events.partition_point(|event| event.frame < frame);
+    for event in self.events[start..].iter() {
+      if event.frame != frame {
+        break;
+      }
+      match event.action {
+        KeyAction::Press => chip8.press_key(event.key),
+        KeyAction::Release => chip8.release_key(event.key),
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn scripts_parse_and_print() {
+    let script: KeyScript = "# comment\n\n 60 press a\n30 RELEASE 4 # late\n60 release A\n"
+      .parse()
+      .unwrap();
+    let lines: Vec<_> = script.events().iter().map(|e| e.to_string()).collect();
+    assert_eq!(lines, vec!["30 release 4", "60 press A", "60 release A"]);
+    assert_eq!(script.to_string().parse(), Ok(script));
+  }
+
+  #[test]
+  fn bad_lines_report_their_number() {
+    assert_eq!(
+      "1 press 1\n2 hold 1".parse::<KeyScript>(),
+      Err(KeyScriptError::Syntax {
+        line: 2,
+        text: String::from("2 hold 1")
+      })
+    );
+    assert_eq!(
+      "1 press 10".parse::<KeyScript>(),
+      Err(KeyScriptError::InvalidKey {
+        line: 1,
+        key: String::from("10")
+      })
+    );
+  }
+
+  #[test]
+  fn apply_runs_events_of_frame() {
+    let script: KeyScript = "2 press 1\n2 press 2\n3 release 1".parse().unwrap();
+    let mut chip8 = Chip8::new();
+    script.apply(1, &mut chip8);
+    assert!(!chip8.cpu().keypad().is_pressed(1));
+    script.apply(2, &mut chip8);
+    assert!(chip8.cpu().keypad().is_pressed(1));
+    assert!(chip8.cpu().keypad().is_pressed(2));
+    script.apply(3, &mut chip8);
+    assert!(!chip8.cpu().keypad().is_pressed(1));
+    assert!(chip8.cpu().keypad().is_pressed(2));
+  }
+}
@@ -0,0 +1,267 @@
+//! launch loads a ROM or Octo cartridge into a Chip8 the way the
+//! frontends do: patched, and run with the platform, quirks and speed
+//! its cartridge or database entry asks for, unless they are chosen on
+//! the command line. It also checks the arguments the frontends share
+//! for playing and recording its sound and video.
+
+use crate::chip8::audio::Beeper;
+use crate::chip8::cartridge::{Cartridge, CartridgeError, Colours, CARTRIDGE_EXTENSION};
+use crate::chip8::chip8::Chip8;
+use crate::chip8::cpu::{CpuError, PROGRAM_START};
+use crate::chip8::database::Database;
+use crate::chip8::platform::{Platform, PLATFORM_NAMES};
+use crate::chip8::quirks::{Quirks, PRESET_NAMES};
+use crate::chip8::recording::VideoFormat;
+use crate::chip8::rom::{Rom, RomError};
+use crate::interface::bus::Bus;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum LaunchError {
+  #[error("Unknown quirk profile {name:?}, expected one of: {}", PRESET_NAMES.join(", "))]
+  UnknownQuirks { name: String },
+
+  #[error("Unknown platform {name:?}, expected one of: {}", PLATFORM_NAMES.join(", "))]
+  UnknownPlatform { name: String },
+
+  #[error("Invalid value for --{arg}: {value:?}")]
+  InvalidArgument { arg: &'static str, value: String },
+
+  #[error("Video {path} should end in .y4m or .gif")]
+  UnknownVideoFormat { path: String },
+
+  #[error("Failed to load ROM {path}: {source}")]
+  Rom { path: String, source: CpuError },
+
+  #[error(transparent)]
+  RomError(#[from] RomError),
+
+  #[error(transparent)]
+  Cartridge(#[from] CartridgeError),
+}
+
+/// parse_number parses `value`, given for the command line argument
+/// `arg`.
+pub fn parse_number<T: FromStr>(value: &str, arg: &'static str) -> Result<T, LaunchError> {
+  value.parse().map_err(|_| LaunchError::InvalidArgument {
+    arg,
+    value: String::from(value),
+  })
+}
+
+/// parse_quirks returns the quirk preset named `name`.
+pub fn parse_quirks(name: &str) -> Result<Quirks, LaunchError> {
+  Quirks::from_name(name).ok_or_else(|| LaunchError::UnknownQuirks {
+    name: String::from(name),
+  })
+}
+
+/// parse_platform returns the platform named `name`.
+pub fn parse_platform(name: &str) -> Result<Platform, LaunchError> {
+  Platform::from_name(name).ok_or_else(|| LaunchError::UnknownPlatform {
+    name: String::from(name),
+  })
+}
+
+/// parse_beeper returns the Beeper for the values of `--sample-rate` and
+/// `--pitch`, which must be below half the sample rate.
+pub fn parse_beeper(sample_rate: &str, pitch: &str) -> Result<Beeper, LaunchError> {
+  let rate: u32 = parse_number(sample_rate, "sample-rate")?;
+  if rate == 0 {
+    return Err(LaunchError::InvalidArgument {
+      arg: "sample-rate",
+      value: String::from(sample_rate),
+    });
+  }
+  let hertz: f32 = parse_number(pitch, "pitch")?;
+  if !(hertz > 0.0 && hertz < rate as f32 / 2.0) {
+    return Err(LaunchError::InvalidArgument {
+      arg: "pitch",
+      value: String::from(pitch),
+    });
+  }
+  Ok(Beeper::new(rate, hertz))
+}
+
+/// parse_video_format returns the format to record the video at `path`
+/// in, from its extension.
+pub fn parse_video_format(path: &str) -> Result<VideoFormat, LaunchError> {
+  VideoFormat::from_path(Path::new(path)).ok_or_else(|| LaunchError::UnknownVideoFormat {
+    path: String::from(path),
+  })
+}
+
+/// Options are what to load and the settings chosen for it.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+  /// Path of the ROM or cartridge, or `-` for standard input.
+  pub path: String,
+  /// Paths of the IPS or BPS patches applied to the ROM, in order.
+  pub patches: Vec<PathBuf>,
+  /// Platform to run the ROM on, rather than its database entry's.
+  pub platform: Option<Platform>,
+  /// Quirks to run with, rather than the cartridge's or database
+  /// entry's. Without either, the program runs with modern quirks.
+  pub quirks: Option<Quirks>,
+  /// Instructions per frame to run, rather than the cartridge's or
+  /// database entry's.
+  pub instructions_per_frame: Option<u32>,
+  /// Whether to look the ROM up in the database.
+  pub database: bool,
+  pub seed: u32,
+  /// Whether writes below the program are refused.
+  pub protect: bool,
+}
+
+/// Settings is how a ROM asks to be shown and played, from its cartridge
+/// or its database entry.
+#[derive(Clone, Debug, Default)]
+pub struct Settings {
+  pub colours: Option<Colours>,
+  /// Keys the ROM is played with, by what they do.
+  pub keys: BTreeMap<String, u8>,
+  /// Names of the cartridge options that can't be applied.
+  pub unsupported: Vec<&'static str>,
+}
+
+/// load creates a Chip8 with the ROM and settings from `options`.
+pub fn load(options: &Options) -> Result<(Chip8, Settings), LaunchError> {
+  let path = options.path.as_str();
+  let mut chip8 = Chip8::new();
+  let mut settings = Settings::default();
+  // Whether the cartridge or database set the quirks.
+  let mut configured = false;
+  let rom = if path.ends_with(CARTRIDGE_EXTENSION) {
+    let cartridge = Cartridge::from_path(Path::new(path))?;
+    let cartridge_options = cartridge.options();
+    chip8.set_quirks(cartridge_options.quirks);
+    configured = true;
+    chip8.set_instructions_per_frame(cartridge_options.instructions_per_frame);
+    settings.colours = Some(cartridge_options.colours);
+    settings.unsupported = cartridge_options.unsupported.clone();
+    patch_rom(cartridge.rom().clone(), &options.patches)?
+  } else {
+    let platform = options.platform.unwrap_or_default();
+    let base = Rom::open(path, platform)?;
+    let mut rom = patch_rom(base.clone(), &options.patches)?;
+    if options.database {
+      // Patched ROMs play like the ROM they patch, unless they have an
+      // entry of their own.
+      let database = Database::embedded();
+      if let Some(entry) = database
+        .get(&rom.hash())
+        .or_else(|| database.get(&base.hash()))
+      {
+        if options.platform.is_none() && entry.platform != platform {
+          rom = Rom::from_bytes(rom.bytes(), entry.platform)?;
+        }
+        entry.apply(&mut chip8);
+        configured = true;
+        settings.keys = entry.keys.clone();
+      }
+    }
+    rom
+  };
+  match options.quirks {
+    Some(quirks) => chip8.set_quirks(quirks),
+    None if !configured => chip8.set_quirks(Quirks::modern()),
+    None => {}
+  }
+  chip8.seed_rng(options.seed);
+  if let Some(instructions_per_frame) = options.instructions_per_frame {
+    chip8.set_instructions_per_frame(instructions_per_frame);
+  }
+  if options.protect {
+    chip8.bus().protect(0..PROGRAM_START);
+  }
+  chip8.load_rom(&rom).map_err(|source| LaunchError::Rom {
+    path: String::from(path),
+    source,
+  })?;
+  Ok((chip8, settings))
+}
+
+// patch_rom returns `rom` with the patches at `patches` applied.
+fn patch_rom(mut rom: Rom, patches: &[PathBuf]) -> Result<Rom, RomError> {
+  for path in patches {
+    rom = rom.patch(path)?;
+  }
+  Ok(rom)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn blinky() -> Options {
+    Options {
+      path: format!("{}/games/BLINKY", env!("CARGO_MANIFEST_DIR")),
+      database: true,
+      ..Options::default()
+    }
+  }
+
+  #[test]
+  fn database_settings_apply_unless_chosen() {
+    let entry_quirks = Quirks::from_name("chip48").unwrap();
+    let (chip8, settings) = load(&blinky()).unwrap();
+    assert_eq!(chip8.quirks(), entry_quirks);
+    assert_eq!(chip8.instructions_per_frame(), 15);
+    assert_eq!(settings.keys.get("up"), Some(&3));
+
+    let options = Options {
+      quirks: Some(Quirks::modern()),
+      instructions_per_frame: Some(30),
+      ..blinky()
+    };
+    let (chip8, _) = load(&options).unwrap();
+    assert_eq!(chip8.quirks(), Quirks::modern());
+    assert_eq!(chip8.instructions_per_frame(), 30);
+
+    let options = Options {
+      database: false,
+      ..blinky()
+    };
+    let (chip8, settings) = load(&options).unwrap();
+    assert_eq!(chip8.quirks(), Quirks::modern());
+    assert!(settings.keys.is_empty());
+  }
+
+  #[test]
+  fn arguments_name_themselves() {
+    assert_eq!(parse_number::<u32>("12", "ipf").unwrap(), 12);
+    assert_eq!(
+      parse_number::<u32>("twelve", "ipf")
+        .unwrap_err()
+        .to_string(),
+      "Invalid value for --ipf: \"twelve\""
+    );
+    assert!(matches!(
+      parse_quirks("vip2"),
+      Err(LaunchError::UnknownQuirks { .. })
+    ));
+    assert_eq!(parse_platform("schip").unwrap(), Platform::Schip);
+  }
+
+  #[test]
+  fn beepers_name_the_argument_out_of_range() {
+    let beeper = parse_beeper("48000", "1000").unwrap();
+    assert_eq!((beeper.sample_rate(), beeper.pitch()), (48000, 1000.0));
+    let invalid = |sample_rate, pitch| match parse_beeper(sample_rate, pitch) {
+      Err(LaunchError::InvalidArgument { arg, .. }) => arg,
+      result => panic!("{:?} is not an invalid argument", result),
+    };
+    assert_eq!(invalid("0", "440"), "sample-rate");
+    assert_eq!(invalid("-1", "440"), "sample-rate");
+    assert_eq!(invalid("8000", "4000"), "pitch");
+    assert_eq!(invalid("8000", "0"), "pitch");
+    assert!(matches!(
+      parse_video_format("run.mp4"),
+      Err(LaunchError::UnknownVideoFormat { .. })
+    ));
+  }
+}
@@ -1,8 +1,72 @@
-extern crate getopts;
-extern crate sdl2;
-extern crate time;
+//! chip8 plays a Chip8 ROM in an SDL window.
+//!
+//! The Chip8 keypad is mapped to the left of a QWERTY keyboard:
+//!
+//! ```text
+//! 1 2 3 C      1 2 3 4
+//! 4 5 6 D  ->  Q W E R
+//! 7 8 9 E      A S D F
+//! A 0 B F      Z X C V
+//! ```
+//!
+//! Escape quits.
+//!
+//! With `--tui` it plays in the terminal instead, drawing the display
+//! with half blocks or braille, and a status line with the frame rate
+//! and program counter. Terminals only report key presses, so keys are
+//! released once they have not been sent again for `--key-timeout`
+//! milliseconds. Ctrl-C quits.
+//!
+//! The session can be recorded while it plays: its sound as a WAV file
+//! with `--record-wav`, its frames as a Y4M or GIF video with
+//! `--record-video`, and the keys pressed with `--record-keys`. The
+//! random number generator is seeded with `--seed`, so
+//! `chip8-headless` can replay the recorded keys to render the same
+//! session again.
+//!
+//! With SDL_VIDEODRIVER=dummy and SDL_AUDIODRIVER=dummy it
+//! runs without a display or sound card, and `--frames` ends the run, so
+//! it can be run by automated tests.
 
-fn main() {}
+use clap::{App, Arg, ArgMatches};
+use rustyemulator::chip8::audio::{Beeper, DEFAULT_PITCH, DEFAULT_SAMPLE_RATE};
+use rustyemulator::chip8::chip8::Chip8;
+use rustyemulator::chip8::display::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use rustyemulator::chip8::keyscript::{KeyAction, KeyEvent, KeyScript};
+use rustyemulator::chip8::launch::{self, parse_number, LaunchError, Options, Settings};
+use rustyemulator::chip8::recording::Recorder;
+use rustyemulator::chip8::terminal::{self, Glyphs, KeyTimeouts, RawMode};
+use rustyemulator::interface::audio::AudioSink;
+use rustyemulator::interface::system::System;
+use rustyemulator::interface::video::FrameSink;
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::PixelFormatEnum;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum FrontendError {
+  #[error(transparent)]
+  Launch(#[from] LaunchError),
+
+  #[error("CPU fault: {0}")]
+  Fault(#[from] rustyemulator::chip8::cpu::CpuError),
+
+  #[error("SDL: {0}")]
+  Sdl(String),
+
+  #[error(transparent)]
+  IOError(#[from] std::io::Error),
+}
 
 // Note about emulation speed based on
 // https://github.com/AfBu/haxe-CHIP-8-emulator/wiki/(Super)CHIP-8-Secrets#speed-of-emulation.
@@ -17,3 +81,654 @@ fn main() {}
 // frequency calculate number of operations that should be performed and
 // perform them at once. This will give you much more precise control
 // over emulation speed.
+const HOST_RATE: u32 = 100;
+// Rate of Chip8 frames, at which the timers count down.
+const FRAME_RATE: u64 = 60;
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+// Longest time caught up on in one pass of the host loop, so that a
+// stall does not make the emulation run flat out afterwards.
+const MAX_CATCH_UP: Duration = Duration::from_millis(100);
+
+// Colours of unset and set pixels, unless a cartridge sets its own.
+const BACKGROUND: [u8; 3] = [0x00, 0x00, 0x00];
+const FOREGROUND: [u8; 3] = [0xFF, 0xFF, 0xFF];
+
+// Host keys bound to the keys a ROM's database entry names, besides the
+// keypad layout.
+const BINDINGS: [(Keycode, &str); 6] = [
+  (Keycode::Up, "up"),
+  (Keycode::Down, "down"),
+  (Keycode::Left, "left"),
+  (Keycode::Right, "right"),
+  (Keycode::Space, "a"),
+  (Keycode::Return, "b"),
+];
+
+// Longest audio queued to play, beyond which samples are dropped
+// rather than falling further behind.
+const MAX_QUEUED: Duration = Duration::from_millis(100);
+
+fn main() {
+  env_logger::init();
+  let (sample_rate, pitch) = (DEFAULT_SAMPLE_RATE.to_string(), DEFAULT_PITCH.to_string());
+  let matches = App::new("Chip8")
+    .version("1.0")
+    .author("Joey Pereira <joey@pereira.io>")
+    .about("Plays a Chip8 ROM")
+    .arg(
+      Arg::with_name("rom")
+        .long("rom")
+        .takes_value(true)
+        .required(true)
+        .help(
+          "Sets the ROM file to play, as Intel HEX or a hex dump if it ends in .hex, .ihx or .txt, an Octo cartridge .gif, or - to read it from standard input",
+        ),
+    )
+    .arg(
+      Arg::with_name("patch")
+        .long("patch")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .help("Applies an IPS or BPS patch to the ROM, in the order given"),
+    )
+    .arg(
+      Arg::with_name("scale")
+        .long("scale")
+        .takes_value(true)
+        .default_value("10")
+        .help("Sets the size in window pixels of each display pixel"),
+    )
+    .arg(
+      Arg::with_name("quirks")
+        .long("quirks")
+        .takes_value(true)
+        .default_value("modern")
+        .help("Sets the quirk profile"),
+    )
+    .arg(
+      Arg::with_name("platform")
+        .long("platform")
+        .takes_value(true)
+        .default_value("chip8")
+        .help("Sets the platform the ROM is for, which sets where it is loaded"),
+    )
+    .arg(
+      Arg::with_name("no-database")
+        .long("no-database")
+        .help("Ignores the platform, quirks and speed the ROM database has for the ROM"),
+    )
+    .arg(
+      Arg::with_name("ipf")
+        .long("ipf")
+        .takes_value(true)
+        .help("Sets the number of instructions per frame"),
+    )
+    .arg(
+      Arg::with_name("seed")
+        .long("seed")
+        .takes_value(true)
+        .default_value("1")
+        .help("Sets the random number generator seed"),
+    )
+    .arg(
+      Arg::with_name("frames")
+        .long("frames")
+        .takes_value(true)
+        .help("Quits after running this many frames"),
+    )
+    .arg(
+      Arg::with_name("tui")
+        .long("tui")
+        .help("Plays in the terminal instead of a window"),
+    )
+    .arg(
+      Arg::with_name("glyphs")
+        .long("glyphs")
+        .takes_value(true)
+        .possible_values(&["halfblock", "braille"])
+        .default_value("halfblock")
+        .help("Sets the characters the terminal display is drawn with"),
+    )
+    .arg(
+      Arg::with_name("key-timeout")
+        .long("key-timeout")
+        .takes_value(true)
+        .default_value("250")
+        .help("Sets the milliseconds after which a key is released in the terminal"),
+    )
+    .arg(
+      Arg::with_name("sample-rate")
+        .long("sample-rate")
+        .takes_value(true)
+        .default_value(&sample_rate)
+        .help("Sets the audio sample rate, in hertz"),
+    )
+    .arg(
+      Arg::with_name("pitch")
+        .long("pitch")
+        .takes_value(true)
+        .default_value(&pitch)
+        .help("Sets the pitch of the beep, in hertz"),
+    )
+    .arg(
+      Arg::with_name("mute")
+        .long("mute")
+        .help("Turns off the beeper"),
+    )
+    .arg(
+      Arg::with_name("record-wav")
+        .long("record-wav")
+        .takes_value(true)
+        .help("Records the sound to this WAV file"),
+    )
+    .arg(
+      Arg::with_name("record-video")
+        .long("record-video")
+        .takes_value(true)
+        .help("Records the frames to this .y4m or .gif file"),
+    )
+    .arg(
+      Arg::with_name("record-scale")
+        .long("record-scale")
+        .takes_value(true)
+        .default_value("1")
+        .help("Sets the size in video pixels of each display pixel"),
+    )
+    .arg(
+      Arg::with_name("record-keys")
+        .long("record-keys")
+        .takes_value(true)
+        .help("Records the keys pressed to this key script, for chip8-headless --keys"),
+    )
+    .get_matches();
+
+  if let Err(err) = run(&matches) {
+    eprintln!("error: {}", err);
+    process::exit(1);
+  }
+}
+
+// load_chip8 creates a Chip8 with the ROM and settings from `args`.
+// Octo cartridges and ROMs in the database set their own platform,
+// quirks and speed, unless --platform, --quirks or --ipf are given.
+fn load_chip8(args: &ArgMatches) -> Result<(Chip8, Settings), FrontendError> {
+  let quirks = launch::parse_quirks(args.value_of("quirks").unwrap())?;
+  let platform = launch::parse_platform(args.value_of("platform").unwrap())?;
+  let options = Options {
+    path: String::from(args.value_of("rom").unwrap()),
+    patches: args
+      .values_of("patch")
+      .into_iter()
+      .flatten()
+      .map(PathBuf::from)
+      .collect(),
+    platform: Some(platform).filter(|_| args.occurrences_of("platform") > 0),
+    quirks: Some(quirks).filter(|_| args.occurrences_of("quirks") > 0),
+    instructions_per_frame: args
+      .value_of("ipf")
+      .map(|ipf| parse_number(ipf, "ipf"))
+      .transpose()?,
+    database: !args.is_present("no-database"),
+    seed: parse_number(args.value_of("seed").unwrap(), "seed")?,
+    protect: false,
+  };
+  let (chip8, settings) = launch::load(&options)?;
+  for name in settings.unsupported.iter() {
+    log::warn!("Cartridge option {} is not supported", name);
+  }
+  Ok((chip8, settings))
+}
+
+// QueueSink plays samples through an SDL audio queue.
+struct QueueSink {
+  queue: AudioQueue<f32>,
+  // Most samples to have queued.
+  max_queued: usize,
+}
+
+impl AudioSink for QueueSink {
+  fn push_samples(&mut self, samples: &[f32]) {
+    let queued = self.queue.size() as usize / std::mem::size_of::<f32>();
+    if queued + samples.len() > self.max_queued {
+      return;
+    }
+    if !self.queue.queue(samples) {
+      log::warn!("Failed to queue audio: {}", sdl2::get_error());
+    }
+  }
+}
+
+// open_audio opens the audio device, playing `sample_rate` samples a
+// second. Sound is not needed to play, so failing to open it is only
+// logged.
+fn open_audio(sdl: &sdl2::Sdl, sample_rate: u32) -> Option<QueueSink> {
+  let desired = AudioSpecDesired {
+    freq: Some(sample_rate as i32),
+    channels: Some(1),
+    samples: None,
+  };
+  let queue = sdl
+    .audio()
+    .and_then(|audio| audio.open_queue::<f32, _>(None, &desired));
+  match queue {
+    Ok(queue) if queue.spec().freq == desired.freq.unwrap() => {
+      queue.resume();
+      let max_queued = MAX_QUEUED.as_secs_f64() * sample_rate as f64;
+      Some(QueueSink {
+        queue,
+        max_queued: max_queued as usize,
+      })
+    }
+    Ok(queue) => {
+      log::warn!(
+        "Audio plays at {} hz rather than {} hz, playing without sound",
+        queue.spec().freq,
+        sample_rate
+      );
+      None
+    }
+    Err(err) => {
+      log::warn!("Failed to open audio, playing without sound: {}", err);
+      None
+    }
+  }
+}
+
+// load_beeper creates the Beeper with the sample rate and pitch from
+// `args`.
+fn load_beeper(args: &ArgMatches) -> Result<Beeper, FrontendError> {
+  Ok(launch::parse_beeper(
+    args.value_of("sample-rate").unwrap(),
+    args.value_of("pitch").unwrap(),
+  )?)
+}
+
+// Recording is what is recorded of the session: its sound and frames,
+// and the keys pressed, with the frame before which they changed.
+struct Recording {
+  recorder: Recorder,
+  // Key script to write, its header of the settings it is played with,
+  // and the keys held.
+  keys_path: Option<String>,
+  header: String,
+  held: [bool; 16],
+  events: Vec<KeyEvent>,
+}
+
+impl Recording {
+  fn from_args(args: &ArgMatches, chip8: &Chip8) -> Result<Self, FrontendError> {
+    let mut recorder = Recorder::default();
+    if let Some(path) = args.value_of("record-video") {
+      let format = launch::parse_video_format(path)?;
+      let scale = parse_number(args.value_of("record-scale").unwrap(), "record-scale")?;
+      recorder.record_video(Path::new(path), format, scale, FRAME_RATE as u32)?;
+    }
+    if let Some(path) = args.value_of("record-wav") {
+      recorder.record_wav(Path::new(path), chip8.sample_rate())?;
+    }
+    // Quirks from a cartridge may not be a preset, but replaying the
+    // cartridge sets them again.
+    let header = format!(
+      "# {} with {} quirks and seed {}, at {} instructions per frame",
+      args.value_of("rom").unwrap(),
+      chip8.quirks().preset_name().unwrap_or("custom"),
+      args.value_of("seed").unwrap(),
+      chip8.instructions_per_frame()
+    );
+    Ok(Recording {
+      recorder,
+      keys_path: args.value_of("record-keys").map(String::from),
+      header,
+      held: [false; 16],
+      events: Vec::new(),
+    })
+  }
+
+  // set_key presses or releases `key` of `chip8` before frame `frame`,
+  // recording it if that changes whether it is held.
+  fn set_key(&mut self, chip8: &mut Chip8, key: usize, action: KeyAction, frame: u64) {
+    match action {
+      KeyAction::Press => chip8.press_key(key),
+      KeyAction::Release => chip8.release_key(key),
+    }
+    let held = action == KeyAction::Press;
+    if self.held[key] != held {
+      self.held[key] = held;
+      self.events.push(KeyEvent {
+        frame: frame as u32,
+        action,
+        key,
+      });
+    }
+  }
+
+  // record records the frame `chip8` has just run, which sounded
+  // `samples`.
+  fn record(&mut self, chip8: &Chip8, samples: &[f32]) {
+    self.recorder.push_samples(samples);
+    self.recorder.push_frame(&chip8.display().frame());
+  }
+
+  // finish ends the recordings, and writes the key script with the
+  // settings it was played with.
+  fn finish(self) -> Result<(), FrontendError> {
+    self.recorder.finish()?;
+    if let Some(path) = self.keys_path {
+      let script = KeyScript::new(self.events);
+      fs::write(path, format!("{}\n{}", self.header, script))?;
+    }
+    Ok(())
+  }
+}
+
+// HostLoop paces the emulation. It passes at HOST_RATE, and each pass
+// runs the frames owed for the time since the last pass.
+struct HostLoop {
+  period: Duration,
+  last: Instant,
+  next: Instant,
+  // Nanoseconds of emulated time owed since the last frame, times the
+  // frame rate.
+  owed: u64,
+  frames_run: u64,
+  // Frames to run before quitting.
+  limit: Option<u64>,
+}
+
+impl HostLoop {
+  fn new(limit: Option<u64>) -> Self {
+    let period = Duration::from_secs(1) / HOST_RATE;
+    let now = Instant::now();
+    HostLoop {
+      period,
+      last: now,
+      next: now + period,
+      owed: 0,
+      frames_run: 0,
+      limit,
+    }
+  }
+
+  // run_frames runs the frames owed, calling `on_frame` after each,
+  // and returns false once the limit of frames is reached.
+  fn run_frames(
+    &mut self,
+    chip8: &mut Chip8,
+    on_frame: &mut dyn FnMut(&mut Chip8),
+  ) -> Result<bool, FrontendError> {
+    let now = Instant::now();
+    self.owed += now.duration_since(self.last).min(MAX_CATCH_UP).as_nanos() as u64 * FRAME_RATE;
+    self.last = now;
+    while self.owed >= NANOS_PER_SECOND {
+      self.owed -= NANOS_PER_SECOND;
+      chip8.run_frame()?;
+      on_frame(chip8);
+      self.frames_run += 1;
+      if self.limit.is_some_and(|limit| self.frames_run >= limit) {
+        return Ok(false);
+      }
+    }
+    Ok(true)
+  }
+
+  // wait sleeps until the next pass.
+  fn wait(&mut self) {
+    let now = Instant::now();
+    thread::sleep(self.next.saturating_duration_since(now));
+    self.next = (self.next + self.period).max(now);
+  }
+}
+
+fn run(args: &ArgMatches) -> Result<(), FrontendError> {
+  let (mut chip8, settings) = load_chip8(args)?;
+  let host = HostLoop::new(match args.value_of("frames") {
+    Some(frames) => Some(parse_number(frames, "frames")?),
+    None => None,
+  });
+  chip8.set_beeper(load_beeper(args)?);
+  let mut recording = Recording::from_args(args, &chip8)?;
+  let result = if args.is_present("tui") {
+    run_tui(args, chip8, host, &mut recording)
+  } else {
+    run_sdl(args, chip8, &settings, host, &mut recording)
+  };
+  // Keep the recording when the CPU faults, for bug reports.
+  let finished = recording.finish();
+  result.and(finished)
+}
+
+// sdl_key returns the Chip8 key for `keycode`: the key bound to it in
+// `keys`, or the key on the keypad layout. The keycodes of keys that
+// type characters are those characters.
+fn sdl_key(keycode: Keycode, keys: &BTreeMap<String, u8>) -> Option<usize> {
+  if let Some((_, name)) = BINDINGS.iter().find(|(bound, _)| *bound == keycode) {
+    return keys.get(*name).map(|&key| key as usize);
+  }
+  let code = keycode as i32;
+  if (0..0x80).contains(&code) {
+    terminal::key(char::from(code as u8))
+  } else {
+    None
+  }
+}
+
+fn run_sdl(
+  args: &ArgMatches,
+  mut chip8: Chip8,
+  settings: &Settings,
+  mut host: HostLoop,
+  recording: &mut Recording,
+) -> Result<(), FrontendError> {
+  let scale: u32 = parse_number(args.value_of("scale").unwrap(), "scale")?;
+  let sdl = sdl2::init().map_err(FrontendError::Sdl)?;
+  let video = sdl.video().map_err(FrontendError::Sdl)?;
+  let window = video
+    .window(
+      "Chip8",
+      DISPLAY_WIDTH as u32 * scale.max(1),
+      DISPLAY_HEIGHT as u32 * scale.max(1),
+    )
+    .position_centered()
+    .build()
+    .map_err(|err| FrontendError::Sdl(err.to_string()))?;
+  let mut canvas = window
+    .into_canvas()
+    .build()
+    .map_err(|err| FrontendError::Sdl(err.to_string()))?;
+  let texture_creator = canvas.texture_creator();
+  let mut texture = texture_creator
+    .create_texture_streaming(
+      PixelFormatEnum::RGB24,
+      DISPLAY_WIDTH as u32,
+      DISPLAY_HEIGHT as u32,
+    )
+    .map_err(|err| FrontendError::Sdl(err.to_string()))?;
+  let mut audio = if args.is_present("mute") {
+    None
+  } else {
+    open_audio(&sdl, chip8.sample_rate())
+  };
+  let mut events = sdl.event_pump().map_err(FrontendError::Sdl)?;
+
+  let (background, foreground) = match settings.colours {
+    Some(colours) => (colours.background, colours.fill),
+    None => (BACKGROUND, FOREGROUND),
+  };
+  let mut pixels = [0u8; DISPLAY_WIDTH * DISPLAY_HEIGHT * 3];
+  let mut samples = Vec::new();
+  'main: loop {
+    for event in events.poll_iter() {
+      match event {
+        Event::Quit { .. }
+        | Event::KeyDown {
+          keycode: Some(Keycode::Escape),
+          ..
+        } => break 'main,
+        Event::KeyDown {
+          keycode: Some(keycode),
+          ..
+        } => {
+          if let Some(key) = sdl_key(keycode, &settings.keys) {
+            recording.set_key(&mut chip8, key, KeyAction::Press, host.frames_run)
+          }
+        }
+        Event::KeyUp {
+          keycode: Some(keycode),
+          ..
+        } => {
+          if let Some(key) = sdl_key(keycode, &settings.keys) {
+            recording.set_key(&mut chip8, key, KeyAction::Release, host.frames_run)
+          }
+        }
+        _ => {}
+      }
+    }
+
+    let mut play = |chip8: &mut Chip8| {
+      samples.clear();
+      chip8.push_audio(&mut samples);
+      if let Some(audio) = &mut audio {
+        audio.push_samples(&samples)
+      }
+      recording.record(chip8, &samples);
+    };
+    if !host.run_frames(&mut chip8, &mut play)? {
+      break;
+    }
+
+    for (pixel, rgb) in chip8.display().pixels().iter().zip(pixels.chunks_mut(3)) {
+      rgb.copy_from_slice(if *pixel { &foreground } else { &background });
+    }
+    texture
+      .update(None, &pixels, DISPLAY_WIDTH * 3)
+      .map_err(|err| FrontendError::Sdl(err.to_string()))?;
+    canvas.clear();
+    canvas
+      .copy(&texture, None, None)
+      .map_err(FrontendError::Sdl)?;
+    canvas.present();
+
+    host.wait();
+  }
+  Ok(())
+}
+
+// Tui is the terminal while it is drawn on. It is put back as it was
+// when dropped.
+struct Tui {
+  _raw_mode: RawMode,
+}
+
+impl Tui {
+  fn enter() -> Result<Tui, FrontendError> {
+    let raw_mode = RawMode::enter()?;
+    // Switch to the alternate screen, and hide the cursor.
+    print!("\x1b[?1049h\x1b[?25l\x1b[2J");
+    io::stdout().flush()?;
+    Ok(Tui {
+      _raw_mode: raw_mode,
+    })
+  }
+}
+
+impl Drop for Tui {
+  fn drop(&mut self) {
+    print!("\x1b[?25h\x1b[?1049l");
+    let _ = io::stdout().flush();
+  }
+}
+
+fn run_tui(
+  args: &ArgMatches,
+  mut chip8: Chip8,
+  mut host: HostLoop,
+  recording: &mut Recording,
+) -> Result<(), FrontendError> {
+  let name = args.value_of("glyphs").unwrap();
+  let glyphs = Glyphs::from_name(name).ok_or_else(|| LaunchError::InvalidArgument {
+    arg: "glyphs",
+    value: String::from(name),
+  })?;
+  let timeout: u64 = parse_number(args.value_of("key-timeout").unwrap(), "key-timeout")?;
+  let mut keys = KeyTimeouts::new(Duration::from_millis(timeout));
+  let mute = args.is_present("mute");
+
+  let _tui = Tui::enter()?;
+  let mut stdin = io::stdin();
+  let mut stdout = io::stdout();
+  let mut input = [0u8; 64];
+  let mut samples = Vec::new();
+  let mut tone = false;
+  // Frames per second, measured over each second.
+  let mut fps = 0.0;
+  let (mut measured_at, mut measured_frames) = (Instant::now(), 0);
+  'main: loop {
+    let now = Instant::now();
+    let read = stdin.read(&mut input)?;
+    let mut bytes = input[..read].iter().peekable();
+    while let Some(&byte) = bytes.next() {
+      match byte {
+        // Ctrl-C, or an escape that does not start a sequence.
+        0x03 => break 'main,
+        0x1B if bytes.peek().is_none() => break 'main,
+        // Skip escape sequences, such as for the arrow keys.
+        0x1B => {
+          bytes.next();
+          for &byte in bytes.by_ref() {
+            if (0x40..=0x7E).contains(&byte) {
+              break;
+            }
+          }
+        }
+        _ => {
+          if let Some(key) = terminal::key(char::from(byte)) {
+            if keys.typed(key, now) {
+              recording.set_key(&mut chip8, key, KeyAction::Press, host.frames_run);
+            }
+          }
+        }
+      }
+    }
+    for key in keys.expired(now) {
+      recording.set_key(&mut chip8, key, KeyAction::Release, host.frames_run);
+    }
+
+    let mut play = |chip8: &mut Chip8| {
+      samples.clear();
+      chip8.push_audio(&mut samples);
+      recording.record(chip8, &samples);
+    };
+    if !host.run_frames(&mut chip8, &mut play)? {
+      break;
+    }
+
+    let elapsed = now.duration_since(measured_at);
+    if elapsed >= Duration::from_secs(1) {
+      fps = (host.frames_run - measured_frames) as f64 / elapsed.as_secs_f64();
+      measured_at = now;
+      measured_frames = host.frames_run;
+    }
+
+    let mut screen = String::from("\x1b[H");
+    for line in terminal::render(chip8.display(), glyphs) {
+      screen.push_str(&line);
+      screen.push_str("\r\n");
+    }
+    screen.push_str(&format!(
+      "{:5.1} fps  PC {:#05X}  Ctrl-C quits\x1b[K",
+      fps,
+      chip8.program_counter()
+    ));
+    // Ring the terminal bell when the buzzer starts.
+    if chip8.tone() && !tone && !mute {
+      screen.push('\x07');
+    }
+    tone = chip8.tone();
+    stdout.write_all(screen.as_bytes())?;
+    stdout.flush()?;
+
+    host.wait();
+  }
+  Ok(())
+}
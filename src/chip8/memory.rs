@@ -0,0 +1,232 @@
+//! memory provides the Chip8's memory as a Bus, so that reads and
+//! writes made by instructions can be observed, and the interpreter and
+//! font area below the program can be write-protected.
+
+use crate::chip8::cpu::MEMORY_SIZE;
+use crate::interface::bus::{Access, Bus, BusError, Observer, ObserverId, Watchers};
+use crate::interface::serialization::Savable;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+// Mask applied to the addresses of instructions, which are fetched from
+// the first MEMORY_SIZE bytes.
+const CODE_MASK: u16 = (MEMORY_SIZE - 1) as u16;
+
+/// Largest size, in bytes, of memory, which is XO-CHIP's 64 KiB.
+pub const MAX_MEMORY_SIZE: usize = 0x10000;
+
+/// Memory is the Chip8's memory: 4 KiB, or up to 64 KiB for XO-CHIP.
+/// Addresses wrap around it.
+///
+/// The observers and protected ranges are configuration, so they are
+/// not saved, and two memories compare equal when their bytes do.
+#[derive(Clone)]
+pub struct Memory {
+  bytes: Vec<u8>,
+  watchers: Watchers,
+}
+
+impl PartialEq for Memory {
+  fn eq(&self, other: &Self) -> bool {
+    self.bytes[..] == other.bytes[..]
+  }
+}
+
+impl Eq for Memory {}
+
+impl std::hash::Hash for Memory {
+  fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+    self.bytes[..].hash(state)
+  }
+}
+
+impl std::fmt::Debug for Memory {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Memory")
+      .field("watchers", &self.watchers)
+      .finish()
+  }
+}
+
+impl Savable for Memory {
+  fn save(&self, fh: &mut dyn std::io::Write) -> std::io::Result<()> {
+    self.bytes.save(fh)
+  }
+  fn load(&mut self, fh: &mut dyn std::io::Read) -> std::io::Result<()> {
+    self.bytes.load(fh)?;
+    if !is_memory_size(self.bytes.len()) {
+      return Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("{} bytes of memory", self.bytes.len()),
+      ));
+    }
+    Ok(())
+  }
+}
+
+impl Default for Memory {
+  fn default() -> Self {
+    Memory::new()
+  }
+}
+
+impl Memory {
+  /// new returns MEMORY_SIZE bytes of zeros, with nothing observed or
+  /// protected.
+  pub fn new() -> Self {
+    Memory::with_size(MEMORY_SIZE)
+  }
+
+  /// with_size returns `size` bytes of zeros, which must be a power of
+  /// two of at least MEMORY_SIZE and at most MAX_MEMORY_SIZE.
+  pub fn with_size(size: usize) -> Self {
+    assert!(is_memory_size(size), "{} bytes of memory", size);
+    Memory {
+      bytes: vec![0; size],
+      watchers: Watchers::default(),
+    }
+  }
+
+  /// resize changes the size of memory as `with_size` would, keeping the
+  /// bytes that still fit and its observers and protection.
+  pub(crate) fn resize(&mut self, size: usize) {
+    assert!(is_memory_size(size), "{} bytes of memory", size);
+    self.bytes.resize(size, 0);
+  }
+
+  pub fn bytes(&self) -> &[u8] {
+    &self.bytes
+  }
+
+  /// bytes_mut returns the bytes to change directly, as loading a program
+  /// does, without observers or protection.
+  pub(crate) fn bytes_mut(&mut self) -> &mut [u8] {
+    &mut self.bytes
+  }
+
+  // address_mask returns the mask applied to addresses so that they wrap
+  // around memory.
+  #[inline]
+  fn address_mask(&self) -> u16 {
+    (self.bytes.len() - 1) as u16
+  }
+
+  /// is_observed returns whether any observer observes `access`.
+  #[inline]
+  pub fn is_observed(&self, access: Access) -> bool {
+    self.watchers.is_observed(access)
+  }
+
+  /// fetch returns the big-endian instruction at `address`, and tells
+  /// the observers of its two bytes. Instructions are only fetched from
+  /// the first MEMORY_SIZE bytes, which jumps and calls can reach, so
+  /// the address wraps around them.
+  #[inline]
+  pub fn fetch(&mut self, address: u16) -> u16 {
+    let high = address & CODE_MASK;
+    let low = address.wrapping_add(1) & CODE_MASK;
+    let opcode = (self.bytes[high as usize] as u16) << 8 | self.bytes[low as usize] as u16;
+    if self.watchers.is_observed(Access::Fetch) {
+      self
+        .watchers
+        .notify(Access::Fetch, high, (opcode >> 8) as u8);
+      self.watchers.notify(Access::Fetch, low, opcode as u8);
+    }
+    opcode
+  }
+}
+
+impl Bus for Memory {
+  #[inline]
+  fn read(&mut self, address: u16) -> u8 {
+    let address = address & self.address_mask();
+    let value = self.bytes[address as usize];
+    if self.watchers.is_observed(Access::Read) {
+      self.watchers.notify(Access::Read, address, value);
+    }
+    value
+  }
+
+  #[inline]
+  fn peek(&self, address: u16) -> u8 {
+    self.bytes[(address & self.address_mask()) as usize]
+  }
+
+  #[inline]
+  fn write(&mut self, address: u16, value: u8) -> Result<(), BusError> {
+    let address = address & self.address_mask();
+    if self.watchers.is_protected(address) {
+      return Err(BusError::Protected { address, value });
+    }
+    self.bytes[address as usize] = value;
+    if self.watchers.is_observed(Access::Write) {
+      self.watchers.notify(Access::Write, address, value);
+    }
+    Ok(())
+  }
+
+  fn observe(&mut self, range: Range<u16>, observer: Arc<Mutex<dyn Observer>>) -> ObserverId {
+    self.watchers.observe(range, observer)
+  }
+
+  fn remove_observer(&mut self, id: ObserverId) -> bool {
+    self.watchers.remove_observer(id)
+  }
+
+  fn protect(&mut self, range: Range<u16>) {
+    self.watchers.protect(range)
+  }
+
+  fn unprotect(&mut self, range: Range<u16>) {
+    self.watchers.unprotect(range)
+  }
+}
+
+// is_memory_size returns whether memory can have `size` bytes.
+fn is_memory_size(size: usize) -> bool {
+  size.is_power_of_two() && (MEMORY_SIZE..=MAX_MEMORY_SIZE).contains(&size)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn addresses_wrap_and_protection_refuses_writes() {
+    let mut memory = Memory::new();
+    memory.write(0x1001, 7).unwrap();
+    assert_eq!(memory.peek(0x001), 7);
+
+    memory.protect(0x000..0x200);
+    assert_eq!(
+      memory.write(0x050, 1),
+      Err(BusError::Protected {
+        address: 0x050,
+        value: 1
+      })
+    );
+    assert_eq!(memory.peek(0x050), 0);
+    assert_eq!(memory.write(0x200, 1), Ok(()));
+  }
+
+  #[test]
+  fn larger_memory_holds_higher_addresses() {
+    let mut memory = Memory::new();
+    memory.write(0x2001, 7).unwrap();
+    memory.resize(MAX_MEMORY_SIZE);
+    assert_eq!(memory.peek(0x001), 7);
+    memory.write(0x2001, 9).unwrap();
+    assert_eq!((memory.peek(0x001), memory.peek(0x2001)), (7, 9));
+
+    // Instructions still come from the first MEMORY_SIZE bytes.
+    memory.write(0x1000, 0x12).unwrap();
+    memory.write(0x000, 0x34).unwrap();
+    assert_eq!(memory.fetch(0xFFF) & 0xFF, 0x34);
+
+    let mut saved = Vec::new();
+    memory.save(&mut saved).unwrap();
+    let mut loaded = Memory::new();
+    loaded.load(&mut saved.as_slice()).unwrap();
+    assert_eq!(loaded, memory);
+  }
+}
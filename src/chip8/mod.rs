@@ -1,4 +1,31 @@
+pub mod analysis;
+pub mod audio;
+pub mod cache;
+pub mod cartridge;
 pub mod chip8;
 pub mod cpu;
+pub mod database;
+pub mod display;
+pub mod flow;
+pub mod gif;
+pub mod hex;
 pub mod instruction;
+pub mod json;
+pub mod keypad;
+pub mod keyscript;
+pub mod launch;
+pub mod memory;
+pub mod octo;
+pub mod patch;
+pub mod platform;
+pub mod quirks;
+pub mod recompiler;
+pub mod recording;
+pub mod rng;
+pub mod rom;
+pub mod rominfo;
+pub mod screenshot;
+pub mod terminal;
 pub mod timer;
+pub mod timing;
+pub mod trace;
@@ -0,0 +1,1007 @@
+//! octo assembles programs written in Octo, the Chip8 assembly language
+//! of John Earnest's Octo IDE, which is how Octo cartridges carry their
+//! programs.
+//!
+//! It covers the language programs are written in: labels, constants,
+//! aliases, macros, structured `if`, `loop` and `while`, and the CHIP-8,
+//! SCHIP and XO-CHIP instructions. Directives that need Octo's
+//! compile-time expressions, such as `:calc` and `:byte { ... }`, are
+//! reported as unsupported.
+//!
+//! As in Octo, programs start with a jump to the `main` label at 0x200.
+//! See https://github.com/JohnEarnest/Octo/blob/gh-pages/docs/Manual.md.
+
+use crate::chip8::cpu::PROGRAM_START;
+use std::collections::{HashMap, VecDeque};
+use thiserror::Error;
+
+// Directives that need Octo's compile-time expressions.
+const UNSUPPORTED: [&str; 5] = [":calc", ":assert", ":stringmode", ":pointer", ":function"];
+
+/// AssembleError is returned when an Octo program can't be assembled.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum AssembleError {
+  #[error("Line {line}: expected {expected}, found {found:?}")]
+  Expected {
+    line: usize,
+    expected: &'static str,
+    found: String,
+  },
+
+  #[error("Line {line}: expected {expected}, found the end of the program")]
+  UnexpectedEnd { line: usize, expected: &'static str },
+
+  #[error("Line {line}: undefined name {name:?}")]
+  Undefined { line: usize, name: String },
+
+  #[error("Line {line}: {name:?} is already defined")]
+  Redefined { line: usize, name: String },
+
+  #[error("Line {line}: {value} does not fit in {bits} bits")]
+  OutOfRange { line: usize, value: i64, bits: u32 },
+
+  #[error("Line {line}: {token} without {opening}")]
+  Unbalanced {
+    line: usize,
+    token: &'static str,
+    opening: &'static str,
+  },
+
+  #[error("Line {line}: {opening} is never closed")]
+  Unclosed { line: usize, opening: &'static str },
+
+  #[error("Line {line}: {directive} is not supported")]
+  Unsupported { line: usize, directive: String },
+
+  #[error("Program has no main label")]
+  NoMain,
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+  text: String,
+  line: usize,
+}
+
+#[derive(Clone, Debug)]
+struct Macro {
+  parameters: Vec<String>,
+  body: Vec<Token>,
+}
+
+// Patch is how an address is written into the program once its label
+// is known.
+#[derive(Clone, Copy, Debug)]
+enum Patch {
+  // The low 12 bits of the instruction.
+  Nnn,
+  // The 16 bits after the instruction, for `i := long`.
+  Long,
+  // The immediate bytes of `v0 := ...` and `v1 := ...`, with the high
+  // four bits of the address after `nibble`, or all of it if none.
+  Unpack { nibble: Option<u8> },
+}
+
+#[derive(Clone, Debug)]
+struct Fixup {
+  // Address of the instruction patched.
+  address: usize,
+  name: String,
+  patch: Patch,
+  line: usize,
+}
+
+// Loop is a `loop` being assembled, with the jumps out of its `while`s.
+struct Loop {
+  start: usize,
+  whiles: Vec<usize>,
+  line: usize,
+}
+
+// Condition is a compiled `if` or `while` condition: the instructions
+// that prepare it, and the instruction that skips when it is false.
+struct Condition {
+  prelude: Vec<u16>,
+  skip_unless: u16,
+}
+
+// inverse returns the skip instruction that skips when `skip` does not.
+fn inverse(skip: u16) -> u16 {
+  match skip & 0xF000 {
+    0x3000 => skip + 0x1000,
+    0x4000 => skip - 0x1000,
+    0x5000 => skip + 0x4000,
+    0x9000 => skip - 0x4000,
+    // EX9E and EXA1.
+    _ if skip & 0xFF == 0x9E => (skip & 0xFF00) | 0xA1,
+    _ => (skip & 0xFF00) | 0x9E,
+  }
+}
+
+/// assemble returns the program assembled from the Octo `source`, to be
+/// loaded at 0x200.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AssembleError> {
+  let mut assembler = Assembler::new(source);
+  assembler.run()?;
+  Ok(assembler.rom)
+}
+
+struct Assembler {
+  tokens: VecDeque<Token>,
+  // Line of the last token read, for errors.
+  line: usize,
+  rom: Vec<u8>,
+  here: usize,
+  labels: HashMap<String, i64>,
+  constants: HashMap<String, i64>,
+  aliases: HashMap<String, u8>,
+  macros: HashMap<String, Macro>,
+  fixups: Vec<Fixup>,
+  // Jumps over the blocks of open `begin`s and `else`s.
+  branches: Vec<(usize, usize)>,
+  loops: Vec<Loop>,
+}
+
+impl Assembler {
+  fn new(source: &str) -> Self {
+    let mut tokens = VecDeque::new();
+    for (index, line) in source.lines().enumerate() {
+      let code = line.split('#').next().unwrap_or("");
+      for text in code.split_whitespace() {
+        tokens.push_back(Token {
+          text: String::from(text),
+          line: index + 1,
+        });
+      }
+    }
+    Assembler {
+      tokens,
+      line: 1,
+      rom: Vec::new(),
+      here: PROGRAM_START as usize,
+      labels: HashMap::new(),
+      constants: HashMap::new(),
+      aliases: HashMap::new(),
+      macros: HashMap::new(),
+      fixups: Vec::new(),
+      branches: Vec::new(),
+      loops: Vec::new(),
+    }
+  }
+
+  fn run(&mut self) -> Result<(), AssembleError> {
+    self.address_op(0x1000, "main", Patch::Nnn);
+    while let Some(token) = self.tokens.pop_front() {
+      self.line = token.line;
+      self.statement(&token.text)?;
+    }
+    if let Some(&(_, line)) = self.branches.last() {
+      return Err(AssembleError::Unclosed {
+        line,
+        opening: "begin",
+      });
+    }
+    if let Some(open) = self.loops.last() {
+      return Err(AssembleError::Unclosed {
+        line: open.line,
+        opening: "loop",
+      });
+    }
+    if !self.labels.contains_key("main") {
+      return Err(AssembleError::NoMain);
+    }
+    for fixup in std::mem::take(&mut self.fixups) {
+      let address = match self.labels.get(&fixup.name) {
+        Some(&address) => address,
+        None => {
+          return Err(AssembleError::Undefined {
+            line: fixup.line,
+            name: fixup.name,
+          })
+        }
+      };
+      self.line = fixup.line;
+      self.patch(fixup.address, address, fixup.patch)?;
+    }
+    Ok(())
+  }
+
+  fn next(&mut self, expected: &'static str) -> Result<String, AssembleError> {
+    match self.tokens.pop_front() {
+      Some(token) => {
+        self.line = token.line;
+        Ok(token.text)
+      }
+      None => Err(AssembleError::UnexpectedEnd {
+        line: self.line,
+        expected,
+      }),
+    }
+  }
+
+  fn peek(&self) -> Option<&str> {
+    self.tokens.front().map(|token| token.text.as_str())
+  }
+
+  fn expect(&mut self, text: &'static str) -> Result<(), AssembleError> {
+    let found = self.next(text)?;
+    if found != text {
+      return Err(self.expected(text, found));
+    }
+    Ok(())
+  }
+
+  fn expected(&self, expected: &'static str, found: String) -> AssembleError {
+    AssembleError::Expected {
+      line: self.line,
+      expected,
+      found,
+    }
+  }
+
+  fn emit_byte(&mut self, byte: u8) {
+    let index = self.here - PROGRAM_START as usize;
+    if index >= self.rom.len() {
+      self.rom.resize(index + 1, 0);
+    }
+    self.rom[index] = byte;
+    self.here += 1;
+  }
+
+  fn emit(&mut self, op: u16) {
+    self.emit_byte((op >> 8) as u8);
+    self.emit_byte(op as u8);
+  }
+
+  fn write(&mut self, address: usize, op: u16) {
+    let index = address - PROGRAM_START as usize;
+    self.rom[index] = (op >> 8) as u8;
+    self.rom[index + 1] = op as u8;
+  }
+
+  fn read(&self, address: usize) -> u16 {
+    let index = address - PROGRAM_START as usize;
+    u16::from_be_bytes([self.rom[index], self.rom[index + 1]])
+  }
+
+  // patch writes `address` into the instruction at `at`.
+  fn patch(&mut self, at: usize, address: i64, patch: Patch) -> Result<(), AssembleError> {
+    match patch {
+      Patch::Nnn => {
+        let address = self.fit(address, 12)?;
+        let op = self.read(at);
+        self.write(at, (op & 0xF000) | address);
+      }
+      Patch::Long => {
+        let address = self.fit(address, 16)?;
+        self.write(at + 2, address);
+      }
+      Patch::Unpack { nibble } => {
+        let (high, low) = match nibble {
+          Some(nibble) => {
+            let address = self.fit(address, 12)?;
+            (((nibble as u16) << 4) | (address >> 8), address & 0xFF)
+          }
+          None => {
+            let address = self.fit(address, 16)?;
+            (address >> 8, address & 0xFF)
+          }
+        };
+        self.write(at, 0x6000 | high);
+        self.write(at + 2, 0x6100 | low);
+      }
+    }
+    Ok(())
+  }
+
+  // fit returns `value` if it is an unsigned value of `bits` bits.
+  fn fit(&self, value: i64, bits: u32) -> Result<u16, AssembleError> {
+    if value < 0 || value >= 1 << bits {
+      return Err(AssembleError::OutOfRange {
+        line: self.line,
+        value,
+        bits,
+      });
+    }
+    Ok(value as u16)
+  }
+
+  // define defines `name` as `value`, as a label or a constant.
+  fn define(&mut self, name: String, value: i64, label: bool) -> Result<(), AssembleError> {
+    if self.labels.contains_key(&name) || self.constants.contains_key(&name) {
+      return Err(AssembleError::Redefined {
+        line: self.line,
+        name,
+      });
+    }
+    if parse_number(&name).is_some() || register(&name).is_some() {
+      return Err(self.expected("a name", name));
+    }
+    if label {
+      self.labels.insert(name, value);
+    } else {
+      self.constants.insert(name, value);
+    }
+    Ok(())
+  }
+
+  // value returns the value of a number, constant or label.
+  fn value(&self, text: &str) -> Option<i64> {
+    parse_number(text)
+      .or_else(|| self.constants.get(text).copied())
+      .or_else(|| self.labels.get(text).copied())
+  }
+
+  fn number(&mut self, expected: &'static str) -> Result<i64, AssembleError> {
+    let text = self.next(expected)?;
+    match self.value(&text) {
+      Some(value) => Ok(value),
+      None => Err(AssembleError::Undefined {
+        line: self.line,
+        name: text,
+      }),
+    }
+  }
+
+  // byte returns the next value, as a byte. Negative values are in two's
+  // complement.
+  fn byte(&mut self) -> Result<u16, AssembleError> {
+    let value = self.number("a byte")?;
+    if !(-128..=255).contains(&value) {
+      return Err(AssembleError::OutOfRange {
+        line: self.line,
+        value,
+        bits: 8,
+      });
+    }
+    Ok(value as u8 as u16)
+  }
+
+  fn nibble(&mut self) -> Result<u16, AssembleError> {
+    let value = self.number("a nibble")?;
+    self.fit(value, 4)
+  }
+
+  fn register(&mut self) -> Result<u16, AssembleError> {
+    let text = self.next("a register")?;
+    self
+      .register_of(&text)
+      .ok_or_else(|| self.expected("a register", text))
+  }
+
+  fn register_of(&self, text: &str) -> Option<u16> {
+    register(text)
+      .or_else(|| self.aliases.get(text).copied())
+      .map(u16::from)
+  }
+
+  // address_op emits `op` with the address of `name` in its low 12
+  // bits, now if it is known, or once it is defined.
+  fn address_op(&mut self, op: u16, name: &str, patch: Patch) {
+    self.fixups.push(Fixup {
+      address: self.here,
+      name: String::from(name),
+      patch,
+      line: self.line,
+    });
+    self.emit(op);
+  }
+
+  // address emits `op` with the next address in its low 12 bits.
+  fn address(&mut self, op: u16) -> Result<(), AssembleError> {
+    let text = self.next("an address")?;
+    match parse_number(&text).or_else(|| self.constants.get(&text).copied()) {
+      Some(value) => {
+        let address = self.fit(value, 12)?;
+        self.emit(op | address);
+      }
+      None => self.address_op(op, &text, Patch::Nnn),
+    }
+    Ok(())
+  }
+
+  fn statement(&mut self, text: &str) -> Result<(), AssembleError> {
+    if UNSUPPORTED.contains(&text) || (text == ":byte" && self.peek() == Some("{")) {
+      return Err(AssembleError::Unsupported {
+        line: self.line,
+        directive: String::from(text),
+      });
+    }
+    match text {
+      ":" => {
+        let name = self.next("a label")?;
+        self.define(name, self.here as i64, true)?;
+      }
+      ":alias" => {
+        let name = self.next("a name")?;
+        let register = self.register()?;
+        self.aliases.insert(name, register as u8);
+      }
+      ":const" => {
+        let name = self.next("a name")?;
+        let value = self.number("a value")?;
+        self.define(name, value, false)?;
+      }
+      ":next" => {
+        let name = self.next("a label")?;
+        self.define(name, self.here as i64 + 1, true)?;
+      }
+      ":org" => {
+        let value = self.number("an address")?;
+        let address = self.fit(value, 16)? as usize;
+        if address < PROGRAM_START as usize {
+          return Err(AssembleError::OutOfRange {
+            line: self.line,
+            value,
+            bits: 16,
+          });
+        }
+        self.here = address;
+      }
+      ":unpack" => {
+        let nibble = match self.next("a nibble or long")?.as_str() {
+          "long" => None,
+          text => {
+            let value = self
+              .value(text)
+              .ok_or_else(|| self.expected("a nibble or long", String::from(text)))?;
+            Some(self.fit(value, 4)? as u8)
+          }
+        };
+        let name = self.next("a label")?;
+        let at = self.here;
+        self.emit(0x6000);
+        self.emit(0x6100);
+        match self.value(&name) {
+          Some(address) => self.patch(at, address, Patch::Unpack { nibble })?,
+          None => self.fixups.push(Fixup {
+            address: at,
+            name,
+            patch: Patch::Unpack { nibble },
+            line: self.line,
+          }),
+        }
+      }
+      ":macro" => self.define_macro()?,
+      ":call" => self.address(0x2000)?,
+      ":byte" => {
+        let byte = self.byte()?;
+        self.emit_byte(byte as u8);
+      }
+      ":breakpoint" => {
+        self.next("a name")?;
+      }
+      ":monitor" => {
+        self.next("an address")?;
+        self.next("a length or format")?;
+      }
+      ";" | "return" => self.emit(0x00EE),
+      "clear" => self.emit(0x00E0),
+      "hires" => self.emit(0x00FF),
+      "lores" => self.emit(0x00FE),
+      "scroll-down" => {
+        let n = self.nibble()?;
+        self.emit(0x00C0 | n)
+      }
+      "scroll-up" => {
+        let n = self.nibble()?;
+        self.emit(0x00D0 | n)
+      }
+      "scroll-right" => self.emit(0x00FB),
+      "scroll-left" => self.emit(0x00FC),
+      "exit" => self.emit(0x00FD),
+      "audio" => self.emit(0xF002),
+      "plane" => {
+        let n = self.nibble()?;
+        self.emit(0xF001 | n << 8)
+      }
+      "bcd" => self.register_op(0xF033)?,
+      "saveflags" => self.register_op(0xF075)?,
+      "loadflags" => self.register_op(0xF085)?,
+      "save" | "load" => {
+        let x = self.register()?;
+        if self.peek() == Some("-") {
+          self.next("-")?;
+          let y = self.register()?;
+          let low = if text == "save" { 0x2 } else { 0x3 };
+          self.emit(0x5000 | x << 8 | y << 4 | low);
+        } else {
+          let op = if text == "save" { 0xF055 } else { 0xF065 };
+          self.emit(op | x << 8);
+        }
+      }
+      "sprite" => {
+        let x = self.register()?;
+        let y = self.register()?;
+        let n = self.nibble()?;
+        self.emit(0xD000 | x << 8 | y << 4 | n);
+      }
+      "jump" => self.address(0x1000)?,
+      "jump0" => self.address(0xB000)?,
+      "native" => self.address(0x0000)?,
+      "delay" | "buzzer" | "pitch" => {
+        self.expect(":=")?;
+        let op = match text {
+          "delay" => 0xF015,
+          "buzzer" => 0xF018,
+          _ => 0xF03A,
+        };
+        self.register_op(op)?;
+      }
+      "i" => self.i_statement()?,
+      "if" => self.if_statement()?,
+      "else" => {
+        let (jump, line) = self.branches.pop().ok_or(AssembleError::Unbalanced {
+          line: self.line,
+          token: "else",
+          opening: "begin",
+        })?;
+        self.branches.push((self.here, line));
+        self.emit(0x1000);
+        self.patch(jump, self.here as i64, Patch::Nnn)?;
+      }
+      "end" => {
+        let (jump, _) = self.branches.pop().ok_or(AssembleError::Unbalanced {
+          line: self.line,
+          token: "end",
+          opening: "begin",
+        })?;
+        self.patch(jump, self.here as i64, Patch::Nnn)?;
+      }
+      "loop" => self.loops.push(Loop {
+        start: self.here,
+        whiles: Vec::new(),
+        line: self.line,
+      }),
+      "while" => {
+        let condition = self.condition()?;
+        if self.loops.is_empty() {
+          return Err(AssembleError::Unbalanced {
+            line: self.line,
+            token: "while",
+            opening: "loop",
+          });
+        }
+        self.emit_condition(&condition, true);
+        let jump = self.here;
+        self.loops.last_mut().unwrap().whiles.push(jump);
+        self.emit(0x1000);
+      }
+      "again" => {
+        let open = self.loops.pop().ok_or(AssembleError::Unbalanced {
+          line: self.line,
+          token: "again",
+          opening: "loop",
+        })?;
+        let start = self.fit(open.start as i64, 12)?;
+        self.emit(0x1000 | start);
+        for jump in open.whiles {
+          self.patch(jump, self.here as i64, Patch::Nnn)?;
+        }
+      }
+      _ => {
+        if let Some(x) = self.register_of(text) {
+          return self.register_statement(x);
+        }
+        if let Some(value) = parse_number(text).or_else(|| self.constants.get(text).copied()) {
+          let byte = if (-128..=255).contains(&value) {
+            value as u8
+          } else {
+            return Err(AssembleError::OutOfRange {
+              line: self.line,
+              value,
+              bits: 8,
+            });
+          };
+          self.emit_byte(byte);
+          return Ok(());
+        }
+        if let Some(definition) = self.macros.get(text).cloned() {
+          return self.expand(definition);
+        }
+        if text.starts_with(':') || text.is_empty() {
+          return Err(AssembleError::Unsupported {
+            line: self.line,
+            directive: String::from(text),
+          });
+        }
+        // Any other name is a call.
+        match self.labels.get(text).copied() {
+          Some(address) => {
+            let address = self.fit(address, 12)?;
+            self.emit(0x2000 | address);
+          }
+          None => self.address_op(0x2000, text, Patch::Nnn),
+        }
+      }
+    }
+    Ok(())
+  }
+
+  // register_op emits `op` with the next register as X.
+  fn register_op(&mut self, op: u16) -> Result<(), AssembleError> {
+    let x = self.register()?;
+    self.emit(op | x << 8);
+    Ok(())
+  }
+
+  fn register_statement(&mut self, x: u16) -> Result<(), AssembleError> {
+    let op = self.next("an operator")?;
+    let operand = self.next("an operand")?;
+    let y = self.register_of(&operand);
+    let xy = x << 8 | y.unwrap_or(0) << 4;
+    let code = match (op.as_str(), operand.as_str(), y) {
+      (":=", _, Some(_)) => 0x8000 | xy,
+      (":=", "delay", None) => 0xF007 | x << 8,
+      (":=", "key", None) => 0xF00A | x << 8,
+      (":=", "random", None) => 0xC000 | x << 8 | self.byte()?,
+      (":=", _, None) => 0x6000 | x << 8 | self.operand_byte(operand)?,
+      ("+=", _, Some(_)) => 0x8004 | xy,
+      ("+=", _, None) => 0x7000 | x << 8 | self.operand_byte(operand)?,
+      ("-=", _, Some(_)) => 0x8005 | xy,
+      ("-=", _, None) => {
+        0x7000 | x << 8 | (self.operand_byte(operand)? as u8).wrapping_neg() as u16
+      }
+      ("=-", _, Some(_)) => 0x8007 | xy,
+      ("|=", _, Some(_)) => 0x8001 | xy,
+      ("&=", _, Some(_)) => 0x8002 | xy,
+      ("^=", _, Some(_)) => 0x8003 | xy,
+      (">>=", _, Some(_)) => 0x8006 | xy,
+      ("<<=", _, Some(_)) => 0x800E | xy,
+      (_, _, Some(_))
+      | ("=-", _, _)
+      | ("|=", _, _)
+      | ("&=", _, _)
+      | ("^=", _, _)
+      | (">>=", _, _)
+      | ("<<=", _, _) => return Err(self.expected("a register", operand)),
+      _ => return Err(self.expected("an operator", op)),
+    };
+    self.emit(code);
+    Ok(())
+  }
+
+  // operand_byte returns the value of `text` as a byte.
+  fn operand_byte(&mut self, text: String) -> Result<u16, AssembleError> {
+    self.tokens.push_front(Token {
+      text,
+      line: self.line,
+    });
+    self.byte()
+  }
+
+  fn i_statement(&mut self) -> Result<(), AssembleError> {
+    let op = self.next("an operator")?;
+    match op.as_str() {
+      "+=" => self.register_op(0xF01E),
+      ":=" => match self.peek() {
+        Some("hex") => {
+          self.next("hex")?;
+          self.register_op(0xF029)
+        }
+        Some("bighex") => {
+          self.next("bighex")?;
+          self.register_op(0xF030)
+        }
+        Some("long") => {
+          self.next("long")?;
+          let text = self.next("an address")?;
+          let at = self.here;
+          self.emit(0xF000);
+          self.emit(0x0000);
+          match self.value(&text) {
+            Some(address) => self.patch(at, address, Patch::Long),
+            None => {
+              self.fixups.push(Fixup {
+                address: at,
+                name: text,
+                patch: Patch::Long,
+                line: self.line,
+              });
+              Ok(())
+            }
+          }
+        }
+        _ => self.address(0xA000),
+      },
+      _ => Err(self.expected("an operator", op)),
+    }
+  }
+
+  fn if_statement(&mut self) -> Result<(), AssembleError> {
+    let condition = self.condition()?;
+    match self.next("then or begin")?.as_str() {
+      "then" => self.emit_condition(&condition, false),
+      "begin" => {
+        self.emit_condition(&condition, true);
+        self.branches.push((self.here, self.line));
+        self.emit(0x1000);
+      }
+      found => return Err(self.expected("then or begin", String::from(found))),
+    }
+    Ok(())
+  }
+
+  // emit_condition emits `condition`, skipping when it is false, or
+  // when it is true if `inverted`.
+  fn emit_condition(&mut self, condition: &Condition, inverted: bool) {
+    for &op in condition.prelude.iter() {
+      self.emit(op);
+    }
+    self.emit(if inverted {
+      inverse(condition.skip_unless)
+    } else {
+      condition.skip_unless
+    });
+  }
+
+  fn condition(&mut self) -> Result<Condition, AssembleError> {
+    let x = self.register()?;
+    let op = self.next("a comparison")?;
+    let simple = |skip_unless| Condition {
+      prelude: Vec::new(),
+      skip_unless,
+    };
+    match op.as_str() {
+      "key" => return Ok(simple(0xE0A1 | x << 8)),
+      "-key" => return Ok(simple(0xE09E | x << 8)),
+      _ => {}
+    }
+    let operand = self.next("an operand")?;
+    let y = self.register_of(&operand);
+    // Loads the operand into vF, as a register or a byte.
+    let load = match y {
+      Some(y) => 0x8F00 | y << 4,
+      None => 0x6F00 | self.operand_byte(operand.clone())?,
+    };
+    Ok(match (op.as_str(), y) {
+      ("==", Some(y)) => simple(0x9000 | x << 8 | y << 4),
+      ("!=", Some(y)) => simple(0x5000 | x << 8 | y << 4),
+      ("==", None) => simple(0x4000 | x << 8 | (load & 0xFF)),
+      ("!=", None) => simple(0x3000 | x << 8 | (load & 0xFF)),
+      // Comparisons subtract in vF, which is left as 1 if there was no
+      // borrow.
+      (">", _) | ("<=", _) => Condition {
+        // vF := y - vx, so vF is 1 if y >= vx.
+        prelude: vec![load, 0x8F05 | x << 4],
+        skip_unless: if op == ">" { 0x4F00 } else { 0x3F00 },
+      },
+      ("<", _) | (">=", _) => Condition {
+        // vF := vx - y, so vF is 1 if vx >= y.
+        prelude: vec![load, 0x8F07 | x << 4],
+        skip_unless: if op == "<" { 0x4F00 } else { 0x3F00 },
+      },
+      _ => return Err(self.expected("a comparison", op)),
+    })
+  }
+
+  fn define_macro(&mut self) -> Result<(), AssembleError> {
+    let name = self.next("a macro name")?;
+    let line = self.line;
+    let mut parameters = Vec::new();
+    loop {
+      let text = self.next("{")?;
+      if text == "{" {
+        break;
+      }
+      parameters.push(text);
+    }
+    let mut body = Vec::new();
+    let mut depth = 1;
+    loop {
+      let token = self.tokens.pop_front().ok_or(AssembleError::Unclosed {
+        line,
+        opening: ":macro",
+      })?;
+      match token.text.as_str() {
+        "{" => depth += 1,
+        "}" => {
+          depth -= 1;
+          if depth == 0 {
+            break;
+          }
+        }
+        _ => {}
+      }
+      body.push(token);
+    }
+    self.macros.insert(name, Macro { parameters, body });
+    Ok(())
+  }
+
+  // expand puts the body of `definition` in place of its call, with its
+  // parameters replaced by the arguments that follow.
+  fn expand(&mut self, definition: Macro) -> Result<(), AssembleError> {
+    let mut arguments = HashMap::new();
+    for parameter in definition.parameters.iter() {
+      arguments.insert(parameter.as_str(), self.next("a macro argument")?);
+    }
+    for token in definition.body.iter().rev() {
+      let text = match arguments.get(token.text.as_str()) {
+        Some(argument) => argument.clone(),
+        None => token.text.clone(),
+      };
+      self.tokens.push_front(Token {
+        text,
+        line: token.line,
+      });
+    }
+    Ok(())
+  }
+}
+
+// register returns the register named `text`, v0 to vf.
+fn register(text: &str) -> Option<u8> {
+  let mut chars = text.chars();
+  match (chars.next(), chars.next(), chars.next()) {
+    (Some('v'), Some(digit), None) | (Some('V'), Some(digit), None) => {
+      digit.to_digit(16).map(|digit| digit as u8)
+    }
+    _ => None,
+  }
+}
+
+// parse_number returns the value of a decimal, 0x hexadecimal or 0b
+// binary number, which may be negative.
+fn parse_number(text: &str) -> Option<i64> {
+  let (negative, digits) = match text.strip_prefix('-') {
+    Some(digits) => (true, digits),
+    None => (false, text),
+  };
+  let value = if let Some(hex) = digits
+    .strip_prefix("0x")
+    .or_else(|| digits.strip_prefix("0X"))
+  {
+    i64::from_str_radix(hex, 16).ok()?
+  } else if let Some(binary) = digits
+    .strip_prefix("0b")
+    .or_else(|| digits.strip_prefix("0B"))
+  {
+    i64::from_str_radix(binary, 2).ok()?
+  } else if !digits.is_empty() && digits.bytes().all(|byte| byte.is_ascii_digit()) {
+    digits.parse().ok()?
+  } else {
+    return None;
+  };
+  Some(if negative { -value } else { value })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn words(rom: &[u8]) -> Vec<u16> {
+    rom
+      .chunks(2)
+      .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+      .collect()
+  }
+
+  #[test]
+  fn instructions_assemble() {
+    let rom = assemble(
+      "# Instructions of each form.
+      : main
+        clear v0 := 5 v1 := -1 v2 += 3 v2 -= 1 v3 := v2 v3 ^= v0
+        v4 := random 0x0F v5 := key v5 := delay delay := v5 buzzer := v0
+        i := sprite i += v1 i := hex v0 sprite v0 v1 5
+        bcd v0 save v2 load v2 v0 >>= v1 v0 =- v1
+        return
+      : sprite 0b11110000 0x90
+      ",
+    )
+    .unwrap();
+    assert_eq!(
+      words(&rom),
+      vec![
+        0x1202, 0x00E0, 0x6005, 0x61FF, 0x7203, 0x72FF, 0x8320, 0x8303, 0xC40F, 0xF50A, 0xF507,
+        0xF515, 0xF018, 0xA22E, 0xF11E, 0xF029, 0xD015, 0xF033, 0xF255, 0xF265, 0x8016, 0x8017,
+        0x00EE, 0xF090,
+      ]
+    );
+  }
+
+  #[test]
+  fn names_are_resolved() {
+    let rom = assemble(
+      ":const SPEED 3
+      :alias ball v6
+      : main
+        ball := SPEED
+        draw
+        jump main
+      : draw
+        i := long data
+        :unpack 0xA data
+      : data",
+    )
+    .unwrap();
+    assert_eq!(
+      words(&rom),
+      vec![0x1202, 0x6603, 0x2208, 0x1202, 0xF000, 0x0210, 0x60A2, 0x6110]
+    );
+  }
+
+  #[test]
+  fn control_flow_assembles() {
+    let rom = assemble(
+      ": main
+        loop
+          while v0 != 10
+          if v0 == v1 then v0 += 1
+          if v0 < 4 begin
+            v1 := 1
+          else
+            v1 := 2
+          end
+          if v2 key then clear
+        again",
+    )
+    .unwrap();
+    assert_eq!(
+      words(&rom),
+      vec![
+        0x1202, // jump main
+        0x400A, 0x121E, // while v0 != 10
+        0x9010, 0x7001, // if v0 == v1 then
+        0x6F04, 0x8F07, 0x3F00, 0x1216, // if v0 < 4 begin
+        0x6101, 0x1218, // else
+        0x6102, // end
+        0xE2A1, 0x00E0, // if v2 key then
+        0x1202, // again
+      ]
+    );
+  }
+
+  #[test]
+  fn macros_expand_their_arguments() {
+    let rom = assemble(
+      ":macro add-twice register amount { register += amount register += amount }
+      : main add-twice v3 2",
+    )
+    .unwrap();
+    assert_eq!(words(&rom), vec![0x1202, 0x7302, 0x7302]);
+  }
+
+  #[test]
+  fn errors_have_lines() {
+    assert_eq!(assemble("clear"), Err(AssembleError::NoMain));
+    assert_eq!(
+      assemble(": main\n  jump nowhere"),
+      Err(AssembleError::Undefined {
+        line: 2,
+        name: String::from("nowhere")
+      })
+    );
+    assert_eq!(
+      assemble(": main\n\n:calc x { 1 + 2 }"),
+      Err(AssembleError::Unsupported {
+        line: 3,
+        directive: String::from(":calc")
+      })
+    );
+    assert_eq!(
+      assemble(": main v0 := 256"),
+      Err(AssembleError::OutOfRange {
+        line: 1,
+        value: 256,
+        bits: 8
+      })
+    );
+    assert_eq!(
+      assemble(": main loop"),
+      Err(AssembleError::Unclosed {
+        line: 1,
+        opening: "loop"
+      })
+    );
+    assert_eq!(
+      assemble(": main end"),
+      Err(AssembleError::Unbalanced {
+        line: 1,
+        token: "end",
+        opening: "begin"
+      })
+    );
+  }
+}
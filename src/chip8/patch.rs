@@ -0,0 +1,421 @@
+//! patch applies ROM hacks, which are shipped as IPS or BPS patches
+//! rather than as the changed ROM.
+//!
+//! IPS patches are a list of bytes to write at offsets, and say nothing
+//! of the ROM they are for, so a patch for another ROM is only noticed
+//! when the result doesn't run. BPS patches record the size and CRC32 of
+//! the ROM they are for, and the CRC32 of the patched ROM and of
+//! themselves, which are all checked.
+
+use crate::chip8::hex::MAX_SPAN;
+use thiserror::Error;
+
+const IPS_HEADER: &[u8] = b"PATCH";
+const IPS_END: &[u8] = b"EOF";
+const BPS_HEADER: &[u8] = b"BPS1";
+// Bytes of the three CRC32s that end a BPS patch.
+const BPS_FOOTER_SIZE: usize = 12;
+
+/// PatchError is returned when a patch can't be read or applied.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum PatchError {
+  #[error("Not an IPS or BPS patch")]
+  UnknownFormat,
+
+  #[error("Patch ends in the middle of a record")]
+  Truncated,
+
+  #[error("Patch is corrupt: {reason}")]
+  Corrupt { reason: &'static str },
+
+  #[error("Patch is corrupt: its CRC32 is {found:08x}, not the {expected:08x} it records")]
+  PatchChecksum { expected: u32, found: u32 },
+
+  #[error(
+    "Patch is for a ROM of {expected_size} bytes with CRC32 {expected:08x}, \
+     not this ROM of {found_size} bytes with CRC32 {found:08x}"
+  )]
+  WrongBase {
+    expected: u32,
+    expected_size: usize,
+    found: u32,
+    found_size: usize,
+  },
+
+  #[error("Patched ROM has CRC32 {found:08x}, not the {expected:08x} the patch expects")]
+  TargetChecksum { expected: u32, found: u32 },
+
+  #[error("Patched ROM of {size} bytes is larger than the {} allowed", MAX_SPAN)]
+  TooLarge { size: usize },
+}
+
+/// PatchFormat is the format of a patch.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum PatchFormat {
+  Ips,
+  Bps,
+}
+
+/// Patch is an IPS or BPS patch.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct Patch {
+  format: PatchFormat,
+  bytes: Vec<u8>,
+}
+
+impl Patch {
+  /// from_bytes returns the patch of `bytes`, which are checked against
+  /// their own CRC32 if they are a BPS patch.
+  pub fn from_bytes(bytes: &[u8]) -> Result<Patch, PatchError> {
+    let format = if bytes.starts_with(IPS_HEADER) {
+      PatchFormat::Ips
+    } else if bytes.starts_with(BPS_HEADER) {
+      if bytes.len() < BPS_HEADER.len() + BPS_FOOTER_SIZE {
+        return Err(PatchError::Truncated);
+      }
+      let (body, checksum) = bytes.split_at(bytes.len() - 4);
+      let expected = read_u32(checksum);
+      let found = crc32(body);
+      if found != expected {
+        return Err(PatchError::PatchChecksum { expected, found });
+      }
+      PatchFormat::Bps
+    } else {
+      return Err(PatchError::UnknownFormat);
+    };
+    Ok(Patch {
+      format,
+      bytes: bytes.to_vec(),
+    })
+  }
+
+  pub fn format(&self) -> PatchFormat {
+    self.format
+  }
+
+  /// apply returns `source` with the patch applied.
+  pub fn apply(&self, source: &[u8]) -> Result<Vec<u8>, PatchError> {
+    match self.format {
+      PatchFormat::Ips => self.apply_ips(source),
+      PatchFormat::Bps => self.apply_bps(source),
+    }
+  }
+
+  fn apply_ips(&self, source: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut reader = Reader::new(&self.bytes[IPS_HEADER.len()..]);
+    let mut target = source.to_vec();
+    loop {
+      let record = reader.take(3)?;
+      if record == IPS_END {
+        break;
+      }
+      let offset = (record[0] as usize) << 16 | (record[1] as usize) << 8 | record[2] as usize;
+      let size = reader.u16()? as usize;
+      // Records of no size repeat one byte, to shorten runs.
+      let data = if size == 0 {
+        let count = reader.u16()? as usize;
+        vec![reader.take(1)?[0]; count]
+      } else {
+        reader.take(size)?.to_vec()
+      };
+      let end = offset + data.len();
+      if end > MAX_SPAN {
+        return Err(PatchError::TooLarge { size: end });
+      }
+      if target.len() < end {
+        target.resize(end, 0);
+      }
+      target[offset..end].copy_from_slice(&data);
+    }
+    // Some patches end with the size to truncate the ROM to.
+    if let Ok(size) = reader.take(3) {
+      target.truncate((size[0] as usize) << 16 | (size[1] as usize) << 8 | size[2] as usize);
+    }
+    Ok(target)
+  }
+
+  fn apply_bps(&self, source: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let footer = &self.bytes[self.bytes.len() - BPS_FOOTER_SIZE..];
+    let (source_crc, target_crc) = (read_u32(&footer[0..4]), read_u32(&footer[4..8]));
+    let mut reader = Reader::new(&self.bytes[BPS_HEADER.len()..self.bytes.len() - BPS_FOOTER_SIZE]);
+    let source_size = reader.number()? as usize;
+    let target_size = reader.number()? as usize;
+    let metadata_size = reader.number()? as usize;
+    reader.take(metadata_size)?;
+
+    let found = crc32(source);
+    if source.len() != source_size || found != source_crc {
+      return Err(PatchError::WrongBase {
+        expected: source_crc,
+        expected_size: source_size,
+        found,
+        found_size: source.len(),
+      });
+    }
+    if target_size > MAX_SPAN {
+      return Err(PatchError::TooLarge { size: target_size });
+    }
+
+    let mut target = Vec::with_capacity(target_size);
+    // Offsets that SourceCopy and TargetCopy actions move relative to.
+    let (mut source_offset, mut target_offset) = (0usize, 0usize);
+    while !reader.is_empty() {
+      let action = reader.number()?;
+      let length = (action >> 2) as usize + 1;
+      if target.len() + length > target_size {
+        return Err(PatchError::Corrupt {
+          reason: "it writes past the end of the patched ROM",
+        });
+      }
+      match action & 3 {
+        // SourceRead copies from the same offset of the source.
+        0 => {
+          let start = target.len();
+          let data = source
+            .get(start..start + length)
+            .ok_or(PatchError::Corrupt {
+              reason: "it reads past the end of the ROM",
+            })?;
+          target.extend_from_slice(data);
+        }
+        // TargetRead copies from the patch.
+        1 => target.extend_from_slice(reader.take(length)?),
+        // SourceCopy copies from anywhere in the source.
+        2 => {
+          source_offset = relative(source_offset, reader.number()?)?;
+          let data =
+            source
+              .get(source_offset..source_offset + length)
+              .ok_or(PatchError::Corrupt {
+                reason: "it reads past the end of the ROM",
+              })?;
+          target.extend_from_slice(data);
+          source_offset += length;
+        }
+        // TargetCopy copies from what is already patched, a byte at a
+        // time so that it can repeat a run.
+        _ => {
+          target_offset = relative(target_offset, reader.number()?)?;
+          for _ in 0..length {
+            let byte = *target.get(target_offset).ok_or(PatchError::Corrupt {
+              reason: "it copies from past the end of the patched ROM",
+            })?;
+            target.push(byte);
+            target_offset += 1;
+          }
+        }
+      }
+    }
+    if target.len() != target_size {
+      return Err(PatchError::Truncated);
+    }
+    let found = crc32(&target);
+    if found != target_crc {
+      return Err(PatchError::TargetChecksum {
+        expected: target_crc,
+        found,
+      });
+    }
+    Ok(target)
+  }
+}
+
+// Reader reads the fields of a patch in order.
+struct Reader<'a> {
+  bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+  fn new(bytes: &'a [u8]) -> Self {
+    Reader { bytes }
+  }
+
+  fn is_empty(&self) -> bool {
+    self.bytes.is_empty()
+  }
+
+  fn take(&mut self, count: usize) -> Result<&'a [u8], PatchError> {
+    if count > self.bytes.len() {
+      return Err(PatchError::Truncated);
+    }
+    let (taken, rest) = self.bytes.split_at(count);
+    self.bytes = rest;
+    Ok(taken)
+  }
+
+  // u16 reads a big-endian 16-bit number, as IPS writes them.
+  fn u16(&mut self) -> Result<u16, PatchError> {
+    let bytes = self.take(2)?;
+    Ok((bytes[0] as u16) << 8 | bytes[1] as u16)
+  }
+
+  // number reads a BPS number, which is 7 bits a byte with the last
+  // byte's top bit set. Each byte after the first also adds one to the
+  // next, so that every number has one encoding.
+  fn number(&mut self) -> Result<u64, PatchError> {
+    let mut number = 0u64;
+    let mut shift = 1u64;
+    loop {
+      let byte = self.take(1)?[0];
+      number += (byte & 0x7F) as u64 * shift;
+      if byte & 0x80 != 0 {
+        return Ok(number);
+      }
+      shift <<= 7;
+      if shift > 1 << 35 {
+        return Err(PatchError::Corrupt {
+          reason: "it has a number that is too large",
+        });
+      }
+      number += shift;
+    }
+  }
+}
+
+// relative returns `offset` moved by the BPS relative offset `encoded`,
+// whose lowest bit is its sign.
+fn relative(offset: usize, encoded: u64) -> Result<usize, PatchError> {
+  let distance = (encoded >> 1) as usize;
+  let moved = if encoded & 1 == 0 {
+    offset.checked_add(distance)
+  } else {
+    offset.checked_sub(distance)
+  };
+  moved.ok_or(PatchError::Corrupt {
+    reason: "it copies from before the start of the ROM",
+  })
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+  u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// crc32 returns the CRC-32 of `bytes`, as zlib and BPS compute it.
+pub fn crc32(bytes: &[u8]) -> u32 {
+  let mut crc = !0u32;
+  for &byte in bytes {
+    crc ^= byte as u32;
+    for _ in 0..8 {
+      crc = if crc & 1 != 0 {
+        (crc >> 1) ^ 0xEDB8_8320
+      } else {
+        crc >> 1
+      };
+    }
+  }
+  !crc
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // number writes `value` as a BPS number.
+  fn number(mut value: u64, out: &mut Vec<u8>) {
+    loop {
+      let byte = (value & 0x7F) as u8;
+      value >>= 7;
+      if value == 0 {
+        out.push(byte | 0x80);
+        return;
+      }
+      out.push(byte);
+      value -= 1;
+    }
+  }
+
+  // bps returns a BPS patch from `source` to `target` with the actions
+  // already encoded in `actions`.
+  fn bps(source: &[u8], target: &[u8], actions: &[u8]) -> Vec<u8> {
+    let mut patch = BPS_HEADER.to_vec();
+    number(source.len() as u64, &mut patch);
+    number(target.len() as u64, &mut patch);
+    number(0, &mut patch);
+    patch.extend_from_slice(actions);
+    patch.extend_from_slice(&crc32(source).to_le_bytes());
+    patch.extend_from_slice(&crc32(target).to_le_bytes());
+    let crc = crc32(&patch);
+    patch.extend_from_slice(&crc.to_le_bytes());
+    patch
+  }
+
+  #[test]
+  fn crc32_matches_known_values() {
+    assert_eq!(crc32(b""), 0);
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+  }
+
+  #[test]
+  fn bps_numbers_round_trip() {
+    for &value in [0, 1, 127, 128, 16511, 16512, 1 << 20].iter() {
+      let mut bytes = Vec::new();
+      number(value, &mut bytes);
+      assert_eq!(Reader::new(&bytes).number(), Ok(value));
+    }
+  }
+
+  #[test]
+  fn ips_writes_runs_and_grows() {
+    let mut patch = IPS_HEADER.to_vec();
+    // Write 0xAA 0xBB at 1.
+    patch.extend_from_slice(&[0, 0, 1, 0, 2, 0xAA, 0xBB]);
+    // Repeat 0xCC three times at 4, past the end of the ROM.
+    patch.extend_from_slice(&[0, 0, 4, 0, 0, 0, 3, 0xCC]);
+    patch.extend_from_slice(IPS_END);
+    let patch = Patch::from_bytes(&patch).unwrap();
+    assert_eq!(patch.format(), PatchFormat::Ips);
+    assert_eq!(
+      patch.apply(&[1, 2, 3, 4]).unwrap(),
+      vec![1, 0xAA, 0xBB, 4, 0xCC, 0xCC, 0xCC]
+    );
+
+    let truncated = Patch::from_bytes(&[IPS_HEADER, &[0, 0, 1, 0, 2, 0xAA]].concat()).unwrap();
+    assert_eq!(truncated.apply(&[1, 2, 3]), Err(PatchError::Truncated));
+  }
+
+  #[test]
+  fn bps_copies_from_the_source_patch_and_target() {
+    let source = [0x12, 0x34, 0x56, 0x78];
+    let target = [0x12, 0x34, 0xAB, 0x56, 0x78, 0xAB, 0x56];
+    let mut actions = Vec::new();
+    // SourceRead 2 bytes.
+    number(1 << 2, &mut actions);
+    // TargetRead 1 byte.
+    number(1, &mut actions);
+    actions.push(0xAB);
+    // SourceCopy 2 bytes from 2.
+    number((1 << 2) | 2, &mut actions);
+    number(2 << 1, &mut actions);
+    // TargetCopy 2 bytes from 2.
+    number((1 << 2) | 3, &mut actions);
+    number(2 << 1, &mut actions);
+    let patch = Patch::from_bytes(&bps(&source, &target, &actions)).unwrap();
+    assert_eq!(patch.format(), PatchFormat::Bps);
+    assert_eq!(patch.apply(&source).unwrap(), target.to_vec());
+
+    assert_eq!(
+      patch.apply(&[0x12, 0x34, 0x56, 0x79]),
+      Err(PatchError::WrongBase {
+        expected: crc32(&source),
+        expected_size: 4,
+        found: crc32(&[0x12, 0x34, 0x56, 0x79]),
+        found_size: 4
+      })
+    );
+  }
+
+  #[test]
+  fn corrupt_patches_are_rejected() {
+    let mut patch = bps(&[1], &[2], &[0x81, 0x02]);
+    let last = patch.len() - 5;
+    patch[last] ^= 1;
+    assert!(matches!(
+      Patch::from_bytes(&patch),
+      Err(PatchError::PatchChecksum { .. })
+    ));
+    assert_eq!(
+      Patch::from_bytes(b"not a patch"),
+      Err(PatchError::UnknownFormat)
+    );
+  }
+}
@@ -0,0 +1,85 @@
+//! platform provides the machines Chip8 programs were written for, which
+//! differ in how much memory they have and where programs start.
+
+/// Platform is a machine that runs Chip8 programs.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Hash, Default)]
+pub enum Platform {
+  /// CHIP-8 on a COSMAC VIP with 4 KiB of memory.
+  #[default]
+  Chip8,
+  /// CHIP-8 on the ETI-660, whose programs start at 0x600.
+  Eti660,
+  /// SCHIP on the HP48, with 4 KiB of memory.
+  Schip,
+  /// XO-CHIP, with 64 KiB of memory.
+  XoChip,
+}
+
+/// Names of the platforms accepted by `Platform::from_name`.
+pub const PLATFORM_NAMES: [&str; 4] = ["chip8", "eti660", "schip", "xochip"];
+
+impl Platform {
+  /// from_name returns the platform with the given name, as listed in
+  /// `PLATFORM_NAMES`.
+  pub fn from_name(name: &str) -> Option<Self> {
+    match name {
+      "chip8" => Some(Platform::Chip8),
+      "eti660" => Some(Platform::Eti660),
+      "schip" => Some(Platform::Schip),
+      "xochip" => Some(Platform::XoChip),
+      _ => None,
+    }
+  }
+
+  pub fn name(self) -> &'static str {
+    match self {
+      Platform::Chip8 => "chip8",
+      Platform::Eti660 => "eti660",
+      Platform::Schip => "schip",
+      Platform::XoChip => "xochip",
+    }
+  }
+
+  /// memory_size returns the bytes of memory programs can address.
+  pub fn memory_size(self) -> usize {
+    match self {
+      Platform::Chip8 | Platform::Eti660 | Platform::Schip => 0x1000,
+      Platform::XoChip => 0x10000,
+    }
+  }
+
+  /// entry_point returns the address programs are loaded at and start
+  /// from.
+  pub fn entry_point(self) -> u16 {
+    match self {
+      Platform::Eti660 => 0x600,
+      Platform::Chip8 | Platform::Schip | Platform::XoChip => 0x200,
+    }
+  }
+
+  /// max_program_size returns the bytes of memory free for programs,
+  /// from the entry point to the end of memory.
+  pub fn max_program_size(self) -> usize {
+    self.memory_size() - self.entry_point() as usize
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn names_round_trip() {
+    for name in PLATFORM_NAMES.iter() {
+      assert_eq!(Platform::from_name(name).unwrap().name(), *name);
+    }
+    assert_eq!(Platform::from_name("vip"), None);
+  }
+
+  #[test]
+  fn program_sizes() {
+    assert_eq!(Platform::Chip8.max_program_size(), 3584);
+    assert_eq!(Platform::Eti660.max_program_size(), 2560);
+    assert_eq!(Platform::XoChip.max_program_size(), 65024);
+  }
+}
@@ -0,0 +1,189 @@
+//! quirks provides the behaviour differences between Chip8
+//! interpreters.
+//!
+//! The original COSMAC VIP interpreter and its successors (CHIP-48,
+//! SCHIP) disagree on the semantics of a handful of instructions, and
+//! ROMs are often written against one specific interpreter. See the NB
+//! notes on `Instruction` for the details of each instruction.
+//!
+//! References include:
+//! * Timendus' quirks test: https://github.com/Timendus/chip8-test-suite
+//! * Chip8 compatibility notes: https://chip8.gulrak.net/
+
+/// Quirks is the set of behaviour toggles for the instructions that
+/// differ between Chip8 interpreters.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Hash)]
+pub struct Quirks {
+  /// 8XY6 and 8XYE shift Vy and store the result in Vx, rather than
+  /// shifting Vx in place.
+  pub shift_uses_vy: bool,
+  /// FX55 and FX65 leave I incremented past the last register.
+  pub memory_increments_i: bool,
+  /// 8XY1, 8XY2 and 8XY3 reset Vf to zero.
+  pub logic_resets_vf: bool,
+  /// BNNN is read as BXNN and jumps to XNN plus Vx, rather than NNN
+  /// plus V0.
+  pub jump_uses_vx: bool,
+  /// Sprites drawn over the edge of the display are clipped, rather
+  /// than wrapped around to the other side.
+  pub clip_sprites: bool,
+  /// DXYN waits for the next display refresh, which limits drawing to
+  /// one sprite per frame.
+  pub display_wait: bool,
+}
+
+/// Names of the quirk presets accepted by `Quirks::from_name`.
+pub const PRESET_NAMES: [&str; 5] = ["modern", "vip", "chip48", "schip", "xochip"];
+
+impl Default for Quirks {
+  fn default() -> Self {
+    Quirks::modern()
+  }
+}
+
+impl Quirks {
+  /// modern is the behaviour most contemporary interpreters (and
+  /// contemporary ROMs) agree on, and matches the semantics documented
+  /// on `Instruction`.
+  pub fn modern() -> Self {
+    Quirks {
+      shift_uses_vy: false,
+      memory_increments_i: false,
+      logic_resets_vf: false,
+      jump_uses_vx: false,
+      clip_sprites: false,
+      display_wait: false,
+    }
+  }
+
+  /// cosmac_vip is the behaviour of the original CHIP-8 interpreter for
+  /// the COSMAC VIP.
+  pub fn cosmac_vip() -> Self {
+    Quirks {
+      shift_uses_vy: true,
+      memory_increments_i: true,
+      logic_resets_vf: true,
+      jump_uses_vx: false,
+      clip_sprites: true,
+      display_wait: true,
+    }
+  }
+
+  /// chip48 is the behaviour of the CHIP-48 interpreter for the HP48.
+  pub fn chip48() -> Self {
+    Quirks {
+      shift_uses_vy: false,
+      memory_increments_i: true,
+      logic_resets_vf: false,
+      jump_uses_vx: true,
+      clip_sprites: true,
+      display_wait: false,
+    }
+  }
+
+  /// schip is the behaviour of the SCHIP 1.1 interpreter for the HP48.
+  pub fn schip() -> Self {
+    Quirks {
+      shift_uses_vy: false,
+      memory_increments_i: false,
+      logic_resets_vf: false,
+      jump_uses_vx: true,
+      clip_sprites: true,
+      display_wait: false,
+    }
+  }
+
+  /// xo_chip is the behaviour of XO-CHIP, as defined by Octo, which
+  /// keeps the original shifts and loads but wraps sprites.
+  pub fn xo_chip() -> Self {
+    Quirks {
+      shift_uses_vy: true,
+      memory_increments_i: true,
+      logic_resets_vf: false,
+      jump_uses_vx: false,
+      clip_sprites: false,
+      display_wait: false,
+    }
+  }
+
+  /// from_name returns the preset with the given name, as listed in
+  /// `PRESET_NAMES`.
+  pub fn from_name(name: &str) -> Option<Self> {
+    match name {
+      "modern" => Some(Quirks::modern()),
+      "vip" => Some(Quirks::cosmac_vip()),
+      "chip48" => Some(Quirks::chip48()),
+      "schip" => Some(Quirks::schip()),
+      "xochip" => Some(Quirks::xo_chip()),
+      _ => None,
+    }
+  }
+
+  /// preset_name returns the name of the preset that has these quirks,
+  /// if any.
+  pub fn preset_name(&self) -> Option<&'static str> {
+    PRESET_NAMES
+      .iter()
+      .copied()
+      .find(|name| Quirks::from_name(name).as_ref() == Some(self))
+  }
+
+  /// differences returns the names of the quirks that are toggled
+  /// differently between `self` and `other`.
+  pub fn differences(&self, other: &Quirks) -> Vec<&'static str> {
+    let fields = [
+      ("shift_uses_vy", self.shift_uses_vy, other.shift_uses_vy),
+      (
+        "memory_increments_i",
+        self.memory_increments_i,
+        other.memory_increments_i,
+      ),
+      (
+        "logic_resets_vf",
+        self.logic_resets_vf,
+        other.logic_resets_vf,
+      ),
+      ("jump_uses_vx", self.jump_uses_vx, other.jump_uses_vx),
+      ("clip_sprites", self.clip_sprites, other.clip_sprites),
+      ("display_wait", self.display_wait, other.display_wait),
+    ];
+    fields
+      .iter()
+      .filter(|(_, left, right)| left != right)
+      .map(|(name, _, _)| *name)
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  #[test]
+  fn from_name_knows_all_presets() {
+    for name in PRESET_NAMES.iter() {
+      assert!(
+        Quirks::from_name(name).is_some(),
+        "Expected preset {} to exist.",
+        name
+      );
+    }
+    assert_eq!(Quirks::from_name("unknown"), None);
+    assert_eq!(Quirks::chip48().preset_name(), Some("chip48"));
+    let custom = Quirks {
+      display_wait: true,
+      ..Quirks::modern()
+    };
+    assert_eq!(custom.preset_name(), None);
+  }
+
+  #[test]
+  fn differences_works() {
+    assert!(Quirks::schip().differences(&Quirks::schip()).is_empty());
+    assert_eq!(
+      Quirks::schip().differences(&Quirks::chip48()),
+      vec!["memory_increments_i"]
+    );
+  }
+}
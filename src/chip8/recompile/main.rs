@@ -0,0 +1,311 @@
+//! recompile translates a Chip8 ROM ahead of time into a Rust crate,
+//! which runs the ROM natively with the library.
+//!
+//! The crate's `src/rom.rs` holds the module written by
+//! `chip8::recompiler`, with one function for each basic block that
+//! could be recovered from the ROM. Its `src/main.rs` runs the ROM in
+//! the terminal, checks it against the interpreter frame by frame with
+//! `--compare`, or measures its speed against the interpreter with
+//! `--bench`.
+//!
+//! The report printed after recompiling shows how much of the ROM could
+//! be statically analysed: the bytes reached as code, and the indirect
+//! jumps and addresses outside of the ROM that were not followed.
+
+use clap::{App, Arg};
+use rustyemulator::chip8::flow::ControlFlow;
+use rustyemulator::chip8::recompiler;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum RecompileError {
+  #[error("Failed to read ROM {path}: {source}")]
+  Rom {
+    path: String,
+    source: std::io::Error,
+  },
+
+  #[error("Failed to write {path}: {source}")]
+  Output {
+    path: String,
+    source: std::io::Error,
+  },
+}
+
+// Cargo manifest of the generated crate. It is its own workspace, so it
+// can be generated anywhere.
+const MANIFEST: &str = r#"[package]
+name = "{name}"
+version = "0.1.0"
+edition = "2018"
+
+[dependencies]
+rustyemulator = { path = "{library}", default-features = false }
+
+[workspace]
+"#;
+
+// Entry point of the generated crate.
+const MAIN: &str = r#"//! Runs {title}, recompiled by chip8-recompile.
+//!
+//! Usage: {name} [--frames N] [--quirks NAME] [--ipf N] [--compare | --bench]
+//!
+//! Frames are drawn to the terminal at 60 frames per second. With
+//! `--compare`, frames are run as fast as possible alongside the
+//! interpreter instead, and it exits with an error at the first frame
+//! where they differ. With `--bench`, the frames are run as fast as
+//! possible recompiled and then interpreted, and their speeds printed.
+
+mod rom;
+
+use rustyemulator::chip8::chip8::Chip8;
+use rustyemulator::chip8::cpu::recompiled::Recompiled;
+use rustyemulator::chip8::display::{Display, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use rustyemulator::chip8::quirks::Quirks;
+use std::io::Write;
+use std::process;
+use std::time::{Duration, Instant};
+
+fn main() {
+  let args: Vec<String> = std::env::args().skip(1).collect();
+  let value = |name: &str| {
+    let index = args.iter().position(|arg| arg == name)?;
+    match args.get(index + 1) {
+      Some(value) => Some(value.clone()),
+      None => fail(&format!("{} takes a value", name)),
+    }
+  };
+  let number = |name: &str| {
+    value(name).map(|value| {
+      value
+        .parse::<u32>()
+        .unwrap_or_else(|_| fail(&format!("{} takes a number", name)))
+    })
+  };
+  let compare = args.iter().any(|arg| arg == "--compare");
+  let bench = args.iter().any(|arg| arg == "--bench");
+
+  let mut chip8 = Chip8::new();
+  if let Some(name) = value("--quirks") {
+    let quirks = Quirks::from_name(&name).unwrap_or_else(|| fail("unknown --quirks"));
+    chip8.set_quirks(quirks);
+  }
+  if let Some(ipf) = number("--ipf") {
+    chip8.set_instructions_per_frame(ipf);
+  }
+  if let Err(err) = chip8.load_program(rom::PROGRAM.rom) {
+    fail(&err.to_string());
+  }
+  let frames = match number("--frames") {
+    Some(frames) => frames,
+    None if compare || bench => 600,
+    None => u32::MAX,
+  };
+  if bench {
+    return run_bench(chip8, frames);
+  }
+
+  let mut recompiled = Recompiled::new(rom::PROGRAM);
+  let mut interpreted = chip8.clone();
+  let frame_time = Duration::from_secs(1) / 60;
+  let mut next_frame = Instant::now();
+  for frame in 0..frames {
+    let result = chip8.run_frame_recompiled(&mut recompiled);
+    if compare {
+      let expected = interpreted.run_frame();
+      if format!("{:?}", result) != format!("{:?}", expected) || chip8 != interpreted {
+        fail(&format!("diverged from the interpreter in frame {}", frame));
+      }
+    }
+    if let Err(err) = result {
+      fail(&format!("frame {}: {}", frame, err));
+    }
+    if !compare {
+      draw(chip8.display());
+      next_frame += frame_time;
+      std::thread::sleep(next_frame.saturating_duration_since(Instant::now()));
+    }
+  }
+  if compare {
+    println!("Matched the interpreter for {} frames.", frames);
+  }
+}
+
+// run_bench runs `frames` frames from `chip8` recompiled and then
+// interpreted, and prints how fast they ran.
+fn run_bench(chip8: Chip8, frames: u32) {
+  let mut recompiled = Recompiled::new(rom::PROGRAM);
+  for &(name, native) in &[("recompiled", true), ("interpreted", false)] {
+    let mut chip8 = chip8.clone();
+    let start = Instant::now();
+    for frame in 0..frames {
+      let result = if native {
+        chip8.run_frame_recompiled(&mut recompiled)
+      } else {
+        chip8.run_frame()
+      };
+      if let Err(err) = result {
+        fail(&format!("frame {}: {}", frame, err));
+      }
+    }
+    let seconds = start.elapsed().as_secs_f64();
+    println!("{}: {:.0} frames/s", name, frames as f64 / seconds);
+  }
+}
+
+fn fail(message: &str) -> ! {
+  eprintln!("error: {}", message);
+  process::exit(1)
+}
+
+// draw writes the display over the terminal, two rows of pixels to a
+// line of half blocks.
+fn draw(display: &Display) {
+  let mut screen = String::from("\x1b[H");
+  for y in (0..DISPLAY_HEIGHT).step_by(2) {
+    for x in 0..DISPLAY_WIDTH {
+      screen.push(match (display.pixel(x, y), display.pixel(x, y + 1)) {
+        (true, true) => '\u{2588}',
+        (true, false) => '\u{2580}',
+        (false, true) => '\u{2584}',
+        (false, false) => ' ',
+      });
+    }
+    screen.push('\n');
+  }
+  let mut stdout = std::io::stdout();
+  let _ = stdout.write_all(screen.as_bytes());
+  let _ = stdout.flush();
+}
+"#;
+
+fn main() {
+  let matches = App::new("Chip8 recompiler")
+    .version("1.0")
+    .author("Joey Pereira <joey@pereira.io>")
+    .about("Recompiles a Chip8 ROM ahead of time into a Rust crate")
+    .arg(
+      Arg::with_name("rom")
+        .long("rom")
+        .takes_value(true)
+        .required(true)
+        .help("Sets the ROM file to recompile"),
+    )
+    .arg(
+      Arg::with_name("output")
+        .short("o")
+        .long("output")
+        .takes_value(true)
+        .required(true)
+        .help("Sets the directory of the generated crate"),
+    )
+    .arg(
+      Arg::with_name("library")
+        .long("library")
+        .takes_value(true)
+        .default_value(env!("CARGO_MANIFEST_DIR"))
+        .help("Sets the path of the rustyemulator crate to link against"),
+    )
+    .get_matches();
+
+  let rom = matches.value_of("rom").unwrap();
+  let output = Path::new(matches.value_of("output").unwrap());
+  if let Err(err) = recompile(Path::new(rom), output, matches.value_of("library").unwrap()) {
+    eprintln!("error: {}", err);
+    process::exit(1);
+  }
+}
+
+// recompile writes the crate for the ROM at `path` to `output`, and
+// prints the report of its control flow.
+fn recompile(path: &Path, output: &Path, library: &str) -> Result<(), RecompileError> {
+  let program = fs::read(path).map_err(|source| RecompileError::Rom {
+    path: path.display().to_string(),
+    source,
+  })?;
+  let title = path
+    .file_stem()
+    .map(|stem| stem.to_string_lossy().into_owned())
+    .unwrap_or_else(|| String::from("rom"));
+  let name = crate_name(&title);
+  let flow = ControlFlow::recover(&program);
+
+  let library = fs::canonicalize(library).unwrap_or_else(|_| PathBuf::from(library));
+  let library = library.display().to_string().replace('\\', "/");
+  write(
+    &output.join("Cargo.toml"),
+    &MANIFEST
+      .replace("{name}", &name)
+      .replace("{library}", &library),
+  )?;
+  write(
+    &output.join("src").join("main.rs"),
+    &MAIN.replace("{title}", &title).replace("{name}", &name),
+  )?;
+  write(
+    &output.join("src").join("rom.rs"),
+    &recompiler::recompile(&title, &program, &flow),
+  )?;
+
+  let blocks = flow
+    .blocks()
+    .filter(|block| !block.instructions.is_empty())
+    .count();
+  println!("Recompiled {} into {}.", title, output.display());
+  println!(
+    "{} basic blocks cover {} of {} bytes ({:.0}%).",
+    blocks,
+    flow.code_size(),
+    program.len(),
+    100.0 * flow.code_size() as f64 / program.len().max(1) as f64
+  );
+  let addresses = |addresses: Vec<u16>| {
+    let addresses: Vec<_> = addresses.iter().map(|a| format!("{:#05X}", a)).collect();
+    addresses.join(", ")
+  };
+  let indirect: Vec<_> = flow.indirect_jumps().collect();
+  if !indirect.is_empty() {
+    println!(
+      "Indirect jumps, whose targets run on the interpreter: {}",
+      addresses(indirect)
+    );
+  }
+  let external: Vec<_> = flow.external().collect();
+  if !external.is_empty() {
+    println!(
+      "Addresses outside of the ROM, which run on the interpreter: {}",
+      addresses(external)
+    );
+  }
+  Ok(())
+}
+
+// crate_name returns a Cargo package name for the ROM `title`.
+fn crate_name(title: &str) -> String {
+  let title: String = title
+    .chars()
+    .map(|c| {
+      if c.is_ascii_alphanumeric() {
+        c.to_ascii_lowercase()
+      } else {
+        '-'
+      }
+    })
+    .collect();
+  format!("chip8-{}", title)
+}
+
+fn write(path: &Path, contents: &str) -> Result<(), RecompileError> {
+  let error = |source| RecompileError::Output {
+    path: path.display().to_string(),
+    source,
+  };
+  if let Some(parent) = path.parent() {
+    fs::create_dir_all(parent).map_err(error)?;
+  }
+  fs::write(path, contents).map_err(error)
+}
@@ -0,0 +1,250 @@
+//! recompiler translates a Chip8 ROM ahead of time into a Rust module,
+//! which `chip8::cpu::recompiled` runs.
+//!
+//! Each basic block that `chip8::flow` recovers becomes a function. The
+//! instructions that only set registers, jump or skip are written out
+//! as Rust, and every other instruction is passed to
+//! `Context::execute` with its decoded `Instruction`. The module has no
+//! code for what the control flow does not reach, which is left to the
+//! interpreter when it runs.
+
+use crate::chip8::flow::{BasicBlock, ControlFlow};
+use crate::chip8::instruction::Instruction;
+use std::fmt::Write;
+
+// Bytes of the ROM written on each line of the module.
+const ROM_BYTES_PER_LINE: usize = 16;
+
+/// recompile returns the source of a Rust module running `rom`, with
+/// the blocks of its control flow `flow`. The module's `PROGRAM` is the
+/// `Program` to run it with, and `name` is used in its documentation.
+pub fn recompile(name: &str, rom: &[u8], flow: &ControlFlow) -> String {
+  let mut source = String::new();
+  // Writing to a String cannot fail.
+  let out = &mut source;
+  let blocks: Vec<_> = flow
+    .blocks()
+    .filter(|block| !block.instructions.is_empty())
+    .collect();
+
+  writeln!(
+    out,
+    "//! {} recompiled by chip8-recompile. Do not edit.",
+    name
+  )
+  .unwrap();
+  writeln!(out, "//!").unwrap();
+  writeln!(
+    out,
+    "//! {} basic blocks cover {} of the ROM's {} bytes.",
+    blocks.len(),
+    flow.code_size(),
+    rom.len()
+  )
+  .unwrap();
+  writeln!(out).unwrap();
+  writeln!(out, "#![allow(unused_imports)]").unwrap();
+  writeln!(out).unwrap();
+  writeln!(
+    out,
+    "use rustyemulator::chip8::cpu::recompiled::{{Context, Program}};"
+  )
+  .unwrap();
+  writeln!(out, "use rustyemulator::chip8::cpu::CpuError;").unwrap();
+  writeln!(
+    out,
+    "use rustyemulator::chip8::instruction::Instruction::*;"
+  )
+  .unwrap();
+  writeln!(out).unwrap();
+  writeln!(out, "/// PROGRAM is the recompiled ROM.").unwrap();
+  writeln!(
+    out,
+    "pub static PROGRAM: Program = Program {{ rom: &ROM, run }};"
+  )
+  .unwrap();
+  writeln!(out).unwrap();
+
+  writeln!(out, "static ROM: [u8; {}] = [", rom.len()).unwrap();
+  for line in rom.chunks(ROM_BYTES_PER_LINE) {
+    let bytes: Vec<_> = line.iter().map(|byte| format!("0x{:02X},", byte)).collect();
+    writeln!(out, "  {}", bytes.join(" ")).unwrap();
+  }
+  writeln!(out, "];").unwrap();
+  writeln!(out).unwrap();
+
+  writeln!(out, "fn run(c: &mut Context) -> Result<(), CpuError> {{").unwrap();
+  writeln!(out, "  loop {{").unwrap();
+  writeln!(out, "    match c.program_counter() {{").unwrap();
+  for block in blocks.iter() {
+    writeln!(
+      out,
+      "      0x{:03X} if c.block(0x{:03X}, 0x{:03X}) => block_{:03x}(c)?,",
+      block.start,
+      block.start,
+      block.end(),
+      block.start
+    )
+    .unwrap();
+  }
+  writeln!(out, "      _ => return Ok(()),").unwrap();
+  writeln!(out, "    }}").unwrap();
+  writeln!(out, "  }}").unwrap();
+  writeln!(out, "}}").unwrap();
+
+  for block in blocks {
+    writeln!(out).unwrap();
+    write_block(out, rom, block);
+  }
+  source
+}
+
+// write_block writes the function of `block`.
+fn write_block(out: &mut String, rom: &[u8], block: &BasicBlock) {
+  writeln!(
+    out,
+    "fn block_{:03x}(c: &mut Context) -> Result<(), CpuError> {{",
+    block.start
+  )
+  .unwrap();
+  // Whether the last instruction left the program counter where
+  // execution continues.
+  let mut continues = false;
+  for &(address, instr) in block.instructions.iter() {
+    let offset = (address - crate::chip8::cpu::PROGRAM_START) as usize;
+    writeln!(
+      out,
+      "  // 0x{:03X}: {:02X}{:02X}",
+      address,
+      rom[offset],
+      rom[offset + 1]
+    )
+    .unwrap();
+    writeln!(out, "  if !c.enter(0x{:03X}) {{", address).unwrap();
+    writeln!(out, "    return Ok(());").unwrap();
+    writeln!(out, "  }}").unwrap();
+    let (statement, sets_pc) = statement(address, &instr);
+    writeln!(out, "  {}", statement).unwrap();
+    continues = sets_pc;
+  }
+  // An invalid last instruction is left to the interpreter, which
+  // faults on it.
+  if !continues || block.invalid {
+    writeln!(
+      out,
+      "  c.jump(0x{:03X});",
+      block.end() - 2 * block.invalid as u16
+    )
+    .unwrap();
+  }
+  writeln!(out, "  Ok(())").unwrap();
+  writeln!(out, "}}").unwrap();
+}
+
+// statement returns the Rust statement executing `instr` at `address`,
+// and whether it sets the program counter.
+fn statement(address: u16, instr: &Instruction) -> (String, bool) {
+  use Instruction::*;
+  let skip = |condition: String| {
+    (
+      format!("c.skip_if(0x{:03X}, {});", address, condition),
+      true,
+    )
+  };
+  match *instr {
+    FlowJumpToAddress { address } => (format!("c.jump(0x{:03X});", address), true),
+    CondSkipIfEqualConst {
+      x_register,
+      constant,
+    } => skip(format!("c.v({}) == 0x{:02X}", x_register, constant)),
+    CondSkipIfNotEqualConst {
+      x_register,
+      constant,
+    } => skip(format!("c.v({}) != 0x{:02X}", x_register, constant)),
+    CondSkipIfEqualVar {
+      x_register,
+      y_register,
+    } => skip(format!("c.v({}) == c.v({})", x_register, y_register)),
+    CondSkipIfNotEqualVar {
+      x_register,
+      y_register,
+    } => skip(format!("c.v({}) != c.v({})", x_register, y_register)),
+    ConstSetVar {
+      x_register,
+      constant,
+    } => (
+      format!("c.set_v({}, 0x{:02X});", x_register, constant),
+      false,
+    ),
+    ConstIncrementVar {
+      x_register,
+      constant,
+    } => (
+      format!(
+        "c.set_v({}, c.v({}).wrapping_add(0x{:02X}));",
+        x_register, x_register, constant
+      ),
+      false,
+    ),
+    AssignVar {
+      x_register,
+      y_register,
+    } => (
+      format!("c.set_v({}, c.v({}));", x_register, y_register),
+      false,
+    ),
+    MemorySetAddress { constant } => (format!("c.set_i(0x{:03X});", constant), false),
+    _ => {
+      // Debug formats instructions as their constructors, except for
+      // those without operands.
+      let mut constructor = format!("{:?}", instr);
+      if !constructor.contains('{') {
+        constructor.push_str("()");
+      }
+      (
+        format!("c.execute(0x{:03X}, {})?;", address, constructor),
+        true,
+      )
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn blocks_become_functions() {
+    let rom = [
+      0x60, 0x01, // 0x200: V0 = 1
+      0x00, 0xE0, // 0x202: clear the display
+      0x30, 0x01, // 0x204: skip if V0 == 1
+      0x12, 0x00, // 0x206: jump 0x200
+      0x70, 0x01, // 0x208: V0 += 1
+      0xFF, 0xFF, // 0x20A: unsupported
+    ];
+    let flow = ControlFlow::recover(&rom);
+    let source = recompile("TEST", &rom, &flow);
+    for expected in &[
+      "//! TEST recompiled by chip8-recompile. Do not edit.",
+      "static ROM: [u8; 12] = [",
+      "      0x200 if c.block(0x200, 0x206) => block_200(c)?,",
+      "      0x206 if c.block(0x206, 0x208) => block_206(c)?,",
+      "      0x208 if c.block(0x208, 0x20C) => block_208(c)?,",
+      "fn block_200(c: &mut Context) -> Result<(), CpuError> {",
+      "  c.set_v(0, 0x01);",
+      "  c.execute(0x202, DisplayClear())?;",
+      "  c.skip_if(0x204, c.v(0) == 0x01);",
+      "  c.jump(0x200);",
+      "  c.set_v(0, c.v(0).wrapping_add(0x01));\n  c.jump(0x20A);",
+    ] {
+      assert!(
+        source.contains(expected),
+        "missing {:?} in:\n{}",
+        expected,
+        source
+      );
+    }
+    assert!(!source.contains("block_20a"));
+  }
+}
@@ -0,0 +1,583 @@
+//! recording writes what the emulator plays to files: its sound as a WAV
+//! file, and its frames as an uncompressed YUV4MPEG2 (Y4M) stream or an
+//! animated GIF. The writers are audio and frame sinks, so a frontend
+//! can feed them while it plays, as can a headless run from a key
+//! script.
+//!
+//! Sinks can't fail, so each writer keeps the first error it meets,
+//! stops writing, and returns the error from `finish`.
+
+use crate::chip8::gif;
+use crate::interface::audio::AudioSink;
+use crate::interface::video::{Frame, FrameSink};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+// Size of the header of a WAV file, before the samples.
+const WAV_HEADER_SIZE: u32 = 44;
+// Offsets in the WAV header of the sizes filled in by `finish`.
+const WAV_RIFF_SIZE_OFFSET: u64 = 4;
+const WAV_DATA_SIZE_OFFSET: u64 = 40;
+
+// Luma of unset and set pixels, and the chroma of grey, in Y4M frames.
+const Y4M_BLACK: u8 = 0x00;
+const Y4M_WHITE: u8 = 0xFF;
+const Y4M_GREY: u8 = 0x80;
+
+// Palette of GIF frames: black for unset pixels, and white for set.
+const GIF_PALETTE: [u8; 6] = [0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF];
+// Shortest delay, in hundredths of a second, between GIF frames. Most
+// viewers play shorter delays slowly, so frames that would show for
+// less are dropped.
+const GIF_MIN_DELAY: u64 = 2;
+// Smallest LZW code size GIF allows, which is enough for the palette.
+const GIF_MIN_CODE_SIZE: u8 = 2;
+
+/// VideoFormat is a format frames can be recorded in.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum VideoFormat {
+  Y4m,
+  Gif,
+}
+
+impl VideoFormat {
+  /// from_path returns the format named by the extension of `path`.
+  pub fn from_path(path: &Path) -> Option<VideoFormat> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    match extension.as_str() {
+      "y4m" => Some(VideoFormat::Y4m),
+      "gif" => Some(VideoFormat::Gif),
+      _ => None,
+    }
+  }
+}
+
+/// WavWriter writes samples as a 16 bit mono PCM WAV file. The sizes in
+/// the header are only known at the end, so they are filled in by
+/// `finish`.
+pub struct WavWriter<W: Write + Seek> {
+  out: W,
+  // Samples written so far.
+  samples: u64,
+  buffer: Vec<u8>,
+  error: Option<io::Error>,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+  /// new writes the header of a WAV file of `sample_rate` samples a
+  /// second to `out`.
+  pub fn new(mut out: W, sample_rate: u32) -> io::Result<Self> {
+    out.write_all(b"RIFF")?;
+    out.write_all(&(WAV_HEADER_SIZE - 8).to_le_bytes())?;
+    out.write_all(b"WAVEfmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    // PCM, with one channel.
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&sample_rate.to_le_bytes())?;
+    // Bytes a second and a sample, and bits a sample.
+    out.write_all(&(sample_rate * 2).to_le_bytes())?;
+    out.write_all(&2u16.to_le_bytes())?;
+    out.write_all(&16u16.to_le_bytes())?;
+    out.write_all(b"data")?;
+    out.write_all(&0u32.to_le_bytes())?;
+    Ok(WavWriter {
+      out,
+      samples: 0,
+      buffer: Vec::new(),
+      error: None,
+    })
+  }
+
+  /// finish fills in the sizes in the header, and returns the output, or
+  /// the first error met while writing.
+  pub fn finish(mut self) -> io::Result<W> {
+    if let Some(err) = self.error {
+      return Err(err);
+    }
+    let data_size = u32::try_from(self.samples * 2).map_err(|_| {
+      io::Error::new(
+        io::ErrorKind::InvalidData,
+        "recording is too long for a WAV file",
+      )
+    })?;
+    self.out.seek(SeekFrom::Start(WAV_RIFF_SIZE_OFFSET))?;
+    self
+      .out
+      .write_all(&(data_size + WAV_HEADER_SIZE - 8).to_le_bytes())?;
+    self.out.seek(SeekFrom::Start(WAV_DATA_SIZE_OFFSET))?;
+    self.out.write_all(&data_size.to_le_bytes())?;
+    self.out.seek(SeekFrom::End(0))?;
+    self.out.flush()?;
+    Ok(self.out)
+  }
+}
+
+impl<W: Write + Seek> AudioSink for WavWriter<W> {
+  fn push_samples(&mut self, samples: &[f32]) {
+    if self.error.is_some() {
+      return;
+    }
+    self.buffer.clear();
+    for sample in samples {
+      let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16;
+      self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+    match self.out.write_all(&self.buffer) {
+      Ok(()) => self.samples += samples.len() as u64,
+      Err(err) => self.error = Some(err),
+    }
+  }
+}
+
+// scaled returns the pixels of `frame` scaled by `scale`, as `set` and
+// `unset` values, with its width and height.
+fn scaled(frame: &Frame, scale: usize, unset: u8, set: u8) -> (usize, usize, Vec<u8>) {
+  let (width, height) = (frame.width * scale, frame.height * scale);
+  let mut pixels = Vec::with_capacity(width * height);
+  for y in 0..height {
+    for x in 0..width {
+      pixels.push(if frame.pixel(x / scale, y / scale) {
+        set
+      } else {
+        unset
+      });
+    }
+  }
+  (width, height, pixels)
+}
+
+// frame_size_changed is the error for a frame whose size differs from
+// the first frame's.
+fn frame_size_changed() -> io::Error {
+  io::Error::new(
+    io::ErrorKind::InvalidInput,
+    "frame size changed during the recording",
+  )
+}
+
+/// Y4mWriter writes frames as an uncompressed YUV4MPEG2 stream, which
+/// video tools such as ffmpeg read. Frames are in 4:2:0 with grey
+/// chroma, and their size is set by the first frame.
+pub struct Y4mWriter<W: Write> {
+  out: W,
+  scale: usize,
+  frame_rate: u32,
+  // Size of the frames, once the header is written.
+  size: Option<(usize, usize)>,
+  error: Option<io::Error>,
+}
+
+impl<W: Write> Y4mWriter<W> {
+  /// new returns a writer of frames shown `frame_rate` times a second,
+  /// with each pixel scaled to `scale` by `scale` pixels.
+  pub fn new(out: W, scale: usize, frame_rate: u32) -> Self {
+    Y4mWriter {
+      out,
+      scale: scale.max(1),
+      frame_rate,
+      size: None,
+      error: None,
+    }
+  }
+
+  /// finish returns the output, or the first error met while writing.
+  pub fn finish(mut self) -> io::Result<W> {
+    if let Some(err) = self.error {
+      return Err(err);
+    }
+    self.out.flush()?;
+    Ok(self.out)
+  }
+
+  fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+    let (width, height, luma) = scaled(frame, self.scale, Y4M_BLACK, Y4M_WHITE);
+    match self.size {
+      None => {
+        writeln!(
+          self.out,
+          "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C420jpeg",
+          width, height, self.frame_rate
+        )?;
+        self.size = Some((width, height));
+      }
+      Some(size) if size != (width, height) => return Err(frame_size_changed()),
+      Some(_) => {}
+    }
+    self.out.write_all(b"FRAME\n")?;
+    self.out.write_all(&luma)?;
+    let chroma = vec![Y4M_GREY; width.div_ceil(2) * height.div_ceil(2)];
+    self.out.write_all(&chroma)?;
+    self.out.write_all(&chroma)
+  }
+}
+
+impl<W: Write> FrameSink for Y4mWriter<W> {
+  fn push_frame(&mut self, frame: &Frame) {
+    if self.error.is_none() {
+      if let Err(err) = self.write_frame(frame) {
+        self.error = Some(err);
+      }
+    }
+  }
+}
+
+// Pending is a GIF frame waiting to be written until it is known how
+// long it shows for.
+struct Pending {
+  pixels: Vec<bool>,
+  // Time it starts showing, in hundredths of a second.
+  start: u64,
+}
+
+/// GifWriter writes frames as an animated GIF that loops forever. Runs
+/// of the same frame are written once, and frames that would show for
+/// less than GIF viewers can time are dropped, so the animation keeps
+/// time with the emulation. Its size is set by the first frame.
+pub struct GifWriter<W: Write> {
+  out: W,
+  scale: usize,
+  frame_rate: u64,
+  // Size of the frames, once the header is written.
+  size: Option<(usize, usize)>,
+  // Frames pushed so far.
+  frames: u64,
+  pending: Option<Pending>,
+  error: Option<io::Error>,
+}
+
+impl<W: Write> GifWriter<W> {
+  /// new returns a writer of frames shown `frame_rate` times a second,
+  /// with each pixel scaled to `scale` by `scale` pixels.
+  ///
+  /// Panics if the frame rate is zero.
+  pub fn new(out: W, scale: usize, frame_rate: u32) -> Self {
+    assert!(frame_rate > 0, "frame rate must not be zero");
+    GifWriter {
+      out,
+      scale: scale.max(1),
+      frame_rate: frame_rate as u64,
+      size: None,
+      frames: 0,
+      pending: None,
+      error: None,
+    }
+  }
+
+  /// finish writes the last frame and the end of the GIF, and returns
+  /// the output, or the first error met while writing.
+  pub fn finish(mut self) -> io::Result<W> {
+    if let Some(err) = self.error.take() {
+      return Err(err);
+    }
+    if let Some(pending) = self.pending.take() {
+      let delay = (self.time(self.frames) - pending.start).max(GIF_MIN_DELAY);
+      self.write_image(&pending.pixels, delay)?;
+    }
+    if self.size.is_some() {
+      self.out.write_all(&[0x3B])?;
+    }
+    self.out.flush()?;
+    Ok(self.out)
+  }
+
+  // time returns when frame `frame` starts, in hundredths of a second.
+  fn time(&self, frame: u64) -> u64 {
+    frame * 100 / self.frame_rate
+  }
+
+  fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+    match self.size {
+      None => {
+        self.write_header(frame.width, frame.height)?;
+        self.size = Some((frame.width, frame.height));
+      }
+      Some(size) if size != (frame.width, frame.height) => return Err(frame_size_changed()),
+      Some(_) => {}
+    }
+    let now = self.time(self.frames);
+    self.frames += 1;
+    let start = match self.pending.take() {
+      Some(pending) if pending.pixels == frame.pixels => {
+        self.pending = Some(pending);
+        return Ok(());
+      }
+      Some(pending) if now - pending.start >= GIF_MIN_DELAY => {
+        self.write_image(&pending.pixels, now - pending.start)?;
+        now
+      }
+      // The pending frame is dropped, and this one shows in its place.
+      Some(pending) => pending.start,
+      None => now,
+    };
+    self.pending = Some(Pending {
+      pixels: frame.pixels.to_vec(),
+      start,
+    });
+    Ok(())
+  }
+
+  fn write_header(&mut self, width: usize, height: usize) -> io::Result<()> {
+    let (width, height) = (self.dimension(width)?, self.dimension(height)?);
+    self.out.write_all(b"GIF89a")?;
+    self.out.write_all(&width.to_le_bytes())?;
+    self.out.write_all(&height.to_le_bytes())?;
+    // A global palette of two colours, then the background colour and
+    // pixel aspect ratio.
+    self.out.write_all(&[0x80, 0x00, 0x00])?;
+    self.out.write_all(&GIF_PALETTE)?;
+    // Loop forever.
+    self.out.write_all(&[0x21, 0xFF, 0x0B])?;
+    self.out.write_all(b"NETSCAPE2.0")?;
+    self.out.write_all(&[0x03, 0x01, 0x00, 0x00, 0x00])
+  }
+
+  // dimension returns the scaled size of a side of the frames, which
+  // GIF limits to 16 bits.
+  fn dimension(&self, size: usize) -> io::Result<u16> {
+    u16::try_from(size * self.scale).map_err(|_| {
+      io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "frames are too large for a GIF",
+      )
+    })
+  }
+
+  fn write_image(&mut self, pixels: &[bool], delay: u64) -> io::Result<()> {
+    let (width, height) = self.size.unwrap();
+    let frame = Frame {
+      width,
+      height,
+      pixels,
+    };
+    let (width, height, indices) = scaled(&frame, self.scale, 0, 1);
+    // Graphic control extension, with the delay and no transparency.
+    let delay = u16::try_from(delay).unwrap_or(u16::MAX);
+    self.out.write_all(&[0x21, 0xF9, 0x04, 0x04])?;
+    self.out.write_all(&delay.to_le_bytes())?;
+    self.out.write_all(&[0x00, 0x00])?;
+    // Image descriptor, covering the whole screen.
+    self.out.write_all(&[0x2C, 0x00, 0x00, 0x00, 0x00])?;
+    self.out.write_all(&(width as u16).to_le_bytes())?;
+    self.out.write_all(&(height as u16).to_le_bytes())?;
+    self.out.write_all(&[0x00, GIF_MIN_CODE_SIZE])?;
+    for block in gif::lzw_encode(&indices, GIF_MIN_CODE_SIZE).chunks(255) {
+      self.out.write_all(&[block.len() as u8])?;
+      self.out.write_all(block)?;
+    }
+    self.out.write_all(&[0x00])
+  }
+}
+
+impl<W: Write> FrameSink for GifWriter<W> {
+  fn push_frame(&mut self, frame: &Frame) {
+    if self.error.is_none() {
+      if let Err(err) = self.write_frame(frame) {
+        self.error = Some(err);
+      }
+    }
+  }
+}
+
+/// VideoWriter writes frames in either video format.
+pub enum VideoWriter<W: Write> {
+  Y4m(Y4mWriter<W>),
+  Gif(GifWriter<W>),
+}
+
+impl<W: Write> VideoWriter<W> {
+  /// new returns a writer of frames in `format`, as for Y4mWriter::new
+  /// and GifWriter::new.
+  pub fn new(format: VideoFormat, out: W, scale: usize, frame_rate: u32) -> Self {
+    match format {
+      VideoFormat::Y4m => VideoWriter::Y4m(Y4mWriter::new(out, scale, frame_rate)),
+      VideoFormat::Gif => VideoWriter::Gif(GifWriter::new(out, scale, frame_rate)),
+    }
+  }
+
+  /// finish ends the video, and returns the output, or the first error
+  /// met while writing.
+  pub fn finish(self) -> io::Result<W> {
+    match self {
+      VideoWriter::Y4m(writer) => writer.finish(),
+      VideoWriter::Gif(writer) => writer.finish(),
+    }
+  }
+}
+
+impl<W: Write> FrameSink for VideoWriter<W> {
+  fn push_frame(&mut self, frame: &Frame) {
+    match self {
+      VideoWriter::Y4m(writer) => writer.push_frame(frame),
+      VideoWriter::Gif(writer) => writer.push_frame(frame),
+    }
+  }
+}
+
+/// Recorder records sound and frames to files, as they are pushed to
+/// it. It records nothing until told where to.
+#[derive(Default)]
+pub struct Recorder {
+  wav: Option<WavWriter<BufWriter<File>>>,
+  video: Option<VideoWriter<BufWriter<File>>>,
+}
+
+impl Recorder {
+  /// record_wav records sound of `sample_rate` samples a second to the
+  /// WAV file at `path`.
+  pub fn record_wav(&mut self, path: &Path, sample_rate: u32) -> io::Result<()> {
+    let out = BufWriter::new(File::create(path)?);
+    self.wav = Some(WavWriter::new(out, sample_rate)?);
+    Ok(())
+  }
+
+  /// record_video records frames to the file at `path` in `format`, as
+  /// for VideoWriter::new.
+  pub fn record_video(
+    &mut self,
+    path: &Path,
+    format: VideoFormat,
+    scale: usize,
+    frame_rate: u32,
+  ) -> io::Result<()> {
+    let out = BufWriter::new(File::create(path)?);
+    self.video = Some(VideoWriter::new(format, out, scale, frame_rate));
+    Ok(())
+  }
+
+  /// finish ends the recordings, returning the first error met while
+  /// writing them.
+  pub fn finish(self) -> io::Result<()> {
+    let wav = self.wav.map(WavWriter::finish).transpose();
+    let video = self.video.map(VideoWriter::finish).transpose();
+    wav.and(video).map(|_| ())
+  }
+}
+
+impl AudioSink for Recorder {
+  fn push_samples(&mut self, samples: &[f32]) {
+    if let Some(wav) = &mut self.wav {
+      wav.push_samples(samples)
+    }
+  }
+}
+
+impl FrameSink for Recorder {
+  fn push_frame(&mut self, frame: &Frame) {
+    if let Some(video) = &mut self.video {
+      video.push_frame(frame)
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+
+  #[test]
+  fn wav_header_has_sizes() {
+    let out = Cursor::new(Vec::new());
+    let mut wav = WavWriter::new(out, 8000).unwrap();
+    wav.push_samples(&[0.0, 1.0, -1.0]);
+    wav.push_samples(&[2.0]);
+    let bytes = wav.finish().unwrap().into_inner();
+    assert_eq!(bytes.len(), 44 + 8);
+    assert_eq!(&bytes[0..4], b"RIFF");
+    assert_eq!(&bytes[4..8], &44u32.to_le_bytes());
+    assert_eq!(&bytes[24..28], &8000u32.to_le_bytes());
+    assert_eq!(&bytes[40..44], &8u32.to_le_bytes());
+    assert_eq!(
+      &bytes[44..],
+      &[0x00, 0x00, 0xFF, 0x7F, 0x01, 0x80, 0xFF, 0x7F]
+    );
+  }
+
+  #[test]
+  fn y4m_writes_scaled_frames() {
+    let pixels = [true, false, false, false, false, false, false, true];
+    let frame = Frame {
+      width: 4,
+      height: 2,
+      pixels: &pixels,
+    };
+    let mut y4m = Y4mWriter::new(Vec::new(), 2, 60);
+    y4m.push_frame(&frame);
+    y4m.push_frame(&frame);
+    let bytes = y4m.finish().unwrap();
+    let header = b"YUV4MPEG2 W8 H4 F60:1 Ip A1:1 C420jpeg\n";
+    assert_eq!(&bytes[..header.len()], header);
+    let frame_size = b"FRAME\n".len() + 8 * 4 + 2 * 4 * 2;
+    assert_eq!(bytes.len(), header.len() + 2 * frame_size);
+    let luma = &bytes[header.len() + 6..header.len() + 6 + 32];
+    assert_eq!(&luma[..8], &[0xFF, 0xFF, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(&luma[24..], &[0, 0, 0, 0, 0, 0, 0xFF, 0xFF]);
+
+    let mut y4m = Y4mWriter::new(Vec::new(), 1, 60);
+    y4m.push_frame(&frame);
+    y4m.push_frame(&Frame {
+      width: 2,
+      height: 4,
+      pixels: &pixels,
+    });
+    assert!(y4m.finish().is_err());
+  }
+
+  // gif_images returns the delay and indices of each image of the GIF
+  // `bytes`.
+  fn gif_images(bytes: &[u8]) -> Vec<(u16, Vec<u8>)> {
+    let gif = gif::decode(bytes).unwrap();
+    assert_eq!(gif.palette.len(), 2);
+    gif
+      .images
+      .into_iter()
+      .map(|image| (image.delay, image.indices))
+      .collect()
+  }
+
+  #[test]
+  fn gif_merges_and_times_frames() {
+    let (on, off) = ([true, false, false, true], [false; 4]);
+    let frame = |pixels| Frame {
+      width: 2,
+      height: 2,
+      pixels,
+    };
+    let mut gif = GifWriter::new(Vec::new(), 1, 60);
+    // Twelve frames on, then one off, then more on. The frame off starts
+    // at 20/100 of a second and would show for only 1/100, so is
+    // dropped.
+    for _ in 0..12 {
+      gif.push_frame(&frame(&on));
+    }
+    gif.push_frame(&frame(&off));
+    for _ in 0..47 {
+      gif.push_frame(&frame(&on));
+    }
+    let images = gif_images(&gif.finish().unwrap());
+    assert_eq!(images, vec![(20, vec![1, 0, 0, 1]), (80, vec![1, 0, 0, 1])]);
+
+    let mut gif = GifWriter::new(Vec::new(), 2, 60);
+    gif.push_frame(&frame(&on));
+    let images = gif_images(&gif.finish().unwrap());
+    assert_eq!(
+      images,
+      vec![(2, vec![1, 1, 0, 0, 1, 1, 0, 0, 0, 0, 1, 1, 0, 0, 1, 1])]
+    );
+  }
+
+  #[test]
+  fn formats_from_extension() {
+    assert_eq!(
+      VideoFormat::from_path(Path::new("run.Y4M")),
+      Some(VideoFormat::Y4m)
+    );
+    assert_eq!(
+      VideoFormat::from_path(Path::new("run.gif")),
+      Some(VideoFormat::Gif)
+    );
+    assert_eq!(VideoFormat::from_path(Path::new("run.mp4")), None);
+  }
+}
@@ -0,0 +1,70 @@
+use crate::interface::serialization::Savable;
+
+/// Rng is the random number generator used by CXNN. It is a xorshift
+/// generator, so that emulation is deterministic for a given seed and
+/// the generator state can be saved along with the rest of the system.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Hash)]
+pub struct Rng {
+  state: u32,
+}
+
+impl Savable for Rng {
+  fn save(&self, fh: &mut dyn std::io::Write) -> std::io::Result<()> {
+    self.state.save(fh)?;
+    Ok(())
+  }
+  fn load(&mut self, fh: &mut dyn std::io::Read) -> std::io::Result<()> {
+    self.state.load(fh)?;
+    Ok(())
+  }
+}
+
+impl Default for Rng {
+  fn default() -> Self {
+    Rng::new(DEFAULT_SEED)
+  }
+}
+
+/// Seed used when none is provided.
+pub const DEFAULT_SEED: u32 = 0x2A6D_365B;
+
+impl Rng {
+  pub fn new(seed: u32) -> Self {
+    // Xorshift never leaves the zero state, so it is not a valid seed.
+    Rng {
+      state: if seed == 0 { DEFAULT_SEED } else { seed },
+    }
+  }
+
+  /// next_byte returns the next pseudo-random byte.
+  pub fn next_byte(&mut self) -> u8 {
+    let mut x = self.state;
+    x ^= x << 13;
+    x ^= x >> 17;
+    x ^= x << 5;
+    self.state = x;
+    (x >> 24) as u8
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  #[test]
+  fn same_seed_is_deterministic() {
+    let mut a = Rng::new(1234);
+    let mut b = Rng::new(1234);
+    for _ in 0..100 {
+      assert_eq!(a.next_byte(), b.next_byte());
+    }
+  }
+
+  #[test]
+  fn zero_seed_is_replaced() {
+    let mut rng = Rng::new(0);
+    assert_eq!(rng, Rng::default());
+    assert_ne!(rng.next_byte() as u32 | rng.state, 0);
+  }
+}
@@ -0,0 +1,363 @@
+//! rom provides Chip8 programs as loaded from files, checked to fit the
+//! memory of the platform they are for, and identified by their SHA-1
+//! hash so that save states and ROM databases can refer to them.
+//!
+//! Files ending in one of TEXT_EXTENSIONS hold the program as Intel HEX
+//! or a hex dump, which `chip8::hex` decodes. ROMs can then have IPS or
+//! BPS patches applied.
+
+use crate::chip8::hex::{self, HexError};
+use crate::chip8::patch::{Patch, PatchError};
+use crate::chip8::platform::Platform;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use thiserror::Error;
+
+/// Path that names standard input, rather than a file.
+pub const STDIN_PATH: &str = "-";
+
+/// Extensions of files that hold a program as Intel HEX or a hex dump,
+/// rather than as bytes.
+pub const TEXT_EXTENSIONS: [&str; 3] = ["hex", "ihx", "txt"];
+
+/// RomError is returned when a ROM can't be loaded.
+#[derive(Error, Debug)]
+pub enum RomError {
+  #[error("ROM is empty")]
+  Empty,
+
+  #[error("ROM of {size} bytes is larger than the {max} bytes free on {}", platform.name())]
+  TooLarge {
+    size: usize,
+    max: usize,
+    platform: Platform,
+  },
+
+  #[error("Failed to read ROM {path}: {source}")]
+  Io { path: String, source: io::Error },
+
+  #[error("Failed to decode ROM {path}: {source}")]
+  Hex { path: String, source: HexError },
+
+  #[error("Failed to apply patch {path} to ROM {hash}: {source}")]
+  Patch {
+    path: String,
+    hash: RomHash,
+    source: PatchError,
+  },
+}
+
+/// RomHash is the SHA-1 hash of a ROM's bytes. It is shown as 40
+/// lowercase hexadecimal digits.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Hash)]
+pub struct RomHash(pub [u8; 20]);
+
+impl RomHash {
+  /// from_hex returns the hash written as 40 hexadecimal digits.
+  pub fn from_hex(text: &str) -> Option<RomHash> {
+    if text.len() != 40 || !text.is_ascii() {
+      return None;
+    }
+    let mut hash = [0; 20];
+    for (i, byte) in hash.iter_mut().enumerate() {
+      *byte = u8::from_str_radix(&text[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(RomHash(hash))
+  }
+}
+
+impl fmt::Display for RomHash {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    for byte in self.0.iter() {
+      write!(f, "{:02x}", byte)?;
+    }
+    Ok(())
+  }
+}
+
+/// Rom is a program for a platform, which fits in its memory.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct Rom {
+  bytes: Vec<u8>,
+  platform: Platform,
+  hash: RomHash,
+}
+
+impl Rom {
+  /// from_bytes returns the ROM of `bytes` for `platform`.
+  pub fn from_bytes(bytes: &[u8], platform: Platform) -> Result<Rom, RomError> {
+    if bytes.is_empty() {
+      return Err(RomError::Empty);
+    }
+    let max = platform.max_program_size();
+    if bytes.len() > max {
+      return Err(RomError::TooLarge {
+        size: bytes.len(),
+        max,
+        platform,
+      });
+    }
+    Ok(Rom {
+      bytes: bytes.to_vec(),
+      platform,
+      hash: RomHash(sha1(bytes)),
+    })
+  }
+
+  /// from_reader reads the ROM for `platform` from `reader`, to its end.
+  /// `name` names where it is read from in errors.
+  pub fn from_reader(
+    reader: &mut dyn Read,
+    name: &str,
+    platform: Platform,
+  ) -> Result<Rom, RomError> {
+    let mut bytes = Vec::new();
+    reader
+      .read_to_end(&mut bytes)
+      .map_err(|source| RomError::Io {
+        path: String::from(name),
+        source,
+      })?;
+    Rom::from_bytes(&bytes, platform)
+  }
+
+  /// from_path reads the ROM for `platform` from the file at `path`,
+  /// decoding it if it has one of TEXT_EXTENSIONS.
+  pub fn from_path(path: &Path, platform: Platform) -> Result<Rom, RomError> {
+    let name = path.display().to_string();
+    let io_error = |source| RomError::Io {
+      path: name.clone(),
+      source,
+    };
+    let mut file = File::open(path).map_err(io_error)?;
+    if !is_text(path) {
+      return Rom::from_reader(&mut file, &name, platform);
+    }
+    let mut text = String::new();
+    file.read_to_string(&mut text).map_err(io_error)?;
+    let bytes = hex::decode(&text, platform.entry_point()).map_err(|source| RomError::Hex {
+      path: name.clone(),
+      source,
+    })?;
+    Rom::from_bytes(&bytes, platform)
+  }
+
+  /// from_stdin reads the ROM for `platform` from standard input.
+  pub fn from_stdin(platform: Platform) -> Result<Rom, RomError> {
+    Rom::from_reader(&mut io::stdin().lock(), "from standard input", platform)
+  }
+
+  /// open reads the ROM for `platform` from the file at `path`, or from
+  /// standard input if `path` is STDIN_PATH.
+  pub fn open(path: &str, platform: Platform) -> Result<Rom, RomError> {
+    if path == STDIN_PATH {
+      Rom::from_stdin(platform)
+    } else {
+      Rom::from_path(Path::new(path), platform)
+    }
+  }
+
+  /// patch returns the ROM with the IPS or BPS patch in the file at
+  /// `path` applied.
+  pub fn patch(&self, path: &Path) -> Result<Rom, RomError> {
+    let name = path.display().to_string();
+    let bytes = fs::read(path).map_err(|source| RomError::Io {
+      path: name.clone(),
+      source,
+    })?;
+    let patched = Patch::from_bytes(&bytes)
+      .and_then(|patch| patch.apply(&self.bytes))
+      .map_err(|source| RomError::Patch {
+        path: name,
+        hash: self.hash,
+        source,
+      })?;
+    Rom::from_bytes(&patched, self.platform)
+  }
+
+  pub fn bytes(&self) -> &[u8] {
+    &self.bytes
+  }
+
+  pub fn len(&self) -> usize {
+    self.bytes.len()
+  }
+
+  /// is_empty is always false, as empty ROMs are not loaded.
+  pub fn is_empty(&self) -> bool {
+    self.bytes.is_empty()
+  }
+
+  pub fn platform(&self) -> Platform {
+    self.platform
+  }
+
+  /// entry_point returns the address the ROM is loaded at and starts
+  /// from.
+  pub fn entry_point(&self) -> u16 {
+    self.platform.entry_point()
+  }
+
+  pub fn hash(&self) -> RomHash {
+    self.hash
+  }
+}
+
+// is_text returns whether the file at `path` has one of TEXT_EXTENSIONS.
+fn is_text(path: &Path) -> bool {
+  path
+    .extension()
+    .and_then(|extension| extension.to_str())
+    .is_some_and(|extension| {
+      TEXT_EXTENSIONS
+        .iter()
+        .any(|text| extension.eq_ignore_ascii_case(text))
+    })
+}
+
+// Initial state of a SHA-1 hash.
+const SHA1_INITIAL: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+// sha1 returns the SHA-1 hash of `bytes`, as specified in FIPS 180-4.
+fn sha1(bytes: &[u8]) -> [u8; 20] {
+  let mut message = bytes.to_vec();
+  let length = (bytes.len() as u64).wrapping_mul(8);
+  message.push(0x80);
+  while message.len() % 64 != 56 {
+    message.push(0);
+  }
+  message.extend_from_slice(&length.to_be_bytes());
+
+  let mut state = SHA1_INITIAL;
+  let mut words = [0u32; 80];
+  for block in message.chunks(64) {
+    for (word, chunk) in words.iter_mut().zip(block.chunks(4)) {
+      *word = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    }
+    for i in 16..80 {
+      words[i] = (words[i - 3] ^ words[i - 8] ^ words[i - 14] ^ words[i - 16]).rotate_left(1);
+    }
+    let [mut a, mut b, mut c, mut d, mut e] = state;
+    for (i, word) in words.iter().enumerate() {
+      let (f, k) = match i {
+        0..=19 => ((b & c) | (!b & d), 0x5A827999),
+        20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+        40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+        _ => (b ^ c ^ d, 0xCA62C1D6),
+      };
+      let temp = a
+        .rotate_left(5)
+        .wrapping_add(f)
+        .wrapping_add(e)
+        .wrapping_add(k)
+        .wrapping_add(*word);
+      e = d;
+      d = c;
+      c = b.rotate_left(30);
+      b = a;
+      a = temp;
+    }
+    for (value, add) in state.iter_mut().zip([a, b, c, d, e].iter()) {
+      *value = value.wrapping_add(*add);
+    }
+  }
+
+  let mut hash = [0; 20];
+  for (chunk, value) in hash.chunks_mut(4).zip(state.iter()) {
+    chunk.copy_from_slice(&value.to_be_bytes());
+  }
+  hash
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn hex(bytes: &[u8]) -> String {
+    RomHash(sha1(bytes)).to_string()
+  }
+
+  #[test]
+  fn sha1_matches_known_hashes() {
+    assert_eq!(hex(b""), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+    assert_eq!(hex(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+    // Two blocks, as the padding does not fit in the first.
+    assert_eq!(
+      hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+      "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+    );
+  }
+
+  #[test]
+  fn sizes_are_checked_for_the_platform() {
+    let program = vec![0x12; 3000];
+    assert!(Rom::from_bytes(&program, Platform::Chip8).is_ok());
+    assert!(matches!(
+      Rom::from_bytes(&program, Platform::Eti660),
+      Err(RomError::TooLarge {
+        size: 3000,
+        max: 2560,
+        platform: Platform::Eti660
+      })
+    ));
+    assert!(Rom::from_bytes(&vec![0; 3585], Platform::Chip8).is_err());
+    assert!(Rom::from_bytes(&vec![0; 3585], Platform::XoChip).is_ok());
+    assert!(matches!(
+      Rom::from_bytes(&[], Platform::Chip8),
+      Err(RomError::Empty)
+    ));
+  }
+
+  #[test]
+  fn missing_files_name_their_path() {
+    let err = Rom::open("does/not/exist.ch8", Platform::Chip8).unwrap_err();
+    assert!(err
+      .to_string()
+      .starts_with("Failed to read ROM does/not/exist.ch8"));
+  }
+
+  #[test]
+  fn text_files_are_decoded_and_patches_applied() {
+    let dir = std::env::temp_dir().join(format!("rom-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let text = dir.join("program.HEX");
+    fs::write(&text, ":04020000124E600139\n:00000001FF\n").unwrap();
+    let rom = Rom::from_path(&text, Platform::Chip8).unwrap();
+    assert_eq!(rom.bytes(), &[0x12, 0x4E, 0x60, 0x01]);
+
+    fs::write(&text, ":04020000124E600100\n:00000001FF\n").unwrap();
+    assert!(matches!(
+      Rom::from_path(&text, Platform::Chip8),
+      Err(RomError::Hex {
+        source: HexError::Checksum { line: 1, .. },
+        ..
+      })
+    ));
+
+    let patch = dir.join("hack.ips");
+    fs::write(&patch, b"PATCH\x00\x00\x03\x00\x01\x02EOF").unwrap();
+    assert_eq!(
+      rom.patch(&patch).unwrap().bytes(),
+      &[0x12, 0x4E, 0x60, 0x02]
+    );
+    fs::write(&patch, b"BPS1").unwrap();
+    let err = rom.patch(&patch).unwrap_err();
+    assert!(err.to_string().contains(&rom.hash().to_string()));
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn roms_have_their_platform_entry_point_and_hash() {
+    let rom = Rom::from_bytes(b"abc", Platform::Eti660).unwrap();
+    assert_eq!(rom.entry_point(), 0x600);
+    assert_eq!(rom.len(), 3);
+    assert_eq!(
+      rom.hash().to_string(),
+      "a9993e364706816aba3e25717850c26c9cd0d89d"
+    );
+    assert_eq!(RomHash::from_hex(&rom.hash().to_string()), Some(rom.hash()));
+    assert_eq!(RomHash::from_hex("a9993e"), None);
+  }
+}
@@ -0,0 +1,127 @@
+//! romdb shows what the ROM database knows about ROM files: the title
+//! and authors of their program, and the platform, quirks, speed and
+//! keys that `chip8` and `chip8-headless` play them with. `--list` lists
+//! the whole database.
+//!
+//! ROMs that are not in the database are shown with their SHA-1 hash, so
+//! that entries can be added for them, and it exits with status 1.
+
+use clap::{App, Arg};
+use rustyemulator::chip8::database::{Database, Entry};
+use rustyemulator::chip8::platform::Platform;
+use rustyemulator::chip8::quirks::Quirks;
+use rustyemulator::chip8::rom::{Rom, RomError};
+use std::process;
+
+fn main() {
+  let matches = App::new("Chip8 ROM database")
+    .version("1.0")
+    .author("Joey Pereira <joey@pereira.io>")
+    .about("Shows what the ROM database knows about Chip8 ROMs")
+    .arg(
+      Arg::with_name("rom")
+        .long("rom")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .required_unless("list")
+        .help("Sets a ROM file to look up, or - to read it from standard input"),
+    )
+    .arg(
+      Arg::with_name("list")
+        .long("list")
+        .help("Lists every ROM in the database"),
+    )
+    .get_matches();
+
+  let database = Database::embedded();
+  if matches.is_present("list") {
+    for entry in database.entries() {
+      println!(
+        "{}  {:<16} {:<14} {}",
+        entry.hash,
+        entry.title,
+        entry.platform_id,
+        entry.file.as_deref().unwrap_or("")
+      );
+    }
+    return;
+  }
+
+  let mut unknown = false;
+  for path in matches.values_of("rom").unwrap() {
+    match lookup(&database, path) {
+      Ok(true) => {}
+      Ok(false) => unknown = true,
+      Err(err) => {
+        eprintln!("error: {}", err);
+        process::exit(2);
+      }
+    }
+  }
+  if unknown {
+    process::exit(1);
+  }
+}
+
+// lookup prints the entry for the ROM at `path`, and returns whether it
+// has one.
+fn lookup(database: &Database, path: &str) -> Result<bool, RomError> {
+  // The hash does not depend on the platform, so the one with the most
+  // memory is used to read ROMs of any size.
+  let rom = Rom::open(path, Platform::XoChip)?;
+  println!("{}", path);
+  println!("  SHA-1:     {}", rom.hash());
+  match database.get(&rom.hash()) {
+    Some(entry) => {
+      print_entry(entry);
+      Ok(true)
+    }
+    None => {
+      println!("  Not in the database.");
+      Ok(false)
+    }
+  }
+}
+
+fn print_entry(entry: &Entry) {
+  println!("  Title:     {}", entry.title);
+  if !entry.authors.is_empty() {
+    println!("  Authors:   {}", entry.authors.join(", "));
+  }
+  if let Some(release) = &entry.release {
+    println!("  Released:  {}", release);
+  }
+  println!(
+    "  Platform:  {} ({})",
+    entry.platform_id,
+    entry.platform.name()
+  );
+  println!("  Quirks:    {}", quirks_name(&entry.quirks));
+  match entry.instructions_per_frame {
+    Some(ipf) => println!("  Speed:     {} instructions per frame", ipf),
+    None => println!("  Speed:     default"),
+  }
+  if !entry.keys.is_empty() {
+    let keys: Vec<String> = entry
+      .keys
+      .iter()
+      .map(|(name, key)| format!("{} {:X}", name, key))
+      .collect();
+    println!("  Keys:      {}", keys.join(", "));
+  }
+}
+
+// quirks_name returns the name of the preset `quirks` match, or the
+// quirks that are set.
+fn quirks_name(quirks: &Quirks) -> String {
+  if let Some(name) = quirks.preset_name() {
+    return String::from(name);
+  }
+  let set = Quirks::modern().differences(quirks);
+  if set.is_empty() {
+    String::from("none")
+  } else {
+    set.join(", ")
+  }
+}
@@ -0,0 +1,233 @@
+//! rominfo summarises what can be found out about a ROM without running
+//! it: its size and hash, how much of it is code that `chip8::flow` can
+//! reach and how much is data, the instructions that code uses, how deep
+//! its calls go, the addresses it refers to outside of itself, and the
+//! platform it was written for.
+
+use crate::chip8::analysis::Analysis;
+use crate::chip8::database::Database;
+use crate::chip8::flow::ControlFlow;
+use crate::chip8::instruction::Instruction;
+use crate::chip8::platform::Platform;
+use crate::chip8::rom::{Rom, RomHash};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// PlatformSource is where the platform of a ROM came from.
+#[derive(PartialEq, Clone, Debug)]
+pub enum PlatformSource {
+  /// The ROM database has an entry with the title.
+  Database { title: String },
+  /// `chip8::analysis` suggested it, with the confidence.
+  Analysis { confidence: f32 },
+}
+
+/// RomInfo is the summary of a ROM.
+#[derive(PartialEq, Clone, Debug)]
+pub struct RomInfo {
+  pub size: usize,
+  pub hash: RomHash,
+  /// Bytes that are part of a reachable instruction.
+  pub code_size: usize,
+  /// Shannon entropy of the bytes, in bits per byte.
+  pub entropy: f64,
+  /// Number of reachable instructions of each kind, by the name of their
+  /// `Instruction` variant.
+  pub instructions: BTreeMap<&'static str, usize>,
+  /// Deepest the call stack gets, or None if subroutines recurse.
+  pub max_stack_depth: Option<usize>,
+  /// Addresses outside of the ROM that reachable instructions jump to,
+  /// call or point I at.
+  pub outside: BTreeSet<u16>,
+  pub platform: Platform,
+  pub platform_source: PlatformSource,
+}
+
+impl RomInfo {
+  /// new summarises `rom`, taking its platform from `database` if it has
+  /// an entry for it.
+  pub fn new(rom: &Rom, database: &Database) -> RomInfo {
+    let bytes = rom.bytes();
+    let start = rom.entry_point() as usize;
+    let flow = ControlFlow::recover_at(bytes, rom.entry_point());
+    let end = start + bytes.len();
+    let mut instructions = BTreeMap::new();
+    let mut outside = BTreeSet::new();
+    for (_, instr) in flow.blocks().flat_map(|block| block.instructions.iter()) {
+      *instructions.entry(name(instr)).or_insert(0) += 1;
+      if let Some(address) = referenced_address(instr) {
+        if address < start || address >= end {
+          outside.insert(address as u16);
+        }
+      }
+    }
+    let (platform, platform_source) = match database.get(&rom.hash()) {
+      Some(entry) => (
+        entry.platform,
+        PlatformSource::Database {
+          title: entry.title.clone(),
+        },
+      ),
+      None => {
+        let analysis = Analysis::analyse(bytes);
+        (
+          analysis.platform,
+          PlatformSource::Analysis {
+            confidence: analysis.confidence,
+          },
+        )
+      }
+    };
+    RomInfo {
+      size: bytes.len(),
+      hash: rom.hash(),
+      code_size: flow.code_size(),
+      entropy: entropy(bytes),
+      instructions,
+      max_stack_depth: flow.max_stack_depth(),
+      outside,
+      platform,
+      platform_source,
+    }
+  }
+
+  /// data_size returns the number of bytes that are not code.
+  pub fn data_size(&self) -> usize {
+    self.size - self.code_size
+  }
+}
+
+// referenced_address returns the address `instr` jumps to, calls or sets
+// I to.
+fn referenced_address(instr: &Instruction) -> Option<usize> {
+  use Instruction::*;
+  match *instr {
+    Call { address } | FlowJumpToAddress { address } | FlowSubroutineCall { address } => {
+      Some(address)
+    }
+    MemorySetAddress { constant } | FlowJumpToAddressPlusVar { constant } => {
+      Some(constant as usize)
+    }
+    _ => None,
+  }
+}
+
+// name returns the name of the `Instruction` variant of `instr`.
+fn name(instr: &Instruction) -> &'static str {
+  use Instruction::*;
+  match instr {
+    Call { .. } => "Call",
+    DisplayClear() => "DisplayClear",
+    FlowSubroutineReturn() => "FlowSubroutineReturn",
+    FlowJumpToAddress { .. } => "FlowJumpToAddress",
+    FlowSubroutineCall { .. } => "FlowSubroutineCall",
+    CondSkipIfEqualConst { .. } => "CondSkipIfEqualConst",
+    CondSkipIfNotEqualConst { .. } => "CondSkipIfNotEqualConst",
+    CondSkipIfEqualVar { .. } => "CondSkipIfEqualVar",
+    ConstSetVar { .. } => "ConstSetVar",
+    ConstIncrementVar { .. } => "ConstIncrementVar",
+    AssignVar { .. } => "AssignVar",
+    BitwiseOrVar { .. } => "BitwiseOrVar",
+    BitwiseAndVar { .. } => "BitwiseAndVar",
+    BitwiseXorVar { .. } => "BitwiseXorVar",
+    MathAddVar { .. } => "MathAddVar",
+    MathSubVar { .. } => "MathSubVar",
+    BitShiftRightVar { .. } => "BitShiftRightVar",
+    MathReverseSubtractVar { .. } => "MathReverseSubtractVar",
+    BitShiftLeftVar { .. } => "BitShiftLeftVar",
+    CondSkipIfNotEqualVar { .. } => "CondSkipIfNotEqualVar",
+    MemorySetAddress { .. } => "MemorySetAddress",
+    FlowJumpToAddressPlusVar { .. } => "FlowJumpToAddressPlusVar",
+    RandomByConstant { .. } => "RandomByConstant",
+    DisplayDraw { .. } => "DisplayDraw",
+    InputKeyIsPressed { .. } => "InputKeyIsPressed",
+    InputKeyIsNotPressed { .. } => "InputKeyIsNotPressed",
+    TimerGetDelay { .. } => "TimerGetDelay",
+    InputKeyAwaitPress { .. } => "InputKeyAwaitPress",
+    TimerSetDelay { .. } => "TimerSetDelay",
+    TimerSetSound { .. } => "TimerSetSound",
+    MemoryAddVerToAddress { .. } => "MemoryAddVerToAddress",
+    MemorySetToVarSpriteLocation { .. } => "MemorySetToVarSpriteLocation",
+    LoadBinaryCodedDecimal { .. } => "LoadBinaryCodedDecimal",
+    MemoryDump { .. } => "MemoryDump",
+    MemoryLoad { .. } => "MemoryLoad",
+    AudioLoadPattern() => "AudioLoadPattern",
+    AudioSetPitch { .. } => "AudioSetPitch",
+  }
+}
+
+// entropy returns the Shannon entropy of `bytes`, in bits per byte.
+fn entropy(bytes: &[u8]) -> f64 {
+  let mut counts = [0usize; 256];
+  for &byte in bytes {
+    counts[byte as usize] += 1;
+  }
+  let total = bytes.len() as f64;
+  counts
+    .iter()
+    .filter(|&&count| count > 0)
+    .map(|&count| {
+      let p = count as f64 / total;
+      -p * p.log2()
+    })
+    .sum()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn summarises_code_data_and_references() {
+    let rom = [
+      0xA3, 0x00, // 0x200: I = 0x300
+      0x22, 0x08, // 0x202: call 0x208
+      0x12, 0x04, // 0x204: jump 0x204
+      0xFF, 0xFF, // 0x206: data
+      0xA2, 0x06, // 0x208: I = 0x206
+      0x00, 0xEE, // 0x20A: return
+    ];
+    let info = RomInfo::new(
+      &Rom::from_bytes(&rom, Platform::Chip8).unwrap(),
+      &Database::default(),
+    );
+    assert_eq!(info.size, 12);
+    assert_eq!(info.code_size, 10);
+    assert_eq!(info.data_size(), 2);
+    assert_eq!(info.instructions.get("MemorySetAddress"), Some(&2));
+    assert_eq!(info.instructions.get("FlowSubroutineReturn"), Some(&1));
+    assert_eq!(info.max_stack_depth, Some(1));
+    assert_eq!(
+      info.outside.iter().copied().collect::<Vec<_>>(),
+      vec![0x300]
+    );
+    assert_eq!(info.platform, Platform::Chip8);
+    assert!(matches!(
+      info.platform_source,
+      PlatformSource::Analysis { .. }
+    ));
+  }
+
+  #[test]
+  fn roms_are_read_from_their_entry_point() {
+    let rom = [
+      0xA6, 0x06, // 0x600: I = 0x606
+      0x26, 0x08, // 0x602: call 0x608
+      0x16, 0x04, // 0x604: jump 0x604
+      0xFF, 0xFF, // 0x606: data
+      0x00, 0xEE, // 0x608: return
+    ];
+    let info = RomInfo::new(
+      &Rom::from_bytes(&rom, Platform::Eti660).unwrap(),
+      &Database::default(),
+    );
+    assert_eq!(info.code_size, 8);
+    assert_eq!(info.max_stack_depth, Some(1));
+    assert!(info.outside.is_empty());
+  }
+
+  #[test]
+  fn entropy_is_in_bits_per_byte() {
+    assert_eq!(entropy(&[7, 7, 7, 7]), 0.0);
+    assert_eq!(entropy(&[0, 1, 2, 3]), 2.0);
+  }
+}
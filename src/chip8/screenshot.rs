@@ -0,0 +1,215 @@
+//! screenshot writes the Chip8 display as an image, in formats that
+//! need no external libraries to write: binary PBM, and PNG compressed
+//! with stored deflate blocks.
+//!
+//! Set pixels are white and unset pixels black in both formats.
+
+use crate::chip8::display::{Display, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use crate::chip8::patch::crc32;
+use std::io::{self, Write};
+use std::path::Path;
+
+// Signature at the start of every PNG file.
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+// Largest length of a stored deflate block.
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+/// Format is an image format screenshots can be written in.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum Format {
+  Png,
+  Pbm,
+}
+
+impl Format {
+  /// from_path returns the format named by the extension of `path`.
+  pub fn from_path(path: &Path) -> Option<Format> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    match extension.as_str() {
+      "png" => Some(Format::Png),
+      "pbm" => Some(Format::Pbm),
+      _ => None,
+    }
+  }
+}
+
+/// write writes `display` to `out` in `format`, with each pixel scaled
+/// to `scale` by `scale` pixels.
+pub fn write(
+  display: &Display,
+  format: Format,
+  scale: usize,
+  out: &mut dyn Write,
+) -> io::Result<()> {
+  let scale = scale.max(1);
+  let rows = packed_rows(display, scale);
+  let (width, height) = (DISPLAY_WIDTH * scale, DISPLAY_HEIGHT * scale);
+  match format {
+    Format::Pbm => {
+      // PBM has black for 1 bits.
+      write!(out, "P4\n{} {}\n", width, height)?;
+      for row in rows.iter() {
+        let inverted: Vec<u8> = row.iter().map(|byte| !byte).collect();
+        out.write_all(&inverted)?;
+      }
+      Ok(())
+    }
+    Format::Png => write_png(width as u32, height as u32, &rows, out),
+  }
+}
+
+// packed_rows returns the rows of the scaled display, with eight pixels
+// to a byte from the most significant bit, and set pixels as 1 bits.
+fn packed_rows(display: &Display, scale: usize) -> Vec<Vec<u8>> {
+  let width = DISPLAY_WIDTH * scale;
+  let mut rows = Vec::with_capacity(DISPLAY_HEIGHT * scale);
+  for y in 0..DISPLAY_HEIGHT {
+    let mut row = vec![0u8; width.div_ceil(8)];
+    for x in 0..width {
+      if display.pixel(x / scale, y) {
+        row[x / 8] |= 0x80 >> (x % 8);
+      }
+    }
+    for _ in 0..scale {
+      rows.push(row.clone());
+    }
+  }
+  rows
+}
+
+// write_png writes a 1 bit grayscale PNG image of `rows`, each packed
+// with eight pixels to a byte.
+fn write_png(width: u32, height: u32, rows: &[Vec<u8>], out: &mut dyn Write) -> io::Result<()> {
+  out.write_all(&PNG_SIGNATURE)?;
+
+  let mut header = Vec::with_capacity(13);
+  header.extend_from_slice(&width.to_be_bytes());
+  header.extend_from_slice(&height.to_be_bytes());
+  // Bit depth 1, grayscale, deflate, no filtering and no interlacing.
+  header.extend_from_slice(&[1, 0, 0, 0, 0]);
+  write_chunk(b"IHDR", &header, out)?;
+
+  // Every row starts with the filter type, which is none.
+  let mut scanlines = Vec::new();
+  for row in rows {
+    scanlines.push(0);
+    scanlines.extend_from_slice(row);
+  }
+  write_chunk(b"IDAT", &zlib_stored(&scanlines), out)?;
+  write_chunk(b"IEND", &[], out)
+}
+
+fn write_chunk(kind: &[u8; 4], data: &[u8], out: &mut dyn Write) -> io::Result<()> {
+  out.write_all(&(data.len() as u32).to_be_bytes())?;
+  out.write_all(kind)?;
+  out.write_all(data)?;
+  let crc = crc32(&[&kind[..], data].concat());
+  out.write_all(&crc.to_be_bytes())
+}
+
+// zlib_stored returns a zlib stream of `data` in uncompressed deflate
+// blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+  // Deflate with a 32K window, and the check bits for the lowest level.
+  let mut stream = vec![0x78, 0x01];
+  let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+  if blocks.peek().is_none() {
+    stream.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+  }
+  while let Some(block) = blocks.next() {
+    let last = blocks.peek().is_none() as u8;
+    let length = block.len() as u16;
+    stream.push(last);
+    stream.extend_from_slice(&length.to_le_bytes());
+    stream.extend_from_slice(&(!length).to_le_bytes());
+    stream.extend_from_slice(block);
+  }
+  stream.extend_from_slice(&adler32(data).to_be_bytes());
+  stream
+}
+
+fn adler32(data: &[u8]) -> u32 {
+  let (mut a, mut b) = (1u32, 0u32);
+  for &byte in data {
+    a = (a + byte as u32) % 65521;
+    b = (b + a) % 65521;
+  }
+  b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn display_with_corner() -> Display {
+    let mut display = Display::new();
+    display.draw_sprite(0, 0, &[0b1000_0001], false);
+    display
+  }
+
+  #[test]
+  fn pbm_has_header_and_packed_rows() -> io::Result<()> {
+    let mut out = Vec::new();
+    write(&display_with_corner(), Format::Pbm, 1, &mut out)?;
+    let header = b"P4\n64 32\n";
+    assert_eq!(&out[..header.len()], header);
+    assert_eq!(out.len(), header.len() + 8 * 32);
+    assert_eq!(&out[header.len()..header.len() + 2], &[0b0111_1110, 0xFF]);
+    Ok(())
+  }
+
+  #[test]
+  fn png_decodes_to_scaled_display() -> io::Result<()> {
+    let mut out = Vec::new();
+    write(&display_with_corner(), Format::Png, 2, &mut out)?;
+    assert_eq!(&out[..8], &PNG_SIGNATURE);
+
+    // Walk the chunks, checking their CRCs and collecting the data.
+    let mut chunks = Vec::new();
+    let mut rest = &out[8..];
+    while !rest.is_empty() {
+      let length = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+      let (kind, data) = (&rest[4..8], &rest[8..8 + length]);
+      let crc = &rest[8 + length..12 + length];
+      assert_eq!(crc, &crc32(&rest[4..8 + length]).to_be_bytes());
+      chunks.push((kind.to_vec(), data.to_vec()));
+      rest = &rest[12 + length..];
+    }
+    let kinds: Vec<_> = chunks.iter().map(|(kind, _)| kind.as_slice()).collect();
+    assert_eq!(kinds, vec![&b"IHDR"[..], b"IDAT", b"IEND"]);
+    assert_eq!(&chunks[0].1[..8], &[0, 0, 0, 128, 0, 0, 0, 64]);
+
+    // Undo the stored deflate blocks.
+    let stream = &chunks[1].1;
+    let (mut scanlines, mut at) = (Vec::new(), 2);
+    loop {
+      let last = stream[at] & 1 != 0;
+      let length = u16::from_le_bytes([stream[at + 1], stream[at + 2]]) as usize;
+      scanlines.extend_from_slice(&stream[at + 5..at + 5 + length]);
+      at += 5 + length;
+      if last {
+        break;
+      }
+    }
+    assert_eq!(&stream[at..], &adler32(&scanlines).to_be_bytes());
+    // Each row is a filter byte and 16 bytes, for 64 * 2 pixels.
+    assert_eq!(scanlines.len(), 64 * 17);
+    for row in 0..2 {
+      let line = &scanlines[row * 17..(row + 1) * 17];
+      assert_eq!(&line[..4], &[0, 0b1100_0000, 0b0000_0011, 0]);
+    }
+    assert!(scanlines[2 * 17..].iter().all(|&byte| byte == 0));
+    Ok(())
+  }
+
+  #[test]
+  fn formats_come_from_extensions() {
+    assert_eq!(
+      Format::from_path(Path::new("a/shot.PNG")),
+      Some(Format::Png)
+    );
+    assert_eq!(Format::from_path(Path::new("shot.pbm")), Some(Format::Pbm));
+    assert_eq!(Format::from_path(Path::new("shot.gif")), None);
+    assert_eq!(Format::from_path(Path::new("shot")), None);
+  }
+}
@@ -0,0 +1,228 @@
+//! terminal provides what is needed to play Chip8 in a text terminal:
+//! rendering the display as Unicode text, reading keys in raw mode, and
+//! releasing keys again, as terminals only report key presses.
+
+use crate::chip8::display::{Display, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Glyphs is how display pixels are drawn as characters.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum Glyphs {
+  /// Half blocks, two pixels to a character cell, stacked.
+  HalfBlock,
+  /// Braille patterns, eight pixels to a character cell, in two columns
+  /// of four.
+  Braille,
+}
+
+impl Glyphs {
+  /// from_name returns the glyphs named "halfblock" or "braille".
+  pub fn from_name(name: &str) -> Option<Glyphs> {
+    match name {
+      "halfblock" => Some(Glyphs::HalfBlock),
+      "braille" => Some(Glyphs::Braille),
+      _ => None,
+    }
+  }
+
+  // cell returns the size in pixels of a character cell.
+  fn cell(self) -> (usize, usize) {
+    match self {
+      Glyphs::HalfBlock => (1, 2),
+      Glyphs::Braille => (2, 4),
+    }
+  }
+}
+
+// Bits of the braille pattern for each pixel of a cell, by row and then
+// column, from U+2800.
+const BRAILLE_DOTS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+
+/// render returns the lines of text that draw `display` with `glyphs`.
+pub fn render(display: &Display, glyphs: Glyphs) -> Vec<String> {
+  let (width, height) = glyphs.cell();
+  let mut lines = Vec::with_capacity(DISPLAY_HEIGHT.div_ceil(height));
+  for y in (0..DISPLAY_HEIGHT).step_by(height) {
+    let mut line = String::with_capacity(DISPLAY_WIDTH / width * 3);
+    for x in (0..DISPLAY_WIDTH).step_by(width) {
+      line.push(match glyphs {
+        Glyphs::HalfBlock => match (display.pixel(x, y), display.pixel(x, y + 1)) {
+          (true, true) => '\u{2588}',
+          (true, false) => '\u{2580}',
+          (false, true) => '\u{2584}',
+          (false, false) => ' ',
+        },
+        Glyphs::Braille => {
+          let mut pattern = 0;
+          for (row, dots) in BRAILLE_DOTS.iter().enumerate() {
+            for (column, dot) in dots.iter().enumerate() {
+              if display.pixel(x + column, y + row) {
+                pattern |= dot;
+              }
+            }
+          }
+          std::char::from_u32(0x2800 + pattern).unwrap()
+        }
+      });
+    }
+    lines.push(line);
+  }
+  lines
+}
+
+/// key returns the Chip8 key for a character typed on the left of a
+/// QWERTY keyboard, which is laid out as the keypad:
+///
+/// ```text
+/// 1 2 3 C      1 2 3 4
+/// 4 5 6 D  ->  Q W E R
+/// 7 8 9 E      A S D F
+/// A 0 B F      Z X C V
+/// ```
+pub fn key(character: char) -> Option<usize> {
+  Some(match character.to_ascii_lowercase() {
+    '1' => 0x1,
+    '2' => 0x2,
+    '3' => 0x3,
+    '4' => 0xC,
+    'q' => 0x4,
+    'w' => 0x5,
+    'e' => 0x6,
+    'r' => 0xD,
+    'a' => 0x7,
+    's' => 0x8,
+    'd' => 0x9,
+    'f' => 0xE,
+    'z' => 0xA,
+    'x' => 0x0,
+    'c' => 0xB,
+    'v' => 0xF,
+    _ => return None,
+  })
+}
+
+/// KeyTimeouts releases keys that have not been typed for a while.
+/// Terminals send a key again while it is held, so a key counts as
+/// held until `timeout` passes without it being sent.
+#[derive(Clone, Debug)]
+pub struct KeyTimeouts {
+  timeout: Duration,
+  // When each key was last typed, while it is held.
+  typed: [Option<Instant>; 16],
+}
+
+impl KeyTimeouts {
+  pub fn new(timeout: Duration) -> Self {
+    KeyTimeouts {
+      timeout,
+      typed: [None; 16],
+    }
+  }
+
+  /// typed records that `key` was typed at `now`, and returns whether
+  /// it was not already held.
+  pub fn typed(&mut self, key: usize, now: Instant) -> bool {
+    self.typed[key].replace(now).is_none()
+  }
+
+  /// expired returns the held keys that were last typed at least the
+  /// timeout before `now`, which are no longer held.
+  pub fn expired(&mut self, now: Instant) -> Vec<usize> {
+    let timeout = self.timeout;
+    let mut keys = Vec::new();
+    for (key, typed) in self.typed.iter_mut().enumerate() {
+      if typed.is_some_and(|typed| now.duration_since(typed) >= timeout) {
+        *typed = None;
+        keys.push(key);
+      }
+    }
+    keys
+  }
+}
+
+/// RawMode puts the terminal of standard input in raw mode, so that
+/// keys are read as they are typed without echoing, and reads do not
+/// wait for input. The terminal is restored when it is dropped.
+pub struct RawMode {
+  #[cfg(unix)]
+  original: libc::termios,
+}
+
+impl RawMode {
+  #[cfg(unix)]
+  pub fn enter() -> io::Result<RawMode> {
+    let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+    if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } != 0 {
+      return Err(io::Error::last_os_error());
+    }
+    let original = termios;
+    unsafe { libc::cfmakeraw(&mut termios) };
+    // Reads return at once, with whatever was typed.
+    termios.c_cc[libc::VMIN] = 0;
+    termios.c_cc[libc::VTIME] = 0;
+    if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) } != 0 {
+      return Err(io::Error::last_os_error());
+    }
+    Ok(RawMode { original })
+  }
+
+  #[cfg(not(unix))]
+  pub fn enter() -> io::Result<RawMode> {
+    Err(io::Error::new(
+      io::ErrorKind::Other,
+      "raw mode is only supported on Unix terminals",
+    ))
+  }
+}
+
+impl Drop for RawMode {
+  fn drop(&mut self) {
+    #[cfg(unix)]
+    unsafe {
+      libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn display_with_corner() -> Display {
+    let mut display = Display::new();
+    // Pixels (0, 0), (1, 1) and (0, 3).
+    display.draw_sprite(0, 0, &[0x80, 0x40, 0x00, 0x80], false);
+    display
+  }
+
+  #[test]
+  fn half_blocks_stack_two_rows() {
+    let lines = render(&display_with_corner(), Glyphs::HalfBlock);
+    assert_eq!(lines.len(), 16);
+    assert!(lines.iter().all(|line| line.chars().count() == 64));
+    assert!(lines[0].starts_with("\u{2580}\u{2584} "));
+    assert!(lines[1].starts_with("\u{2584} "));
+  }
+
+  #[test]
+  fn braille_packs_eight_pixels() {
+    let lines = render(&display_with_corner(), Glyphs::Braille);
+    assert_eq!(lines.len(), 8);
+    assert!(lines.iter().all(|line| line.chars().count() == 32));
+    let cell = 0x2800 + 0x01 + 0x10 + 0x40;
+    assert_eq!(lines[0].chars().next(), std::char::from_u32(cell));
+    assert_eq!(lines[1].chars().next(), Some('\u{2800}'));
+  }
+
+  #[test]
+  fn keys_release_after_timeout() {
+    let mut keys = KeyTimeouts::new(Duration::from_millis(100));
+    let start = Instant::now();
+    assert!(keys.typed(5, start));
+    assert!(!keys.typed(5, start + Duration::from_millis(50)));
+    assert!(keys.expired(start + Duration::from_millis(140)).is_empty());
+    assert_eq!(keys.expired(start + Duration::from_millis(150)), vec![5]);
+    assert!(keys.typed(5, start + Duration::from_millis(160)));
+  }
+}
@@ -1,5 +1,6 @@
 use crate::interface::emulator::Clocked;
 use crate::interface::serialization::Savable;
+use std::convert::Infallible;
 
 /// Timer provdes the emulation for the Chip8 timer components. The
 /// Chip8 contains two timers, the sound and delay timer, which both run
@@ -21,13 +22,15 @@ impl Savable for Timer {
 }
 
 impl Clocked for Timer {
+  type Fault = Infallible;
+
   fn clock(&mut self) {
     if self.counter > 0 {
       self.counter -= 1
     }
   }
 
-  fn clock_rate(self) -> u32 {
+  fn clock_rate(&self) -> u32 {
     60
   }
 }
@@ -50,6 +53,10 @@ impl Timer {
   pub fn set_counter(&mut self, counter: u8) {
     self.counter = counter
   }
+
+  pub fn counter(self) -> u8 {
+    self.counter
+  }
 }
 
 #[cfg(test)]
@@ -104,6 +111,23 @@ mod tests {
     );
   }
 
+  #[test]
+  fn clock_rate_is_60hz() {
+    let timer = Timer::new();
+    assert_eq!(timer.clock_rate(), 60);
+    assert_eq!(timer.busy_cycles(), 0);
+  }
+
+  #[test]
+  fn run_cycles_counts_down() {
+    let mut timer = Timer::new();
+    timer.set_counter(5);
+    assert_eq!(timer.run_cycles(3), (3, Ok(())));
+    assert_eq!(timer.counter(), 2);
+    assert_eq!(timer.run_cycles(3), (3, Ok(())));
+    assert!(timer.is_zero());
+  }
+
   #[test]
   fn clock_works() {
     let mut timer = Timer::new();
@@ -0,0 +1,395 @@
+//! timing provides the instruction timing of the original CHIP-8
+//! interpreter for the COSMAC VIP.
+//!
+//! The VIP interpreter is an RCA 1802 program, and every Chip8
+//! instruction costs the 1802 machine cycles of the interpreter's fetch
+//! and decode loop plus the routine implementing the instruction. Some
+//! routines loop, so their cost depends on the operands: DXYN shifts
+//! each sprite row into place bit by bit, FX33 converts to decimal by
+//! repeated subtraction and FX55/FX65 copy one register at a time.
+//!
+//! The display interrupt of the CDP1861 additionally takes over the CPU
+//! for the visible part of every frame, so only part of each frame is
+//! left for the interpreter.
+//!
+//! Costs are counted from the interpreter's routines, as disassembled
+//! in the analysis below, at two machine cycles per 1802 instruction.
+//! The instructions starting with F are dispatched through a jump table
+//! of their own, which is included in their cost.
+//!
+//! References include:
+//! * Laurence Scotford's VIP interpreter analysis:
+//!   https://www.laurencescotford.net/2020/07/25/chip-8-on-the-cosmac-vip-index/
+
+pub use crate::cdp1802::cpu::{CLOCKS_PER_MACHINE_CYCLE, VIP_CLOCK_RATE};
+use crate::chip8::audio::PATTERN_SIZE;
+use crate::chip8::cpu::Cpu;
+use crate::chip8::instruction::Instruction;
+
+/// Machine cycles per 60hz display frame of the CDP1861, which is 262
+/// lines of 14 machine cycles.
+pub const MACHINE_CYCLES_PER_FRAME: u32 = 262 * 14;
+/// Machine cycles taken by the display interrupt each frame. The
+/// interrupt routine runs for the 128 visible lines (DMA included) and
+/// spends about 40 more cycles on entry, exit and the timers.
+pub const DISPLAY_INTERRUPT_CYCLES: u32 = 128 * 14 + 40;
+/// Machine cycles left for the interpreter each frame.
+pub const INTERPRETER_CYCLES_PER_FRAME: u32 = MACHINE_CYCLES_PER_FRAME - DISPLAY_INTERRUPT_CYCLES;
+/// Machine cycles of the interpreter's fetch and decode loop, which
+/// every instruction pays in addition to its own cost: 33 instructions
+/// to fetch the opcode and jump to its routine, and the branch back.
+pub const FETCH_CYCLES: u32 = 68;
+
+/// TimingEntry is the cost of an instruction in the VIP interpreter,
+/// excluding `FETCH_CYCLES`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct TimingEntry {
+  /// Opcode pattern, in the notation of `Instruction`.
+  pub opcode: &'static str,
+  /// Machine cycles of the instruction's routine.
+  pub cycles: u32,
+  /// Description of the cycles added depending on the operands, if
+  /// any.
+  pub variable: Option<&'static str>,
+}
+
+// Machine cycles added by a skip that is taken.
+const SKIP_TAKEN_CYCLES: u32 = 4;
+// Machine cycles per sprite row drawn by DXYN.
+const DRAW_ROW_CYCLES: u32 = 34;
+// Machine cycles added per sprite row that straddles two display bytes.
+const DRAW_STRADDLE_CYCLES: u32 = 20;
+// Machine cycles per bit a sprite row is shifted by DXYN.
+const DRAW_SHIFT_CYCLES: u32 = 4;
+// Machine cycles added by BNNN and FX1E when the addition carries into
+// the high byte of the address.
+const JUMP_PAGE_CYCLES: u32 = 2;
+const ADD_ADDRESS_PAGE_CYCLES: u32 = 6;
+// Machine cycles per subtraction of FX33.
+const BCD_SUBTRACT_CYCLES: u32 = 16;
+// Machine cycles per register copied by FX55 and FX65.
+const MEMORY_REGISTER_CYCLES: u32 = 14;
+
+/// VIP_TIMING_TABLE is the cost of every instruction in the VIP
+/// interpreter. The XO-CHIP instructions, which the VIP interpreter
+/// does not have, are costed as the closest instructions it does have.
+pub const VIP_TIMING_TABLE: [TimingEntry; 37] = [
+  entry("0NNN", 0, Some("cycles of the machine code routine")),
+  entry("00E0", 3078, None),
+  entry("00EE", 10, None),
+  entry("1NNN", 12, None),
+  entry("2NNN", 26, None),
+  entry("3XNN", 10, Some("+4 if the skip is taken")),
+  entry("4XNN", 10, Some("+4 if the skip is taken")),
+  entry("5XY0", 14, Some("+4 if the skip is taken")),
+  entry("6XNN", 6, None),
+  entry("7XNN", 10, None),
+  entry("8XY0", 12, None),
+  entry("8XY1", 44, None),
+  entry("8XY2", 44, None),
+  entry("8XY3", 44, None),
+  entry("8XY4", 44, None),
+  entry("8XY5", 44, None),
+  entry("8XY6", 44, None),
+  entry("8XY7", 44, None),
+  entry("8XYE", 44, None),
+  entry("9XY0", 14, Some("+4 if the skip is taken")),
+  entry("ANNN", 12, None),
+  entry("BNNN", 22, Some("+2 if the target is on another page")),
+  entry("CXNN", 36, None),
+  entry(
+    "DXYN",
+    26,
+    Some(concat!(
+      "+34 per row, +20 and +4 per bit of Vx mod 8 for unaligned rows, ",
+      "then waits for the display interrupt"
+    )),
+  ),
+  entry("EX9E", 14, Some("+4 if the skip is taken")),
+  entry("EXA1", 14, Some("+4 if the skip is taken")),
+  entry("FX07", 10, None),
+  entry("FX0A", 18, Some("per keypad poll until a key is pressed")),
+  entry("FX15", 10, None),
+  entry("FX18", 10, None),
+  entry("FX1E", 16, Some("+6 if I moves to another page")),
+  entry("FX29", 20, None),
+  entry(
+    "FX33",
+    84,
+    Some("+16 per unit of the sum of the decimal digits"),
+  ),
+  entry("FX55", 18, Some("+14 per register")),
+  entry("FX65", 18, Some("+14 per register")),
+  entry("F002", 18, Some("+14 per byte, as FX65 copying 16 bytes")),
+  entry("FX3A", 10, None),
+];
+
+const fn entry(opcode: &'static str, cycles: u32, variable: Option<&'static str>) -> TimingEntry {
+  TimingEntry {
+    opcode,
+    cycles,
+    variable,
+  }
+}
+
+/// timing_entry returns the table entry for the instruction's opcode.
+pub fn timing_entry(instr: &Instruction) -> &'static TimingEntry {
+  use Instruction::*;
+  let index = match instr {
+    Call { .. } => 0,
+    DisplayClear() => 1,
+    FlowSubroutineReturn() => 2,
+    FlowJumpToAddress { .. } => 3,
+    FlowSubroutineCall { .. } => 4,
+    CondSkipIfEqualConst { .. } => 5,
+    CondSkipIfNotEqualConst { .. } => 6,
+    CondSkipIfEqualVar { .. } => 7,
+    ConstSetVar { .. } => 8,
+    ConstIncrementVar { .. } => 9,
+    AssignVar { .. } => 10,
+    BitwiseOrVar { .. } => 11,
+    BitwiseAndVar { .. } => 12,
+    BitwiseXorVar { .. } => 13,
+    MathAddVar { .. } => 14,
+    MathSubVar { .. } => 15,
+    BitShiftRightVar { .. } => 16,
+    MathReverseSubtractVar { .. } => 17,
+    BitShiftLeftVar { .. } => 18,
+    CondSkipIfNotEqualVar { .. } => 19,
+    MemorySetAddress { .. } => 20,
+    FlowJumpToAddressPlusVar { .. } => 21,
+    RandomByConstant { .. } => 22,
+    DisplayDraw { .. } => 23,
+    InputKeyIsPressed { .. } => 24,
+    InputKeyIsNotPressed { .. } => 25,
+    TimerGetDelay { .. } => 26,
+    InputKeyAwaitPress { .. } => 27,
+    TimerSetDelay { .. } => 28,
+    TimerSetSound { .. } => 29,
+    MemoryAddVerToAddress { .. } => 30,
+    MemorySetToVarSpriteLocation { .. } => 31,
+    LoadBinaryCodedDecimal { .. } => 32,
+    MemoryDump { .. } => 33,
+    MemoryLoad { .. } => 34,
+    AudioLoadPattern() => 35,
+    AudioSetPitch { .. } => 36,
+  };
+  &VIP_TIMING_TABLE[index]
+}
+
+/// instruction_cycles returns the machine cycles the VIP interpreter
+/// takes to execute `instr` with the state of `cpu` before execution,
+/// including `FETCH_CYCLES`.
+///
+/// The wait of DXYN for the display interrupt is not included, as it
+/// depends on when in the frame the instruction executes.
+pub fn instruction_cycles(instr: &Instruction, cpu: &Cpu) -> u32 {
+  use Instruction::*;
+  let v = cpu.v_registers();
+  let skip = |taken: bool| if taken { SKIP_TAKEN_CYCLES } else { 0 };
+  let variable = match *instr {
+    CondSkipIfEqualConst {
+      x_register,
+      constant,
+    } => skip(v[x_register] == constant),
+    CondSkipIfNotEqualConst {
+      x_register,
+      constant,
+    } => skip(v[x_register] != constant),
+    CondSkipIfEqualVar {
+      x_register,
+      y_register,
+    } => skip(v[x_register] == v[y_register]),
+    CondSkipIfNotEqualVar {
+      x_register,
+      y_register,
+    } => skip(v[x_register] != v[y_register]),
+    InputKeyIsPressed { x_register } => skip(cpu.keypad().is_pressed(v[x_register] as usize)),
+    InputKeyIsNotPressed { x_register } => skip(!cpu.keypad().is_pressed(v[x_register] as usize)),
+    FlowJumpToAddressPlusVar { constant } => {
+      let register = if cpu.quirks().jump_uses_vx {
+        ((constant & 0x0F00) >> 8) as usize
+      } else {
+        0
+      };
+      if (constant & 0xFF) + v[register] as u16 > 0xFF {
+        JUMP_PAGE_CYCLES
+      } else {
+        0
+      }
+    }
+    MemoryAddVerToAddress { x_register }
+      if (cpu.i_register() & 0xFF) + v[x_register] as u16 > 0xFF =>
+    {
+      ADD_ADDRESS_PAGE_CYCLES
+    }
+    DisplayDraw {
+      x_register,
+      constant,
+      ..
+    } => draw_cycles(v[x_register], constant),
+    LoadBinaryCodedDecimal { x_register } => {
+      let value = v[x_register] as u32;
+      BCD_SUBTRACT_CYCLES * (value / 100 + (value / 10) % 10 + value % 10)
+    }
+    MemoryDump { x_register } | MemoryLoad { x_register } => {
+      MEMORY_REGISTER_CYCLES * (x_register as u32 + 1)
+    }
+    AudioLoadPattern() => MEMORY_REGISTER_CYCLES * PATTERN_SIZE as u32,
+    _ => 0,
+  };
+  FETCH_CYCLES + timing_entry(instr).cycles + variable
+}
+
+/// draw_cycles returns the variable cycles of DXYN, drawing `rows`
+/// sprite rows at the horizontal position `x`.
+pub fn draw_cycles(x: u8, rows: u8) -> u32 {
+  let shift = (x % 8) as u32;
+  let per_row = if shift == 0 {
+    DRAW_ROW_CYCLES
+  } else {
+    DRAW_ROW_CYCLES + DRAW_STRADDLE_CYCLES + shift * DRAW_SHIFT_CYCLES
+  };
+  per_row * rows as u32
+}
+
+/// Timing is how the Chip8 schedules instructions into frames.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum Timing {
+  /// Every instruction takes the same time, and a fixed number of
+  /// instructions are executed per frame.
+  InstructionsPerFrame,
+  /// Every instruction costs its VIP interpreter machine cycles, and
+  /// each frame has `INTERPRETER_CYCLES_PER_FRAME` machine cycles.
+  CosmacVip,
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use crate::chip8::cpu::state::CpuBuilder;
+  use crate::chip8::instruction::parse_instruction;
+  use crate::chip8::quirks::Quirks;
+
+  #[test]
+  fn table_matches_opcodes() {
+    let opcodes: [u16; 37] = [
+      0x0123, 0x00E0, 0x00EE, 0x1000, 0x2000, 0x3000, 0x4000, 0x5000, 0x6000, 0x7000, 0x8000,
+      0x8001, 0x8002, 0x8003, 0x8004, 0x8005, 0x8006, 0x8007, 0x800E, 0x9000, 0xA000, 0xB000,
+      0xC000, 0xD000, 0xE09E, 0xE0A1, 0xF007, 0xF00A, 0xF015, 0xF018, 0xF01E, 0xF029, 0xF033,
+      0xF055, 0xF065, 0xF002, 0xF03A,
+    ];
+    for (opcode, expected) in opcodes.iter().zip(VIP_TIMING_TABLE.iter()) {
+      let instr = parse_instruction(*opcode).unwrap();
+      assert_eq!(
+        timing_entry(&instr),
+        expected,
+        "Unexpected entry for {:04X}",
+        opcode
+      );
+    }
+  }
+
+  // routine_cycles returns the cycles of `opcode` on `cpu`, without
+  // `FETCH_CYCLES`.
+  fn routine_cycles(opcode: u16, cpu: &Cpu) -> u32 {
+    instruction_cycles(&parse_instruction(opcode).unwrap(), cpu) - FETCH_CYCLES
+  }
+
+  #[test]
+  fn costs_match_the_vip_interpreter() {
+    let cpu = CpuBuilder::new()
+      .v(0, 0x10)
+      .v(1, 0x10)
+      .v(3, 0xFF)
+      .i(0x2F0)
+      .build()
+      .unwrap();
+    let costs: [(u16, u32); 24] = [
+      (0x00E0, 3078),
+      (0x00EE, 10),
+      (0x1234, 12),
+      (0x2234, 26),
+      (0x3010, 14),
+      (0x3011, 10),
+      (0x4010, 10),
+      (0x5010, 18),
+      (0x6012, 6),
+      (0x7012, 10),
+      (0x8010, 12),
+      (0x8014, 44),
+      (0x801E, 44),
+      (0x9010, 14),
+      (0xA123, 12),
+      (0xB210, 22),
+      (0xB2F0, 24),
+      (0xC0FF, 36),
+      (0xF007, 10),
+      (0xF015, 10),
+      (0xF01E, 22),
+      (0xF029, 20),
+      (0xF333, 84 + 16 * (2 + 5 + 5)),
+      (0xF355, 18 + 14 * 4),
+    ];
+    for (opcode, cycles) in costs.iter() {
+      assert_eq!(
+        routine_cycles(*opcode, &cpu),
+        *cycles,
+        "Unexpected cycles for {:04X}",
+        opcode
+      );
+    }
+    assert_eq!(
+      instruction_cycles(&parse_instruction(0x6012).unwrap(), &cpu),
+      74
+    );
+  }
+
+  #[test]
+  fn jumps_cross_pages_with_the_register_they_add() {
+    let mut cpu = CpuBuilder::new().v(1, 0x20).build().unwrap();
+    assert_eq!(routine_cycles(0xB1F0, &cpu), 22);
+    cpu.set_quirks(Quirks {
+      jump_uses_vx: true,
+      ..Quirks::cosmac_vip()
+    });
+    assert_eq!(routine_cycles(0xB1F0, &cpu), 24);
+  }
+
+  #[test]
+  fn skips_cost_more_when_taken() {
+    let cpu = Cpu::new();
+    let taken = parse_instruction(0x3000).unwrap();
+    let not_taken = parse_instruction(0x3001).unwrap();
+    assert_eq!(
+      instruction_cycles(&taken, &cpu),
+      instruction_cycles(&not_taken, &cpu) + SKIP_TAKEN_CYCLES
+    );
+  }
+
+  #[test]
+  fn draw_depends_on_height_and_alignment() {
+    assert_eq!(draw_cycles(8, 0), 0);
+    assert_eq!(draw_cycles(8, 5), 5 * DRAW_ROW_CYCLES);
+    assert!(draw_cycles(8, 5) < draw_cycles(9, 5));
+    assert!(draw_cycles(9, 5) < draw_cycles(15, 5));
+    assert!(draw_cycles(15, 5) < draw_cycles(15, 6));
+  }
+
+  #[test]
+  fn memory_copies_depend_on_register_count() {
+    let cpu = Cpu::new();
+    let one = instruction_cycles(&parse_instruction(0xF055).unwrap(), &cpu);
+    let all = instruction_cycles(&parse_instruction(0xFF65).unwrap(), &cpu);
+    assert_eq!(all - one, 15 * MEMORY_REGISTER_CYCLES);
+  }
+
+  #[test]
+  fn frame_budget_is_consistent() {
+    assert_eq!(
+      VIP_CLOCK_RATE / CLOCKS_PER_MACHINE_CYCLE / MACHINE_CYCLES_PER_FRAME,
+      60
+    );
+  }
+}
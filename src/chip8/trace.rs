@@ -0,0 +1,549 @@
+//! trace provides per-instruction execution traces of the Chip8, and
+//! the comparison of two traces to find where they first diverge.
+//!
+//! Each executed instruction is recorded as one `TraceEntry` holding
+//! the instruction's address and opcode, the register state after it
+//! executed, and the memory it changed. Traces are written as one line
+//! of text per entry, so that they can be stored, diffed and read by
+//! hand:
+//!
+//! ```text
+//!       12 0204 F155 I=0300 SP=0 V=01020000000000000000000000000000 W=0300:01,0301:02
+//! ```
+//!
+//! Comparing the traces of two configurations (such as two quirk
+//! profiles) on the same ROM finds the first instruction whose
+//! behaviour depends on that configuration.
+
+use crate::chip8::chip8::Chip8;
+use crate::chip8::cpu::CpuError;
+use crate::interface::bus::{Access, Bus, Observer};
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+
+/// TraceParseError is returned when a line of a trace can't be read.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum TraceParseError {
+  #[error("Trace line is missing the {field} field")]
+  MissingField { field: &'static str },
+
+  #[error("Trace line has an invalid {field} field: {value:?}")]
+  InvalidField { field: &'static str, value: String },
+}
+
+/// TraceEntry is the record of a single executed instruction.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TraceEntry {
+  /// Number of instructions executed before this one.
+  pub cycle: u64,
+  /// Address of the executed instruction.
+  pub pc: u16,
+  /// Raw bytes of the executed instruction.
+  pub opcode: u16,
+  /// Address (I) register after execution.
+  pub i: u16,
+  /// Stack pointer after execution.
+  pub sp: u8,
+  /// Variable (V0-VF) registers after execution.
+  pub v: [u8; 16],
+  /// Memory written by the instruction, as (address, value) pairs in
+  /// the order written.
+  pub writes: Vec<(u16, u8)>,
+}
+
+impl fmt::Display for TraceEntry {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "{:>8} {:04X} {:04X} I={:04X} SP={:X} V=",
+      self.cycle, self.pc, self.opcode, self.i, self.sp
+    )?;
+    for value in self.v.iter() {
+      write!(f, "{:02X}", value)?;
+    }
+    write!(f, " W=")?;
+    if self.writes.is_empty() {
+      return write!(f, "-");
+    }
+    for (idx, (address, value)) in self.writes.iter().enumerate() {
+      if idx > 0 {
+        write!(f, ",")?;
+      }
+      write!(f, "{:04X}:{:02X}", address, value)?;
+    }
+    Ok(())
+  }
+}
+
+impl FromStr for TraceEntry {
+  type Err = TraceParseError;
+
+  fn from_str(line: &str) -> Result<Self, Self::Err> {
+    let mut fields = line.split_whitespace();
+    let mut next =
+      |field: &'static str| fields.next().ok_or(TraceParseError::MissingField { field });
+
+    let cycle = next("cycle")?;
+    let cycle = cycle.parse().map_err(|_| invalid("cycle", cycle))?;
+    let pc = parse_hex_u16("pc", next("pc")?)?;
+    let opcode = parse_hex_u16("opcode", next("opcode")?)?;
+    let i = parse_hex_u16("I", strip_field("I", next("I")?)?)?;
+    let sp = strip_field("SP", next("SP")?)?;
+    let sp = u8::from_str_radix(sp, 16).map_err(|_| invalid("SP", sp))?;
+
+    let v_field = strip_field("V", next("V")?)?;
+    if v_field.len() != 32 {
+      return Err(invalid("V", v_field));
+    }
+    let mut v = [0u8; 16];
+    for (idx, register) in v.iter_mut().enumerate() {
+      let digits = &v_field[idx * 2..idx * 2 + 2];
+      *register = u8::from_str_radix(digits, 16).map_err(|_| invalid("V", v_field))?;
+    }
+
+    let w_field = strip_field("W", next("W")?)?;
+    let mut writes = Vec::new();
+    if w_field != "-" {
+      for write in w_field.split(',') {
+        let mut parts = write.splitn(2, ':');
+        let address = parts.next().unwrap_or("");
+        let value = parts.next().ok_or_else(|| invalid("W", w_field))?;
+        writes.push((
+          parse_hex_u16("W", address)?,
+          u8::from_str_radix(value, 16).map_err(|_| invalid("W", w_field))?,
+        ));
+      }
+    }
+
+    Ok(TraceEntry {
+      cycle,
+      pc,
+      opcode,
+      i,
+      sp,
+      v,
+      writes,
+    })
+  }
+}
+
+fn invalid(field: &'static str, value: &str) -> TraceParseError {
+  TraceParseError::InvalidField {
+    field,
+    value: String::from(value),
+  }
+}
+
+fn parse_hex_u16(field: &'static str, value: &str) -> Result<u16, TraceParseError> {
+  u16::from_str_radix(value, 16).map_err(|_| invalid(field, value))
+}
+
+// strip_field removes the `NAME=` prefix from a trace field.
+fn strip_field<'a>(field: &'static str, value: &'a str) -> Result<&'a str, TraceParseError> {
+  value
+    .strip_prefix(field)
+    .and_then(|rest| rest.strip_prefix('='))
+    .ok_or_else(|| invalid(field, value))
+}
+
+/// parse_trace reads a trace written as one `TraceEntry` per line.
+/// Blank lines and lines starting with `#` are skipped.
+pub fn parse_trace(text: &str) -> Result<Vec<TraceEntry>, TraceParseError> {
+  text
+    .lines()
+    .map(str::trim)
+    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+    .map(str::parse)
+    .collect()
+}
+
+// Writes holds the writes to memory made by an instruction.
+#[derive(Default)]
+struct Writes(Vec<(u16, u8)>);
+
+impl Observer for Writes {
+  fn access(&mut self, _access: Access, address: u16, value: u8) {
+    self.0.push((address, value))
+  }
+
+  fn observes(&self, access: Access) -> bool {
+    access == Access::Write
+  }
+}
+
+/// record_step will execute a single instruction of `chip8` and return
+/// its trace entry, with `cycle` as the entry's cycle number.
+pub fn record_step(chip8: &mut Chip8, cycle: u64) -> Result<TraceEntry, CpuError> {
+  let cpu = chip8.cpu();
+  let pc = cpu.program_counter();
+  let opcode = cpu.opcode_at(pc);
+
+  let observed = Arc::new(Mutex::new(Writes::default()));
+  // A range can't end past 0xFFFF, so a write to that last byte of
+  // XO-CHIP's memory is the one left out.
+  let id = chip8.bus().observe(0..u16::MAX, observed.clone());
+  let result = chip8.step();
+  chip8.bus().remove_observer(id);
+  result?;
+
+  let cpu = chip8.cpu();
+  let writes = std::mem::take(&mut observed.lock().unwrap().0);
+  Ok(TraceEntry {
+    cycle,
+    pc,
+    opcode,
+    i: cpu.i_register(),
+    sp: cpu.sp(),
+    v: *cpu.v_registers(),
+    writes,
+  })
+}
+
+/// record will execute up to `cycles` instructions of `chip8`, and
+/// returns the recorded trace. Recording stops early on a CPU fault,
+/// which is returned alongside the trace up to that point.
+pub fn record(chip8: &mut Chip8, cycles: u64) -> (Vec<TraceEntry>, Option<CpuError>) {
+  let mut entries = Vec::new();
+  for cycle in 0..cycles {
+    match record_step(chip8, cycle) {
+      Ok(entry) => entries.push(entry),
+      Err(err) => return (entries, Some(err)),
+    }
+  }
+  (entries, None)
+}
+
+/// differing_fields returns the names of the fields that differ between
+/// the two entries, in the order of the trace format. Registers are
+/// named individually (v0-vf).
+pub fn differing_fields(left: &TraceEntry, right: &TraceEntry) -> Vec<String> {
+  let mut fields = Vec::new();
+  if left.pc != right.pc {
+    fields.push(String::from("pc"));
+  }
+  if left.opcode != right.opcode {
+    fields.push(String::from("opcode"));
+  }
+  if left.i != right.i {
+    fields.push(String::from("i"));
+  }
+  if left.sp != right.sp {
+    fields.push(String::from("sp"));
+  }
+  for (idx, (l, r)) in left.v.iter().zip(right.v.iter()).enumerate() {
+    if l != r {
+      fields.push(format!("v{:x}", idx));
+    }
+  }
+  if left.writes != right.writes {
+    fields.push(String::from("writes"));
+  }
+  fields
+}
+
+/// Divergence is the first point at which two traces differ.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence {
+  /// Cycle at which the traces diverge.
+  pub cycle: u64,
+  /// Index of the diverging entry in the left trace, which is the
+  /// length of the trace when it ended early.
+  pub left: usize,
+  /// Index of the diverging entry in the right trace.
+  pub right: usize,
+  /// Names of the differing fields, see `differing_fields`. A trace
+  /// that ended early is reported as the field `end of left trace` or
+  /// `end of right trace`, and one that skipped cycles the other has as
+  /// the field `cycle`.
+  pub fields: Vec<String>,
+}
+
+/// first_divergence lines up the two traces by cycle and returns the
+/// first entry that differs, or None if the traces match. Entries
+/// before the cycle both traces start at are skipped, so that a trace
+/// can be compared with one recorded from later on.
+pub fn first_divergence(left: &[TraceEntry], right: &[TraceEntry]) -> Option<Divergence> {
+  let start = match (left.first(), right.first()) {
+    (Some(l), Some(r)) => l.cycle.max(r.cycle),
+    _ => 0,
+  };
+  let mut l = left.iter().take_while(|entry| entry.cycle < start).count();
+  let mut r = right.iter().take_while(|entry| entry.cycle < start).count();
+  loop {
+    let (cycle, fields) = match (left.get(l), right.get(r)) {
+      (None, None) => return None,
+      (Some(entry), None) => (entry.cycle, vec![String::from("end of right trace")]),
+      (None, Some(entry)) => (entry.cycle, vec![String::from("end of left trace")]),
+      (Some(le), Some(re)) if le.cycle != re.cycle => {
+        (le.cycle.min(re.cycle), vec![String::from("cycle")])
+      }
+      (Some(le), Some(re)) => (le.cycle, differing_fields(le, re)),
+    };
+    if !fields.is_empty() {
+      return Some(Divergence {
+        cycle,
+        left: l,
+        right: r,
+        fields,
+      });
+    }
+    l += 1;
+    r += 1;
+  }
+}
+
+/// format_divergence renders the diverging entries of both traces with
+/// up to `context` matching entries before them.
+pub fn format_divergence(
+  left: &[TraceEntry],
+  right: &[TraceEntry],
+  divergence: &Divergence,
+  context: usize,
+) -> String {
+  let mut report = String::new();
+  let start = divergence.left.saturating_sub(context);
+  for entry in &left[start..divergence.left] {
+    report.push_str(&format!("  {}\n", entry));
+  }
+  let describe = |entry: Option<&TraceEntry>| match entry {
+    Some(entry) => entry.to_string(),
+    None => String::from("<end of trace>"),
+  };
+  report.push_str(&format!("< {}\n", describe(left.get(divergence.left))));
+  report.push_str(&format!("> {}\n", describe(right.get(divergence.right))));
+  report.push_str(&format!("differs in: {}\n", divergence.fields.join(", ")));
+  report
+}
+
+/// Lockstep is the result of `run_lockstep`.
+#[derive(Debug)]
+pub struct Lockstep {
+  /// Number of instructions that executed identically on both sides.
+  pub matched_cycles: u64,
+  /// First divergence, indexing into `left` and `right`.
+  pub divergence: Option<Divergence>,
+  /// Trailing entries of the left side, up to and including the
+  /// divergence.
+  pub left: Vec<TraceEntry>,
+  /// Trailing entries of the right side, up to and including the
+  /// divergence.
+  pub right: Vec<TraceEntry>,
+  /// Fault that stopped the left side, if any.
+  pub left_fault: Option<CpuError>,
+  /// Fault that stopped the right side, if any.
+  pub right_fault: Option<CpuError>,
+}
+
+/// run_lockstep executes `left` and `right` side by side for up to
+/// `cycles` instructions, stopping at the first divergence in state. Up
+/// to `context` matching entries before the divergence are kept.
+///
+/// When both sides fault on the same instruction, the run stops without
+/// a divergence.
+pub fn run_lockstep(left: &mut Chip8, right: &mut Chip8, cycles: u64, context: usize) -> Lockstep {
+  let mut left_entries = VecDeque::with_capacity(context + 1);
+  let mut right_entries = VecDeque::with_capacity(context + 1);
+  let mut lockstep = Lockstep {
+    matched_cycles: 0,
+    divergence: None,
+    left: Vec::new(),
+    right: Vec::new(),
+    left_fault: None,
+    right_fault: None,
+  };
+
+  for cycle in 0..cycles {
+    let left_result = record_step(left, cycle);
+    let right_result = record_step(right, cycle);
+    if left_entries.len() > context {
+      left_entries.pop_front();
+      right_entries.pop_front();
+    }
+    let fields = match (left_result, right_result) {
+      (Ok(l), Ok(r)) => {
+        let fields = differing_fields(&l, &r);
+        left_entries.push_back(l);
+        right_entries.push_back(r);
+        fields
+      }
+      (Err(l), Err(r)) => {
+        lockstep.left_fault = Some(l);
+        lockstep.right_fault = Some(r);
+        break;
+      }
+      (Ok(l), Err(r)) => {
+        left_entries.push_back(l);
+        lockstep.right_fault = Some(r);
+        vec![String::from("end of right trace")]
+      }
+      (Err(l), Ok(r)) => {
+        right_entries.push_back(r);
+        lockstep.left_fault = Some(l);
+        vec![String::from("end of left trace")]
+      }
+    };
+    if !fields.is_empty() {
+      // The diverging entry is the last one of a side that executed it,
+      // and one past the end of a side that faulted.
+      let index =
+        |entries: &VecDeque<TraceEntry>, faulted: bool| entries.len() - if faulted { 0 } else { 1 };
+      lockstep.divergence = Some(Divergence {
+        cycle,
+        left: index(&left_entries, lockstep.left_fault.is_some()),
+        right: index(&right_entries, lockstep.right_fault.is_some()),
+        fields,
+      });
+      break;
+    }
+    lockstep.matched_cycles += 1;
+  }
+
+  lockstep.left = left_entries.into_iter().collect();
+  lockstep.right = right_entries.into_iter().collect();
+  lockstep
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use crate::chip8::quirks::Quirks;
+
+  fn entry(cycle: u64) -> TraceEntry {
+    TraceEntry {
+      cycle,
+      pc: 0x200 + 2 * cycle as u16,
+      opcode: 0x6001,
+      i: 0x300,
+      sp: 1,
+      v: [0; 16],
+      writes: Vec::new(),
+    }
+  }
+
+  #[test]
+  fn format_roundtrips() {
+    let mut with_writes = entry(12);
+    with_writes.v[3] = 0xAB;
+    with_writes.writes = vec![(0x300, 1), (0x301, 0xFF)];
+    for original in [entry(0), with_writes].iter() {
+      let line = original.to_string();
+      let parsed: TraceEntry = line.parse().unwrap();
+      assert_eq!(&parsed, original, "Roundtrip of {:?} failed", line);
+    }
+  }
+
+  #[test]
+  fn parse_rejects_invalid_lines() {
+    assert_eq!(
+      "12 0200".parse::<TraceEntry>(),
+      Err(TraceParseError::MissingField { field: "opcode" })
+    );
+    assert!(matches!(
+      "12 0200 6001 I=0300 SP=1 V=00 W=-".parse::<TraceEntry>(),
+      Err(TraceParseError::InvalidField { field: "V", .. })
+    ));
+  }
+
+  #[test]
+  fn first_divergence_finds_register_difference() {
+    let left = vec![entry(0), entry(1), entry(2)];
+    let mut right = left.clone();
+    right[1].v[0xA] = 1;
+    right[1].i = 0;
+    right[2].pc = 0;
+    assert_eq!(
+      first_divergence(&left, &right),
+      Some(Divergence {
+        cycle: 1,
+        left: 1,
+        right: 1,
+        fields: vec![String::from("i"), String::from("va")],
+      })
+    );
+    assert_eq!(first_divergence(&left, &left), None);
+  }
+
+  #[test]
+  fn first_divergence_lines_up_traces_by_cycle() {
+    let left: Vec<_> = (0..5).map(entry).collect();
+    // Recorded from cycle 2, so it lines up with the left trace's third
+    // entry.
+    let mut right: Vec<_> = (2..5).map(entry).collect();
+    assert_eq!(first_divergence(&left, &right), None);
+
+    right[2].v[0] = 1;
+    let divergence = first_divergence(&left, &right).unwrap();
+    assert_eq!(
+      (divergence.cycle, divergence.left, divergence.right),
+      (4, 4, 2)
+    );
+
+    // A trace that skips a cycle diverges there.
+    right.remove(1);
+    let divergence = first_divergence(&left, &right).unwrap();
+    assert_eq!(
+      (divergence.cycle, divergence.left, divergence.right),
+      (3, 3, 1)
+    );
+    assert_eq!(divergence.fields, vec![String::from("cycle")]);
+  }
+
+  #[test]
+  fn first_divergence_reports_shorter_trace() {
+    let left = vec![entry(0), entry(1)];
+    let right = vec![entry(0)];
+    let divergence = first_divergence(&left, &right).unwrap();
+    assert_eq!((divergence.left, divergence.right), (1, 1));
+    assert_eq!(divergence.fields, vec![String::from("end of right trace")]);
+    let report = format_divergence(&left, &right, &divergence, 3);
+    assert!(report.contains("> <end of trace>"), "{}", report);
+  }
+
+  #[test]
+  fn record_captures_memory_writes() {
+    // V0 = 7; I = 0x300; dump V0
+    let mut chip8 = Chip8::new();
+    chip8
+      .load_program(&[0x60, 0x07, 0xA3, 0x00, 0xF0, 0x55])
+      .unwrap();
+    let (trace, fault) = record(&mut chip8, 3);
+    assert!(fault.is_none());
+    assert_eq!(trace.len(), 3);
+    assert_eq!(trace[2].pc, 0x204);
+    assert_eq!(trace[2].opcode, 0xF055);
+    assert_eq!(trace[2].writes, vec![(0x300, 7)]);
+  }
+
+  #[test]
+  fn record_captures_writes_of_unchanged_values() {
+    // I = 0x300; dump V0, which is 0 as memory is.
+    let mut chip8 = Chip8::new();
+    chip8.load_program(&[0xA3, 0x00, 0xF0, 0x55]).unwrap();
+    let (trace, _) = record(&mut chip8, 2);
+    assert_eq!(trace[0].writes, vec![]);
+    assert_eq!(trace[1].writes, vec![(0x300, 0)]);
+  }
+
+  #[test]
+  fn lockstep_finds_quirk_dependent_instruction() {
+    // VF = 1; V0 = 1; V1 = 2; V0 |= V1; V2 = 3
+    let program = [0x6F, 0x01, 0x60, 0x01, 0x61, 0x02, 0x80, 0x11, 0x62, 0x03];
+    let mut left = Chip8::new();
+    let mut right = Chip8::new();
+    left.load_program(&program).unwrap();
+    right.load_program(&program).unwrap();
+    right.set_quirks(Quirks::cosmac_vip());
+
+    let lockstep = run_lockstep(&mut left, &mut right, 10, 1);
+    let divergence = lockstep.divergence.unwrap();
+    assert_eq!(lockstep.matched_cycles, 3);
+    assert_eq!(divergence.fields, vec![String::from("vf")]);
+    assert_eq!(lockstep.left[divergence.left].opcode, 0x8011);
+    assert_eq!(divergence.cycle, 3);
+    assert_eq!(lockstep.left.len(), 2);
+  }
+}
@@ -0,0 +1,268 @@
+//! tracediff records per-instruction traces of Chip8 ROMs and finds the
+//! first instruction where two traces diverge.
+//!
+//! It has three commands:
+//! * `record`: runs a ROM and writes its trace, one entry per line.
+//! * `diff`: lines up two recorded traces and reports the first
+//!   divergence.
+//! * `compare`: runs a ROM under two configurations side by side and
+//!   stops at the first divergence in state.
+//!
+//! Comparing two quirk profiles on the same ROM is the quickest way to
+//! find which quirk a ROM depends on.
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use rustyemulator::chip8::chip8::Chip8;
+use rustyemulator::chip8::launch::{self, parse_number, parse_quirks, LaunchError, Options};
+use rustyemulator::chip8::quirks::Quirks;
+use rustyemulator::chip8::trace;
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::process;
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum TraceDiffError {
+  #[error(transparent)]
+  Launch(#[from] LaunchError),
+
+  #[error("Failed to read trace {path}: {source}")]
+  Trace {
+    path: String,
+    source: trace::TraceParseError,
+  },
+
+  #[error(transparent)]
+  IOError(#[from] std::io::Error),
+}
+
+// Exit status when the traces diverge, following diff(1).
+const EXIT_DIVERGED: i32 = 1;
+// Exit status when the traces could not be compared.
+const EXIT_ERROR: i32 = 2;
+
+fn main() {
+  let rom_args = || {
+    vec![
+      Arg::with_name("rom")
+        .long("rom")
+        .takes_value(true)
+        .required(true)
+        .help("Sets the ROM file to run, or - to read it from standard input"),
+      Arg::with_name("cycles")
+        .long("cycles")
+        .takes_value(true)
+        .default_value("100000")
+        .help("Sets the maximum number of instructions to execute"),
+      Arg::with_name("seed")
+        .long("seed")
+        .takes_value(true)
+        .default_value("1")
+        .help("Sets the random number generator seed"),
+      Arg::with_name("ipf")
+        .long("ipf")
+        .takes_value(true)
+        .help("Sets the number of instructions per frame"),
+    ]
+  };
+  let context_arg = Arg::with_name("context")
+    .short("C")
+    .long("context")
+    .takes_value(true)
+    .default_value("5")
+    .help("Sets the number of matching instructions shown before the divergence");
+
+  let matches = App::new("Chip8 trace diff")
+    .version("1.0")
+    .author("Joey Pereira <joey@pereira.io>")
+    .about("Finds the first instruction where two Chip8 executions diverge")
+    .setting(AppSettings::SubcommandRequiredElseHelp)
+    .subcommand(
+      SubCommand::with_name("record")
+        .about("Records the trace of a ROM")
+        .args(&rom_args())
+        .arg(
+          Arg::with_name("quirks")
+            .long("quirks")
+            .takes_value(true)
+            .default_value("modern")
+            .help("Sets the quirk profile"),
+        )
+        .arg(
+          Arg::with_name("output")
+            .short("o")
+            .long("output")
+            .takes_value(true)
+            .help("Sets the trace output file, instead of stdout"),
+        ),
+    )
+    .subcommand(
+      SubCommand::with_name("diff")
+        .about("Finds the first divergence between two recorded traces")
+        .arg(Arg::with_name("LEFT").required(true))
+        .arg(Arg::with_name("RIGHT").required(true))
+        .arg(context_arg.clone()),
+    )
+    .subcommand(
+      SubCommand::with_name("compare")
+        .about("Runs a ROM under two configurations and stops at the first divergence")
+        .args(&rom_args())
+        .arg(
+          Arg::with_name("left-quirks")
+            .long("left-quirks")
+            .takes_value(true)
+            .default_value("vip")
+            .help("Sets the quirk profile of the left side"),
+        )
+        .arg(
+          Arg::with_name("right-quirks")
+            .long("right-quirks")
+            .takes_value(true)
+            .default_value("modern")
+            .help("Sets the quirk profile of the right side"),
+        )
+        .arg(context_arg),
+    )
+    .get_matches();
+
+  let result = match matches.subcommand() {
+    ("record", Some(args)) => record(args),
+    ("diff", Some(args)) => diff(args),
+    ("compare", Some(args)) => compare(args),
+    _ => unreachable!("clap requires a subcommand"),
+  };
+  match result {
+    Ok(true) => {}
+    Ok(false) => process::exit(EXIT_DIVERGED),
+    Err(err) => {
+      eprintln!("error: {}", err);
+      process::exit(EXIT_ERROR);
+    }
+  }
+}
+
+// load_chip8 creates a Chip8 with the ROM and settings from `args`.
+fn load_chip8(args: &ArgMatches, quirks: Quirks) -> Result<Chip8, TraceDiffError> {
+  let options = Options {
+    path: String::from(args.value_of("rom").unwrap()),
+    quirks: Some(quirks),
+    instructions_per_frame: args
+      .value_of("ipf")
+      .map(|ipf| parse_number(ipf, "ipf"))
+      .transpose()?,
+    seed: parse_number(args.value_of("seed").unwrap(), "seed")?,
+    ..Options::default()
+  };
+  Ok(launch::load(&options)?.0)
+}
+
+// record writes the trace of a single run. It always succeeds in
+// comparison terms, so returns true.
+fn record(args: &ArgMatches) -> Result<bool, TraceDiffError> {
+  let mut chip8 = load_chip8(args, parse_quirks(args.value_of("quirks").unwrap())?)?;
+  let (entries, fault) = trace::record(
+    &mut chip8,
+    parse_number(args.value_of("cycles").unwrap(), "cycles")?,
+  );
+
+  let mut output: Box<dyn Write> = match args.value_of("output") {
+    Some(path) => Box::new(BufWriter::new(fs::File::create(path)?)),
+    None => Box::new(BufWriter::new(std::io::stdout())),
+  };
+  writeln!(output, "# rom={}", args.value_of("rom").unwrap())?;
+  writeln!(output, "# quirks={}", args.value_of("quirks").unwrap())?;
+  for entry in entries.iter() {
+    writeln!(output, "{}", entry)?;
+  }
+  if let Some(fault) = fault {
+    writeln!(output, "# fault: {}", fault)?;
+  }
+  output.flush()?;
+  Ok(true)
+}
+
+fn read_trace(path: &str) -> Result<Vec<trace::TraceEntry>, TraceDiffError> {
+  trace::parse_trace(&fs::read_to_string(path)?).map_err(|source| TraceDiffError::Trace {
+    path: String::from(path),
+    source,
+  })
+}
+
+// diff compares two recorded traces, and returns whether they match.
+fn diff(args: &ArgMatches) -> Result<bool, TraceDiffError> {
+  let left = read_trace(args.value_of("LEFT").unwrap())?;
+  let right = read_trace(args.value_of("RIGHT").unwrap())?;
+  match trace::first_divergence(&left, &right) {
+    None => {
+      println!("Traces match for {} instructions.", left.len());
+      Ok(true)
+    }
+    Some(divergence) => {
+      println!("Traces diverge at cycle {}:", divergence.cycle);
+      print!(
+        "{}",
+        trace::format_divergence(
+          &left,
+          &right,
+          &divergence,
+          parse_number(args.value_of("context").unwrap(), "context")?
+        )
+      );
+      Ok(false)
+    }
+  }
+}
+
+// compare runs both configurations in lockstep, and returns whether
+// they match.
+fn compare(args: &ArgMatches) -> Result<bool, TraceDiffError> {
+  let left_quirks = parse_quirks(args.value_of("left-quirks").unwrap())?;
+  let right_quirks = parse_quirks(args.value_of("right-quirks").unwrap())?;
+  let mut left = load_chip8(args, left_quirks)?;
+  let mut right = load_chip8(args, right_quirks)?;
+
+  let differences = left_quirks.differences(&right_quirks);
+  if differences.is_empty() {
+    println!("Quirk profiles are identical.");
+  } else {
+    println!("Quirk profiles differ in: {}", differences.join(", "));
+  }
+
+  let lockstep = trace::run_lockstep(
+    &mut left,
+    &mut right,
+    parse_number(args.value_of("cycles").unwrap(), "cycles")?,
+    parse_number(args.value_of("context").unwrap(), "context")?,
+  );
+  for (side, fault) in [
+    ("left", &lockstep.left_fault),
+    ("right", &lockstep.right_fault),
+  ]
+  .iter()
+  {
+    if let Some(fault) = fault {
+      println!("The {} side faulted: {}", side, fault);
+    }
+  }
+  match lockstep.divergence {
+    None => {
+      println!(
+        "Executions match for {} instructions.",
+        lockstep.matched_cycles
+      );
+      Ok(true)
+    }
+    Some(divergence) => {
+      println!(
+        "Executions diverge after {} matching instructions:",
+        lockstep.matched_cycles
+      );
+      print!(
+        "{}",
+        trace::format_divergence(&lockstep.left, &lockstep.right, &divergence, usize::MAX)
+      );
+      Ok(false)
+    }
+  }
+}
@@ -0,0 +1,18 @@
+//! Audio provides the interface between emulated sound hardware and
+//! whatever plays or records it.
+
+/// AudioSink consumes the PCM audio produced by an emulator. Samples
+/// are mono, in the range -1.0 to 1.0, at the sample rate the producer
+/// was configured with.
+pub trait AudioSink {
+  /// push_samples will consume `samples`, which follow on from those
+  /// pushed before.
+  fn push_samples(&mut self, samples: &[f32]);
+}
+
+/// Vec collects the samples, so they can be inspected.
+impl AudioSink for Vec<f32> {
+  fn push_samples(&mut self, samples: &[f32]) {
+    self.extend_from_slice(samples)
+  }
+}
@@ -0,0 +1,247 @@
+//! Bus provides the interface between a CPU and the memory it addresses,
+//! so that whatever needs to see or guard memory accesses, such as
+//! debugger watchpoints, cheats or coverage, can do so through one
+//! mechanism.
+
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+
+/// Access is what a CPU did at an address.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Hash)]
+pub enum Access {
+  /// Read of an instruction to execute.
+  Fetch,
+  Read,
+  Write,
+}
+
+impl Access {
+  // bit returns the bit of the access in a set of accesses.
+  fn bit(self) -> u8 {
+    1 << self as u8
+  }
+}
+
+/// Observer is told of the accesses to the addresses it observes. It is
+/// Send, so that what it is added to can be sent to another thread.
+pub trait Observer: Send {
+  /// access is called after `value` is read from or written to
+  /// `address`.
+  fn access(&mut self, access: Access, address: u16, value: u8);
+
+  /// observes returns whether the observer is told of `access`. It is
+  /// asked once, when the observer is added, so that a Bus need not
+  /// report the accesses no observer wants.
+  fn observes(&self, _access: Access) -> bool {
+    true
+  }
+}
+
+impl<F: FnMut(Access, u16, u8) + Send> Observer for F {
+  fn access(&mut self, access: Access, address: u16, value: u8) {
+    self(access, address, value)
+  }
+}
+
+/// ObserverId identifies an observer added to a Bus, to remove it.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Hash)]
+pub struct ObserverId(usize);
+
+/// BusError is an access a Bus refused.
+#[derive(Error, Debug, Eq, PartialEq, Clone, Copy)]
+pub enum BusError {
+  #[error("Write of {value:#04X} to protected address {address:#05X}")]
+  Protected { address: u16, value: u8 },
+}
+
+/// Bus is the memory a CPU addresses.
+pub trait Bus {
+  /// read returns the byte at `address`, and tells its observers.
+  fn read(&mut self, address: u16) -> u8;
+
+  /// peek returns the byte at `address` without telling its observers,
+  /// for looking at memory rather than the CPU reading it.
+  fn peek(&self, address: u16) -> u8;
+
+  /// write stores `value` at `address`, and tells its observers. Writes
+  /// to protected addresses are refused.
+  fn write(&mut self, address: u16, value: u8) -> Result<(), BusError>;
+
+  /// observe adds `observer` to be told of accesses to `range`. It is
+  /// shared, so that it can be looked at while it observes.
+  fn observe(&mut self, range: Range<u16>, observer: Arc<Mutex<dyn Observer>>) -> ObserverId;
+
+  /// remove_observer removes the observer added as `id`, and returns
+  /// whether there was one.
+  fn remove_observer(&mut self, id: ObserverId) -> bool;
+
+  /// protect refuses writes to `range`, until it is unprotected.
+  fn protect(&mut self, range: Range<u16>);
+
+  /// unprotect allows writes to `range` again.
+  fn unprotect(&mut self, range: Range<u16>);
+}
+
+// An observer, with its id, the range it observes and the bits of the
+// accesses it observes.
+type Observed = (ObserverId, Range<u16>, Arc<Mutex<dyn Observer>>, u8);
+
+/// Watchers holds the observers and protected ranges of a Bus, for
+/// implementations to keep. Clones share the observers.
+#[derive(Clone, Default)]
+pub struct Watchers {
+  observers: Vec<Observed>,
+  // Bits of the accesses any observer observes.
+  observed: u8,
+  // Protected ranges, which do not overlap.
+  protected: Vec<Range<u16>>,
+  next_id: usize,
+}
+
+impl std::fmt::Debug for Watchers {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Watchers")
+      .field("observers", &self.observers.len())
+      .field("protected", &self.protected)
+      .finish()
+  }
+}
+
+impl Watchers {
+  /// is_observed returns whether any observer observes `access`, so
+  /// that accesses no observer wants need not be reported.
+  #[inline]
+  pub fn is_observed(&self, access: Access) -> bool {
+    self.observed & access.bit() != 0
+  }
+
+  /// notify tells the observers of `address` of an access.
+  pub fn notify(&self, access: Access, address: u16, value: u8) {
+    for (_, range, observer, observed) in self.observers.iter() {
+      if observed & access.bit() != 0 && range.contains(&address) {
+        // An observer that panicked is still told of accesses.
+        let mut observer = observer.lock().unwrap_or_else(|err| err.into_inner());
+        observer.access(access, address, value);
+      }
+    }
+  }
+
+  /// is_protected returns whether writes to `address` are refused.
+  #[inline]
+  pub fn is_protected(&self, address: u16) -> bool {
+    self.protected.iter().any(|range| range.contains(&address))
+  }
+
+  pub fn observe(&mut self, range: Range<u16>, observer: Arc<Mutex<dyn Observer>>) -> ObserverId {
+    let id = ObserverId(self.next_id);
+    self.next_id += 1;
+    let observed = {
+      let observer = observer.lock().unwrap_or_else(|err| err.into_inner());
+      [Access::Fetch, Access::Read, Access::Write]
+        .iter()
+        .filter(|access| observer.observes(**access))
+        .fold(0, |bits, access| bits | access.bit())
+    };
+    self.observed |= observed;
+    self.observers.push((id, range, observer, observed));
+    id
+  }
+
+  pub fn remove_observer(&mut self, id: ObserverId) -> bool {
+    let count = self.observers.len();
+    self.observers.retain(|(observer, _, _, _)| *observer != id);
+    self.observed = self
+      .observers
+      .iter()
+      .fold(0, |bits, (_, _, _, observed)| bits | observed);
+    self.observers.len() != count
+  }
+
+  pub fn protect(&mut self, range: Range<u16>) {
+    self.unprotect(range.clone());
+    if !range.is_empty() {
+      self.protected.push(range);
+    }
+  }
+
+  pub fn unprotect(&mut self, range: Range<u16>) {
+    let mut protected = Vec::new();
+    for other in self.protected.drain(..) {
+      // Keep the parts of `other` either side of `range`.
+      let before = other.start..other.end.min(range.start);
+      let after = other.start.max(range.end)..other.end;
+      protected.extend([before, after].iter().filter(|r| !r.is_empty()).cloned());
+    }
+    self.protected = protected;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn observers_see_accesses_in_their_range() {
+    let mut watchers = Watchers::default();
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let log = seen.clone();
+    let id = watchers.observe(
+      0x300..0x302,
+      Arc::new(Mutex::new(move |access, address, value| {
+        log.lock().unwrap().push((access, address, value))
+      })),
+    );
+    watchers.notify(Access::Write, 0x300, 1);
+    watchers.notify(Access::Read, 0x302, 2);
+    watchers.notify(Access::Read, 0x301, 3);
+    assert_eq!(
+      *seen.lock().unwrap(),
+      vec![(Access::Write, 0x300, 1), (Access::Read, 0x301, 3)]
+    );
+
+    assert!(watchers.remove_observer(id));
+    assert!(!watchers.remove_observer(id));
+    assert!(!watchers.is_observed(Access::Read));
+  }
+
+  // Writes counts the writes it is told of, and observes nothing else.
+  #[derive(Default)]
+  struct Writes(usize);
+
+  impl Observer for Writes {
+    fn access(&mut self, _access: Access, _address: u16, _value: u8) {
+      self.0 += 1
+    }
+
+    fn observes(&self, access: Access) -> bool {
+      access == Access::Write
+    }
+  }
+
+  #[test]
+  fn observers_are_only_told_of_the_accesses_they_observe() {
+    let mut watchers = Watchers::default();
+    let writes = Arc::new(Mutex::new(Writes::default()));
+    watchers.observe(0x000..0x1000, writes.clone());
+    assert!(watchers.is_observed(Access::Write));
+    assert!(!watchers.is_observed(Access::Read));
+    assert!(!watchers.is_observed(Access::Fetch));
+    watchers.notify(Access::Read, 0x300, 1);
+    watchers.notify(Access::Write, 0x300, 1);
+    assert_eq!(writes.lock().unwrap().0, 1);
+  }
+
+  #[test]
+  fn protected_ranges_can_be_split() {
+    let mut watchers = Watchers::default();
+    watchers.protect(0x000..0x200);
+    watchers.unprotect(0x050..0x060);
+    assert!(watchers.is_protected(0x000));
+    assert!(!watchers.is_protected(0x050));
+    assert!(!watchers.is_protected(0x05F));
+    assert!(watchers.is_protected(0x060));
+    assert!(watchers.is_protected(0x1FF));
+    assert!(!watchers.is_protected(0x200));
+  }
+}
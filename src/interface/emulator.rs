@@ -10,6 +10,10 @@
 /// This is also limited to only be plausible for systems which have
 /// well-understood clock behaviour for components.
 pub trait Clocked {
+  /// Fault is what stops the component running, such as an instruction
+  /// it can't execute.
+  type Fault;
+
   /// clock will execute a single clock cycled for the Clocked component.
   ///
   // TODO(joey): Consider returning a Result.
@@ -17,5 +21,27 @@ pub trait Clocked {
 
   /// clock_rate returns the clock rate, in hertz, for the Clocked
   /// component.
-  fn clock_rate(self) -> u32;
+  fn clock_rate(&self) -> u32;
+
+  /// busy_cycles returns the clock cycles left of the operation in
+  /// progress, such as an instruction taking several cycles, during
+  /// which clocking the component only waits for it to finish.
+  /// Components whose operations take a single cycle are never busy.
+  fn busy_cycles(&self) -> u32 {
+    0
+  }
+
+  /// run_cycles executes `cycles` clock cycles, and returns the number
+  /// it executed. Operations are not split, so it can run over by the
+  /// rest of the last operation it started, and it runs fewer if the
+  /// component faults, returning the fault with them.
+  ///
+  /// Components that can run many cycles faster than clocking each one
+  /// should implement it themselves.
+  fn run_cycles(&mut self, cycles: u32) -> (u32, Result<(), Self::Fault>) {
+    for _ in 0..cycles {
+      self.clock();
+    }
+    (cycles, Ok(()))
+  }
 }
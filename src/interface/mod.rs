@@ -1,4 +1,8 @@
 //! Interface provides varioud common emulator interfaces.
 
+pub mod audio;
+pub mod bus;
 pub mod emulator;
 pub mod serialization;
+pub mod system;
+pub mod video;
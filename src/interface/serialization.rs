@@ -117,6 +117,18 @@ impl Savable for u64 {
   }
 }
 
+impl Savable for f32 {
+  fn save(&self, fh: &mut dyn Write) -> Result<()> {
+    self.to_bits().save(fh)
+  }
+  fn load(&mut self, fh: &mut dyn Read) -> Result<()> {
+    let mut bits = 0u32;
+    bits.load(fh)?;
+    *self = f32::from_bits(bits);
+    Ok(())
+  }
+}
+
 impl Savable for usize {
   fn save(&self, fh: &mut dyn Write) -> Result<()> {
     (*self as u64).save(fh)
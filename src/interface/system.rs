@@ -0,0 +1,48 @@
+//! System provides the interface to a whole emulated machine, so that
+//! frontends, recorders, rewind and netplay can be written once for any
+//! machine rather than against the internals of one.
+
+use crate::interface::audio::AudioSink;
+use crate::interface::video::Framebuffer;
+
+/// System is an emulated machine, run a frame at a time.
+pub trait System {
+  /// Rom is the program the System runs.
+  type Rom;
+  /// Error is a fault raised while loading or running a program.
+  type Error: std::error::Error;
+
+  /// reset returns the machine to power on, with the program last
+  /// loaded, keeping its configuration.
+  fn reset(&mut self);
+
+  /// load_rom replaces the program with `rom`, and resets the machine to
+  /// run it.
+  fn load_rom(&mut self, rom: &Self::Rom) -> Result<(), Self::Error>;
+
+  /// run_frame will run the machine until the end of the current frame.
+  fn run_frame(&mut self) -> Result<(), Self::Error>;
+
+  /// frame_rate returns the frames run each second.
+  fn frame_rate(&self) -> u32;
+
+  /// framebuffer returns what the display shows.
+  fn framebuffer(&self) -> Framebuffer<'_>;
+
+  /// sample_rate returns the audio samples produced each second.
+  fn sample_rate(&self) -> u32;
+
+  /// push_audio pushes the samples of the frame just run to `sink`. It
+  /// is called once after each frame.
+  fn push_audio(&mut self, sink: &mut dyn AudioSink);
+
+  /// input_count returns the number of inputs, such as keys or buttons,
+  /// which are numbered from zero.
+  fn input_count(&self) -> usize;
+
+  /// set_input presses or holds `input`.
+  fn set_input(&mut self, input: usize);
+
+  /// clear_input releases `input`.
+  fn clear_input(&mut self, input: usize);
+}
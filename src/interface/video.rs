@@ -0,0 +1,98 @@
+//! Video provides the interface between emulated displays and whatever
+//! shows or records them.
+
+/// Frame is a picture of a monochrome display. Its pixels are in rows
+/// from the top left, and are true where lit.
+#[derive(Clone, Copy, Debug)]
+pub struct Frame<'a> {
+  pub width: usize,
+  pub height: usize,
+  pub pixels: &'a [bool],
+}
+
+impl<'a> Frame<'a> {
+  /// pixel returns whether the pixel at `x`, `y` is lit.
+  pub fn pixel(&self, x: usize, y: usize) -> bool {
+    self.pixels[y * self.width + x]
+  }
+}
+
+/// PixelFormat is how the pixels of a Framebuffer are stored.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Hash)]
+pub enum PixelFormat {
+  /// One bool for each pixel, true where lit.
+  Monochrome,
+  /// Three bytes for each pixel: red, green and blue.
+  Rgb24,
+}
+
+/// Pixels are the pixels of a Framebuffer, in rows from the top left.
+#[derive(Clone, Copy, Debug)]
+pub enum Pixels<'a> {
+  Monochrome(&'a [bool]),
+  Rgb24(&'a [u8]),
+}
+
+/// Framebuffer is the picture a System shows, in whichever pixel format
+/// its display has.
+#[derive(Clone, Copy, Debug)]
+pub struct Framebuffer<'a> {
+  pub width: usize,
+  pub height: usize,
+  pub pixels: Pixels<'a>,
+}
+
+impl<'a> Framebuffer<'a> {
+  pub fn format(&self) -> PixelFormat {
+    match self.pixels {
+      Pixels::Monochrome(_) => PixelFormat::Monochrome,
+      Pixels::Rgb24(_) => PixelFormat::Rgb24,
+    }
+  }
+
+  /// rgb returns the colour of the pixel at `x`, `y`. Lit monochrome
+  /// pixels are white, and unlit ones black.
+  pub fn rgb(&self, x: usize, y: usize) -> [u8; 3] {
+    let index = y * self.width + x;
+    match self.pixels {
+      Pixels::Monochrome(pixels) if pixels[index] => [0xFF; 3],
+      Pixels::Monochrome(_) => [0; 3],
+      Pixels::Rgb24(pixels) => [
+        pixels[index * 3],
+        pixels[index * 3 + 1],
+        pixels[index * 3 + 2],
+      ],
+    }
+  }
+
+  /// frame returns the framebuffer as a Frame, for frame sinks, if it is
+  /// monochrome.
+  pub fn frame(&self) -> Option<Frame<'a>> {
+    match self.pixels {
+      Pixels::Monochrome(pixels) => Some(Frame {
+        width: self.width,
+        height: self.height,
+        pixels,
+      }),
+      Pixels::Rgb24(_) => None,
+    }
+  }
+}
+
+impl<'a> From<Frame<'a>> for Framebuffer<'a> {
+  fn from(frame: Frame<'a>) -> Self {
+    Framebuffer {
+      width: frame.width,
+      height: frame.height,
+      pixels: Pixels::Monochrome(frame.pixels),
+    }
+  }
+}
+
+/// FrameSink consumes the frames produced by an emulator, one for each
+/// frame it runs, at the frame rate the consumer was configured with.
+pub trait FrameSink {
+  /// push_frame will consume `frame`, which follows on from those
+  /// pushed before.
+  fn push_frame(&mut self, frame: &Frame);
+}
@@ -1,2 +1,4 @@
+pub mod cdp1802;
 pub mod chip8;
 pub mod interface;
+pub mod vip;
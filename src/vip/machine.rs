@@ -0,0 +1,467 @@
+//! machine provides the emulation of the whole RCA COSMAC VIP, as an
+//! accuracy reference for the high-level `chip8::Chip8`.
+//!
+//! The VIP is a CDP1802 with 2 KiB or 4 KiB of RAM, a 512 byte monitor
+//! ROM, a CDP1861 for video, a hexadecimal keypad and a tone generator.
+//! CHIP-8 is not built in: it is an interpreter program loaded into RAM
+//! at 0x0000, which the monitor runs on reset unless key C is held.
+//! Neither the monitor nor the interpreter are included, so images of
+//! them must be supplied.
+//!
+//! The VIP's I/O is:
+//! * INP 1 (69) turns the display on and OUT 1 (61) turns it off.
+//! * OUT 2 (62) latches the keypad key to scan, and EF3 is asserted
+//!   while that key is held.
+//! * EF1 is the CDP1861's display status.
+//! * Q turns the tone generator on.
+//!
+//! References include:
+//! * RCA VP-111 COSMAC VIP Instruction Manual.
+
+use crate::cdp1802::bus::Bus;
+use crate::cdp1802::cpu::{Cdp1802, CLOCKS_PER_MACHINE_CYCLE, VIP_CLOCK_RATE};
+use crate::chip8::keypad::Keypad;
+use crate::interface::emulator::Clocked;
+use crate::interface::serialization::Savable;
+use crate::vip::pixie::Cdp1861;
+use std::convert::Infallible;
+use thiserror::Error;
+
+/// Size, in bytes, of the monitor ROM.
+pub const MONITOR_SIZE: usize = 512;
+/// Address where the monitor ROM is mapped, mirrored up to 0xFFFF.
+pub const MONITOR_ADDRESS: u16 = 0x8000;
+/// Address where the CHIP-8 interpreter is loaded.
+pub const INTERPRETER_ADDRESS: u16 = 0x0000;
+/// Address where CHIP-8 programs are loaded.
+pub const PROGRAM_ADDRESS: u16 = 0x0200;
+
+/// VipError is an error configuring the VIP.
+#[derive(Error, Debug)]
+pub enum VipError {
+  #[error("Monitor image of {size} bytes is larger than the {MONITOR_SIZE} byte ROM")]
+  MonitorTooLarge { size: usize },
+
+  #[error("Unsupported RAM size of {size} bytes, expected 2048 or 4096")]
+  RamSize { size: usize },
+
+  #[error("Image of {size} bytes at {address:#06X} does not fit in RAM")]
+  ImageTooLarge { address: u16, size: usize },
+}
+
+/// VipBus is everything the VIP's CDP1802 is connected to.
+#[derive(Clone, Debug)]
+pub struct VipBus {
+  // RAM, mirrored up to 0x7FFF.
+  ram: Vec<u8>,
+  // Monitor ROM, which is configuration and not saved.
+  monitor: [u8; MONITOR_SIZE],
+  // Maps the monitor ROM over RAM after reset, until the first access
+  // with A15 set.
+  monitor_overlay: bool,
+  pixie: Cdp1861,
+  keypad: Keypad,
+  // Key scanned by EF3, latched by OUT 2.
+  keypad_latch: u8,
+}
+
+impl Savable for VipBus {
+  fn save(&self, fh: &mut dyn std::io::Write) -> std::io::Result<()> {
+    self.ram.save(fh)?;
+    self.monitor_overlay.save(fh)?;
+    self.pixie.save(fh)?;
+    self.keypad.save(fh)?;
+    self.keypad_latch.save(fh)?;
+    Ok(())
+  }
+  fn load(&mut self, fh: &mut dyn std::io::Read) -> std::io::Result<()> {
+    self.ram.load(fh)?;
+    self.monitor_overlay.load(fh)?;
+    self.pixie.load(fh)?;
+    self.keypad.load(fh)?;
+    self.keypad_latch.load(fh)?;
+    Ok(())
+  }
+}
+
+impl Bus for VipBus {
+  fn read(&mut self, address: u16) -> u8 {
+    if address & MONITOR_ADDRESS != 0 {
+      self.monitor_overlay = false;
+      self.monitor[address as usize % MONITOR_SIZE]
+    } else if self.monitor_overlay {
+      self.monitor[address as usize % MONITOR_SIZE]
+    } else {
+      self.ram[address as usize % self.ram.len()]
+    }
+  }
+
+  fn write(&mut self, address: u16, value: u8) {
+    if address & MONITOR_ADDRESS != 0 {
+      self.monitor_overlay = false;
+      return;
+    }
+    let index = address as usize % self.ram.len();
+    self.ram[index] = value
+  }
+
+  fn output(&mut self, port: u8, value: u8) {
+    match port {
+      1 => self.pixie.set_enabled(false),
+      2 => self.keypad_latch = value & 0xF,
+      _ => {}
+    }
+  }
+
+  fn input(&mut self, port: u8) -> u8 {
+    if port == 1 {
+      self.pixie.set_enabled(true)
+    }
+    0
+  }
+
+  fn flag(&mut self, flag: u8) -> bool {
+    match flag {
+      1 => self.pixie.ef1(),
+      3 => self.keypad.is_pressed(self.keypad_latch as usize),
+      _ => false,
+    }
+  }
+}
+
+/// Vip is an emulation component for the COSMAC VIP.
+#[derive(Clone, Debug)]
+pub struct Vip {
+  cpu: Cdp1802<VipBus>,
+}
+
+impl Savable for Vip {
+  fn save(&self, fh: &mut dyn std::io::Write) -> std::io::Result<()> {
+    self.cpu.save(fh)?;
+    self.cpu.bus().save(fh)?;
+    Ok(())
+  }
+  fn load(&mut self, fh: &mut dyn std::io::Read) -> std::io::Result<()> {
+    self.cpu.load(fh)?;
+    self.cpu.bus_mut().load(fh)?;
+    Ok(())
+  }
+}
+
+impl Clocked for Vip {
+  type Fault = Infallible;
+
+  /// clock executes one machine cycle. The CPU is stalled during the
+  /// CDP1861's DMA cycles.
+  fn clock(&mut self) {
+    let cycle = self.cpu.bus().pixie.cycle();
+    self.cpu.set_interrupt_line(cycle.interrupt);
+    if cycle.dma {
+      let value = self.cpu.dma_out();
+      self.cpu.bus_mut().pixie.dma(value);
+    } else {
+      self.cpu.clock();
+    }
+    self.cpu.bus_mut().pixie.advance();
+  }
+
+  fn clock_rate(&self) -> u32 {
+    VIP_CLOCK_RATE / CLOCKS_PER_MACHINE_CYCLE
+  }
+}
+
+impl Vip {
+  /// new returns a VIP with `ram_size` bytes of RAM (2048 or 4096) and
+  /// the monitor ROM image `monitor`, in its reset state.
+  pub fn new(ram_size: usize, monitor: &[u8]) -> Result<Self, VipError> {
+    if ram_size != 2048 && ram_size != 4096 {
+      return Err(VipError::RamSize { size: ram_size });
+    }
+    if monitor.len() > MONITOR_SIZE {
+      return Err(VipError::MonitorTooLarge {
+        size: monitor.len(),
+      });
+    }
+    let mut rom = [0; MONITOR_SIZE];
+    rom[..monitor.len()].copy_from_slice(monitor);
+    let bus = VipBus {
+      ram: vec![0; ram_size],
+      monitor: rom,
+      monitor_overlay: true,
+      pixie: Cdp1861::new(),
+      keypad: Keypad::new(),
+      keypad_latch: 0,
+    };
+    Ok(Vip {
+      cpu: Cdp1802::new(bus),
+    })
+  }
+
+  /// with_chip8 returns a 4 KiB VIP that boots into the CHIP-8
+  /// interpreter image `interpreter`, running `program`.
+  pub fn with_chip8(monitor: &[u8], interpreter: &[u8], program: &[u8]) -> Result<Self, VipError> {
+    let mut vip = Vip::new(4096, monitor)?;
+    vip.load_image(INTERPRETER_ADDRESS, interpreter)?;
+    vip.load_image(PROGRAM_ADDRESS, program)?;
+    Ok(vip)
+  }
+
+  /// load_image copies `image` into RAM at `address`.
+  pub fn load_image(&mut self, address: u16, image: &[u8]) -> Result<(), VipError> {
+    let ram = &mut self.cpu.bus_mut().ram;
+    let start = address as usize;
+    if start + image.len() > ram.len() {
+      return Err(VipError::ImageTooLarge {
+        address,
+        size: image.len(),
+      });
+    }
+    ram[start..start + image.len()].copy_from_slice(image);
+    Ok(())
+  }
+
+  /// reset resets the CPU and the CDP1861, and maps the monitor ROM
+  /// over RAM so that it runs. RAM is left unchanged.
+  pub fn reset(&mut self) {
+    self.cpu.reset();
+    let bus = self.cpu.bus_mut();
+    bus.monitor_overlay = true;
+    bus.pixie.reset();
+  }
+
+  pub fn cpu(&self) -> &Cdp1802<VipBus> {
+    &self.cpu
+  }
+
+  pub fn ram(&self) -> &[u8] {
+    &self.cpu.bus().ram
+  }
+
+  pub fn pixie(&self) -> &Cdp1861 {
+    &self.cpu.bus().pixie
+  }
+
+  /// pixel returns whether the pixel at (`x`, `y`) is lit, on the
+  /// CDP1861's 64x128 display.
+  pub fn pixel(&self, x: usize, y: usize) -> bool {
+    self.pixie().pixel(x, y)
+  }
+
+  /// tone returns whether the tone generator is sounding.
+  pub fn tone(&self) -> bool {
+    self.cpu.registers().q
+  }
+
+  pub fn press_key(&mut self, key: usize) {
+    self.cpu.bus_mut().keypad.press(key)
+  }
+
+  pub fn release_key(&mut self, key: usize) {
+    self.cpu.bus_mut().keypad.release(key)
+  }
+
+  /// run_frame executes machine cycles until the CDP1861 completes a
+  /// frame.
+  pub fn run_frame(&mut self) {
+    let frames = self.pixie().frames();
+    while self.pixie().frames() == frames {
+      self.clock();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use crate::chip8::chip8::Chip8;
+  use crate::vip::pixie::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+
+  // MONITOR clears the ROM overlay by jumping to the ROM at 0x8008, and
+  // then runs RAM from 0x0000 with P = 0, as the VIP monitor does when
+  // key C is not held.
+  const MONITOR: [u8; 13] = [
+    0xF8, 0x80, 0xB2, 0xF8, 0x08, 0xA2, 0xD2, 0x00, // R2 = 0x8008; SEP R2
+    0xF8, 0x00, 0xB0, 0xA0, 0xD0, // R0 = 0; SEP R0
+  ];
+
+  // display_program turns on the display, with an interrupt routine at
+  // 0x40 that points R0 at 0x100 each frame. R0 is the DMA pointer, so
+  // the program runs with R3 as its program counter. The routine waits
+  // for EF1 at the end of the display before returning, as the
+  // interrupt is still requested when the display starts.
+  fn display_program() -> Vec<u8> {
+    let mut program = vec![0; 0x60];
+    program[..0x07].copy_from_slice(&[
+      0xF8, 0x00, 0xB3, 0xF8, 0x10, 0xA3, 0xD3, // R3 = 0x0010; SEP R3
+    ]);
+    program[0x10..0x20].copy_from_slice(&[
+      0xF8, 0x00, 0xB1, 0xF8, 0x42, 0xA1, // R1 = 0x0042
+      0xF8, 0x07, 0xB2, 0xF8, 0xFF, 0xA2, // R2 = 0x07FF
+      0xE2, 0x69, // SEX R2; INP 1
+      0x30, 0x1E, // BR 1E
+    ]);
+    program[0x40..0x52].copy_from_slice(&[
+      0x72, 0x70, // exit: LDXA; RET
+      0x22, 0x78, 0x22, 0x52, // entry: DEC R2; SAV; DEC R2; STR R2
+      0xF8, 0x01, 0xB0, 0xF8, 0x00, 0xA0, // R0 = 0x0100
+      0x34, 0x4C, 0x3C, 0x4E, // B1 4C; BN1 4E
+      0x30, 0x40, // BR exit
+    ]);
+    program
+  }
+
+  #[test]
+  fn serialization_roundtrip_works() -> std::io::Result<()> {
+    let mut vip = Vip::new(2048, &MONITOR).unwrap();
+    vip.load_image(0, &display_program()).unwrap();
+    for _ in 0..1000 {
+      vip.clock();
+    }
+    let buf = &mut Vec::new();
+    vip.save(buf)?;
+    let mut loaded = Vip::new(2048, &MONITOR).unwrap();
+    loaded.load(&mut buf.as_slice())?;
+    assert_eq!(vip.cpu().registers(), loaded.cpu().registers());
+    assert_eq!(vip.ram(), loaded.ram());
+    assert_eq!(vip.pixie(), loaded.pixie());
+    Ok(())
+  }
+
+  #[test]
+  fn monitor_runs_ram_after_reset() {
+    // LDI 42; IDL
+    let mut vip = Vip::new(2048, &MONITOR).unwrap();
+    vip.load_image(0, &[0xF8, 0x42, 0x00]).unwrap();
+    for _ in 0..100 {
+      vip.clock();
+    }
+    assert_eq!(vip.cpu().registers().d, 0x42);
+    assert!(vip.cpu().registers().idle);
+  }
+
+  #[test]
+  fn rejects_bad_configuration() {
+    assert!(matches!(
+      Vip::new(1024, &MONITOR),
+      Err(VipError::RamSize { size: 1024 })
+    ));
+    assert!(matches!(
+      Vip::new(2048, &[0; 513]),
+      Err(VipError::MonitorTooLarge { .. })
+    ));
+    let mut vip = Vip::new(2048, &MONITOR).unwrap();
+    assert!(matches!(
+      vip.load_image(0x700, &[0; 0x101]),
+      Err(VipError::ImageTooLarge { .. })
+    ));
+  }
+
+  #[test]
+  fn keypad_latch_drives_ef3() {
+    // R1 = 0x20; SEX R1; OUT 2 (key 5); B3 09; IDL; (09) SEQ; IDL
+    let mut vip = Vip::new(2048, &MONITOR).unwrap();
+    vip
+      .load_image(
+        0,
+        &[
+          0xF8, 0x20, 0xA1, 0xE1, 0x62, 0x36, 0x09, 0x00, 0x00, 0x7B, 0x00,
+        ],
+      )
+      .unwrap();
+    vip.load_image(0x20, &[0x05]).unwrap();
+    vip.press_key(5);
+    for _ in 0..100 {
+      vip.clock();
+    }
+    assert!(vip.tone());
+  }
+
+  #[test]
+  fn interrupt_routine_displays_ram() {
+    // The routine points the DMA at 0x100, which holds the display one
+    // line of 8 bytes after another. Light its first pixel, one in the
+    // middle, and its last.
+    let mut vip = Vip::new(2048, &MONITOR).unwrap();
+    vip.load_image(0, &display_program()).unwrap();
+    vip.load_image(0x100, &[0x80]).unwrap();
+    vip.load_image(0x100 + 64 * 8 + 3, &[0x10]).unwrap();
+    vip.load_image(0x4FF, &[0x01]).unwrap();
+    for _ in 0..3 {
+      vip.run_frame();
+    }
+    let lit: Vec<_> = (0..DISPLAY_HEIGHT)
+      .flat_map(|y| (0..DISPLAY_WIDTH).map(move |x| (x, y)))
+      .filter(|&(x, y)| vip.pixel(x, y))
+      .collect();
+    assert_eq!(lit, vec![(0, 0), (27, 64), (63, 127)]);
+  }
+
+  // interpreter_images reads the monitor ROM and the CHIP-8 interpreter
+  // from the paths in VIP_MONITOR and VIP_CHIP8.
+  fn interpreter_images() -> (Vec<u8>, Vec<u8>) {
+    let read = |var: &str| {
+      let path = std::env::var(var).unwrap_or_else(|_| panic!("{} is not set", var));
+      std::fs::read(&path).unwrap_or_else(|err| panic!("reading {}: {}", path, err))
+    };
+    (read("VIP_MONITOR"), read("VIP_CHIP8"))
+  }
+
+  // assert_interpreter_matches_chip8 runs `program` for `frames` frames
+  // on the original interpreter and on Chip8 with the VIP quirks, and
+  // compares their displays. The interpreter shows each Chip8 row as 4
+  // lines.
+  fn assert_interpreter_matches_chip8(program: &[u8], frames: usize) {
+    let (monitor, interpreter) = interpreter_images();
+    let mut chip8 = Chip8::new();
+    chip8.set_quirks(crate::chip8::quirks::Quirks::cosmac_vip());
+    chip8.load_program(program).unwrap();
+    let mut vip = Vip::with_chip8(&monitor, &interpreter, program).unwrap();
+    for _ in 0..frames {
+      chip8.run_frame().unwrap();
+      vip.run_frame();
+    }
+    for y in 0..32 {
+      for x in 0..DISPLAY_WIDTH {
+        assert_eq!(
+          vip.pixel(x, y * 4),
+          chip8.display().pixel(x, y),
+          "pixel ({}, {})",
+          x,
+          y
+        );
+      }
+    }
+  }
+
+  // with_loop appends a jump to itself to `program`, so that it stops
+  // once it is done.
+  fn with_loop(mut program: Vec<u8>) -> Vec<u8> {
+    let end = PROGRAM_ADDRESS as usize + program.len();
+    program.extend_from_slice(&[0x10 | (end >> 8) as u8, end as u8]);
+    program
+  }
+
+  #[test]
+  #[ignore = "needs the VIP_MONITOR and VIP_CHIP8 images"]
+  fn display_matches_chip8() {
+    // Draws the font's 0 and 8 at (0, 0) and (12, 3).
+    let program = with_loop(vec![
+      0x60, 0x00, 0xF0, 0x29, 0xD0, 0x05, // V0 = 0; I = font 0; draw
+      0x60, 0x0C, 0x61, 0x03, 0x62, 0x08, // V0 = 12; V1 = 3; V2 = 8
+      0xF2, 0x29, 0xD0, 0x15, // I = font 8; draw
+    ]);
+    assert_interpreter_matches_chip8(&program, 10);
+  }
+
+  #[test]
+  #[ignore = "needs the VIP_MONITOR and VIP_CHIP8 images"]
+  fn original_interpreter_matches_chip8() {
+    // Draws the font's 0-7 across the display.
+    let mut program = Vec::new();
+    for digit in 0..8u8 {
+      program.extend_from_slice(&[0x60, digit, 0xF0, 0x29, 0x61, digit * 8, 0xD1, 0x25]);
+    }
+    assert_interpreter_matches_chip8(&with_loop(program), 30);
+  }
+}
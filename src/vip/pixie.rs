@@ -0,0 +1,261 @@
+//! pixie provides the emulation of the RCA CDP1861 video display
+//! controller, nicknamed the "Pixie", as used in the COSMAC VIP.
+//!
+//! The CDP1861 does not have video memory of its own. Each frame, it
+//! interrupts the CPU shortly before the visible area, and then steals
+//! 8 output DMA cycles for every visible line to fetch that line's 64
+//! pixels from memory at R0. Where the pixels come from is up to the
+//! interrupt routine, which sets R0.
+//!
+//! The interrupt is requested until the first DMA cycle, and the 1802
+//! takes interrupts whenever they are enabled, so interrupt routines
+//! must not return before the display starts. The VIP's routines keep
+//! running for the whole display, resetting R0 to repeat lines.
+//!
+//! References include:
+//! * RCA CDP1861 datasheet.
+//! * MAME's cdp1861 device.
+
+use crate::interface::serialization::Savable;
+
+/// Width, in pixels, of the display.
+pub const DISPLAY_WIDTH: usize = 64;
+/// Height, in lines, of the visible area of the display.
+pub const DISPLAY_HEIGHT: usize = 128;
+/// Machine cycles per line.
+pub const CYCLES_PER_LINE: u32 = 14;
+/// Lines per frame, which is 60hz at the VIP's clock rate.
+pub const LINES_PER_FRAME: u32 = 262;
+/// Machine cycles per frame.
+pub const CYCLES_PER_FRAME: u32 = CYCLES_PER_LINE * LINES_PER_FRAME;
+/// First visible line.
+pub const DISPLAY_START_LINE: u32 = 80;
+/// Machine cycle in each visible line of the first of its 8 DMA cycles.
+pub const DMA_START_CYCLE: u32 = 6;
+/// Machine cycles the interrupt is requested for before the first DMA
+/// cycle of the frame.
+pub const INTERRUPT_CYCLES: u32 = 29;
+/// Lines for which EF1 is asserted before the start and before the end
+/// of the visible area.
+pub const EF1_LINES: u32 = 4;
+
+// Bytes, of 8 pixels each, fetched for each line.
+const BYTES_PER_LINE: usize = DISPLAY_WIDTH / 8;
+// Frame cycle of the first DMA cycle.
+const DISPLAY_START_CYCLE: u32 = DISPLAY_START_LINE * CYCLES_PER_LINE + DMA_START_CYCLE;
+
+/// Cycle is what the CDP1861 does in a machine cycle.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Hash)]
+pub struct Cycle {
+  /// Level of the interrupt request.
+  pub interrupt: bool,
+  /// Whether the cycle is an output DMA cycle, whose byte is passed to
+  /// `Cdp1861::dma`.
+  pub dma: bool,
+}
+
+/// Cdp1861 is an emulation component for the CDP1861.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Hash)]
+pub struct Cdp1861 {
+  // Set by INP 1 and cleared by OUT 1.
+  enabled: bool,
+  // Machine cycle within the current frame.
+  frame_cycle: u32,
+  // Frames completed since reset.
+  frames: u64,
+  // Pixels fetched for each visible line, most significant bit
+  // leftmost.
+  lines: [[u8; BYTES_PER_LINE]; DISPLAY_HEIGHT],
+}
+
+impl Savable for Cdp1861 {
+  fn save(&self, fh: &mut dyn std::io::Write) -> std::io::Result<()> {
+    self.enabled.save(fh)?;
+    self.frame_cycle.save(fh)?;
+    self.frames.save(fh)?;
+    for line in self.lines.iter() {
+      line.save(fh)?;
+    }
+    Ok(())
+  }
+  fn load(&mut self, fh: &mut dyn std::io::Read) -> std::io::Result<()> {
+    self.enabled.load(fh)?;
+    self.frame_cycle.load(fh)?;
+    self.frames.load(fh)?;
+    for line in self.lines.iter_mut() {
+      line.load(fh)?;
+    }
+    Ok(())
+  }
+}
+
+impl Default for Cdp1861 {
+  fn default() -> Self {
+    Cdp1861::new()
+  }
+}
+
+impl Cdp1861 {
+  pub fn new() -> Self {
+    Cdp1861 {
+      enabled: false,
+      frame_cycle: 0,
+      frames: 0,
+      lines: [[0; BYTES_PER_LINE]; DISPLAY_HEIGHT],
+    }
+  }
+
+  /// reset disables the display, and starts a new frame.
+  pub fn reset(&mut self) {
+    *self = Cdp1861 {
+      frames: self.frames,
+      ..Cdp1861::new()
+    }
+  }
+
+  pub fn enabled(&self) -> bool {
+    self.enabled
+  }
+
+  /// set_enabled turns the display on or off, which the VIP does with
+  /// INP 1 and OUT 1.
+  pub fn set_enabled(&mut self, enabled: bool) {
+    self.enabled = enabled
+  }
+
+  /// frames returns the number of frames completed since reset.
+  pub fn frames(&self) -> u64 {
+    self.frames
+  }
+
+  /// frame_cycle returns the machine cycle within the current frame.
+  pub fn frame_cycle(&self) -> u32 {
+    self.frame_cycle
+  }
+
+  /// ef1 returns the level of the EF1 output, which is asserted shortly
+  /// before the start and the end of the visible area so that programs
+  /// can synchronise with the display.
+  pub fn ef1(&self) -> bool {
+    let line = self.frame_cycle / CYCLES_PER_LINE;
+    let end_line = DISPLAY_START_LINE + DISPLAY_HEIGHT as u32;
+    (DISPLAY_START_LINE - EF1_LINES..DISPLAY_START_LINE).contains(&line)
+      || (end_line - EF1_LINES..end_line).contains(&line)
+  }
+
+  /// cycle returns what the CDP1861 does during the current machine
+  /// cycle. Nothing happens while the display is off.
+  pub fn cycle(&self) -> Cycle {
+    if !self.enabled {
+      return Cycle {
+        interrupt: false,
+        dma: false,
+      };
+    }
+    let interrupt =
+      (DISPLAY_START_CYCLE - INTERRUPT_CYCLES..DISPLAY_START_CYCLE).contains(&self.frame_cycle);
+    Cycle {
+      interrupt,
+      dma: self.dma_position().is_some(),
+    }
+  }
+
+  /// dma stores `value`, fetched by the output DMA cycle that `cycle`
+  /// requested, as the next 8 pixels of the current line.
+  pub fn dma(&mut self, value: u8) {
+    if let Some((line, byte)) = self.dma_position() {
+      self.lines[line][byte] = value
+    }
+  }
+
+  /// advance moves on to the next machine cycle, and returns whether a
+  /// frame was completed. A frame with the display off is blank.
+  pub fn advance(&mut self) -> bool {
+    self.frame_cycle += 1;
+    if self.frame_cycle < CYCLES_PER_FRAME {
+      return false;
+    }
+    self.frame_cycle = 0;
+    self.frames += 1;
+    if !self.enabled {
+      self.lines = [[0; BYTES_PER_LINE]; DISPLAY_HEIGHT];
+    }
+    true
+  }
+
+  /// pixel returns whether the pixel at (`x`, `y`) was lit in the last
+  /// DMA of its line. Coordinates outside the display are unlit.
+  pub fn pixel(&self, x: usize, y: usize) -> bool {
+    x < DISPLAY_WIDTH && y < DISPLAY_HEIGHT && self.lines[y][x / 8] & (0x80 >> (x % 8)) != 0
+  }
+
+  // dma_position returns the line and byte fetched by the current
+  // machine cycle, if it is a DMA cycle.
+  fn dma_position(&self) -> Option<(usize, usize)> {
+    let line = (self.frame_cycle / CYCLES_PER_LINE).checked_sub(DISPLAY_START_LINE)? as usize;
+    let byte = (self.frame_cycle % CYCLES_PER_LINE).checked_sub(DMA_START_CYCLE)? as usize;
+    if line < DISPLAY_HEIGHT && byte < BYTES_PER_LINE {
+      Some((line, byte))
+    } else {
+      None
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use crate::interface::serialization;
+
+  #[test]
+  fn serialization_roundtrip_works() -> std::io::Result<()> {
+    let buf = &mut Vec::new();
+    let mut pixie = Cdp1861::new();
+    pixie.set_enabled(true);
+    pixie.advance();
+    pixie.save(buf)?;
+    let loaded = serialization::read_value::<Cdp1861>(&mut buf.as_slice())?;
+    assert_eq!(pixie, loaded);
+    Ok(())
+  }
+
+  #[test]
+  fn frame_has_interrupt_then_dma_for_each_line() {
+    let mut pixie = Cdp1861::new();
+    pixie.set_enabled(true);
+    let mut interrupt_cycles = 0;
+    let mut dma_cycles = 0;
+    let mut first_dma = None;
+    let mut last_interrupt = None;
+    for cycle in 0..CYCLES_PER_FRAME {
+      let action = pixie.cycle();
+      if action.interrupt {
+        interrupt_cycles += 1;
+        last_interrupt = Some(cycle);
+      }
+      if action.dma {
+        dma_cycles += 1;
+        first_dma = first_dma.or(Some(cycle));
+        pixie.dma(0xFF);
+      }
+      assert_eq!(pixie.advance(), cycle == CYCLES_PER_FRAME - 1);
+    }
+    assert_eq!(interrupt_cycles, INTERRUPT_CYCLES);
+    assert_eq!(first_dma.unwrap(), last_interrupt.unwrap() + 1);
+    assert_eq!(dma_cycles, 8 * DISPLAY_HEIGHT);
+    assert!(pixie.pixel(63, 127));
+    assert_eq!(pixie.frames(), 1);
+  }
+
+  #[test]
+  fn disabled_display_is_blank() {
+    let mut pixie = Cdp1861::new();
+    pixie.lines[0][0] = 0xFF;
+    for _ in 0..CYCLES_PER_FRAME {
+      assert!(!pixie.cycle().interrupt && !pixie.cycle().dma);
+      pixie.advance();
+    }
+    assert!(!pixie.pixel(0, 0));
+  }
+}
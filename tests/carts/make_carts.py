@@ -0,0 +1,113 @@
+#!/usr/bin/env python3
+"""Writes the sample Octo cartridges used by chip8::cartridge's tests.
+
+Cartridges are laid out as Octo saves them: a 16-colour GIF whose
+palette indices are a label colour in the high two bits and two bits of
+the payload in the low two. The payload is its length as a big-endian
+32-bit number, followed by the JSON of the program and its options.
+
+Run from this directory: python3 make_carts.py
+"""
+
+import json
+import struct
+
+WIDTH = 64
+
+# Octo's palette: four label colours, each in four shades.
+PALETTE = [
+    bytes((label * 0x55, shade * 0x11, shade * 0x11))
+    for label in range(4)
+    for shade in range(4)
+]
+
+COUNT = """# Counts up in v0, forever.
+: main
+  v0 := 0
+  loop
+    v0 += 1
+  again
+"""
+
+FLAGS = """# Draws a smiley with the flags Octo was set to.
+: face 0x3C 0x42 0xA5 0x81 0xA5 0x99 0x42 0x3C
+: main
+  i := face
+  v0 := 28
+  v1 := 12
+  sprite v0 v1 8
+  loop again
+"""
+
+FLAG_OPTIONS = {
+    "tickrate": 200,
+    "fillColor": "#FFFFFF",
+    "backgroundColor": "#000000",
+    "shiftQuirks": True,
+    "loadStoreQuirks": True,
+    "vfOrderQuirks": True,
+    "clipQuirks": True,
+    "jumpQuirks": True,
+    "vBlankQuirks": False,
+    "logicQuirks": False,
+    "screenRotation": 90,
+    "maxSize": 3583,
+}
+
+
+def lzw(indices):
+    """Compresses indices with 4-bit LZW, as single-pixel codes.
+
+    A clear code is sent before the table grows past 5-bit codes, so
+    that every code is 5 bits."""
+    clear, end = 16, 17
+    codes = []
+    for i, index in enumerate(indices):
+        if i % 12 == 0:
+            codes.append(clear)
+        codes.append(index)
+    codes.append(end)
+    data, bits, count = bytearray(), 0, 0
+    for code in codes:
+        bits |= code << count
+        count += 5
+        while count >= 8:
+            data.append(bits & 0xFF)
+            bits >>= 8
+            count -= 8
+    if count:
+        data.append(bits)
+    return bytes(data)
+
+
+def cartridge(program, options):
+    payload = json.dumps({"program": program, "options": options}).encode()
+    payload = struct.pack(">I", len(payload)) + payload
+    indices = []
+    for byte in payload:
+        for shift in (6, 4, 2, 0):
+            indices.append((byte >> shift) & 3)
+    height = -(-len(indices) // WIDTH)
+    indices += [0] * (WIDTH * height - len(indices))
+    # Label the image with diagonal stripes.
+    for i in range(len(indices)):
+        x, y = i % WIDTH, i // WIDTH
+        indices[i] |= ((x + y) // 8 % 4) << 2
+
+    data = lzw(indices)
+    gif = bytearray(b"GIF89a")
+    gif += struct.pack("<HHBBB", WIDTH, height, 0xF3, 0, 0)
+    gif += b"".join(PALETTE)
+    gif += b"\x2C" + struct.pack("<HHHHB", 0, 0, WIDTH, height, 0)
+    gif += b"\x04"
+    for i in range(0, len(data), 255):
+        block = data[i:i + 255]
+        gif += bytes((len(block),)) + block
+    gif += b"\x00\x3B"
+    return bytes(gif)
+
+
+with open("count.gif", "wb") as f:
+    f.write(cartridge(COUNT, {}))
+with open("flags.gif", "wb") as f:
+    f.write(cartridge(FLAGS, FLAG_OPTIONS))
@@ -0,0 +1,41 @@
+//! frontend runs the SDL frontend under SDL's dummy video and audio
+//! drivers.
+
+#![cfg(feature = "sdl")]
+
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn run_chip8(rom: &PathBuf, frames: u32) -> Output {
+  Command::new(env!("CARGO_BIN_EXE_chip8"))
+    .arg("--rom")
+    .arg(rom)
+    .args(["--frames", &frames.to_string()])
+    .env("SDL_VIDEODRIVER", "dummy")
+    .env("SDL_AUDIODRIVER", "dummy")
+    .output()
+    .unwrap()
+}
+
+#[test]
+fn plays_frames_and_quits() {
+  let rom = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+    .join("games")
+    .join("PONG");
+  let output = run_chip8(&rom, 30);
+  assert!(
+    output.status.success(),
+    "{}",
+    String::from_utf8_lossy(&output.stderr)
+  );
+}
+
+#[test]
+fn faults_exit_with_error() {
+  // A return with nothing on the stack.
+  let rom = std::env::temp_dir().join("chip8-frontend-fault.ch8");
+  std::fs::write(&rom, [0x00, 0xEE]).unwrap();
+  let output = run_chip8(&rom, 30);
+  assert!(!output.status.success());
+  assert!(String::from_utf8_lossy(&output.stderr).contains("CPU fault"));
+}
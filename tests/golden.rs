@@ -0,0 +1,183 @@
+//! golden runs each ROM in `games/` for a number of frames, with a fixed
+//! seed, the quirks and speed its database entry gives it and a script
+//! of key presses, and checks the display at chosen frames against the
+//! golden files in `tests/golden/`. Some ROMs are also run with other
+//! quirk profiles, so that each profile draws a checked display.
+//!
+//! After a change that is meant to alter what the ROMs draw, the golden
+//! files are regenerated with:
+//!
+//! ```text
+//! UPDATE_GOLDENS=1 cargo test --test golden
+//! ```
+
+use rustyemulator::chip8::display::{Display, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use rustyemulator::chip8::keyscript::KeyScript;
+use rustyemulator::chip8::launch::{self, Options};
+use std::fmt::Write;
+use std::fs;
+use std::path::PathBuf;
+
+// Seed of the random number generator for every ROM.
+const SEED: u32 = 1;
+
+// Case is how one ROM is run.
+struct Case {
+  rom: &'static str,
+  // Quirk profile to run with, rather than the database entry's quirks
+  // and speed.
+  quirks: Option<&'static str>,
+  // Frames after which the display is checked, in increasing order.
+  frames: &'static [u32],
+  // Key script, in the format read by `KeyScript`.
+  keys: &'static str,
+}
+
+// render returns the text rendering of `display`, with set pixels as `#`.
+fn render(display: &Display) -> String {
+  let mut text = String::new();
+  for y in 0..DISPLAY_HEIGHT {
+    for x in 0..DISPLAY_WIDTH {
+      text.push(if display.pixel(x, y) { '#' } else { '.' });
+    }
+    text.push('\n');
+  }
+  text
+}
+
+// run returns the golden file contents for `case`: a header, then the
+// rendering of the display at each of its frames.
+fn run(case: &Case) -> String {
+  let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+  let keys: KeyScript = case.keys.parse().unwrap();
+  let options = Options {
+    path: root.join("games").join(case.rom).display().to_string(),
+    quirks: case.quirks.map(|name| launch::parse_quirks(name).unwrap()),
+    database: true,
+    seed: SEED,
+    ..Options::default()
+  };
+  let (mut chip8, _) = launch::load(&options).unwrap();
+
+  let mut text = String::new();
+  writeln!(
+    text,
+    "# {} with {} quirks and seed {}",
+    case.rom,
+    case.quirks.unwrap_or("database"),
+    SEED
+  )
+  .unwrap();
+  let mut frame = 0;
+  for &until in case.frames {
+    while frame < until {
+      keys.apply(frame, &mut chip8);
+      if let Err(err) = chip8.run_frame() {
+        panic!("{} faulted in frame {}: {}", case.rom, frame, err);
+      }
+      frame += 1;
+    }
+    writeln!(text, "\nframe {}", frame).unwrap();
+    text.push_str(&render(chip8.display()));
+  }
+  text
+}
+
+// check compares the run of `case` with its golden file, or writes the
+// golden file when UPDATE_GOLDENS is set.
+fn check(case: &Case) {
+  let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+    .join("tests")
+    .join("golden")
+    .join(match case.quirks {
+      Some(quirks) => format!("{}-{}.txt", case.rom, quirks),
+      None => format!("{}.txt", case.rom),
+    });
+  let actual = run(case);
+  if std::env::var_os("UPDATE_GOLDENS").is_some() {
+    fs::write(&path, actual).unwrap();
+    return;
+  }
+  let expected = fs::read_to_string(&path).unwrap_or_else(|err| {
+    panic!(
+      "Failed to read {}: {}. Run with UPDATE_GOLDENS=1 to create it.",
+      path.display(),
+      err
+    )
+  });
+  if actual != expected {
+    // Report the first frame whose display differs.
+    let frames = |text: &str| -> Vec<String> { text.split("\nframe ").map(String::from).collect() };
+    let (actual, expected) = (frames(&actual), frames(&expected));
+    let differs = actual
+      .iter()
+      .zip(expected.iter())
+      .position(|(actual, expected)| actual != expected)
+      .unwrap_or_else(|| actual.len().min(expected.len()));
+    panic!(
+      "{} differs from {} at:\nframe {}\nExpected:\nframe {}\nRun with UPDATE_GOLDENS=1 if the change is intended.",
+      case.rom,
+      path.display(),
+      actual.get(differs).map_or("(missing)", String::as_str),
+      expected.get(differs).map_or("(missing)", String::as_str),
+    );
+  }
+}
+
+macro_rules! golden {
+  ($($name:ident: $rom:expr, $quirks:tt, $frames:expr, $keys:expr;)*) => {
+    $(
+      #[test]
+      fn $name() {
+        check(&Case {
+          rom: $rom,
+          quirks: golden!(@quirks $quirks),
+          frames: $frames,
+          keys: $keys,
+        });
+      }
+    )*
+  };
+  (@quirks database) => { None };
+  (@quirks $quirks:literal) => { Some($quirks) };
+}
+
+golden! {
+  fifteen_puzzle: "15PUZZLE", database, &[120, 600],
+    "200 press 6\n204 release 6\n260 press 9\n264 release 9\n320 press E\n324 release E";
+  blinky: "BLINKY", database, &[1200, 2400],
+    "1300 press 8\n1400 release 8\n1420 press 3\n1600 release 3";
+  blitz: "BLITZ", database, &[120, 600], "150 press 5\n154 release 5";
+  brix: "BRIX", database, &[120, 600], "60 press 4\n200 release 4\n260 press 6\n380 release 6";
+  connect4: "CONNECT4", database, &[120, 600],
+    "150 press 6\n154 release 6\n180 press 5\n184 release 5\n300 press 4\n304 release 4\n330 press 5\n334 release 5";
+  guess: "GUESS", database, &[120, 600], "150 press 5\n154 release 5\n250 press 0\n254 release 0";
+  hidden: "HIDDEN", database, &[120, 600],
+    "150 press 5\n154 release 5\n250 press 6\n254 release 6\n300 press 5\n304 release 5";
+  ibm: "IBM", database, &[60], "";
+  invaders: "INVADERS", database, &[300, 900],
+    "350 press 5\n354 release 5\n500 press 4\n560 release 4\n600 press 5\n604 release 5";
+  kaleid: "KALEID", database, &[120, 600],
+    "60 press 2\n90 release 2\n100 press 6\n130 release 6\n140 press 8\n170 release 8\n200 press 0\n204 release 0";
+  maze: "MAZE", database, &[30, 300], "";
+  merlin: "MERLIN", database, &[120, 600], "300 press 4\n304 release 4";
+  missile: "MISSILE", database, &[120, 600], "150 press 8\n154 release 8\n300 press 8\n304 release 8";
+  pong: "PONG", database, &[120, 600], "60 press 1\n180 release 1\n240 press D\n360 release D";
+  pong2: "PONG2", database, &[120, 600], "60 press 4\n180 release 4\n240 press C\n360 release C";
+  puzzle: "PUZZLE", database, &[120, 600], "300 press 6\n304 release 6\n400 press 2\n404 release 2";
+  syzygy: "SYZYGY", database, &[120, 600], "150 press F\n154 release F\n300 press 3\n360 release 3";
+  tank: "TANK", database, &[120, 600], "60 press 8\n120 release 8\n200 press 6\n260 release 6\n300 press 5\n304 release 5";
+  tetris: "TETRIS", database, &[120, 600], "100 press 6\n104 release 6\n200 press 4\n204 release 4\n300 press 7\n400 release 7";
+  tictac: "TICTAC", database, &[120, 600], "150 press 5\n154 release 5\n300 press 1\n304 release 1";
+  ufo: "UFO", database, &[120, 600], "150 press 5\n154 release 5\n350 press 4\n354 release 4";
+  vbrix: "VBRIX", database, &[120, 600], "150 press 7\n154 release 7\n200 press 1\n300 release 1";
+  vers: "VERS", database, &[120, 600], "150 press 7\n154 release 7\n200 press 1\n300 release 1";
+  wipeoff: "WIPEOFF", database, &[120, 600], "60 press 4\n200 release 4\n260 press 6\n380 release 6";
+
+  // The same ROMs with the other quirk profiles.
+  brix_modern: "BRIX", "modern", &[120, 600], "60 press 4\n200 release 4\n260 press 6\n380 release 6";
+  invaders_chip48: "INVADERS", "chip48", &[300, 900],
+    "350 press 5\n354 release 5\n500 press 4\n560 release 4\n600 press 5\n604 release 5";
+  blinky_schip: "BLINKY", "schip", &[1200, 2400],
+    "1300 press 8\n1400 release 8\n1420 press 3\n1600 release 3";
+}
@@ -0,0 +1,97 @@
+//! headless runs chip8-headless on small ROMs and checks what it writes.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+// A ROM that waits for a key, then draws its digit.
+const KEY_DIGIT: [u8; 8] = [0xF0, 0x0A, 0xF0, 0x29, 0xD0, 0x15, 0x12, 0x06];
+
+// temp_dir returns an empty directory for the test `name`.
+fn temp_dir(name: &str) -> PathBuf {
+  let dir = std::env::temp_dir().join(format!("chip8-headless-{}", name));
+  let _ = std::fs::remove_dir_all(&dir);
+  std::fs::create_dir_all(&dir).unwrap();
+  dir
+}
+
+fn run_headless(rom: &Path, args: &[&str]) -> Output {
+  Command::new(env!("CARGO_BIN_EXE_chip8-headless"))
+    .arg("--rom")
+    .arg(rom)
+    .args(args)
+    .output()
+    .unwrap()
+}
+
+fn assert_success(output: &Output) {
+  assert!(
+    output.status.success(),
+    "{}",
+    String::from_utf8_lossy(&output.stderr)
+  );
+}
+
+#[test]
+fn faults_exit_with_error() {
+  let dir = temp_dir("fault");
+  // A machine code routine, which can't be run.
+  let rom = dir.join("fault.ch8");
+  std::fs::write(&rom, [0x00, 0x00]).unwrap();
+  let output = run_headless(&rom, &["--frames", "10"]);
+  assert_eq!(output.status.code(), Some(1));
+  assert!(String::from_utf8_lossy(&output.stderr).contains("CPU fault in frame 0"));
+}
+
+#[test]
+fn screenshots_are_numbered_by_frame() {
+  let dir = temp_dir("screenshots");
+  let rom = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+    .join("games")
+    .join("IBM");
+  let pattern = dir.join("shot-{frame}.pbm");
+  let output = run_headless(
+    &rom,
+    &[
+      "--frames",
+      "20",
+      "--screenshot",
+      pattern.to_str().unwrap(),
+      "--at",
+      "1,20",
+    ],
+  );
+  assert_success(&output);
+  let mut written: Vec<_> = std::fs::read_dir(&dir)
+    .unwrap()
+    .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+    .collect();
+  written.sort();
+  assert_eq!(written, vec!["shot-1.pbm", "shot-20.pbm"]);
+  for name in &written {
+    let image = std::fs::read(dir.join(name)).unwrap();
+    assert!(image.starts_with(b"P4\n64 32\n"), "{} is not a PBM", name);
+  }
+}
+
+#[test]
+fn key_scripts_press_keys() {
+  let dir = temp_dir("keys");
+  let rom = dir.join("key-digit.ch8");
+  std::fs::write(&rom, KEY_DIGIT).unwrap();
+  let keys = dir.join("digit.keys");
+  std::fs::write(&keys, "1 press 5\n3 release 5\n").unwrap();
+  let screenshot = |name: &str, extra: &[&str]| {
+    let path = dir.join(name);
+    let mut args = vec!["--frames", "10", "--screenshot", path.to_str().unwrap()];
+    args.extend_from_slice(extra);
+    assert_success(&run_headless(&rom, &args));
+    std::fs::read(path).unwrap()
+  };
+
+  let waiting = screenshot("waiting.pbm", &[]);
+  let pressed = screenshot("pressed.pbm", &["--keys", keys.to_str().unwrap()]);
+  // Without the key the display stays clear.
+  let pixels = &waiting[b"P4\n64 32\n".len()..];
+  assert!(pixels.iter().all(|&byte| byte == pixels[0]));
+  assert_ne!(waiting, pressed);
+}
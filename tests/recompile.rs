@@ -0,0 +1,56 @@
+//! recompile recompiles a ROM with chip8-recompile, then builds the
+//! generated crate and checks it against the interpreter.
+//!
+//! Building the crate takes a while, so it only runs when asked for:
+//!
+//! ```bash
+//! cargo test --test recompile -- --ignored
+//! ```
+
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn assert_success(output: &Output) {
+  assert!(
+    output.status.success(),
+    "{}{}",
+    String::from_utf8_lossy(&output.stdout),
+    String::from_utf8_lossy(&output.stderr)
+  );
+}
+
+#[test]
+#[ignore]
+fn recompiled_crates_match_the_interpreter() {
+  let library = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+  let rom = library.join("games").join("BLINKY");
+  let output = std::env::temp_dir().join("chip8-recompile-blinky");
+  let _ = std::fs::remove_dir_all(&output);
+
+  assert_success(
+    &Command::new(env!("CARGO_BIN_EXE_chip8-recompile"))
+      .arg("--rom")
+      .arg(&rom)
+      .arg("--output")
+      .arg(&output)
+      .arg("--library")
+      .arg(&library)
+      .output()
+      .unwrap(),
+  );
+
+  let cargo = std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+  let run = Command::new(cargo)
+    .arg("run")
+    .arg("--quiet")
+    .arg("--manifest-path")
+    .arg(output.join("Cargo.toml"))
+    .args(["--", "--compare", "--frames", "300"])
+    .output()
+    .unwrap();
+  assert_success(&run);
+  assert_eq!(
+    String::from_utf8_lossy(&run.stdout),
+    "Matched the interpreter for 300 frames.\n"
+  );
+}